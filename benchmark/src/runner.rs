@@ -171,6 +171,7 @@ impl CommandRunner {
                 self.args.endpoints.clone(),
                 self.args.use_curp,
                 ClientTimeout::default(),
+                None,
             )
             .await?;
             clients.push(client);
@@ -0,0 +1,134 @@
+//! Compare the conflict detection of the command workers and the speculative pool on the key
+//! interval index with the linear scan over all the in-flight commands, at thousands of in-flight
+//! commands. Commands that are not keyed are still checked by the linear scan, which was the only
+//! way before the index was introduced.
+
+#![allow(missing_docs)] // criterion macros generate undocumented items
+
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use curp::{
+    cmd::{Command, ConflictCheck, ProposeId},
+    server::bench::{Filter, SpecPool},
+};
+use serde::{Deserialize, Serialize};
+
+/// Command writing some keys, it conflicts with the commands writing any of its keys
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct BenchCommand {
+    /// Propose id
+    id: ProposeId,
+    /// Written keys
+    keys: Vec<u32>,
+    /// Whether the command is looked up in the index
+    keyed: bool,
+}
+
+impl BenchCommand {
+    fn new(id: String, key: u32, keyed: bool) -> Arc<Self> {
+        Arc::new(Self {
+            id: ProposeId::new(id),
+            keys: vec![key],
+            keyed,
+        })
+    }
+}
+
+impl ConflictCheck for BenchCommand {
+    fn is_conflict(&self, other: &Self) -> bool {
+        self.id == other.id || self.keys.iter().any(|key| other.keys.contains(key))
+    }
+}
+
+impl Command for BenchCommand {
+    type K = u32;
+
+    type ER = ();
+
+    type ASR = ();
+
+    fn keys(&self) -> &[Self::K] {
+        &self.keys
+    }
+
+    fn id(&self) -> &ProposeId {
+        &self.id
+    }
+
+    fn is_keyed(&self) -> bool {
+        self.keyed
+    }
+}
+
+/// Generate in-flight commands writing the even keys, so none of them conflict with each other
+fn in_flight_cmds(n: u32) -> Vec<Arc<BenchCommand>> {
+    (0..n)
+        .map(|i| BenchCommand::new(format!("in-flight-{i}"), i * 2, true))
+        .collect()
+}
+
+/// Generate new commands writing the odd keys, so they have to be checked against all the
+/// in-flight commands without an index
+fn new_cmds(n: u32, keyed: bool) -> Vec<Arc<BenchCommand>> {
+    (0..100)
+        .map(|i| BenchCommand::new(format!("new-{i}"), i * n / 50 + 1, keyed))
+        .collect()
+}
+
+/// Name of the benchmark of the way the conflicts are detected
+fn lookup(keyed: bool) -> &'static str {
+    if keyed {
+        "interval_index"
+    } else {
+        "linear_scan"
+    }
+}
+
+fn bench_filter_insert_new_vertex(c: &mut Criterion) {
+    let mut group = c.benchmark_group("filter_insert_new_vertex");
+    for n in [1_000, 4_000, 16_000] {
+        let in_flight = in_flight_cmds(n);
+        for keyed in [false, true] {
+            let cmds = new_cmds(n, keyed);
+            let _ig = group.bench_with_input(BenchmarkId::new(lookup(keyed), n), &n, |b, _| {
+                b.iter_batched(
+                    || Filter::new(in_flight.iter().cloned()),
+                    |mut filter| {
+                        for cmd in &cmds {
+                            filter.insert_new_vertex(Arc::clone(cmd));
+                        }
+                        filter
+                    },
+                    BatchSize::LargeInput,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_spec_pool_has_conflict_with(c: &mut Criterion) {
+    let mut group = c.benchmark_group("spec_pool_has_conflict_with");
+    for n in [1_000, 4_000, 16_000] {
+        let pool = SpecPool::new(in_flight_cmds(n));
+        for keyed in [false, true] {
+            let cmds = new_cmds(n, keyed);
+            let _ig = group.bench_with_input(BenchmarkId::new(lookup(keyed), n), &n, |b, _| {
+                b.iter(|| {
+                    for cmd in &cmds {
+                        let _ig = black_box(pool.has_conflict_with(cmd));
+                    }
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_filter_insert_new_vertex,
+    bench_spec_pool_has_conflict_with
+);
+criterion_main!(benches);
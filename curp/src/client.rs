@@ -1,20 +1,31 @@
-use std::{cmp::Ordering, collections::HashMap, fmt::Debug, iter, marker::PhantomData, sync::Arc};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Debug,
+    iter,
+    marker::PhantomData,
+    sync::Arc,
+};
 
 use event_listener::Event;
 use futures::{pin_mut, stream::FuturesUnordered, StreamExt};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use prometheus::{IntCounterVec, Opts, Registry};
 use tokio::{sync::broadcast, time::timeout};
+use tonic::transport::ClientTlsConfig;
 use tracing::{debug, instrument, warn};
 use utils::{config::ClientTimeout, parking_lot_lock::RwLockMap};
 
 use crate::{
-    cmd::Command,
+    cmd::{ClientSession, Command, ProposeId},
     error::ProposeError,
+    members::{ConfChange, ConfChangeEntry},
     message::ServerId,
     rpc::{
         self,
         connect::{Connect, ConnectApi},
-        FetchLeaderRequest, ProposeRequest, SyncError, SyncResult, WaitSyncedRequest,
+        FetchLeaderRequest, ProposeConfChangeRequest, ProposeRequest, SyncError, SyncResult,
+        WaitSyncedRequest,
     },
 };
 
@@ -22,10 +33,18 @@ use crate::{
 pub struct Client<C: Command> {
     /// Current leader and term
     state: RwLock<State>,
-    /// All servers's `Connect`
-    connects: HashMap<ServerId, Arc<Connect>>,
+    /// All servers's `Connect`, updated on membership changes
+    connects: RwLock<HashMap<ServerId, Arc<Connect>>>,
+    /// Learners in the cluster, they are not counted in any quorum
+    learners: RwLock<HashSet<ServerId>>,
+    /// Session of the client
+    session: Mutex<Session>,
     /// Curp client timeout settings
     timeout: ClientTimeout,
+    /// The tls config used to connect to the servers
+    tls_config: Option<ClientTlsConfig>,
+    /// Metrics of the client
+    metrics: Metrics,
     /// To keep Command type
     phantom: PhantomData<C>,
 }
@@ -35,6 +54,8 @@ impl<C: Command> Debug for Client<C> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Client")
             .field("state", &self.state)
+            .field("learners", &self.learners)
+            .field("session", &self.session)
             .field("timeout", &self.timeout)
             .finish()
     }
@@ -83,33 +104,122 @@ impl State {
     }
 }
 
+/// Session of a client, proposals with the ids generated in it are applied exactly once
+#[derive(Debug)]
+struct Session {
+    /// Id of the client, it's randomly generated
+    client_id: u64,
+    /// Sequence number of the next proposal
+    next_seq_num: u64,
+    /// Sequence numbers of the proposals that haven't completed
+    incomplete: BTreeSet<u64>,
+}
+
+impl Session {
+    /// Create a new session
+    fn new() -> Self {
+        Self {
+            client_id: rand::random(),
+            next_seq_num: 0,
+            incomplete: BTreeSet::new(),
+        }
+    }
+
+    /// Generate a new propose id in the session
+    fn gen_propose_id(&mut self) -> ProposeId {
+        let seq_num = self.next_seq_num;
+        self.next_seq_num = seq_num.wrapping_add(1);
+        let _ig = self.incomplete.insert(seq_num);
+        ProposeId::new_in_session(ClientSession {
+            client_id: self.client_id,
+            seq_num,
+            first_incomplete: self.incomplete.first().copied().unwrap_or(seq_num),
+        })
+    }
+
+    /// Mark a proposal completed, so that the server can discard its result
+    fn complete(&mut self, id: &ProposeId) {
+        if let Some(session) = id.session() {
+            if session.client_id == self.client_id {
+                let _ig = self.incomplete.remove(&session.seq_num);
+            }
+        }
+    }
+}
+
+/// Completes a proposal in the session when dropped, so a canceled proposal completes as well
+struct ProposalGuard<'a> {
+    /// The session
+    session: &'a Mutex<Session>,
+    /// Id of the proposal
+    id: ProposeId,
+}
+
+impl Drop for ProposalGuard<'_> {
+    fn drop(&mut self) {
+        self.session.lock().complete(&self.id);
+    }
+}
+
 impl<C> Client<C>
 where
     C: Command + 'static,
 {
-    /// Create a new protocol client based on the addresses
+    /// Create a new protocol client based on the addresses, the client connects to the servers
+    /// through tls if `tls_config` is set
     #[inline]
-    pub async fn new(addrs: HashMap<ServerId, String>, timeout: ClientTimeout) -> Self {
+    pub async fn new(
+        addrs: HashMap<ServerId, String>,
+        timeout: ClientTimeout,
+        tls_config: Option<ClientTlsConfig>,
+    ) -> Self {
         Self {
             state: RwLock::new(State::new()),
-            connects: rpc::connect(addrs, None).await,
+            connects: RwLock::new(rpc::connect(addrs, None, tls_config.clone()).await),
+            learners: RwLock::new(HashSet::new()),
+            session: Mutex::new(Session::new()),
             timeout,
+            tls_config,
+            metrics: Metrics::new(),
             phantom: PhantomData,
         }
     }
 
+    /// Get the registry of the client metrics
+    #[inline]
+    #[must_use]
+    pub fn metrics_registry(&self) -> Registry {
+        self.metrics.registry.clone()
+    }
+
+    /// Get the `Connect` of a server
+    fn get_connect(&self, id: &ServerId) -> Option<Arc<Connect>> {
+        self.connects.read().get(id).map(Arc::clone)
+    }
+
+    /// Get the `Connect`s of all voters, learners are excluded
+    fn voter_connects(&self) -> Vec<Arc<Connect>> {
+        let learners_r = self.learners.read();
+        self.connects
+            .read()
+            .iter()
+            .filter(|&(id, _)| !learners_r.contains(id))
+            .map(|(_, connect)| Arc::clone(connect))
+            .collect()
+    }
+
     /// The fast round of Curp protocol
-    /// It broadcast the requests to all the curp servers.
+    /// It broadcast the requests to all the voters, learners don't take part in the fast path.
     #[instrument(skip(self))]
     async fn fast_round(
         &self,
         cmd_arc: Arc<C>,
     ) -> Result<(Option<<C as Command>::ER>, bool), ProposeError> {
-        let max_fault = self.connects.len().wrapping_div(2);
+        let connects = self.voter_connects();
+        let max_fault = connects.len().wrapping_div(2);
         let req = ProposeRequest::new(cmd_arc.as_ref())?;
-        let mut rpcs: FuturesUnordered<_> = self
-            .connects
-            .values()
+        let mut rpcs: FuturesUnordered<_> = connects
+            .iter()
             .zip(iter::repeat(req))
             .map(|(connect, req_cloned)| {
                 connect.propose(req_cloned, *self.timeout.propose_timeout())
@@ -202,11 +312,14 @@ where
                 }
             };
 
+            let Some(connect) = self.get_connect(&leader_id) else {
+                // the leader joined the cluster recently, wait for the membership change to reach the client
+                warn!("leader {leader_id} is unknown to the client");
+                tokio::time::sleep(retry_timeout).await;
+                continue;
+            };
             debug!("wait synced request sent to {}", leader_id);
-            let resp = match self
-                .connects
-                .get(&leader_id)
-                .unwrap_or_else(|| unreachable!("leader {leader_id} not found"))
+            let resp = match connect
                 .wait_synced(
                     WaitSyncedRequest::new(cmd.id())?,
                     *self.timeout.wait_synced_timeout(),
@@ -263,12 +376,13 @@ where
             } else {
                 self.fetch_leader().await
             };
+            let Some(connect) = self.get_connect(&leader_id) else {
+                warn!("leader {leader_id} is unknown to the client");
+                continue;
+            };
             debug!("resend propose to {leader_id}");
 
-            let resp = self
-                .connects
-                .get(&leader_id)
-                .unwrap_or_else(|| unreachable!("leader {leader_id} not found"))
+            let resp = connect
                 .propose(
                     ProposeRequest::new(cmd.as_ref())?,
                     *self.timeout.propose_timeout(),
@@ -325,13 +439,13 @@ where
         }
     }
 
-    /// Send fetch leader requests to all servers until there is a leader
+    /// Send fetch leader requests to all voters until there is a leader
     /// Note: The fetched leader may still be outdated
     async fn fetch_leader(&self) -> ServerId {
         loop {
-            let mut rpcs: FuturesUnordered<_> = self
-                .connects
-                .values()
+            let connects = self.voter_connects();
+            let mut rpcs: FuturesUnordered<_> = connects
+                .iter()
                 .map(|connect| async {
                     (
                         connect.id().clone(),
@@ -346,7 +460,7 @@ where
 
             let mut ok_cnt = 0;
             #[allow(clippy::integer_arithmetic)]
-            let majority_cnt = self.connects.len() / 2 + 1;
+            let majority_cnt = connects.len() / 2 + 1;
             while let Some((id, resp)) = rpcs.next().await {
                 let resp = match resp {
                     Ok(resp) => resp.into_inner(),
@@ -398,6 +512,7 @@ where
     #[inline]
     #[allow(clippy::too_many_lines)] // FIXME: split to smaller functions
     pub async fn propose(&self, cmd: C) -> Result<C::ER, ProposeError> {
+        let _guard = self.proposal_guard(cmd.id());
         let cmd_arc = Arc::new(cmd);
         let fast_round = self.fast_round(Arc::clone(&cmd_arc));
         let slow_round = self.slow_round(cmd_arc);
@@ -410,18 +525,24 @@ where
             futures::future::Either::Left((fast_result, slow_round)) => {
                 let (fast_er, success) = fast_result?;
                 if success {
+                    self.metrics.on_propose_completed(true);
                     #[allow(clippy::unwrap_used)]
                     // when success is true fast_er must be Some
                     Ok(fast_er.unwrap())
                 } else {
                     let (_asr, er) = slow_round.await?;
+                    self.metrics.on_propose_completed(false);
                     Ok(er)
                 }
             }
             futures::future::Either::Right((slow_result, fast_round)) => match slow_result {
-                Ok((_asr, er)) => Ok(er),
+                Ok((_asr, er)) => {
+                    self.metrics.on_propose_completed(false);
+                    Ok(er)
+                }
                 Err(e) => {
                     if let Ok((Some(er), true)) = fast_round.await {
+                        self.metrics.on_propose_completed(true);
                         return Ok(er);
                     }
                     Err(e)
@@ -441,6 +562,7 @@ where
     #[inline]
     #[allow(clippy::else_if_without_else)] // the else is redundant
     pub async fn propose_indexed(&self, cmd: C) -> Result<(C::ER, C::ASR), ProposeError> {
+        let _guard = self.proposal_guard(cmd.id());
         let cmd_arc = Arc::new(cmd);
         let fast_round = self.fast_round(Arc::clone(&cmd_arc));
         let slow_round = self.slow_round(cmd_arc);
@@ -449,11 +571,97 @@ where
         let (_fast_result, slow_result) = tokio::join!(fast_round, slow_round);
 
         match slow_result {
-            Ok((asr, er)) => Ok((er, asr)),
+            Ok((asr, er)) => {
+                self.metrics.on_propose_completed(false);
+                Ok((er, asr))
+            }
             Err(e) => Err(e),
         }
     }
 
+    /// Propose a membership change to the leader and wait for it to take effect
+    /// # Errors
+    ///   `ProposeError::InvalidConfChange` if the change is rejected by the leader
+    ///   `ProposeError::RpcError` rpc error met, usually it's network error
+    ///   `ProposeError::EncodeError` if the change can't be serialized
+    #[inline]
+    pub async fn propose_conf_change(
+        &self,
+        id: ProposeId,
+        change: ConfChange,
+    ) -> Result<(), ProposeError> {
+        let _guard = self.proposal_guard(&id);
+        let req = ProposeConfChangeRequest::new(&ConfChangeEntry::new(id, change))?;
+        let retry_timeout = *self.timeout.retry_timeout();
+        loop {
+            let leader_id = if let Some(id) = self.leader() {
+                id
+            } else {
+                self.fetch_leader().await
+            };
+            let Some(connect) = self.get_connect(&leader_id) else {
+                return Err(ProposeError::ProtocolError(format!(
+                    "leader {leader_id} is unknown to the client"
+                )));
+            };
+            debug!("propose conf change to {leader_id}");
+
+            let resp = match connect
+                .propose_conf_change(req.clone(), *self.timeout.wait_synced_timeout())
+                .await
+            {
+                Ok(resp) => resp.into_inner(),
+                Err(e) => {
+                    warn!("propose conf change rpc error: {e}");
+                    // the leader may have crashed, fetch the leader again
+                    tokio::time::sleep(retry_timeout).await;
+                    let _leader = self.fetch_leader().await;
+                    continue;
+                }
+            };
+
+            match resp.error()? {
+                None => return Ok(()),
+                Some(ProposeError::NotLeader) => {
+                    // the leader has changed, retry with the new leader
+                    let new_leader = resp.leader_id.filter(|id| id != &leader_id);
+                    let updated = self.state.map_write(|mut state| match new_leader {
+                        Some(id) if state.term <= resp.term => {
+                            state.update_to_term(resp.term);
+                            state.set_leader(id);
+                            true
+                        }
+                        Some(_) | None => {
+                            state.leader = None;
+                            false
+                        }
+                    });
+                    if !updated {
+                        // wait for the election to complete
+                        tokio::time::sleep(retry_timeout).await;
+                    }
+                }
+                Some(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Generate a new `ProposeId` in the session of the client, a cmd with it is applied exactly
+    /// once even if it's retried. The id should be proposed, otherwise the servers will keep the
+    /// results of the proposals after it.
+    #[inline]
+    pub fn gen_propose_id(&self) -> ProposeId {
+        self.session.lock().gen_propose_id()
+    }
+
+    /// Get a guard that completes the proposal in the session when the proposal finishes
+    fn proposal_guard(&self, id: &ProposeId) -> ProposalGuard<'_> {
+        ProposalGuard {
+            session: &self.session,
+            id: id.clone(),
+        }
+    }
+
     /// Get the current leader.
     #[inline]
     pub fn leader(&self) -> Option<ServerId> {
@@ -465,6 +673,75 @@ where
     pub fn leader_rx(&self) -> broadcast::Receiver<ServerId> {
         self.state.read().leader_tx.subscribe()
     }
+
+    /// Connect to a new server, or reconnect to a server whose address has changed
+    #[inline]
+    pub async fn add_server(&self, id: ServerId, address: String) {
+        let new_connects = rpc::connect(
+            HashMap::from([(id, address)]),
+            None,
+            self.tls_config.clone(),
+        )
+        .await;
+        self.connects.write().extend(new_connects);
+    }
+
+    /// Disconnect from a server that has been removed from the cluster
+    #[inline]
+    pub fn remove_server(&self, id: &ServerId) {
+        let _ig = self.connects.write().remove(id);
+        let _ig = self.learners.write().remove(id);
+    }
+
+    /// Set the learners of the cluster, proposals will not wait for them
+    #[inline]
+    pub fn set_learners(&self, learners: HashSet<ServerId>) {
+        *self.learners.write() = learners;
+    }
+}
+
+/// Label value of the proposals completed on the fast path, whose speculative execution results
+/// are accepted by a superquorum
+const FAST_PATH: &str = "fast";
+
+/// Label value of the proposals completed on the slow path, which wait for the after sync
+const SLOW_PATH: &str = "slow";
+
+/// Metrics of a client
+struct Metrics {
+    /// The registry which all the metrics are registered to
+    registry: Registry,
+    /// Proposals completed by the client, labelled with the path they complete on
+    proposals: IntCounterVec,
+}
+
+impl Metrics {
+    /// Create the metrics and register them to a new registry
+    fn new() -> Self {
+        let registry = Registry::new();
+        let proposals = IntCounterVec::new(
+            Opts::new(
+                "curp_client_proposals_total",
+                "Proposals completed by the client, labelled with the fast or the slow path",
+            ),
+            &["path"],
+        )
+        .unwrap_or_else(|e| unreachable!("invalid metric, {e}"));
+        registry
+            .register(Box::new(proposals.clone()))
+            .unwrap_or_else(|e| unreachable!("metrics should only be registered once, {e}"));
+        Self {
+            registry,
+            proposals,
+        }
+    }
+
+    /// Record a proposal completed on the fast path or the slow path
+    fn on_propose_completed(&self, fast: bool) {
+        self.proposals
+            .with_label_values(&[if fast { FAST_PATH } else { SLOW_PATH }])
+            .inc();
+    }
 }
 
 #[cfg(test)]
@@ -485,4 +762,37 @@ mod tests {
         assert!(rx.recv().await.is_err());
         assert_eq!(rx.recv().await.unwrap().as_str(), "S3");
     }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn session_will_track_the_first_incomplete_proposal() {
+        let session = Mutex::new(Session::new());
+        let id0 = session.lock().gen_propose_id();
+        let id1 = session.lock().gen_propose_id();
+        assert_eq!(id1.session().unwrap().seq_num, 1);
+        assert_eq!(id1.session().unwrap().first_incomplete, 0);
+
+        drop(ProposalGuard {
+            session: &session,
+            id: id0,
+        });
+        let id2 = session.lock().gen_propose_id();
+        assert_eq!(id2.session().unwrap().first_incomplete, 1);
+
+        session.lock().complete(&id1);
+        session.lock().complete(&id2);
+        let id3 = session.lock().gen_propose_id();
+        assert_eq!(id3.session().unwrap().first_incomplete, 3);
+    }
+
+    #[test]
+    fn metrics_will_count_proposals_by_path() {
+        let metrics = Metrics::new();
+        metrics.on_propose_completed(true);
+        metrics.on_propose_completed(false);
+        metrics.on_propose_completed(false);
+        assert_eq!(metrics.proposals.with_label_values(&[FAST_PATH]).get(), 1);
+        assert_eq!(metrics.proposals.with_label_values(&[SLOW_PATH]).get(), 2);
+        assert_eq!(metrics.registry.gather().len(), 1);
+    }
 }
@@ -2,8 +2,9 @@ use std::{fmt::Display, hash::Hash};
 
 use async_trait::async_trait;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use utils::interval_map::Interval;
 
-use crate::message::LogIndex;
+use crate::{message::LogIndex, snapshot::Snapshot};
 
 /// Command to execute on the server side
 #[async_trait]
@@ -11,7 +12,8 @@ pub trait Command:
     Sized + Sync + Send + DeserializeOwned + Serialize + std::fmt::Debug + Clone + ConflictCheck
 {
     /// K (key) is used to tell confliction
-    /// The key can be a single key or a key range
+    /// The key can be a single key or a key range, wrap it in `AccessedKey` to let
+    /// the commands that only read a key not conflict with each other
     type K: std::fmt::Debug
         + Eq
         + Hash
@@ -20,7 +22,8 @@ pub trait Command:
         + Clone
         + Serialize
         + DeserializeOwned
-        + ConflictCheck;
+        + ConflictCheck
+        + IntervalKey;
 
     /// Execution result
     type ER: std::fmt::Debug + Send + Sync + Clone + Serialize + DeserializeOwned;
@@ -34,6 +37,14 @@ pub trait Command:
     /// Get propose id
     fn id(&self) -> &ProposeId;
 
+    /// Whether the command can only conflict with a keyed command when some of their keys conflict.
+    /// Keyed commands are looked up by the intervals of their keys when detecting conflicts,
+    /// instead of being compared with every in-flight command
+    #[inline]
+    fn is_keyed(&self) -> bool {
+        false
+    }
+
     /// Execute the command according to the executor
     #[inline]
     async fn execute<E>(&self, e: &E) -> Result<Self::ER, E::Error>
@@ -56,24 +67,59 @@ pub trait Command:
 /// Command Id wrapper, abstracting underlying implementation
 #[allow(clippy::module_name_repetitions)] // the name is ok even with repetitions
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, Hash)]
-pub struct ProposeId(String);
+pub struct ProposeId {
+    /// The unique id
+    id: String,
+    /// Session of the client that proposes the cmd, cmds in a session are applied exactly once
+    session: Option<ClientSession>,
+}
 
 impl ProposeId {
     /// Create a new propose id
     #[inline]
     #[must_use]
     pub fn new(id: String) -> Self {
-        Self(id)
+        Self { id, session: None }
+    }
+
+    /// Create a new propose id in a client session
+    #[inline]
+    #[must_use]
+    pub fn new_in_session(session: ClientSession) -> Self {
+        Self {
+            id: format!("{}-{}", session.client_id, session.seq_num),
+            session: Some(session),
+        }
+    }
+
+    /// Get the client session of the propose id
+    #[inline]
+    #[must_use]
+    pub fn session(&self) -> Option<&ClientSession> {
+        self.session.as_ref()
     }
 }
 
 impl Display for ProposeId {
     #[inline]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "\"{}\"", self.0)
+        write!(f, "\"{}\"", self.id)
     }
 }
 
+/// A proposal in a client session, the server uses it to tell whether a retried proposal has been applied
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Hash)]
+#[allow(clippy::exhaustive_structs)] // the session is stable
+pub struct ClientSession {
+    /// Id of the client
+    pub client_id: u64,
+    /// Sequence number of the proposal, it increases monotonically in a client
+    pub seq_num: u64,
+    /// The smallest sequence number whose proposal hasn't completed, results of the previous
+    /// proposals can be discarded by the server
+    pub first_incomplete: u64,
+}
+
 /// Check conflict of two keys
 pub trait ConflictCheck {
     /// check if this keys conflicts with the `other` key
@@ -98,6 +144,115 @@ impl ConflictCheck for u32 {
     }
 }
 
+/// A key that covers an interval of points, keys are indexed by their intervals so that the keys
+/// that may conflict with a key can be found in logarithmic time. Two keys whose intervals don't
+/// overlap must not conflict.
+pub trait IntervalKey {
+    /// Point of the interval
+    type Point: Ord + Clone + Send + Sync + std::fmt::Debug;
+
+    /// Get the interval covered by the key
+    fn interval(&self) -> Interval<Self::Point>;
+}
+
+impl IntervalKey for String {
+    type Point = String;
+
+    #[inline]
+    fn interval(&self) -> Interval<Self::Point> {
+        Interval::point(self.clone())
+    }
+}
+
+impl IntervalKey for u32 {
+    type Point = u32;
+
+    #[inline]
+    fn interval(&self) -> Interval<Self::Point> {
+        Interval::point(*self)
+    }
+}
+
+/// How a command accesses a key
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, Hash)]
+#[allow(clippy::exhaustive_enums)] // a key can only be read or written
+pub enum AccessMode {
+    /// The command only reads the key
+    Read,
+    /// The command may modify the key
+    Write,
+}
+
+impl ConflictCheck for AccessMode {
+    /// Only reads don't conflict with each other
+    #[inline]
+    fn is_conflict(&self, other: &Self) -> bool {
+        *self == AccessMode::Write || *other == AccessMode::Write
+    }
+}
+
+/// A key with the mode it is accessed in, two accessed keys conflict only if their keys
+/// conflict and at least one of them is written
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, Hash)]
+pub struct AccessedKey<K> {
+    /// The key
+    key: K,
+    /// How the key is accessed
+    mode: AccessMode,
+}
+
+impl<K> AccessedKey<K> {
+    /// Create a key that is only read
+    #[inline]
+    #[must_use]
+    pub fn read(key: K) -> Self {
+        Self {
+            key,
+            mode: AccessMode::Read,
+        }
+    }
+
+    /// Create a key that may be written
+    #[inline]
+    #[must_use]
+    pub fn write(key: K) -> Self {
+        Self {
+            key,
+            mode: AccessMode::Write,
+        }
+    }
+
+    /// Get the key
+    #[inline]
+    #[must_use]
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Get the access mode
+    #[inline]
+    #[must_use]
+    pub fn mode(&self) -> AccessMode {
+        self.mode
+    }
+}
+
+impl<K: ConflictCheck> ConflictCheck for AccessedKey<K> {
+    #[inline]
+    fn is_conflict(&self, other: &Self) -> bool {
+        self.mode.is_conflict(&other.mode) && self.key.is_conflict(&other.key)
+    }
+}
+
+impl<K: IntervalKey> IntervalKey for AccessedKey<K> {
+    type Point = K::Point;
+
+    #[inline]
+    fn interval(&self) -> Interval<Self::Point> {
+        self.key.interval()
+    }
+}
+
 /// Command executor which actually executes the command.
 /// It is usually defined by the protocol user.
 #[async_trait]
@@ -114,8 +269,12 @@ where
     /// Execute the after_sync callback
     async fn after_sync(&self, cmd: &C, index: LogIndex) -> Result<C::ASR, Self::Error>;
 
-    /// Reset the command executor to the initial state
-    async fn reset(&self);
+    /// Reset the command executor to the state in the `snapshot`, or to the initial state if `snapshot` is `None`
+    async fn reset(&self, snapshot: Option<&Snapshot>) -> Result<(), Self::Error>;
+
+    /// Take a snapshot of the command executor
+    /// The snapshot should contain all changes made by the log entries up to `last_applied`
+    async fn snapshot(&self) -> Result<Vec<u8>, Self::Error>;
 
     /// Index of the last log entry that has been successfully applied to the command executor
     fn last_applied(&self) -> Result<LogIndex, Self::Error>;
@@ -64,6 +64,21 @@ pub enum ProposeError {
     /// Protocol error
     #[error("protocol error {0}")]
     ProtocolError(String),
+    /// The request can only be handled by the leader
+    #[error("the server is not the leader")]
+    NotLeader,
+    /// The membership change is rejected
+    #[error("invalid membership change: {0}")]
+    InvalidConfChange(String),
+    /// The leader is transferring its leadership and refuses new proposals
+    #[error("the leadership is being transferred")]
+    LeaderTransferring,
+    /// The leadership transfer is rejected or fails
+    #[error("leader transfer error: {0}")]
+    LeaderTransfer(String),
+    /// The read index can't be got or applied
+    #[error("read index error: {0}")]
+    ReadIndex(String),
 }
 
 impl From<tonic::transport::Error> for ProposeError {
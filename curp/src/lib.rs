@@ -147,7 +147,10 @@
 )]
 
 pub use message::LogIndex;
-pub use rpc::{connect::TxFilter, ProtocolServer};
+pub use rpc::{
+    connect::{RpcKind, TxAction, TxFilter},
+    InnerProtocolServer, ProtocolServer,
+};
 
 /// Client side, sending requests and determining requests' state
 pub mod client;
@@ -161,6 +164,12 @@ pub mod error;
 /// The command to be executed
 pub mod cmd;
 
+/// Snapshot of the command executor
+pub mod snapshot;
+
+/// Cluster membership changes
+pub mod members;
+
 /// Message sent between servers and clients
 mod message;
 
@@ -2,6 +2,11 @@ use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    cmd::{Command, ProposeId},
+    members::ConfChangeEntry,
+};
+
 /// Log entry
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct LogEntry<C> {
@@ -9,13 +14,45 @@ pub(crate) struct LogEntry<C> {
     pub(crate) term: u64,
     /// Index
     pub(crate) index: usize,
-    /// The Command
-    pub(crate) cmd: Arc<C>,
+    /// The data carried by the entry
+    pub(crate) data: EntryData<C>,
+}
+
+/// Data carried by a log entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum EntryData<C> {
+    /// A command to be executed by the command executor
+    Command(Arc<C>),
+    /// A change to the cluster membership
+    ConfChange(Arc<ConfChangeEntry>),
 }
 
 impl<C> LogEntry<C> {
-    /// Create a new `LogEntry`
+    /// Create a new `LogEntry` of a command
     pub(super) fn new(index: usize, term: u64, cmd: Arc<C>) -> Self {
-        Self { term, index, cmd }
+        Self {
+            term,
+            index,
+            data: EntryData::Command(cmd),
+        }
+    }
+
+    /// Create a new `LogEntry` of a membership change
+    pub(super) fn new_conf_change(index: usize, term: u64, change: Arc<ConfChangeEntry>) -> Self {
+        Self {
+            term,
+            index,
+            data: EntryData::ConfChange(change),
+        }
+    }
+}
+
+impl<C: Command> LogEntry<C> {
+    /// Get the propose id of the entry
+    pub(crate) fn id(&self) -> &ProposeId {
+        match self.data {
+            EntryData::Command(ref cmd) => cmd.id(),
+            EntryData::ConfChange(ref change) => change.id(),
+        }
     }
 }
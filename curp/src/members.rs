@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+
+use crate::cmd::ProposeId;
+
+/// A change to the cluster membership, only one server can be added or removed at a time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum ConfChange {
+    /// Add a new server to the cluster
+    AddNode {
+        /// Id of the new server
+        id: String,
+        /// Address of the new server
+        address: String,
+    },
+    /// Add a new learner to the cluster, it receives the log but doesn't count toward quorum
+    AddLearner {
+        /// Id of the new learner
+        id: String,
+        /// Address of the new learner
+        address: String,
+    },
+    /// Add a new witness to the cluster, it only keeps a speculative pool that is recovered
+    /// in elections, and never votes or executes commands
+    AddWitness {
+        /// Id of the new witness
+        id: String,
+        /// Address of the new witness
+        address: String,
+    },
+    /// Promote a learner to a voting member
+    PromoteLearner {
+        /// Id of the learner
+        id: String,
+    },
+    /// Remove a server from the cluster
+    RemoveNode {
+        /// Id of the server to be removed
+        id: String,
+    },
+    /// Update the address of an existing server
+    UpdateNode {
+        /// Id of the server
+        id: String,
+        /// New address of the server
+        address: String,
+    },
+    /// Update the client urls advertised by an existing server, a server publishes its client
+    /// urls through it once it starts
+    UpdateClientUrls {
+        /// Id of the server
+        id: String,
+        /// Client urls of the server
+        client_urls: Vec<String>,
+    },
+}
+
+impl ConfChange {
+    /// Get the id of the server that the change is applied to
+    #[inline]
+    #[must_use]
+    pub fn node_id(&self) -> &str {
+        match *self {
+            ConfChange::AddNode { ref id, .. }
+            | ConfChange::AddLearner { ref id, .. }
+            | ConfChange::AddWitness { ref id, .. }
+            | ConfChange::PromoteLearner { ref id }
+            | ConfChange::RemoveNode { ref id }
+            | ConfChange::UpdateNode { ref id, .. }
+            | ConfChange::UpdateClientUrls { ref id, .. } => id,
+        }
+    }
+}
+
+/// A member of the cluster
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    /// Address of the member
+    address: String,
+    /// Whether the member is a learner
+    is_learner: bool,
+    /// Whether the member is a witness
+    is_witness: bool,
+    /// Client urls advertised by the member, empty until the member publishes them
+    client_urls: Vec<String>,
+}
+
+impl Member {
+    /// Create a new `Member`
+    #[inline]
+    #[must_use]
+    pub fn new(address: String, is_learner: bool) -> Self {
+        Self {
+            address,
+            is_learner,
+            is_witness: false,
+            client_urls: vec![],
+        }
+    }
+
+    /// Create a new witness `Member`
+    #[inline]
+    #[must_use]
+    pub fn new_witness(address: String) -> Self {
+        Self {
+            address,
+            is_learner: false,
+            is_witness: true,
+            client_urls: vec![],
+        }
+    }
+
+    /// Get the address of the member
+    #[inline]
+    #[must_use]
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Get the client urls advertised by the member
+    #[inline]
+    #[must_use]
+    pub fn client_urls(&self) -> &[String] {
+        &self.client_urls
+    }
+
+    /// Whether the member is a learner
+    #[inline]
+    #[must_use]
+    pub fn is_learner(&self) -> bool {
+        self.is_learner
+    }
+
+    /// Whether the member is a witness
+    #[inline]
+    #[must_use]
+    pub fn is_witness(&self) -> bool {
+        self.is_witness
+    }
+
+    /// Whether the member votes in elections and counts toward the quorum of the log
+    #[inline]
+    #[must_use]
+    pub fn is_voter(&self) -> bool {
+        !self.is_learner && !self.is_witness
+    }
+
+    /// Update the address of the member
+    pub(crate) fn set_address(&mut self, address: String) {
+        self.address = address;
+    }
+
+    /// Update the client urls advertised by the member
+    pub(crate) fn set_client_urls(&mut self, client_urls: Vec<String>) {
+        self.client_urls = client_urls;
+    }
+
+    /// Promote the learner to a voting member
+    pub(crate) fn promote(&mut self) {
+        self.is_learner = false;
+    }
+}
+
+/// A proposed `ConfChange`, it's replicated through the log like a command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ConfChangeEntry {
+    /// Propose id, used to notify the proposer when the change is applied
+    id: ProposeId,
+    /// The change
+    change: ConfChange,
+}
+
+impl ConfChangeEntry {
+    /// Create a new `ConfChangeEntry`
+    pub(crate) fn new(id: ProposeId, change: ConfChange) -> Self {
+        Self { id, change }
+    }
+
+    /// Get the propose id
+    pub(crate) fn id(&self) -> &ProposeId {
+        &self.id
+    }
+
+    /// Get the change
+    pub(crate) fn change(&self) -> &ConfChange {
+        &self.change
+    }
+}
@@ -4,6 +4,7 @@ use async_trait::async_trait;
 #[cfg(test)]
 use mockall::automock;
 use tokio::sync::RwLock;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
 use tracing::{debug, instrument};
 use utils::tracing::Inject;
 
@@ -11,36 +12,78 @@ use crate::{
     error::ProposeError,
     message::ServerId,
     rpc::{
-        proto::protocol_client::ProtocolClient, AppendEntriesRequest, AppendEntriesResponse,
-        FetchLeaderRequest, FetchLeaderResponse, ProposeRequest, ProposeResponse, VoteRequest,
-        VoteResponse, WaitSyncedRequest, WaitSyncedResponse,
+        proto::{inner_protocol_client::InnerProtocolClient, protocol_client::ProtocolClient},
+        AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest, FetchLeaderResponse,
+        InstallSnapshotRequest, InstallSnapshotResponse, ProposeConfChangeRequest,
+        ProposeConfChangeResponse, ProposeRequest, ProposeResponse, ReadIndexRequest,
+        ReadIndexResponse, TimeoutNowRequest, TimeoutNowResponse, VoteRequest, VoteResponse,
+        WaitSyncedRequest, WaitSyncedResponse,
     },
+    snapshot::Snapshot,
 };
 
+/// Max size of the snapshot data carried by a single `install_snapshot` request
+pub(crate) const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Kind of the rpc sent by a `Connect`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RpcKind {
+    /// `ProposeRequest`
+    Propose,
+    /// `WaitSyncedRequest`
+    WaitSynced,
+    /// `AppendEntriesRequest`
+    AppendEntries,
+    /// `VoteRequest`
+    Vote,
+    /// `FetchLeaderRequest`
+    FetchLeader,
+    /// A chunk of `InstallSnapshotRequest`
+    InstallSnapshot,
+    /// `ProposeConfChangeRequest`
+    ProposeConfChange,
+    /// `TimeoutNowRequest`
+    TimeoutNow,
+    /// `ReadIndexRequest`
+    ReadIndex,
+}
+
+/// What a `Connect` should do with a request, decided by the `TxFilter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TxAction {
+    /// Send the request as it is
+    Pass,
+    /// Drop the request, the sender gets an unreachable error immediately
+    Drop,
+    /// Send the request after a delay, the delay is counted into the rpc timeout
+    Delay(Duration),
+    /// Send the protobuf encoded bytes instead of the request. If the bytes can't be decoded to
+    /// the request, the sender gets an error as if the receiver rejected it
+    Corrupt(Vec<u8>),
+}
+
 /// Connect will call filter(request) before it sends out a request
 pub trait TxFilter: Send + Sync + Debug {
-    /// Filter request
-    // TODO: add request as a parameter
-    fn filter(&self) -> Option<()>;
+    /// Decide what to do with a request of `kind` sent to the server `target`, the `request` is
+    /// protobuf encoded
+    fn filter(&self, kind: RpcKind, target: &str, request: &[u8]) -> TxAction;
     /// Clone self
     fn boxed_clone(&self) -> Box<dyn TxFilter>;
 }
 
-/// Convert a vec of addr string to a vec of `Connect`
+/// Convert a vec of addr string to a vec of `Connect`, the connects use tls if `tls_config` is set
 pub(crate) async fn connect(
     addrs: HashMap<ServerId, String>,
     tx_filter: Option<Box<dyn TxFilter>>,
+    tls_config: Option<ClientTlsConfig>,
 ) -> HashMap<ServerId, Arc<Connect>> {
-    futures::future::join_all(addrs.into_iter().map(|(id, mut addr)| async move {
-        // Addrs must start with "http" to communicate with the server
-        if !addr.starts_with("http://") {
-            addr.insert_str(0, "http://");
-        }
-        (
-            id,
-            addr.clone(),
-            ProtocolClient::connect(addr.clone()).await,
-        )
+    let tls_config = tls_config.as_ref();
+    futures::future::join_all(addrs.into_iter().map(|(id, addr)| async move {
+        let addr = with_scheme(addr, tls_config.is_some());
+        let conn = connect_to(addr.clone(), tls_config).await;
+        (id, addr, conn)
     }))
     .await
     .into_iter()
@@ -51,12 +94,86 @@ pub(crate) async fn connect(
             rpc_connect: RwLock::new(conn),
             addr,
             tx_filter: tx_filter.as_ref().map(|f| f.boxed_clone()),
+            tls_config: tls_config.cloned(),
         });
         (id, connect)
     })
     .collect()
 }
 
+/// Addrs must start with a scheme to communicate with the server, `https` is used if tls is enabled
+fn with_scheme(mut addr: String, tls: bool) -> String {
+    if !addr.starts_with("http://") && !addr.starts_with("https://") {
+        addr.insert_str(0, if tls { "https://" } else { "http://" });
+    }
+    addr
+}
+
+/// Connect to the server at `addr`
+async fn connect_to(
+    addr: String,
+    tls_config: Option<&ClientTlsConfig>,
+) -> Result<Channel, tonic::transport::Error> {
+    let mut endpoint = Endpoint::from_shared(addr)?;
+    if let Some(tls_config) = tls_config {
+        endpoint = endpoint.tls_config(tls_config.clone())?;
+    }
+    endpoint.connect().await
+}
+
+/// Establishes connects to other servers, the server doesn't care how requests reach them
+#[async_trait]
+pub(crate) trait Connector: Send + Sync + Debug + 'static {
+    /// Connect to the servers at `addrs`
+    async fn connect(
+        &self,
+        addrs: HashMap<ServerId, String>,
+    ) -> HashMap<ServerId, Arc<dyn ConnectApi>>;
+}
+
+/// Connector that connects to other servers through tonic
+#[derive(Debug)]
+pub(crate) struct TonicConnector {
+    /// The filter injected into every connect
+    tx_filter: Option<Box<dyn TxFilter>>,
+    /// The tls config used to connect to other servers
+    tls_config: Option<ClientTlsConfig>,
+}
+
+impl TonicConnector {
+    /// Create a new `TonicConnector`
+    pub(crate) fn new(
+        tx_filter: Option<Box<dyn TxFilter>>,
+        tls_config: Option<ClientTlsConfig>,
+    ) -> Self {
+        Self {
+            tx_filter,
+            tls_config,
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for TonicConnector {
+    async fn connect(
+        &self,
+        addrs: HashMap<ServerId, String>,
+    ) -> HashMap<ServerId, Arc<dyn ConnectApi>> {
+        connect(
+            addrs,
+            self.tx_filter.as_ref().map(|f| f.boxed_clone()),
+            self.tls_config.clone(),
+        )
+        .await
+        .into_iter()
+        .map(|(id, connect)| {
+            let connect: Arc<dyn ConnectApi> = connect;
+            (id, connect)
+        })
+        .collect()
+    }
+}
+
 /// Connect interface
 #[cfg_attr(test, automock)]
 #[async_trait]
@@ -64,11 +181,6 @@ pub(crate) trait ConnectApi: Send + Sync + 'static {
     /// Get server id
     fn id(&self) -> &ServerId;
 
-    /// Get the internal rpc connection/client
-    async fn get(
-        &self,
-    ) -> Result<ProtocolClient<tonic::transport::Channel>, tonic::transport::Error>;
-
     /// Send `ProposeRequest`
     async fn propose(
         &self,
@@ -103,6 +215,36 @@ pub(crate) trait ConnectApi: Send + Sync + 'static {
         request: FetchLeaderRequest,
         timeout: Duration,
     ) -> Result<tonic::Response<FetchLeaderResponse>, ProposeError>;
+
+    /// Send the snapshot in chunks through a stream of `InstallSnapshotRequest`s, `timeout` is applied to each chunk
+    async fn install_snapshot(
+        &self,
+        term: u64,
+        leader_id: ServerId,
+        snapshot: Arc<Snapshot>,
+        timeout: Duration,
+    ) -> Result<tonic::Response<InstallSnapshotResponse>, ProposeError>;
+
+    /// Send `ProposeConfChangeRequest`
+    async fn propose_conf_change(
+        &self,
+        request: ProposeConfChangeRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<ProposeConfChangeResponse>, ProposeError>;
+
+    /// Send `TimeoutNowRequest`
+    async fn timeout_now(
+        &self,
+        request: TimeoutNowRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<TimeoutNowResponse>, ProposeError>;
+
+    /// Send `ReadIndexRequest`
+    async fn read_index(
+        &self,
+        request: ReadIndexRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<ReadIndexResponse>, ProposeError>;
 }
 
 /// The connection struct to hold the real rpc connections, it may failed to connect, but it also
@@ -111,12 +253,14 @@ pub(crate) trait ConnectApi: Send + Sync + 'static {
 pub(crate) struct Connect {
     /// Server id
     id: ServerId,
-    /// The rpc connection, if it fails it contains a error, otherwise the rpc client is there
-    rpc_connect: RwLock<Result<ProtocolClient<tonic::transport::Channel>, tonic::transport::Error>>,
+    /// The rpc connection, if it fails it contains a error, otherwise the channel is there
+    rpc_connect: RwLock<Result<Channel, tonic::transport::Error>>,
     /// The addr used to connect if failing met
     addr: String,
     /// The injected filter
     tx_filter: Option<Box<dyn TxFilter>>,
+    /// The tls config used when reconnecting
+    tls_config: Option<ClientTlsConfig>,
 }
 
 #[async_trait]
@@ -126,27 +270,6 @@ impl ConnectApi for Connect {
         &self.id
     }
 
-    /// Get the internal rpc connection/client
-    async fn get(
-        &self,
-    ) -> Result<ProtocolClient<tonic::transport::Channel>, tonic::transport::Error> {
-        if let Ok(ref client) = *self.rpc_connect.read().await {
-            return Ok(client.clone());
-        }
-        let mut connect_write = self.rpc_connect.write().await;
-        if let Ok(ref client) = *connect_write {
-            return Ok(client.clone());
-        }
-        let client = ProtocolClient::<_>::connect(self.addr.clone())
-            .await
-            .map(|client| {
-                *connect_write = Ok(client.clone());
-                client
-            })?;
-        *connect_write = Ok(client.clone());
-        Ok(client)
-    }
-
     /// Send `ProposeRequest`
     #[instrument(skip(self), name = "client propose")]
     async fn propose(
@@ -154,7 +277,7 @@ impl ConnectApi for Connect {
         request: ProposeRequest,
         timeout: Duration,
     ) -> Result<tonic::Response<ProposeResponse>, ProposeError> {
-        self.filter()?;
+        let (request, timeout) = self.filter(RpcKind::Propose, request, timeout).await?;
 
         let mut client = self.get().await?;
         let mut req = tonic::Request::new(request);
@@ -170,7 +293,7 @@ impl ConnectApi for Connect {
         request: WaitSyncedRequest,
         timeout: Duration,
     ) -> Result<tonic::Response<WaitSyncedResponse>, ProposeError> {
-        self.filter()?;
+        let (request, timeout) = self.filter(RpcKind::WaitSynced, request, timeout).await?;
 
         let mut client = self.get().await?;
         let mut req = tonic::Request::new(request);
@@ -185,9 +308,11 @@ impl ConnectApi for Connect {
         request: AppendEntriesRequest,
         timeout: Duration,
     ) -> Result<tonic::Response<AppendEntriesResponse>, ProposeError> {
-        self.filter()?;
+        let (request, timeout) = self
+            .filter(RpcKind::AppendEntries, request, timeout)
+            .await?;
 
-        let mut client = self.get().await?;
+        let mut client = self.get_inner().await?;
         let mut req = tonic::Request::new(request);
         req.set_timeout(timeout);
         client.append_entries(req).await.map_err(Into::into)
@@ -199,9 +324,9 @@ impl ConnectApi for Connect {
         request: VoteRequest,
         timeout: Duration,
     ) -> Result<tonic::Response<VoteResponse>, ProposeError> {
-        self.filter()?;
+        let (request, timeout) = self.filter(RpcKind::Vote, request, timeout).await?;
 
-        let mut client = self.get().await?;
+        let mut client = self.get_inner().await?;
         let mut req = tonic::Request::new(request);
         req.set_timeout(timeout);
         client.vote(req).await.map_err(Into::into)
@@ -213,24 +338,140 @@ impl ConnectApi for Connect {
         request: FetchLeaderRequest,
         timeout: Duration,
     ) -> Result<tonic::Response<FetchLeaderResponse>, ProposeError> {
-        self.filter()?;
+        let (request, timeout) = self.filter(RpcKind::FetchLeader, request, timeout).await?;
 
         let mut client = self.get().await?;
         let mut req = tonic::Request::new(request);
         req.set_timeout(timeout);
         client.fetch_leader(req).await.map_err(Into::into)
     }
+
+    /// Send the snapshot in chunks through a stream of `InstallSnapshotRequest`s
+    async fn install_snapshot(
+        &self,
+        term: u64,
+        leader_id: ServerId,
+        snapshot: Arc<Snapshot>,
+        timeout: Duration,
+    ) -> Result<tonic::Response<InstallSnapshotResponse>, ProposeError> {
+        let mut chunks = vec![];
+        let mut total_timeout = Duration::ZERO;
+        for chunk in
+            InstallSnapshotRequest::new_chunks(term, &leader_id, &snapshot, SNAPSHOT_CHUNK_SIZE)
+        {
+            let (chunk, left) = self
+                .filter(RpcKind::InstallSnapshot, chunk, timeout)
+                .await?;
+            chunks.push(chunk);
+            total_timeout = total_timeout.saturating_add(left);
+        }
+
+        let mut client = self.get_inner().await?;
+        let mut req = tonic::Request::new(futures::stream::iter(chunks));
+        req.set_timeout(total_timeout);
+        client.install_snapshot(req).await.map_err(Into::into)
+    }
+
+    /// Send `ProposeConfChangeRequest`
+    #[instrument(skip(self), name = "client propose conf change")]
+    async fn propose_conf_change(
+        &self,
+        request: ProposeConfChangeRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<ProposeConfChangeResponse>, ProposeError> {
+        let (request, timeout) = self
+            .filter(RpcKind::ProposeConfChange, request, timeout)
+            .await?;
+
+        let mut client = self.get_inner().await?;
+        let mut req = tonic::Request::new(request);
+        req.set_timeout(timeout);
+        req.metadata_mut().inject_current();
+        client.propose_conf_change(req).await.map_err(Into::into)
+    }
+
+    /// Send `TimeoutNowRequest`
+    async fn timeout_now(
+        &self,
+        request: TimeoutNowRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<TimeoutNowResponse>, ProposeError> {
+        let (request, timeout) = self.filter(RpcKind::TimeoutNow, request, timeout).await?;
+
+        let mut client = self.get_inner().await?;
+        let mut req = tonic::Request::new(request);
+        req.set_timeout(timeout);
+        client.timeout_now(req).await.map_err(Into::into)
+    }
+
+    /// Send `ReadIndexRequest`
+    async fn read_index(
+        &self,
+        request: ReadIndexRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<ReadIndexResponse>, ProposeError> {
+        let (request, timeout) = self.filter(RpcKind::ReadIndex, request, timeout).await?;
+
+        let mut client = self.get().await?;
+        let mut req = tonic::Request::new(request);
+        req.set_timeout(timeout);
+        client.read_index(req).await.map_err(Into::into)
+    }
 }
 
 impl Connect {
-    /// Filter requests
-    // TODO: add request as input
-    fn filter(&self) -> Result<(), ProposeError> {
-        self.tx_filter.as_ref().map_or(Ok(()), |f| {
-            f.filter().map_or_else(
-                || Err(ProposeError::RpcError("unreachable".to_owned())),
-                |_| Ok(()),
-            )
-        })
+    /// Get the client of the protocol served to the clients and the peers
+    async fn get(&self) -> Result<ProtocolClient<Channel>, tonic::transport::Error> {
+        self.channel().await.map(ProtocolClient::new)
+    }
+
+    /// Get the client of the protocol served to the peers only
+    async fn get_inner(&self) -> Result<InnerProtocolClient<Channel>, tonic::transport::Error> {
+        self.channel().await.map(InnerProtocolClient::new)
+    }
+
+    /// Get the internal rpc connection, reconnect if the last connection failed
+    async fn channel(&self) -> Result<Channel, tonic::transport::Error> {
+        if let Ok(ref channel) = *self.rpc_connect.read().await {
+            return Ok(channel.clone());
+        }
+        let mut connect_write = self.rpc_connect.write().await;
+        if let Ok(ref channel) = *connect_write {
+            return Ok(channel.clone());
+        }
+        let channel = connect_to(self.addr.clone(), self.tls_config.as_ref()).await?;
+        *connect_write = Ok(channel.clone());
+        Ok(channel)
+    }
+
+    /// Filter a request before it's sent, return the request to send and the time left before
+    /// the rpc times out
+    async fn filter<R: prost::Message + Default>(
+        &self,
+        kind: RpcKind,
+        request: R,
+        timeout: Duration,
+    ) -> Result<(R, Duration), ProposeError> {
+        let Some(ref tx_filter) = self.tx_filter else {
+            return Ok((request, timeout));
+        };
+        match tx_filter.filter(kind, &self.id, &request.encode_to_vec()) {
+            TxAction::Pass => Ok((request, timeout)),
+            TxAction::Drop => Err(ProposeError::RpcError("unreachable".to_owned())),
+            TxAction::Delay(delay) => {
+                if delay >= timeout {
+                    tokio::time::sleep(timeout).await;
+                    return Err(ProposeError::RpcError(format!(
+                        "{kind:?} to {} timeout",
+                        self.id
+                    )));
+                }
+                tokio::time::sleep(delay).await;
+                Ok((request, timeout.saturating_sub(delay)))
+            }
+            TxAction::Corrupt(data) => R::decode(data.as_slice())
+                .map(|corrupted| (corrupted, timeout))
+                .map_err(|e| ProposeError::RpcError(format!("{kind:?} is corrupted, {e}"))),
+        }
     }
 }
@@ -1,22 +1,30 @@
 use std::sync::Arc;
 
 use clippy_utilities::NumericCast;
+use itertools::Itertools;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-pub use self::proto::protocol_server::ProtocolServer;
 pub(crate) use self::proto::{
+    inner_protocol_server::InnerProtocol,
     propose_response::ExeResult,
     protocol_server::Protocol,
     wait_synced_response::{Success, SyncResult as SyncResultRaw},
     AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest, FetchLeaderResponse,
-    ProposeRequest, ProposeResponse, VoteRequest, VoteResponse, WaitSyncedRequest,
-    WaitSyncedResponse,
+    InstallSnapshotRequest, InstallSnapshotResponse, ProposeConfChangeRequest,
+    ProposeConfChangeResponse, ProposeRequest, ProposeResponse, ReadIndexRequest,
+    ReadIndexResponse, TimeoutNowRequest, TimeoutNowResponse, VoteRequest, VoteResponse,
+    WaitSyncedRequest, WaitSyncedResponse,
+};
+pub use self::proto::{
+    inner_protocol_server::InnerProtocolServer, protocol_server::ProtocolServer,
 };
 use crate::{
     cmd::{Command, ProposeId},
     error::ProposeError,
     log_entry::LogEntry,
+    members::ConfChangeEntry,
     message::ServerId,
+    snapshot::{Snapshot, SnapshotMeta},
 };
 
 /// Rpc connect
@@ -303,12 +311,19 @@ impl AppendEntriesResponse {
 
 impl VoteRequest {
     /// Create a new vote request
-    pub fn new(term: u64, candidate_id: String, last_log_index: usize, last_log_term: u64) -> Self {
+    pub fn new(
+        term: u64,
+        candidate_id: String,
+        last_log_index: usize,
+        last_log_term: u64,
+        is_pre_vote: bool,
+    ) -> Self {
         Self {
             term,
             candidate_id,
             last_log_index: last_log_index.numeric_cast(),
             last_log_term,
+            is_pre_vote,
         }
     }
 }
@@ -346,3 +361,162 @@ impl VoteResponse {
             .collect()
     }
 }
+
+impl InstallSnapshotRequest {
+    /// Split the snapshot into a sequence of `install_snapshot` requests, each of which carries
+    /// at most `chunk_size` bytes of the snapshot data, the client sessions and the members are carried by the last one
+    #[allow(clippy::integer_arithmetic)] // offset is smaller than the data length, won't overflow
+    pub(crate) fn new_chunks(
+        term: u64,
+        leader_id: &ServerId,
+        snapshot: &Snapshot,
+        chunk_size: usize,
+    ) -> Vec<Self> {
+        let meta = snapshot.meta();
+        let mut chunks = snapshot.data().chunks(chunk_size).collect_vec();
+        if chunks.is_empty() {
+            // an empty snapshot still needs one request to be installed
+            chunks.push(&[]);
+        }
+        let last = chunks.len() - 1;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(i, chunk)| Self {
+                term,
+                leader_id: leader_id.clone(),
+                last_included_index: meta.last_included_index,
+                last_included_term: meta.last_included_term,
+                offset: (i * chunk_size).numeric_cast(),
+                data: chunk.to_vec(),
+                done: i == last,
+                sessions: if i == last {
+                    snapshot.sessions().to_vec()
+                } else {
+                    vec![]
+                },
+                members: if i == last {
+                    snapshot.members().to_vec()
+                } else {
+                    vec![]
+                },
+            })
+            .collect()
+    }
+
+    /// Get the meta of the snapshot being installed
+    pub(crate) fn meta(&self) -> SnapshotMeta {
+        SnapshotMeta {
+            last_included_index: self.last_included_index,
+            last_included_term: self.last_included_term,
+        }
+    }
+}
+
+impl InstallSnapshotResponse {
+    /// Create a new `install_snapshot` response
+    pub(crate) fn new(term: u64) -> Self {
+        Self { term }
+    }
+}
+
+impl TimeoutNowRequest {
+    /// Create a new `timeout_now` request
+    pub(crate) fn new(term: u64, leader_id: ServerId) -> Self {
+        Self { term, leader_id }
+    }
+}
+
+impl TimeoutNowResponse {
+    /// Create a new `timeout_now` response
+    pub(crate) fn new(term: u64) -> Self {
+        Self { term }
+    }
+}
+
+impl ReadIndexRequest {
+    /// Create a new `ReadIndexRequest`
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+impl ReadIndexResponse {
+    /// Create a response carrying the read index
+    pub(crate) fn new_ok(leader_id: Option<ServerId>, term: u64, read_index: u64) -> Self {
+        Self {
+            leader_id,
+            term,
+            error: None,
+            read_index,
+        }
+    }
+
+    /// Create an error response
+    pub(crate) fn new_error(
+        leader_id: Option<ServerId>,
+        term: u64,
+        error: &ProposeError,
+    ) -> bincode::Result<Self> {
+        Ok(Self {
+            leader_id,
+            term,
+            error: Some(bincode::serialize(error)?),
+            read_index: 0,
+        })
+    }
+
+    /// Get the error, `None` if the leadership has been confirmed
+    pub(crate) fn error(&self) -> bincode::Result<Option<ProposeError>> {
+        self.error
+            .as_ref()
+            .map(|e| bincode::deserialize(e))
+            .transpose()
+    }
+}
+
+impl ProposeConfChangeRequest {
+    /// Create a new `ProposeConfChange` request
+    pub(crate) fn new(entry: &ConfChangeEntry) -> bincode::Result<Self> {
+        Ok(Self {
+            change: bincode::serialize(entry)?,
+        })
+    }
+
+    /// Get the membership change
+    pub(crate) fn entry(&self) -> bincode::Result<ConfChangeEntry> {
+        bincode::deserialize(&self.change)
+    }
+}
+
+impl ProposeConfChangeResponse {
+    /// Create a response indicating that the change has taken effect
+    pub(crate) fn new_ok(leader_id: Option<ServerId>, term: u64) -> Self {
+        Self {
+            leader_id,
+            term,
+            error: None,
+        }
+    }
+
+    /// Create an error response
+    pub(crate) fn new_error(
+        leader_id: Option<ServerId>,
+        term: u64,
+        error: &ProposeError,
+    ) -> bincode::Result<Self> {
+        Ok(Self {
+            leader_id,
+            term,
+            error: Some(bincode::serialize(error)?),
+        })
+    }
+
+    /// Get the error, `None` if the change has taken effect
+    pub(crate) fn error(&self) -> bincode::Result<Option<ProposeError>> {
+        self.error
+            .as_ref()
+            .map(|e| bincode::deserialize(e))
+            .transpose()
+    }
+}
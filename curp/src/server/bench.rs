@@ -0,0 +1,64 @@
+use std::{fmt::Debug, sync::Arc};
+
+use super::{cmd_worker::ConflictGraph, spec_pool::SpeculativePool};
+use crate::cmd::Command;
+
+/// Conflict graph of the command workers holding the in-flight cmds
+pub struct Filter<C: Command> {
+    /// The conflict graph
+    graph: ConflictGraph<C>,
+}
+
+impl<C: Command> Filter<C> {
+    /// Create a filter holding the `cmds`, none of them is executed
+    #[inline]
+    #[must_use]
+    pub fn new<I: IntoIterator<Item = Arc<C>>>(cmds: I) -> Self {
+        let mut graph = ConflictGraph::new();
+        for cmd in cmds {
+            graph.insert(cmd);
+        }
+        Self { graph }
+    }
+
+    /// Insert a cmd as a new vertex, it's linked to the vertexes of the conflicting cmds
+    #[inline]
+    pub fn insert_new_vertex(&mut self, cmd: Arc<C>) {
+        self.graph.insert(cmd);
+    }
+}
+
+impl<C: Command> Debug for Filter<C> {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Filter").finish_non_exhaustive()
+    }
+}
+
+/// Speculative pool holding the in-flight cmds
+#[derive(Debug)]
+pub struct SpecPool<C: Command> {
+    /// The speculative pool
+    pool: SpeculativePool<C>,
+}
+
+impl<C: Command + 'static> SpecPool<C> {
+    /// Create a speculative pool holding the `cmds`, the cmds conflicting with the earlier ones
+    /// are left out
+    #[inline]
+    #[must_use]
+    pub fn new<I: IntoIterator<Item = Arc<C>>>(cmds: I) -> Self {
+        let mut pool = SpeculativePool::new();
+        for cmd in cmds {
+            let _ig = pool.insert(cmd);
+        }
+        Self { pool }
+    }
+
+    /// Check whether the `cmd` conflicts with any cmd in the pool
+    #[inline]
+    #[must_use]
+    pub fn has_conflict_with(&self, cmd: &C) -> bool {
+        self.pool.has_conflict_with(cmd)
+    }
+}
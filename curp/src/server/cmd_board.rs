@@ -5,6 +5,7 @@ use indexmap::{IndexMap, IndexSet};
 use parking_lot::RwLock;
 use utils::parking_lot_lock::RwLockMap;
 
+use super::session::{CmdResult, SessionTable};
 use crate::cmd::{Command, ProposeId};
 
 /// Ref to the cmd board
@@ -17,12 +18,18 @@ pub(super) struct CommandBoard<C: Command> {
     er_notifiers: HashMap<ProposeId, Event>,
     /// Store all notifiers for after sync results
     asr_notifiers: HashMap<ProposeId, Event>,
+    /// Store all notifiers for membership changes
+    conf_notifiers: HashMap<ProposeId, Event>,
     /// The cmd has been received before, this is used for dedup
     pub(super) sync: IndexSet<ProposeId>,
     /// Store all execution results
     pub(super) er_buffer: IndexMap<ProposeId, Result<C::ER, String>>,
     /// Store all after sync results
     pub(super) asr_buffer: IndexMap<ProposeId, Result<C::ASR, String>>,
+    /// Store all membership changes that have taken effect
+    pub(super) conf_buffer: IndexSet<ProposeId>,
+    /// Sessions of clients, it's not cleared with other buffers since it's part of the state machine
+    pub(super) sessions: SessionTable<C>,
 }
 
 impl<C: Command> CommandBoard<C> {
@@ -31,9 +38,12 @@ impl<C: Command> CommandBoard<C> {
         Self {
             er_notifiers: HashMap::new(),
             asr_notifiers: HashMap::new(),
+            conf_notifiers: HashMap::new(),
             sync: IndexSet::new(),
             er_buffer: IndexMap::new(),
             asr_buffer: IndexMap::new(),
+            conf_buffer: IndexSet::new(),
+            sessions: SessionTable::new(),
         }
     }
 
@@ -45,12 +55,16 @@ impl<C: Command> CommandBoard<C> {
         self.asr_notifiers
             .drain()
             .for_each(|(_, event)| event.notify(usize::MAX));
+        self.conf_notifiers
+            .drain()
+            .for_each(|(_, event)| event.notify(usize::MAX));
     }
 
     /// Clear
     pub(super) fn clear(&mut self) {
         self.er_buffer.clear();
         self.asr_buffer.clear();
+        self.conf_buffer.clear();
         self.release_notifiers();
     }
 
@@ -80,6 +94,26 @@ impl<C: Command> CommandBoard<C> {
         self.notify_asr(id);
     }
 
+    /// Insert the results of a cmd that has been applied before, results already in the buffers are kept
+    pub(super) fn insert_applied(&mut self, id: &ProposeId, (er, asr): CmdResult<C>) {
+        if !self.er_buffer.contains_key(id) {
+            self.insert_er(id, er);
+        }
+        if let Some(asr) = asr {
+            if !self.asr_buffer.contains_key(id) {
+                self.insert_asr(id, asr);
+            }
+        }
+    }
+
+    /// Insert a membership change that has taken effect
+    pub(super) fn insert_conf(&mut self, id: &ProposeId) {
+        let _ig = self.conf_buffer.insert(id.clone());
+        if let Some(notifier) = self.conf_notifiers.remove(id) {
+            notifier.notify(usize::MAX);
+        }
+    }
+
     /// Get a listener for execution result
     fn er_listener(&mut self, id: &ProposeId) -> EventListener {
         let event = self
@@ -106,6 +140,19 @@ impl<C: Command> CommandBoard<C> {
         listener
     }
 
+    /// Get a listener for membership change
+    fn conf_listener(&mut self, id: &ProposeId) -> EventListener {
+        let event = self
+            .conf_notifiers
+            .entry(id.clone())
+            .or_insert_with(Event::new);
+        let listener = event.listen();
+        if self.conf_buffer.contains(id) {
+            event.notify(usize::MAX);
+        }
+        listener
+    }
+
     /// Notify execution results
     fn notify_er(&mut self, id: &ProposeId) {
         if let Some(notifier) = self.er_notifiers.remove(id) {
@@ -149,4 +196,15 @@ impl<C: Command> CommandBoard<C> {
             listener.await;
         }
     }
+
+    /// Wait for a membership change to take effect
+    pub(super) async fn wait_for_conf(cb: &CmdBoardRef<C>, id: &ProposeId) {
+        loop {
+            if cb.map_read(|cb_r| cb_r.conf_buffer.contains(id)) {
+                return;
+            }
+            let listener = cb.write().conf_listener(id);
+            listener.await;
+        }
+    }
 }
@@ -11,8 +11,12 @@ use std::{
 
 use tracing::{debug, error};
 
-use super::CEEvent;
-use crate::cmd::{Command, ProposeId};
+use super::{AppliedIndex, CEEvent};
+use crate::{
+    cmd::{Command, ProposeId},
+    server::conflict_index::ConflictIndex,
+    snapshot::{Snapshot, SnapshotMeta},
+};
 
 /// CE task
 pub(super) struct Task<C> {
@@ -29,7 +33,9 @@ pub(super) enum TaskType<C> {
     /// After sync a cmd
     AS(Arc<C>, usize),
     /// Reset the CE
-    Reset,
+    Reset(Option<Arc<Snapshot>>),
+    /// Take a snapshot of the CE
+    Snapshot(SnapshotMeta),
 }
 
 impl<C> Task<C> {
@@ -75,7 +81,19 @@ enum VertexInner<C> {
         as_st: AsState,
     },
     /// A reset vertex
-    Reset(ResetState),
+    Reset {
+        /// The snapshot to reset to
+        snapshot: Option<Arc<Snapshot>>,
+        /// Reset state
+        st: ResetState,
+    },
+    /// A snapshot vertex
+    Snapshot {
+        /// Meta of the snapshot to take
+        meta: SnapshotMeta,
+        /// Snapshot state
+        st: SnapshotState,
+    },
 }
 
 /// Execute state of a cmd
@@ -113,27 +131,44 @@ enum ResetState {
     Completed,
 }
 
+/// Snapshot state
+#[derive(Debug, Clone, Copy)]
+enum SnapshotState {
+    /// Snapshot ready
+    SnapshotReady,
+    /// Taking snapshot
+    Snapshotting,
+    /// Completed
+    Completed,
+}
+
 /// The filter will block any msg if its predecessors(msgs that arrive earlier and conflict with it) haven't finished process
 /// Internally it maintains a dependency graph of conflicting cmds
-struct Filter<C> {
+struct Filter<C: Command> {
     /// Index from `ProposeId` to `vertex`
     cmd_vid: HashMap<ProposeId, u64>,
     /// Conflict graph
     vs: HashMap<u64, Vertex<C>>,
+    /// Index of the vertexes for conflict detection
+    index: ConflictIndex<C, u64>,
     /// Next vertex id
     next_id: u64,
     /// Send task to users
     filter_tx: flume::Sender<Task<C>>,
+    /// Progress of the command executor, after sync tasks and resets are marked finished here
+    applied: Arc<AppliedIndex>,
 }
 
 impl<C: Command> Filter<C> {
     /// Create a new filter that checks conflict in between msgs
-    fn new(filter_tx: flume::Sender<Task<C>>) -> Self {
+    fn new(filter_tx: flume::Sender<Task<C>>, applied: Arc<AppliedIndex>) -> Self {
         Self {
             cmd_vid: HashMap::new(),
             vs: HashMap::new(),
+            index: ConflictIndex::new(),
             next_id: 0,
             filter_tx,
+            applied,
         }
     }
 
@@ -146,12 +181,27 @@ impl<C: Command> Filter<C> {
 
     /// Insert a new vertex to inner graph
     fn insert_new_vertex(&mut self, new_vid: u64, mut new_v: Vertex<C>) {
-        for v in self.vs.values_mut() {
-            if v.is_conflict(&new_v) {
-                assert!(v.successors.insert(new_vid), "cannot insert a vertex twice");
-                new_v.predecessor_cnt += 1;
+        let cmd = match new_v.inner {
+            VertexInner::Cmd { ref cmd, .. } => Some(cmd.as_ref()),
+            _ => None,
+        };
+        if let Some(vids) = cmd.and_then(|c| self.index.candidates(c)) {
+            for vid in vids {
+                let v = self.get_vertex_mut(vid);
+                if v.is_conflict(&new_v) {
+                    assert!(v.successors.insert(new_vid), "cannot insert a vertex twice");
+                    new_v.predecessor_cnt += 1;
+                }
+            }
+        } else {
+            for v in self.vs.values_mut() {
+                if v.is_conflict(&new_v) {
+                    assert!(v.successors.insert(new_vid), "cannot insert a vertex twice");
+                    new_v.predecessor_cnt += 1;
+                }
             }
         }
+        self.index.insert(new_vid, cmd);
         assert!(
             self.vs.insert(new_vid, new_v).is_none(),
             "cannot insert a vertex twice"
@@ -188,7 +238,7 @@ impl<C: Command> Filter<C> {
     fn mark_reset(&mut self, vid: u64) {
         let v = self.get_vertex_mut(vid);
         match v.inner {
-            VertexInner::Reset(ref mut st) => {
+            VertexInner::Reset { ref mut st, .. } => {
                 debug_assert!(matches!(*st, ResetState::Resetting));
                 *st = ResetState::Completed;
             }
@@ -197,6 +247,58 @@ impl<C: Command> Filter<C> {
         self.update_graph(vid);
     }
 
+    /// Mark a snapshot taken
+    fn mark_snapshot_taken(&mut self, vid: u64) {
+        let v = self.get_vertex_mut(vid);
+        match v.inner {
+            VertexInner::Snapshot { ref mut st, .. } => {
+                debug_assert!(matches!(*st, SnapshotState::Snapshotting));
+                *st = SnapshotState::Completed;
+            }
+            _ => unreachable!("impossible vertex type"),
+        }
+        self.update_graph(vid);
+    }
+
+    /// Insert a new snapshot vertex to inner graph
+    /// Unlike other vertexes, a snapshot vertex only waits for cmds that are ready for after sync (or have been after synced),
+    /// because the snapshot should contain exactly the log entries that have been applied so far.
+    fn insert_snapshot_vertex(&mut self, new_vid: u64, mut new_v: Vertex<C>) {
+        for v in self.vs.values_mut() {
+            let is_predecessor = match v.inner {
+                VertexInner::Cmd { as_st, .. } => !matches!(as_st, AsState::NotSynced),
+                _ => true,
+            };
+            if is_predecessor {
+                assert!(v.successors.insert(new_vid), "cannot insert a vertex twice");
+                new_v.predecessor_cnt += 1;
+            }
+        }
+        self.index.insert(new_vid, None);
+        assert!(
+            self.vs.insert(new_vid, new_v).is_none(),
+            "cannot insert a vertex twice"
+        );
+    }
+
+    /// A cmd that was not synced when a snapshot vertex was inserted must not be after synced before the snapshot is taken
+    fn wait_for_pending_snapshots(&mut self, vid: u64) {
+        let pending_snapshots = self
+            .vs
+            .iter()
+            .filter_map(|(&id, v)| match v.inner {
+                VertexInner::Snapshot { st, .. } if !matches!(st, SnapshotState::Completed) => {
+                    (!v.successors.contains(&vid)).then_some(id)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for snapshot_vid in pending_snapshots {
+            let _ig = self.get_vertex_mut(snapshot_vid).successors.insert(vid);
+            self.get_vertex_mut(vid).predecessor_cnt += 1;
+        }
+    }
+
     /// Update a graph after a vertex has been updated
     fn update_graph(&mut self, vid: u64) {
         let vertex_finished = self.update_vertex(vid);
@@ -206,6 +308,7 @@ impl<C: Command> Filter<C> {
                 .vs
                 .remove(&vid)
                 .expect("no such vertex in conflict graph");
+            self.index.remove(&vid);
             if let VertexInner::Cmd { ref cmd, .. } = v.inner {
                 assert!(self.cmd_vid.remove(cmd.id()).is_some(), "no such cmd");
             }
@@ -260,8 +363,12 @@ impl<C: Command> Filter<C> {
                     }
                     false
                 }
-                (ExeState::Executed(true), AsState::AfterSynced)
-                | (ExeState::Executed(false), AsState::AfterSyncReady(_)) => true,
+                (ExeState::Executed(true), AsState::AfterSynced) => true,
+                // a cmd whose execution failed won't be after synced
+                (ExeState::Executed(false), AsState::AfterSyncReady(index)) => {
+                    self.applied.finish(index);
+                    true
+                }
                 (ExeState::Executing | ExeState::Executed(_), AsState::NotSynced)
                 | (ExeState::Executing, AsState::AfterSyncReady(_) | AsState::AfterSyncing)
                 | (ExeState::Executed(true), AsState::AfterSyncing) => false,
@@ -269,11 +376,14 @@ impl<C: Command> Filter<C> {
                     unreachable!("no such cmd state can be reached: {exe_st:?}, {as_st:?}")
                 }
             },
-            VertexInner::Reset(ref mut st) => match *st {
+            VertexInner::Reset {
+                ref snapshot,
+                ref mut st,
+            } => match *st {
                 ResetState::ResetReady => {
                     let task = Task {
                         vid,
-                        inner: TaskType::Reset,
+                        inner: TaskType::Reset(snapshot.clone()),
                     };
                     *st = ResetState::Resetting;
                     if let Err(e) = self.filter_tx.send(task) {
@@ -284,6 +394,21 @@ impl<C: Command> Filter<C> {
                 ResetState::Resetting => false,
                 ResetState::Completed => true,
             },
+            VertexInner::Snapshot { meta, ref mut st } => match *st {
+                SnapshotState::SnapshotReady => {
+                    let task = Task {
+                        vid,
+                        inner: TaskType::Snapshot(meta),
+                    };
+                    *st = SnapshotState::Snapshotting;
+                    if let Err(e) = self.filter_tx.send(task) {
+                        error!("failed to send task through filter, {e}");
+                    }
+                    false
+                }
+                SnapshotState::Snapshotting => false,
+                SnapshotState::Completed => true,
+            },
         }
     }
 
@@ -320,12 +445,21 @@ impl<C: Command> Filter<C> {
             CEEvent::ASReady(cmd, index) => {
                 if let Some(vid) = self.cmd_vid.get(cmd.id()).copied() {
                     let v = self.get_vertex_mut(vid);
-                    match v.inner {
-                        VertexInner::Cmd { ref mut as_st, .. } => {
+                    let exe_failed = match v.inner {
+                        VertexInner::Cmd {
+                            ref mut as_st,
+                            exe_st,
+                            ..
+                        } => {
                             debug_assert!(matches!(*as_st, AsState::NotSynced));
                             *as_st = AsState::AfterSyncReady(index);
+                            matches!(exe_st, ExeState::Executed(false))
                         }
                         _ => unreachable!("impossible vertex type"),
+                    };
+                    // a cmd whose execution failed won't be after synced, so it needn't wait
+                    if !exe_failed {
+                        self.wait_for_pending_snapshots(vid);
                     }
                     vid
                 } else {
@@ -347,30 +481,82 @@ impl<C: Command> Filter<C> {
                     new_vid
                 }
             }
-            CEEvent::Reset => {
+            CEEvent::Reset(snapshot) => {
                 // since a reset is needed, all other vertexes doesn't matter anymore, so delete them all
                 self.cmd_vid.clear();
                 self.vs.clear();
+                self.index.clear();
 
                 let new_vid = self.next_vertex_id();
                 let new_v = Vertex {
                     successors: HashSet::new(),
                     predecessor_cnt: 0,
-                    inner: VertexInner::Reset(ResetState::ResetReady),
+                    inner: VertexInner::Reset {
+                        snapshot,
+                        st: ResetState::ResetReady,
+                    },
                 };
                 self.insert_new_vertex(new_vid, new_v);
                 new_vid
             }
+            CEEvent::Snapshot(meta) => {
+                let new_vid = self.next_vertex_id();
+                let new_v = Vertex {
+                    successors: HashSet::new(),
+                    predecessor_cnt: 0,
+                    inner: VertexInner::Snapshot {
+                        meta,
+                        st: SnapshotState::SnapshotReady,
+                    },
+                };
+                self.insert_snapshot_vertex(new_vid, new_v);
+                new_vid
+            }
         };
         self.update_graph(vid);
     }
 }
 
+/// Conflict graph of the filter, the benchmarks insert cmds into it without executing them
+#[cfg(feature = "bench")]
+pub(in crate::server) struct ConflictGraph<C: Command> {
+    /// The filter maintaining the graph
+    filter: Filter<C>,
+}
+
+#[cfg(feature = "bench")]
+impl<C: Command> ConflictGraph<C> {
+    /// Create an empty conflict graph
+    pub(in crate::server) fn new() -> Self {
+        let (filter_tx, _filter_rx) = flume::unbounded();
+        Self {
+            filter: Filter::new(filter_tx, Arc::default()),
+        }
+    }
+
+    /// Insert a cmd ready for speculative execution, it stays in the graph since it's never executed
+    pub(in crate::server) fn insert(&mut self, cmd: Arc<C>) {
+        let new_vid = self.filter.next_vertex_id();
+        let new_v = Vertex {
+            successors: HashSet::new(),
+            predecessor_cnt: 0,
+            inner: VertexInner::Cmd {
+                cmd,
+                exe_st: ExeState::ExecuteReady,
+                as_st: AsState::NotSynced,
+            },
+        };
+        self.filter.insert_new_vertex(new_vid, new_v);
+    }
+}
+
 /// Create conflict checked channel. The channel guarantees there will be no conflicted msgs received by multiple receivers at the same time.
 // Message flow:
 // send_tx -> filter_rx -> filter -> filter_tx -> recv_rx -> done_tx -> done_rx
 #[allow(clippy::type_complexity)] // it's clear
-pub(super) fn channel<C: 'static + Command>() -> (
+pub(super) fn channel<C: 'static + Command>(
+    applied: Arc<AppliedIndex>,
+) -> (
     flume::Sender<CEEvent<C>>,
     flume::Receiver<Task<C>>,
     flume::Sender<(Task<C>, bool)>,
@@ -382,7 +568,7 @@ pub(super) fn channel<C: 'static + Command>() -> (
     // recv from user to mark a msg done
     let (done_tx, done_rx) = flume::unbounded::<(Task<C>, bool)>();
     let _ig = tokio::spawn(async move {
-        let mut filter = Filter::new(filter_tx);
+        let mut filter = Filter::new(filter_tx, applied);
         #[allow(clippy::integer_arithmetic, clippy::pattern_type_mismatch)]
         // tokio internal triggers
         loop {
@@ -391,8 +577,15 @@ pub(super) fn channel<C: 'static + Command>() -> (
                 Ok((task, succeeded)) = done_rx.recv_async() => {
                     match task.inner {
                         TaskType::SpecExe(_) => filter.mark_executed(task.vid, succeeded),
-                        TaskType::AS(_, _) => filter.mark_after_synced(task.vid),
-                        TaskType::Reset => filter.mark_reset(task.vid),
+                        TaskType::AS(_, index) => {
+                            filter.mark_after_synced(task.vid);
+                            filter.applied.finish(index);
+                        }
+                        TaskType::Reset(_) => {
+                            filter.mark_reset(task.vid);
+                            filter.applied.finish_reset();
+                        }
+                        TaskType::Snapshot(_) => filter.mark_snapshot_taken(task.vid),
                     }
                 },
                 Ok(event) = filter_rx.recv_async() => {
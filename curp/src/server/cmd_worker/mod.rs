@@ -1,25 +1,35 @@
 //! `exe` stands for execution
 //! `as` stands for after sync
 
-use std::{fmt::Debug, iter, sync::Arc};
+use std::{collections::BTreeSet, fmt::Debug, iter, sync::Arc};
 
 use async_trait::async_trait;
-use clippy_utilities::NumericCast;
+use clippy_utilities::{NumericCast, OverflowArithmetic};
+use event_listener::Event;
 #[cfg(test)]
 use mockall::automock;
-use tokio::task::JoinHandle;
+use parking_lot::Mutex;
+use tokio::{sync::mpsc, task::JoinHandle};
 use tracing::{debug, error};
 
 use self::conflict_checked_mpmc::Task;
-use super::{curp_node::UncommittedPoolRef, spec_pool::SpecPoolRef};
+use super::{
+    curp_node::UncommittedPoolRef,
+    session::{Applied, CmdResult},
+    spec_pool::SpecPoolRef,
+};
 use crate::{
     cmd::{Command, CommandExecutor},
     server::{cmd_board::CmdBoardRef, cmd_worker::conflict_checked_mpmc::TaskType},
+    snapshot::{Snapshot, SnapshotMeta},
 };
 
 /// The special conflict checked mpmc
 mod conflict_checked_mpmc;
 
+#[cfg(feature = "bench")]
+pub(super) use self::conflict_checked_mpmc::ConflictGraph;
+
 /// Number of execute workers
 const N_WORKERS: usize = 8;
 
@@ -29,8 +39,10 @@ enum CEEvent<C> {
     SpecExeReady(Arc<C>),
     /// The cmd is ready for after sync
     ASReady(Arc<C>, usize),
-    /// Reset the command executor
-    Reset,
+    /// Reset the command executor, to the snapshot if there is one
+    Reset(Option<Arc<Snapshot>>),
+    /// Take a snapshot of the command executor
+    Snapshot(SnapshotMeta),
 }
 
 impl<C: Command> Debug for CEEvent<C> {
@@ -42,11 +54,114 @@ impl<C: Command> Debug for CEEvent<C> {
                 .field(cmd.id())
                 .field(index)
                 .finish(),
-            Self::Reset => write!(f, "Reset"),
+            Self::Reset(ref snapshot) => f
+                .debug_tuple("Reset")
+                .field(&snapshot.as_ref().map(|s| s.meta()))
+                .finish(),
+            Self::Snapshot(ref meta) => f.debug_tuple("Snapshot").field(meta).finish(),
         }
     }
 }
 
+/// Progress of the command executor, readers can wait until all log entries up to an index have been applied
+#[derive(Debug, Default)]
+pub(super) struct AppliedIndex {
+    /// Inner state
+    inner: Mutex<AppliedIndexInner>,
+    /// Notified when an after sync task or a reset finishes
+    event: Event,
+}
+
+/// Inner state of `AppliedIndex`
+#[derive(Debug, Default)]
+struct AppliedIndexInner {
+    /// Log entries up to this index have been sent to the command executor, or they needn't be executed
+    sent: usize,
+    /// Indexes of the unfinished after sync tasks
+    pending: BTreeSet<usize>,
+    /// Number of unfinished resets
+    resets: usize,
+}
+
+impl AppliedIndex {
+    /// Record an after sync task of the log entry at `index`
+    fn push(&self, index: usize) {
+        let mut inner = self.inner.lock();
+        let _ig = inner.pending.insert(index);
+        inner.sent = inner.sent.max(index);
+    }
+
+    /// Mark the after sync task of the log entry at `index` finished
+    fn finish(&self, index: usize) {
+        let _ig = self.inner.lock().pending.remove(&index);
+        self.event.notify(usize::MAX);
+    }
+
+    /// Record a reset to the snapshot whose last included index is `index`, unfinished after sync
+    /// tasks are dropped by the reset
+    fn push_reset(&self, index: usize) {
+        let mut inner = self.inner.lock();
+        inner.pending.clear();
+        inner.resets = inner.resets.overflow_add(1);
+        inner.sent = inner.sent.max(index);
+    }
+
+    /// Mark a reset finished
+    fn finish_reset(&self) {
+        let mut inner = self.inner.lock();
+        inner.resets = inner.resets.overflow_sub(1);
+        drop(inner);
+        self.event.notify(usize::MAX);
+    }
+
+    /// Mark log entries up to `index` applied, they are already applied or needn't be executed by the command executor
+    pub(super) fn advance(&self, index: usize) {
+        let mut inner = self.inner.lock();
+        if inner.sent < index {
+            inner.sent = index;
+            drop(inner);
+            self.event.notify(usize::MAX);
+        }
+    }
+
+    /// Whether all log entries up to `index` have been applied
+    pub(super) fn is_applied(&self, index: usize) -> bool {
+        let inner = self.inner.lock();
+        inner.resets == 0
+            && inner.sent >= index
+            && inner.pending.first().map_or(true, |first| *first > index)
+    }
+
+    /// Get the index up to which all log entries have been applied
+    pub(super) fn applied_index(&self) -> usize {
+        let inner = self.inner.lock();
+        inner
+            .pending
+            .first()
+            .map_or(inner.sent, |first| inner.sent.min(first.overflow_sub(1)))
+    }
+
+    /// Wait until all log entries up to `index` have been applied
+    pub(super) async fn wait(&self, index: usize) {
+        loop {
+            let listener = self.event.listen();
+            if self.is_applied(index) {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
+
+/// Get the results of a cmd if it has been applied in its client session and its results are known
+fn applied_result<C: Command>(cb: &CmdBoardRef<C>, cmd: &C) -> Option<CmdResult<C>> {
+    let session = cmd.id().session()?;
+    match cb.read().sessions.result(session)? {
+        Applied::Done(result) => Some(result),
+        Applied::Pending => None,
+    }
+}
+
 /// Worker that execute commands
 async fn cmd_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
     dispatch_rx: TaskRx<C>,
@@ -55,33 +170,74 @@ async fn cmd_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
     sp: SpecPoolRef<C>,
     ucp: UncommittedPoolRef<C>,
     ce: Arc<CE>,
+    snapshot_tx: mpsc::UnboundedSender<Snapshot>,
 ) {
     while let Ok(task) = dispatch_rx.recv().await {
         let succeeded = match *task.inner() {
             TaskType::SpecExe(ref cmd) => {
-                let er = ce.execute(cmd.as_ref()).await.map_err(|e| e.to_string());
-                let er_ok = er.is_ok();
-                debug!("cmd({}) is speculatively executed", cmd.id());
-                cb.write().insert_er(cmd.id(), er);
-                er_ok
+                // a retried proposal that has been applied is not executed again
+                if let Some(result) = applied_result(&cb, cmd.as_ref()) {
+                    debug!("cmd({}) has been applied, skip its execution", cmd.id());
+                    let er_ok = result.0.is_ok();
+                    cb.write().insert_applied(cmd.id(), result);
+                    er_ok
+                } else {
+                    let er = ce.execute(cmd.as_ref()).await.map_err(|e| e.to_string());
+                    let er_ok = er.is_ok();
+                    debug!("cmd({}) is speculatively executed", cmd.id());
+                    cb.write().insert_er(cmd.id(), er);
+                    er_ok
+                }
             }
             TaskType::AS(ref cmd, index) => {
+                // duplicated entries of a retried proposal are skipped, only its first entry is applied
                 let asr = ce
                     .after_sync(cmd.as_ref(), index.numeric_cast())
                     .await
                     .map_err(|e| e.to_string());
                 let asr_ok = asr.is_ok();
-                cb.write().insert_asr(cmd.id(), asr);
+                let mut cb_w = cb.write();
+                if let Some(session) = cmd.id().session() {
+                    let er = cb_w.er_buffer.get(cmd.id()).cloned();
+                    let result = er.map(|er| (er, Some(asr.clone())));
+                    cb_w.sessions.complete(session, result);
+                }
+                cb_w.insert_asr(cmd.id(), asr);
+                drop(cb_w);
+                debug!("cmd({}) after sync is called", cmd.id());
                 sp.lock().remove(cmd.id());
                 let _ig = ucp.lock().remove(cmd.id());
-                debug!("cmd({}) after sync is called", cmd.id());
                 asr_ok
             }
-            TaskType::Reset => {
-                ce.reset().await;
-                debug!("command executor has been reset");
-                true
-            }
+            TaskType::Reset(ref snapshot) => match ce.reset(snapshot.as_deref()).await {
+                Ok(()) => {
+                    debug!("command executor has been reset");
+                    true
+                }
+                Err(e) => {
+                    error!("failed to reset command executor, {e}");
+                    false
+                }
+            },
+            TaskType::Snapshot(meta) => match ce.snapshot().await {
+                Ok(data) => {
+                    debug!("snapshot at log[{}] is taken", meta.last_included_index);
+                    match cb.read().sessions.encode() {
+                        Ok(sessions) => {
+                            let snapshot = Snapshot::new(meta, data).with_sessions(sessions);
+                            if let Err(e) = snapshot_tx.send(snapshot) {
+                                error!("failed to send snapshot, {e}");
+                            }
+                        }
+                        Err(e) => error!("failed to encode sessions, {e}"),
+                    }
+                    true
+                }
+                Err(e) => {
+                    error!("failed to take snapshot, {e}");
+                    false
+                }
+            },
         };
         if let Err(e) = done_tx.send((task, succeeded)) {
             error!("can't mark a task done, the channel could be closed, {e}");
@@ -92,7 +248,14 @@ async fn cmd_worker<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
 
 /// Send event to background command executor workers
 #[derive(Debug, Clone)]
-pub(super) struct CEEventTx<C: Command + 'static>(flume::Sender<CEEvent<C>>);
+pub(super) struct CEEventTx<C: Command + 'static>(flume::Sender<CEEvent<C>>, Arc<AppliedIndex>);
+
+impl<C: Command + 'static> CEEventTx<C> {
+    /// Get the progress of the command executor
+    pub(super) fn applied(&self) -> Arc<AppliedIndex> {
+        Arc::clone(&self.1)
+    }
+}
 
 /// Recv cmds that need to be executed
 #[derive(Clone)]
@@ -107,8 +270,15 @@ pub(super) trait CEEventTxApi<C: Command + 'static>: Send + Sync + 'static {
     /// Send after sync event to the background cmd worker so that after sync can be called
     fn send_after_sync(&self, cmd: Arc<C>, index: usize);
 
-    /// Send reset
-    fn send_reset(&self);
+    /// Skip the after sync of the log entry at `index` whose cmd has been applied in a previous entry,
+    /// it's applied once the entries before it are applied
+    fn skip_after_sync(&self, index: usize);
+
+    /// Send reset, the command executor will be reset to the snapshot if there is one
+    fn send_reset(&self, snapshot: Option<Arc<Snapshot>>);
+
+    /// Send snapshot event so that a snapshot containing all log entries that have been sent to after sync will be taken
+    fn send_snapshot(&self, meta: SnapshotMeta);
 }
 
 impl<C: Command + 'static> CEEventTxApi<C> for CEEventTx<C> {
@@ -120,18 +290,36 @@ impl<C: Command + 'static> CEEventTxApi<C> for CEEventTx<C> {
     }
 
     fn send_after_sync(&self, cmd: Arc<C>, index: usize) {
+        // record the task before sending it, so that it's recorded before any later membership change
+        self.1.push(index);
         let event = CEEvent::ASReady(cmd, index);
         if let Err(e) = self.0.send(event) {
             error!("failed to send cmd as event to background cmd worker, {e}");
         }
     }
 
-    fn send_reset(&self) {
-        let msg = CEEvent::Reset;
+    fn skip_after_sync(&self, index: usize) {
+        self.1.advance(index);
+    }
+
+    fn send_reset(&self, snapshot: Option<Arc<Snapshot>>) {
+        self.1.push_reset(
+            snapshot
+                .as_ref()
+                .map_or(0, |s| s.meta().last_included_index.numeric_cast()),
+        );
+        let msg = CEEvent::Reset(snapshot);
         if let Err(e) = self.0.send(msg) {
             error!("failed to send reset event to background cmd worker, {e}");
         }
     }
+
+    fn send_snapshot(&self, meta: SnapshotMeta) {
+        let msg = CEEvent::Snapshot(meta);
+        if let Err(e) = self.0.send(msg) {
+            error!("failed to send snapshot event to background cmd worker, {e}");
+        }
+    }
 }
 
 /// Cmd exe recv interface
@@ -151,14 +339,17 @@ impl<C: Command + 'static> TaskRxApi<C> for TaskRx<C> {
 }
 
 /// Run cmd execute workers. Returns a channel to interact with these workers. The channel guarantees the execution order and that after sync is called after execution completes.
+/// Snapshots taken by the workers will be sent through `snapshot_tx`
 pub(super) fn start_cmd_workers<C: Command + 'static, CE: 'static + CommandExecutor<C>>(
     cmd_executor: CE,
     spec_pool: SpecPoolRef<C>,
     uncommitted_pool: UncommittedPoolRef<C>,
     cmd_board: CmdBoardRef<C>,
     shutdown_trigger: Arc<event_listener::Event>,
+    snapshot_tx: mpsc::UnboundedSender<Snapshot>,
 ) -> CEEventTx<C> {
-    let (event_tx, task_rx, done_tx) = conflict_checked_mpmc::channel();
+    let applied = Arc::new(AppliedIndex::default());
+    let (event_tx, task_rx, done_tx) = conflict_checked_mpmc::channel(Arc::clone(&applied));
     #[allow(clippy::shadow_unrelated)] // false positive
     let bg_worker_handles: Vec<JoinHandle<_>> = iter::repeat((
         task_rx,
@@ -167,10 +358,19 @@ pub(super) fn start_cmd_workers<C: Command + 'static, CE: 'static + CommandExecu
         spec_pool,
         uncommitted_pool,
         Arc::new(cmd_executor),
+        snapshot_tx,
     ))
     .take(N_WORKERS)
-    .map(|(task_rx, done_tx, cb, sp, ucp, ce)| {
-        tokio::spawn(cmd_worker(TaskRx(task_rx), done_tx, cb, sp, ucp, ce))
+    .map(|(task_rx, done_tx, cb, sp, ucp, ce, snapshot_tx)| {
+        tokio::spawn(cmd_worker(
+            TaskRx(task_rx),
+            done_tx,
+            cb,
+            sp,
+            ucp,
+            ce,
+            snapshot_tx,
+        ))
     })
     .collect();
 
@@ -181,7 +381,7 @@ pub(super) fn start_cmd_workers<C: Command + 'static, CE: 'static + CommandExecu
         }
     });
 
-    CEEventTx(event_tx)
+    CEEventTx(event_tx, applied)
 }
 
 #[cfg(test)]
@@ -217,6 +417,7 @@ mod tests {
             uncommitted_pool,
             Arc::clone(&cmd_board),
             Arc::new(event_listener::Event::new()),
+            mpsc::unbounded_channel().0,
         );
 
         let cmd = Arc::new(TestCommand::default());
@@ -244,6 +445,7 @@ mod tests {
             uncommitted_pool,
             Arc::clone(&cmd_board),
             Arc::new(event_listener::Event::new()),
+            mpsc::unbounded_channel().0,
         );
 
         let begin = Instant::now();
@@ -276,6 +478,7 @@ mod tests {
             uncommitted_pool,
             Arc::clone(&cmd_board),
             Arc::new(event_listener::Event::new()),
+            mpsc::unbounded_channel().0,
         );
 
         let cmd = Arc::new(
@@ -312,6 +515,7 @@ mod tests {
             uncommitted_pool,
             Arc::clone(&cmd_board),
             Arc::new(event_listener::Event::new()),
+            mpsc::unbounded_channel().0,
         );
 
         let cmd = Arc::new(TestCommand::default());
@@ -338,6 +542,7 @@ mod tests {
             uncommitted_pool,
             Arc::clone(&cmd_board),
             Arc::new(event_listener::Event::new()),
+            mpsc::unbounded_channel().0,
         );
         let cmd = Arc::new(TestCommand::default().set_exe_should_fail());
 
@@ -364,6 +569,7 @@ mod tests {
             uncommitted_pool,
             Arc::clone(&cmd_board),
             Arc::new(event_listener::Event::new()),
+            mpsc::unbounded_channel().0,
         );
 
         let cmd1 = Arc::new(TestCommand::new_put(vec![1], 1));
@@ -404,6 +610,7 @@ mod tests {
             uncommitted_pool,
             Arc::clone(&cmd_board),
             Arc::new(event_listener::Event::new()),
+            mpsc::unbounded_channel().0,
         );
 
         let cmd1 =
@@ -414,7 +621,7 @@ mod tests {
 
         assert_eq!(er_rx.recv().await.unwrap().1, vec![]);
 
-        exe_tx.send_reset();
+        exe_tx.send_reset(None);
 
         let cmd3 = Arc::new(TestCommand::new_get(vec![1]));
         exe_tx.send_after_sync(cmd3, 1);
@@ -425,4 +632,36 @@ mod tests {
         assert!(as_rx.recv().await.is_some());
         assert!(as_rx.try_recv().is_err());
     }
+
+    #[tokio::test]
+    async fn applied_index_will_wait_for_unfinished_after_sync_tasks_and_resets() {
+        let applied = AppliedIndex::default();
+        applied.advance(1);
+        assert!(applied.is_applied(1));
+
+        applied.push(2);
+        applied.push(3);
+        // log[4] is a membership change which needn't be executed
+        applied.advance(4);
+        applied.finish(3);
+        assert!(!applied.is_applied(2));
+        assert!(!applied.is_applied(4));
+        applied.finish(2);
+        assert!(applied.is_applied(4));
+        assert!(!applied.is_applied(5));
+
+        // the leader retires, the command executor is reset and committed entries are after synced again
+        applied.push(5);
+        applied.push_reset(3);
+        applied.push(4);
+        applied.push(5);
+        assert!(!applied.is_applied(3));
+        applied.finish_reset();
+        assert!(applied.is_applied(3));
+        applied.finish(4);
+        applied.finish(5);
+        tokio::time::timeout(Duration::from_secs(1), applied.wait(5))
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,103 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use itertools::Itertools;
+use utils::interval_map::{Interval, IntervalMap};
+
+use crate::cmd::{Command, IntervalKey};
+
+/// Point of the intervals of the keys of `C`
+type Point<C> = <<C as Command>::K as IntervalKey>::Point;
+
+/// Index of in-flight commands for conflict detection. Keyed commands are indexed by the intervals
+/// of their keys, so the commands that may conflict with a keyed command are found in logarithmic
+/// time, while the other commands may conflict with anything.
+#[derive(Debug)]
+pub(super) struct ConflictIndex<C: Command, V> {
+    /// Values of the keyed commands, indexed by the intervals of their keys
+    keyed: IntervalMap<Point<C>, V>,
+    /// Intervals the values in `keyed` are indexed by
+    intervals: HashMap<V, Vec<Interval<Point<C>>>>,
+    /// Values that are not indexed by keys
+    unkeyed: HashSet<V>,
+}
+
+impl<C: Command, V: Clone + Eq + Hash> ConflictIndex<C, V> {
+    /// Create an empty index
+    pub(super) fn new() -> Self {
+        Self {
+            keyed: IntervalMap::new(),
+            intervals: HashMap::new(),
+            unkeyed: HashSet::new(),
+        }
+    }
+
+    /// Insert a value, it is indexed by the keys of the `cmd` if the `cmd` is keyed
+    pub(super) fn insert(&mut self, value: V, cmd: Option<&C>) {
+        let Some(cmd) = cmd.filter(|c| c.is_keyed()) else {
+            let _ig = self.unkeyed.insert(value);
+            return;
+        };
+        let intervals = cmd.keys().iter().map(IntervalKey::interval).collect_vec();
+        for interval in &intervals {
+            self.keyed.insert(interval.clone(), value.clone());
+        }
+        let _ig = self.intervals.insert(value, intervals);
+    }
+
+    /// Remove a value
+    pub(super) fn remove(&mut self, value: &V) {
+        if let Some(intervals) = self.intervals.remove(value) {
+            for interval in &intervals {
+                let _ig = self.keyed.remove(interval, value);
+            }
+        } else {
+            let _ig = self.unkeyed.remove(value);
+        }
+    }
+
+    /// Remove all values
+    pub(super) fn clear(&mut self) {
+        self.keyed = IntervalMap::new();
+        self.intervals.clear();
+        self.unkeyed.clear();
+    }
+
+    /// Get the values that may conflict with the `cmd`, return `None` if the `cmd` is not keyed
+    /// and it should be checked against all values
+    pub(super) fn candidates(&self, cmd: &C) -> Option<HashSet<V>> {
+        if !cmd.is_keyed() {
+            return None;
+        }
+        let mut candidates = self.unkeyed.clone();
+        for key in cmd.keys() {
+            let overlaps = self.keyed.find_overlaps(&key.interval());
+            candidates.extend(overlaps.into_iter().cloned());
+        }
+        Some(candidates)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::test_cmd::TestCommand;
+
+    #[test]
+    fn candidates_will_include_overlapping_and_unkeyed_cmds() {
+        let mut index = ConflictIndex::<TestCommand, u64>::new();
+        index.insert(0, Some(&TestCommand::new_put(vec![1, 2], 0)));
+        index.insert(1, Some(&TestCommand::new_put(vec![3], 0)));
+        index.insert(2, None);
+
+        let cmd = TestCommand::new_get(vec![2]);
+        assert_eq!(index.candidates(&cmd), Some(HashSet::from([0, 2])));
+
+        index.remove(&0);
+        index.remove(&2);
+        assert_eq!(index.candidates(&cmd), Some(HashSet::new()));
+        assert!(index.candidates(&TestCommand::new_get(vec![])).is_none());
+    }
+}
@@ -2,23 +2,24 @@ use std::{collections::HashMap, fmt::Debug, sync::Arc, time::Duration};
 
 use clippy_utilities::NumericCast;
 use event_listener::Event;
-use futures::{pin_mut, stream::FuturesUnordered, StreamExt};
-use madsim::rand::{thread_rng, Rng};
+use futures::{pin_mut, stream::FuturesUnordered, Stream, StreamExt};
+use itertools::Itertools;
 use parking_lot::{Mutex, RwLock};
+use rand::Rng;
 use thiserror::Error;
 use tokio::{
     sync::{broadcast, mpsc},
-    task::JoinHandle,
-    time::MissedTickBehavior,
+    time::{Instant, MissedTickBehavior},
 };
 use tracing::{debug, error, info, warn};
 use utils::config::CurpConfig;
 
 use super::{
     cmd_board::{CmdBoardRef, CommandBoard},
-    cmd_worker::start_cmd_workers,
+    cmd_worker::{start_cmd_workers, AppliedIndex},
     gc::run_gc_tasks,
-    raw_curp::{AppendEntries, RawCurp, TickAction, Vote},
+    random::with_rng,
+    raw_curp::{AppendEntries, RawCurp, SyncAction, TickAction, Vote},
     spec_pool::{SpecPoolRef, SpeculativePool},
     storage::{StorageApi, StorageError},
 };
@@ -26,14 +27,18 @@ use crate::{
     cmd::{Command, CommandExecutor, ProposeId},
     error::ProposeError,
     log_entry::LogEntry,
-    message::ServerId,
+    members::{ConfChange, ConfChangeEntry, Member},
+    message::{LogIndex, ServerId},
     rpc::{
-        self, connect::ConnectApi, AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest,
-        FetchLeaderResponse, ProposeRequest, ProposeResponse, VoteRequest, VoteResponse,
+        connect::{ConnectApi, Connector},
+        AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest, FetchLeaderResponse,
+        InstallSnapshotRequest, InstallSnapshotResponse, ProposeConfChangeRequest,
+        ProposeConfChangeResponse, ProposeRequest, ProposeResponse, ReadIndexRequest,
+        ReadIndexResponse, TimeoutNowRequest, TimeoutNowResponse, VoteRequest, VoteResponse,
         WaitSyncedRequest, WaitSyncedResponse,
     },
     server::storage::rocksdb::RocksDBStorage,
-    TxFilter,
+    snapshot::Snapshot,
 };
 
 /// Uncommitted pool type
@@ -42,6 +47,12 @@ pub(super) type UncommittedPool<C> = HashMap<ProposeId, Arc<C>>;
 /// Reference to uncommitted pool
 pub(super) type UncommittedPoolRef<C> = Arc<Mutex<UncommittedPool<C>>>;
 
+/// Connects to other servers, they are shared between tasks and updated on membership changes
+type Connects = Arc<RwLock<HashMap<ServerId, Arc<dyn ConnectApi>>>>;
+
+/// Capacity of the channel that broadcasts applied membership changes
+const CONF_CHANGE_CHANNEL_CAP: usize = 128;
+
 /// Curp error
 #[derive(Debug, Error)]
 pub(super) enum CurpError {
@@ -68,6 +79,14 @@ pub(super) struct CurpNode<C: Command> {
     shutdown_trigger: Arc<Event>,
     /// Storage
     storage: Arc<dyn StorageApi<Command = C>>,
+    /// Broadcasts the membership changes after they take effect
+    conf_change_bcast: broadcast::Sender<ConfChange>,
+    /// Connects to other servers
+    connects: Connects,
+    /// Connector to other servers
+    connector: Arc<dyn Connector>,
+    /// Progress of the command executor
+    applied: Arc<AppliedIndex>,
 }
 
 // handlers
@@ -92,12 +111,33 @@ impl<C: 'static + Command> CurpNode<C> {
         Ok(resp)
     }
 
+    /// Handle `ProposeConfChange` requests
+    pub(super) async fn propose_conf_change(
+        &self,
+        req: ProposeConfChangeRequest,
+    ) -> Result<ProposeConfChangeResponse, CurpError> {
+        let entry = Arc::new(req.entry()?);
+
+        let ((leader_id, term), result) = self.curp.handle_propose_conf_change(Arc::clone(&entry));
+        let resp = match result {
+            // a duplicated change has been proposed before, wait for it as well
+            Ok(()) | Err(ProposeError::Duplicated) => {
+                CommandBoard::wait_for_conf(&self.cmd_board, entry.id()).await;
+                ProposeConfChangeResponse::new_ok(leader_id, term)
+            }
+            Err(err) => ProposeConfChangeResponse::new_error(leader_id, term, &err)?,
+        };
+
+        Ok(resp)
+    }
+
     /// Handle `AppendEntries` requests
-    pub(super) fn append_entries(
+    pub(super) async fn append_entries(
         &self,
         req: AppendEntriesRequest,
     ) -> Result<AppendEntriesResponse, CurpError> {
         let entries = req.entries()?;
+        let last_entry = entries.last().map(|entry| (entry.index, entry.term));
 
         let result = self.curp.handle_append_entries(
             req.term,
@@ -108,7 +148,16 @@ impl<C: 'static + Command> CurpNode<C> {
             req.leader_commit.numeric_cast(),
         );
         let resp = match result {
-            Ok(term) => AppendEntriesResponse::new_accept(term),
+            Ok(term) => {
+                // acknowledge the entries only after they are persisted
+                if let Some((index, entry_term)) = last_entry {
+                    if !self.curp.wait_persisted(index, entry_term).await {
+                        let hint = self.curp.commit_index() + 1;
+                        return Ok(AppendEntriesResponse::new_reject(term, hint));
+                    }
+                }
+                AppendEntriesResponse::new_accept(term)
+            }
             Err((term, hint)) => AppendEntriesResponse::new_reject(term, hint),
         };
 
@@ -123,10 +172,14 @@ impl<C: 'static + Command> CurpNode<C> {
             req.candidate_id.clone(),
             req.last_log_index.numeric_cast(),
             req.last_log_term,
+            req.is_pre_vote,
         );
         let resp = match result {
             Ok((term, sp)) => {
-                self.storage.flush_voted_for(term, req.candidate_id).await?;
+                // a pre-vote doesn't change the state, so there is nothing to persist
+                if !req.is_pre_vote {
+                    self.storage.flush_voted_for(term, req.candidate_id).await?;
+                }
                 VoteResponse::new_accept(term, sp)?
             }
             Err(term) => VoteResponse::new_reject(term),
@@ -150,6 +203,71 @@ impl<C: 'static + Command> CurpNode<C> {
         Ok(resp)
     }
 
+    /// Handle `InstallSnapshot` stream
+    pub(super) async fn install_snapshot(
+        &self,
+        req_stream: impl Stream<Item = Result<InstallSnapshotRequest, tonic::Status>>,
+    ) -> Result<InstallSnapshotResponse, CurpError> {
+        pin_mut!(req_stream);
+        let mut chunk = Self::next_snapshot_chunk(&mut req_stream).await?;
+        let (term, leader_id, meta) = (chunk.term, chunk.leader_id.clone(), chunk.meta());
+        if let Err(cur_term) = self
+            .curp
+            .handle_install_snapshot(term, leader_id.clone(), meta)
+        {
+            return Ok(InstallSnapshotResponse::new(cur_term));
+        }
+
+        // receive the whole snapshot
+        let mut data = vec![];
+        let (sessions, encoded_members) = loop {
+            let received: u64 = data.len().numeric_cast();
+            if chunk.offset != received {
+                return Err(CurpError::Internal(format!(
+                    "snapshot chunk offset mismatch, expect {}, got {}",
+                    data.len(),
+                    chunk.offset
+                )));
+            }
+            data.extend(chunk.data);
+            if chunk.done {
+                break (chunk.sessions, chunk.members);
+            }
+            chunk = Self::next_snapshot_chunk(&mut req_stream).await?;
+        };
+
+        // the state may have changed during the transfer, so check it again
+        let term = match self
+            .curp
+            .handle_install_snapshot(term, leader_id.clone(), meta)
+        {
+            Ok(term) => term,
+            Err(cur_term) => return Ok(InstallSnapshotResponse::new(cur_term)),
+        };
+        let index: usize = meta.last_included_index.numeric_cast();
+        // the members are persisted first, the membership changes in the snapshot won't be applied again
+        let members = if encoded_members.is_empty() {
+            None
+        } else {
+            let (is_learner, others) = self
+                .curp
+                .snapshot_members(&leader_id, bincode::deserialize(&encoded_members)?);
+            self.storage.put_members(is_learner, &others, index).await?;
+            Some((is_learner, others))
+        };
+        let snapshot = Snapshot::new(meta, data)
+            .with_sessions(sessions)
+            .with_members(encoded_members);
+        // the snapshot must be persisted before it is installed
+        let discard_to = self.curp.snapshot_discard_index(meta);
+        self.storage.install_snapshot(&snapshot, discard_to).await?;
+        self.curp.install_snapshot(snapshot);
+        if let Some((is_learner, others)) = members {
+            self.install_members(index, is_learner, others).await;
+        }
+        Ok(InstallSnapshotResponse::new(term))
+    }
+
     /// Handle fetch leader requests
     #[allow(clippy::unnecessary_wraps, clippy::needless_pass_by_value)] // To keep type consistent with other request handlers
     pub(super) fn fetch_leader(
@@ -159,17 +277,137 @@ impl<C: 'static + Command> CurpNode<C> {
         let (leader_id, term) = self.curp.leader();
         Ok(FetchLeaderResponse::new(leader_id, term))
     }
+
+    /// Handle `TimeoutNow` requests, the transferee starts an election immediately
+    #[allow(clippy::unnecessary_wraps)] // To keep type consistent with other request handlers
+    pub(super) fn timeout_now(
+        &self,
+        req: TimeoutNowRequest,
+    ) -> Result<TimeoutNowResponse, CurpError> {
+        let votes = match self.curp.handle_timeout_now(req.term, &req.leader_id) {
+            Ok(votes) => votes,
+            Err(term) => return Ok(TimeoutNowResponse::new(term)),
+        };
+        let curp = Arc::clone(&self.curp);
+        let connects = self.connects.read().clone();
+        let _handle = tokio::spawn(async move {
+            Self::bcast_votes(curp, &connects, votes).await;
+        });
+        Ok(TimeoutNowResponse::new(self.curp.leader().1))
+    }
+
+    /// Transfer the leadership to `target`, wait until it becomes the leader
+    pub(super) async fn move_leader(&self, target: ServerId) -> Result<(), ProposeError> {
+        let mut leader_rx = self.curp.leader_rx();
+        if self.curp.handle_move_leader(&target)? {
+            return Ok(());
+        }
+        // the transfer is aborted after an election timeout, and the transferee may need
+        // another one to win the election
+        let cfg = self.curp.cfg();
+        let wait_timeout = cfg.heartbeat_interval * u32::from(cfg.follower_timeout_ticks) * 2;
+        let transferred = async {
+            while self.curp.leader().0.as_ref() != Some(&target) {
+                // it's ok to lag behind since the leader is checked again
+                let _ig = leader_rx.recv().await;
+            }
+        };
+        tokio::time::timeout(wait_timeout, transferred)
+            .await
+            .map_err(|_elapsed| {
+                ProposeError::LeaderTransfer(format!(
+                    "failed to transfer the leadership to {target} in time"
+                ))
+            })
+    }
+
+    /// Handle `ReadIndex` requests, the read index is returned after the leadership is confirmed
+    pub(super) async fn read_index(
+        &self,
+        _req: ReadIndexRequest,
+    ) -> Result<ReadIndexResponse, CurpError> {
+        let (leader_id, term) = self.curp.leader();
+        let resp = match self.confirm_read_index().await {
+            Ok(index) => ReadIndexResponse::new_ok(leader_id, term, index.numeric_cast()),
+            Err(err) => ReadIndexResponse::new_error(leader_id, term, &err)?,
+        };
+        Ok(resp)
+    }
+
+    /// Wait until a linearizable read can be served by this server, i.e. all log entries up to
+    /// the read index have been applied. A follower will ask the leader for the read index.
+    pub(super) async fn wait_read_index(&self) -> Result<(), ProposeError> {
+        let index = match self.confirm_read_index().await {
+            Err(ProposeError::NotLeader) => self.fetch_read_index().await?,
+            result => result?,
+        };
+        let wait_timeout = self.curp.cfg().wait_synced_timeout;
+        tokio::time::timeout(wait_timeout, self.applied.wait(index))
+            .await
+            .map_err(|_elapsed| {
+                ProposeError::ReadIndex(format!("log[{index}] is not applied in time"))
+            })
+    }
+
+    /// The leader confirms its leadership by a round of heartbeats, return the read index
+    async fn confirm_read_index(&self) -> Result<usize, ProposeError> {
+        let read_index = self.curp.handle_read_index()?;
+        if read_index.acks == 0 {
+            return Ok(read_index.index);
+        }
+        let connects = self.connects.read().clone();
+        let resps = Self::send_heartbeats(&self.curp, &connects, read_index.heartbeats);
+        pin_mut!(resps);
+        let mut acks = 0_usize;
+        while let Some((id, resp)) = resps.next().await {
+            let result = self.curp.handle_append_entries_resp(
+                &id,
+                None,
+                resp.term,
+                resp.success,
+                resp.hint_index.numeric_cast(),
+            );
+            if result.is_err() {
+                return Err(ProposeError::NotLeader);
+            }
+            acks = acks.saturating_add(1);
+            if acks >= read_index.acks {
+                return Ok(read_index.index);
+            }
+        }
+        Err(ProposeError::ReadIndex(
+            "the leadership is not confirmed by a quorum".to_owned(),
+        ))
+    }
+
+    /// Ask the leader for the read index
+    async fn fetch_read_index(&self) -> Result<usize, ProposeError> {
+        let Some(leader_id) = self.curp.leader().0 else {
+            return Err(ProposeError::ReadIndex("the leader is unknown".to_owned()));
+        };
+        let Some(connect) = self.connects.read().get(&leader_id).cloned() else {
+            return Err(ProposeError::ReadIndex(format!(
+                "no connect for the leader {leader_id}"
+            )));
+        };
+        let resp = connect
+            .read_index(ReadIndexRequest::new(), self.curp.cfg().rpc_timeout)
+            .await?
+            .into_inner();
+        if let Some(err) = resp.error()? {
+            return Err(err);
+        }
+        Ok(resp.read_index.numeric_cast())
+    }
 }
 
 /// Spawned tasks
 impl<C: 'static + Command> CurpNode<C> {
     /// Tick periodically
-    async fn tick_task(curp: Arc<RawCurp<C>>, connects: HashMap<ServerId, Arc<impl ConnectApi>>) {
+    async fn tick_task(curp: Arc<RawCurp<C>>, connects: Connects) {
         let heartbeat_interval = curp.cfg().heartbeat_interval;
         // wait for some random time before tick starts to minimize vote split possibility
-        let rand = thread_rng()
-            .gen_range(0..heartbeat_interval.as_millis())
-            .numeric_cast();
+        let rand = with_rng(|rng| rng.gen_range(0..heartbeat_interval.as_millis())).numeric_cast();
         tokio::time::sleep(Duration::from_millis(rand)).await;
 
         let mut ticker = tokio::time::interval(heartbeat_interval);
@@ -178,13 +416,27 @@ impl<C: 'static + Command> CurpNode<C> {
         loop {
             let _now = ticker.tick().await;
             let action = curp.tick();
+            let connects = connects.read().clone();
             match action {
                 TickAction::Heartbeat(hbs) => {
                     Self::bcast_heartbeats(Arc::clone(&curp), &connects, hbs).await;
                 }
+                TickAction::PreVotes(pre_votes) => {
+                    let votes =
+                        Self::bcast_pre_votes(Arc::clone(&curp), &connects, pre_votes).await;
+                    if let Some(votes) = votes {
+                        Self::bcast_votes(Arc::clone(&curp), &connects, votes).await;
+                    }
+                }
                 TickAction::Votes(votes) => {
                     Self::bcast_votes(Arc::clone(&curp), &connects, votes).await;
                 }
+                TickAction::TimeoutNow(hbs, transferee, term) => {
+                    let ((), ()) = tokio::join!(
+                        Self::bcast_heartbeats(Arc::clone(&curp), &connects, hbs),
+                        Self::send_timeout_now(Arc::clone(&curp), &connects, transferee, term),
+                    );
+                }
                 TickAction::Nothing => {}
             }
         }
@@ -193,23 +445,91 @@ impl<C: 'static + Command> CurpNode<C> {
     /// Background leader calibrate followers
     async fn calibrate_task(
         curp: Arc<RawCurp<C>>,
-        connects: HashMap<ServerId, Arc<impl ConnectApi>>,
+        connects: Connects,
         mut calibrate_rx: mpsc::UnboundedReceiver<ServerId>,
     ) {
-        let mut handlers: HashMap<ServerId, JoinHandle<()>> = HashMap::new();
+        // a follower is sent here only when its calibration is not in progress
         while let Some(follower_id) = calibrate_rx.recv().await {
-            if handlers
-                .get(&follower_id)
-                .map_or(false, |hd| !hd.is_finished())
-            {
+            // the server may have been removed, or the connection is not established yet
+            let Some(connect) = connects.read().get(&follower_id).cloned() else {
+                warn!("no connect for server {follower_id}, skip calibrating");
+                curp.finish_calibrating(&follower_id);
                 continue;
+            };
+            let _handle =
+                tokio::spawn(Self::leader_calibrates_follower(Arc::clone(&curp), connect));
+        }
+    }
+
+    /// Membership change task, makes committed membership changes take effect
+    #[allow(clippy::too_many_arguments)] // only called once
+    async fn conf_change_task(
+        curp: Arc<RawCurp<C>>,
+        connects: Connects,
+        cmd_board: CmdBoardRef<C>,
+        mut conf_change_rx: mpsc::UnboundedReceiver<(usize, Arc<ConfChangeEntry>)>,
+        conf_change_bcast: broadcast::Sender<ConfChange>,
+        storage: Arc<dyn StorageApi<Command = C>>,
+        connector: Arc<dyn Connector>,
+        applied: Arc<AppliedIndex>,
+    ) {
+        while let Some((index, entry)) = conf_change_rx.recv().await {
+            let change = entry.change();
+            if curp.apply_conf_change(index, change) {
+                Self::conf_change_take_effect(&curp, &connects, change, &connector).await;
+                // the members must be durable before the change is regarded as applied, or it will be lost on restart
+                while let Err(err) = storage
+                    .put_members(curp.is_learner(), &curp.others(), index)
+                    .await
+                {
+                    error!("failed to persist members, retry later, {err}");
+                    tokio::time::sleep(curp.cfg().retry_timeout).await;
+                }
+            }
+            // membership changes are not executed by the command executor
+            applied.advance(index);
+            cmd_board.write().insert_conf(entry.id());
+            // it's ok if there is no subscriber
+            let _ig = conf_change_bcast.send(change.clone());
+        }
+        error!("conf change task exits unexpectedly");
+    }
+
+    /// Update the connections to other servers after a membership change is applied
+    async fn conf_change_take_effect(
+        curp: &RawCurp<C>,
+        connects: &Connects,
+        change: &ConfChange,
+        connector: &Arc<dyn Connector>,
+    ) {
+        match *change {
+            ConfChange::AddNode {
+                ref id,
+                ref address,
+            }
+            | ConfChange::AddLearner {
+                ref id,
+                ref address,
             }
-            let connect = connects
-                .get(&follower_id)
-                .cloned()
-                .unwrap_or_else(|| unreachable!("no server {follower_id}'s connect"));
-            let hd = tokio::spawn(Self::leader_calibrates_follower(Arc::clone(&curp), connect));
-            let _prev_hd = handlers.insert(follower_id, hd);
+            | ConfChange::AddWitness {
+                ref id,
+                ref address,
+            }
+            | ConfChange::UpdateNode {
+                ref id,
+                ref address,
+            } => {
+                if id != curp.id() {
+                    let new_connects = connector
+                        .connect(HashMap::from([(id.clone(), address.clone())]))
+                        .await;
+                    connects.write().extend(new_connects);
+                }
+            }
+            ConfChange::RemoveNode { ref id } => {
+                let _ig = connects.write().remove(id);
+            }
+            ConfChange::PromoteLearner { .. } | ConfChange::UpdateClientUrls { .. } => {}
         }
     }
 }
@@ -224,11 +544,13 @@ impl<C: 'static + Command> CurpNode<C> {
         others: HashMap<ServerId, String>,
         cmd_executor: CE,
         curp_cfg: Arc<CurpConfig>,
-        tx_filter: Option<Box<dyn TxFilter>>,
+        connector: Arc<dyn Connector>,
     ) -> Result<Self, CurpError> {
         let (sync_tx, sync_rx) = mpsc::unbounded_channel();
         let (calibrate_tx, calibrate_rx) = mpsc::unbounded_channel();
+        let (conf_change_tx, conf_change_rx) = mpsc::unbounded_channel();
         let (log_tx, log_rx) = mpsc::unbounded_channel();
+        let (snapshot_tx, snapshot_rx) = mpsc::unbounded_channel();
         let shutdown_trigger = Arc::new(Event::new());
         let cmd_board = Arc::new(RwLock::new(CommandBoard::new()));
         let spec_pool = Arc::new(Mutex::new(SpeculativePool::new()));
@@ -238,6 +560,17 @@ impl<C: 'static + Command> CurpNode<C> {
             .map_err(|e| CurpError::Internal(format!("get applied index error, {e}")))?;
 
         let storage = Arc::new(RocksDBStorage::new(&curp_cfg.data_dir)?);
+        // the persisted members are more up to date if the membership has been changed
+        let (is_learner, others, members_index) =
+            storage.recover_members().await?.unwrap_or_else(|| {
+                let voters = others
+                    .into_iter()
+                    .map(|(id, address)| (id, Member::new(address, false)))
+                    .collect();
+                (false, voters, 0)
+            });
+        let (conf_change_bcast, _conf_change_brx) = broadcast::channel(CONF_CHANGE_CHANNEL_CAP);
+        let connects = Arc::new(RwLock::new(HashMap::new()));
 
         // start cmd workers
         let exe_tx = start_cmd_workers(
@@ -246,14 +579,18 @@ impl<C: 'static + Command> CurpNode<C> {
             Arc::clone(&uncommitted_pool),
             Arc::clone(&cmd_board),
             Arc::clone(&shutdown_trigger),
+            snapshot_tx,
         );
+        let applied = exe_tx.applied();
+        applied.advance(last_applied.numeric_cast());
 
         // create curp state machine
-        let (voted_for, entries) = storage.recover().await?;
-        let curp = if voted_for.is_none() && entries.is_empty() {
+        let (voted_for, snapshot, entries) = storage.recover().await?;
+        let curp = if voted_for.is_none() && snapshot.is_none() && entries.is_empty() {
             Arc::new(RawCurp::new(
                 id,
-                others.keys().cloned().collect(),
+                others.clone(),
+                is_learner,
                 is_leader,
                 Arc::clone(&cmd_board),
                 Arc::clone(&spec_pool),
@@ -262,18 +599,21 @@ impl<C: 'static + Command> CurpNode<C> {
                 Box::new(exe_tx),
                 sync_tx,
                 calibrate_tx,
+                conf_change_tx,
                 log_tx,
             ))
         } else {
             info!(
-                "{} recovered voted_for({voted_for:?}), entries from {:?} to {:?}",
+                "{} recovered voted_for({voted_for:?}), snapshot({:?}), entries from {:?} to {:?}",
                 id,
+                snapshot.as_ref().map(Snapshot::meta),
                 entries.first(),
                 entries.last()
             );
             Arc::new(RawCurp::recover_from(
                 id,
-                others.keys().cloned().collect(),
+                others.clone(),
+                is_learner,
                 is_leader,
                 Arc::clone(&cmd_board),
                 Arc::clone(&spec_pool),
@@ -282,34 +622,84 @@ impl<C: 'static + Command> CurpNode<C> {
                 Box::new(exe_tx),
                 sync_tx,
                 calibrate_tx,
+                conf_change_tx,
                 log_tx,
                 voted_for,
+                snapshot,
                 entries,
                 last_applied.numeric_cast(),
+                members_index,
             ))
         };
 
         run_gc_tasks(Arc::clone(&cmd_board), Arc::clone(&spec_pool));
 
+        let curp_w = Arc::downgrade(&curp);
+        let applied_c = Arc::clone(&applied);
+        curp.metrics().register_state(move || {
+            curp_w
+                .upgrade()
+                .map(|curp| curp.sample_state(applied_c.applied_index()))
+        });
+
         let curp_c = Arc::clone(&curp);
+        let cmd_board_c = Arc::clone(&cmd_board);
         let shutdown_trigger_c = Arc::clone(&shutdown_trigger);
         let storage_c = Arc::clone(&storage);
+        let conf_change_bcast_c = conf_change_bcast.clone();
+        let connects_c = Arc::clone(&connects);
+        let applied_c = Arc::clone(&applied);
+        let connector_c = Arc::clone(&connector);
         let _ig = tokio::spawn(async move {
             // establish connection with other servers
-            let connects = rpc::connect(others, tx_filter).await;
-            let tick_task = tokio::spawn(Self::tick_task(Arc::clone(&curp_c), connects.clone()));
+            let addrs = others
+                .into_iter()
+                .map(|(id, member)| (id, member.address().to_owned()))
+                .collect();
+            let new_connects = connector_c.connect(addrs).await;
+            connects_c.write().extend(new_connects);
+            let tick_task = tokio::spawn(Self::tick_task(
+                Arc::clone(&curp_c),
+                Arc::clone(&connects_c),
+            ));
             let sync_task = tokio::spawn(Self::sync_task(
                 Arc::clone(&curp_c),
-                connects.clone(),
+                Arc::clone(&connects_c),
                 sync_rx,
             ));
-            let calibrate_task = tokio::spawn(Self::calibrate_task(curp_c, connects, calibrate_rx));
-            let log_persist_task = tokio::spawn(Self::log_persist_task(log_rx, storage_c));
+            let calibrate_task = tokio::spawn(Self::calibrate_task(
+                Arc::clone(&curp_c),
+                Arc::clone(&connects_c),
+                calibrate_rx,
+            ));
+            let conf_change_task = tokio::spawn(Self::conf_change_task(
+                Arc::clone(&curp_c),
+                connects_c,
+                cmd_board_c,
+                conf_change_rx,
+                conf_change_bcast_c,
+                Arc::clone(&storage_c),
+                connector_c,
+                Arc::clone(&applied_c),
+            ));
+            let log_persist_task = tokio::spawn(Self::log_persist_task(
+                Arc::clone(&curp_c),
+                log_rx,
+                Arc::clone(&storage_c),
+            ));
+            let snapshot_task = tokio::spawn(Self::snapshot_task(
+                curp_c,
+                snapshot_rx,
+                storage_c,
+                applied_c,
+            ));
             shutdown_trigger_c.listen().await;
             tick_task.abort();
             sync_task.abort();
             calibrate_task.abort();
+            conf_change_task.abort();
             log_persist_task.abort();
+            snapshot_task.abort();
         });
 
         Ok(Self {
@@ -318,23 +708,45 @@ impl<C: 'static + Command> CurpNode<C> {
             cmd_board,
             shutdown_trigger,
             storage,
+            conf_change_bcast,
+            connects,
+            connector,
+            applied,
         })
     }
 
     /// Leader broadcasts heartbeats
     async fn bcast_heartbeats(
         curp: Arc<RawCurp<C>>,
-        connects: &HashMap<ServerId, Arc<impl ConnectApi>>,
+        connects: &HashMap<ServerId, Arc<dyn ConnectApi>>,
         hbs: HashMap<ServerId, AppendEntries<C>>,
     ) {
-        let rpc_timeout = curp.cfg().rpc_timeout;
-        let resps = hbs
-            .into_iter()
-            .map(|(id, hb)| {
-                let connect = connects
-                    .get(&id)
-                    .cloned()
-                    .unwrap_or_else(|| unreachable!("no server {id}'s connect"));
+        let resps = Self::send_heartbeats(&curp, connects, hbs);
+        pin_mut!(resps);
+        while let Some((id, resp)) = resps.next().await {
+            let result = curp.handle_append_entries_resp(
+                &id,
+                None,
+                resp.term,
+                resp.success,
+                resp.hint_index.numeric_cast(),
+            );
+            if result.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Send heartbeats to other servers, return a stream of their responses
+    fn send_heartbeats(
+        curp: &Arc<RawCurp<C>>,
+        connects: &HashMap<ServerId, Arc<dyn ConnectApi>>,
+        hbs: HashMap<ServerId, AppendEntries<C>>,
+    ) -> impl Stream<Item = (ServerId, AppendEntriesResponse)> {
+        hbs.into_iter()
+            .filter_map(|(id, hb)| {
+                // the connection with a newly added server may not be established yet
+                let connect = connects.get(&id).cloned()?;
                 let req = AppendEntriesRequest::new_heartbeat(
                     hb.term,
                     hb.leader_id,
@@ -342,10 +754,11 @@ impl<C: 'static + Command> CurpNode<C> {
                     hb.prev_log_term,
                     hb.leader_commit,
                 );
-                async move {
-                    let resp = connect.append_entries(req, rpc_timeout).await;
+                let curp = Arc::clone(curp);
+                Some(async move {
+                    let resp = Self::send_append_entries(&curp, connect.as_ref(), req).await;
                     (id, resp)
-                }
+                })
             })
             .collect::<FuturesUnordered<_>>()
             .filter_map(|(id, resp)| async move {
@@ -356,46 +769,46 @@ impl<C: 'static + Command> CurpNode<C> {
                     }
                     Ok(resp) => Some((id, resp.into_inner())),
                 }
-            });
-        pin_mut!(resps);
-        while let Some((id, resp)) = resps.next().await {
-            let result = curp.handle_append_entries_resp(
-                &id,
-                None,
-                resp.term,
-                resp.success,
-                resp.hint_index.numeric_cast(),
-            );
-            if result.is_err() {
-                return;
-            }
+            })
+    }
+
+    /// Send `AppendEntries` to a follower, the latency is recorded if it succeeds
+    async fn send_append_entries(
+        curp: &RawCurp<C>,
+        connect: &dyn ConnectApi,
+        req: AppendEntriesRequest,
+    ) -> Result<tonic::Response<AppendEntriesResponse>, ProposeError> {
+        let start = Instant::now();
+        let resp = connect.append_entries(req, curp.cfg().rpc_timeout).await;
+        if resp.is_ok() {
+            curp.metrics()
+                .on_append_entries(connect.id(), start.elapsed());
         }
+        resp
     }
 
-    /// Candidate broadcasts votes
-    async fn bcast_votes(
-        curp: Arc<RawCurp<C>>,
-        connects: &HashMap<ServerId, Arc<impl ConnectApi>>,
+    /// Send votes to other servers, return a stream of their responses
+    fn send_votes(
+        connects: &HashMap<ServerId, Arc<dyn ConnectApi>>,
         votes: HashMap<ServerId, Vote>,
-    ) {
-        let rpc_timeout = curp.cfg().rpc_timeout;
-        let resps = votes
+        rpc_timeout: Duration,
+    ) -> impl Stream<Item = (ServerId, VoteResponse)> {
+        votes
             .into_iter()
-            .map(|(id, vote)| {
-                let connect = connects
-                    .get(&id)
-                    .cloned()
-                    .unwrap_or_else(|| unreachable!("no server {id}'s connect"));
+            .filter_map(|(id, vote)| {
+                // the connection with a newly added server may not be established yet
+                let connect = connects.get(&id).cloned()?;
                 let req = VoteRequest::new(
                     vote.term,
                     vote.candidate_id,
                     vote.last_log_index,
                     vote.last_log_term,
+                    vote.is_pre_vote,
                 );
-                async move {
+                Some(async move {
                     let resp = connect.vote(req, rpc_timeout).await;
                     (id, resp)
-                }
+                })
             })
             .collect::<FuturesUnordered<_>>()
             .filter_map(|(id, resp)| async move {
@@ -406,7 +819,35 @@ impl<C: 'static + Command> CurpNode<C> {
                     }
                     Ok(resp) => Some((id, resp.into_inner())),
                 }
-            });
+            })
+    }
+
+    /// Pre-candidate broadcasts pre-votes
+    /// Return the votes to broadcast if the pre-vote succeeds
+    async fn bcast_pre_votes(
+        curp: Arc<RawCurp<C>>,
+        connects: &HashMap<ServerId, Arc<dyn ConnectApi>>,
+        pre_votes: HashMap<ServerId, Vote>,
+    ) -> Option<HashMap<ServerId, Vote>> {
+        let resps = Self::send_votes(connects, pre_votes, curp.cfg().rpc_timeout);
+        pin_mut!(resps);
+        while let Some((id, resp)) = resps.next().await {
+            match curp.handle_pre_vote_resp(&id, resp.term, resp.vote_granted) {
+                Ok(None) => {}
+                Ok(Some(votes)) => return Some(votes),
+                Err(()) => return None,
+            }
+        }
+        None
+    }
+
+    /// Candidate broadcasts votes
+    async fn bcast_votes(
+        curp: Arc<RawCurp<C>>,
+        connects: &HashMap<ServerId, Arc<dyn ConnectApi>>,
+        votes: HashMap<ServerId, Vote>,
+    ) {
+        let resps = Self::send_votes(connects, votes, curp.cfg().rpc_timeout);
         pin_mut!(resps);
         while let Some((id, resp)) = resps.next().await {
             // collect follower spec pool
@@ -426,151 +867,185 @@ impl<C: 'static + Command> CurpNode<C> {
         }
     }
 
+    /// Leader asks the transferee to start an election immediately
+    async fn send_timeout_now(
+        curp: Arc<RawCurp<C>>,
+        connects: &HashMap<ServerId, Arc<dyn ConnectApi>>,
+        transferee: ServerId,
+        term: u64,
+    ) {
+        let Some(connect) = connects.get(&transferee) else {
+            warn!("no connect for server {transferee}, skip sending timeout_now");
+            return;
+        };
+        let req = TimeoutNowRequest::new(term, curp.id().clone());
+        match connect.timeout_now(req, curp.cfg().rpc_timeout).await {
+            Ok(resp) => curp.handle_timeout_now_resp(resp.into_inner().term),
+            Err(e) => warn!("timeout_now to {transferee} failed, {e}"),
+        }
+    }
+
     /// Leader calibrates a follower
     #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)] // log.len() >= 1 because we have a fake log[0], indexing of `next_index` or `match_index` won't panic because we created an entry when initializing the server state
     async fn leader_calibrates_follower(curp: Arc<RawCurp<C>>, connect: Arc<dyn ConnectApi>) {
         debug!("{} starts calibrating follower {}", curp.id(), connect.id());
         let (rpc_timeout, retry_timeout) = (curp.cfg().rpc_timeout, curp.cfg().retry_timeout);
+        // the follower must leave calibrating on every exit, or it won't be calibrated again
+        let guard = CalibrationGuard {
+            curp: curp.as_ref(),
+            follower_id: connect.id(),
+        };
         loop {
-            // send append entry
-            let Ok(ae) = curp.append_entries(connect.id()) else {
+            let Ok(action) = curp.sync(connect.id()) else {
                 return;
             };
-            let last_sent_index = ae.prev_log_index + ae.entries.len();
-            let req = match AppendEntriesRequest::new(
-                ae.term,
-                ae.leader_id,
-                ae.prev_log_index,
-                ae.prev_log_term,
-                ae.entries,
-                ae.leader_commit,
-            ) {
-                Err(e) => {
-                    error!("unable to serialize append entries request: {}", e);
-                    return;
-                }
-                Ok(req) => req,
-            };
+            match action {
+                SyncAction::AppendEntries(ae) => {
+                    // send append entry
+                    let last_sent_index = ae.prev_log_index + ae.entries.len();
+                    let req = match AppendEntriesRequest::new(
+                        ae.term,
+                        ae.leader_id,
+                        ae.prev_log_index,
+                        ae.prev_log_term,
+                        ae.entries,
+                        ae.leader_commit,
+                    ) {
+                        Err(e) => {
+                            error!("unable to serialize append entries request: {}", e);
+                            return;
+                        }
+                        Ok(req) => req,
+                    };
 
-            let resp = connect.append_entries(req, rpc_timeout).await;
+                    let resp = Self::send_append_entries(&curp, connect.as_ref(), req).await;
 
-            #[allow(clippy::unwrap_used)]
-            // indexing of `next_index` or `match_index` won't panic because we created an entry when initializing the server state
-            match resp {
-                Err(e) => {
-                    warn!("append_entries error: {e}");
-                }
-                Ok(resp) => {
-                    let resp = resp.into_inner();
-
-                    let result = curp.handle_append_entries_resp(
-                        connect.id(),
-                        Some(last_sent_index),
-                        resp.term,
-                        resp.success,
-                        resp.hint_index.numeric_cast(),
-                    );
+                    #[allow(clippy::unwrap_used)]
+                    // indexing of `next_index` or `match_index` won't panic because we created an entry when initializing the server state
+                    match resp {
+                        Err(e) => {
+                            warn!("append_entries error: {e}");
+                        }
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
 
-                    match result {
-                        Ok(true) => {
-                            debug!(
-                                "{} successfully calibrates follower {}",
-                                curp.id(),
-                                connect.id()
+                            let result = curp.handle_append_entries_resp(
+                                connect.id(),
+                                Some(last_sent_index),
+                                resp.term,
+                                resp.success,
+                                resp.hint_index.numeric_cast(),
                             );
-                            return;
+
+                            match result {
+                                Ok(true) => {
+                                    debug!(
+                                        "{} successfully calibrates follower {}",
+                                        curp.id(),
+                                        connect.id()
+                                    );
+                                    break;
+                                }
+                                Ok(false) => {}
+                                Err(()) => {
+                                    return;
+                                }
+                            }
                         }
-                        Ok(false) => {}
-                        Err(()) => {
-                            return;
+                    };
+                }
+                SyncAction::Snapshot { term, snapshot } => {
+                    // the follower lags behind the snapshot, install the snapshot on it
+                    let meta = snapshot.meta();
+                    let resp = connect
+                        .install_snapshot(term, curp.id().clone(), snapshot, rpc_timeout)
+                        .await;
+                    match resp {
+                        Err(e) => {
+                            warn!("install_snapshot error: {e}");
+                        }
+                        Ok(resp) => {
+                            let resp = resp.into_inner();
+                            if curp
+                                .handle_install_snapshot_resp(connect.id(), meta, resp.term)
+                                .is_err()
+                            {
+                                return;
+                            }
+                            // send the entries after the snapshot immediately
+                            continue;
                         }
                     }
                 }
-            };
+            }
             tokio::time::sleep(retry_timeout).await;
         }
+        // the remaining entries are sent through the pipeline
+        drop(guard);
+        let _handle = tokio::spawn(Self::pipeline_logs(curp, connect));
     }
 
     /// Sync task is responsible for replicating log entries
     async fn sync_task(
         curp: Arc<RawCurp<C>>,
-        connects: HashMap<ServerId, Arc<impl ConnectApi>>,
+        connects: Connects,
         mut sync_rx: mpsc::UnboundedReceiver<usize>,
     ) {
-        while let Some(i) = sync_rx.recv().await {
-            let req = {
-                let Ok(ae) = curp.append_entries_single(i) else {
-                    continue;
-                };
-                let req = AppendEntriesRequest::new(
-                    ae.term,
-                    ae.leader_id,
-                    ae.prev_log_index,
-                    ae.prev_log_term,
-                    ae.entries,
-                    ae.leader_commit,
-                );
-                match req {
-                    Err(e) => {
-                        error!("can't serialize log entries, {e}");
-                        continue;
-                    }
-                    Ok(req) => req,
-                }
-            };
-            // send append_entries to each server in parallel
-            for connect in connects.values() {
-                let _handle = tokio::spawn(Self::send_log_until_succeed(
-                    Arc::clone(&curp),
-                    Arc::clone(connect),
-                    i,
-                    req.clone(),
-                ));
+        while sync_rx.recv().await.is_some() {
+            // new entries pushed in the meantime are sent in the same batch
+            while sync_rx.try_recv().is_ok() {}
+            // replicate to each server in parallel
+            let connects = connects.read().values().cloned().collect_vec();
+            for connect in connects {
+                let _handle = tokio::spawn(Self::pipeline_logs(Arc::clone(&curp), connect));
             }
             curp.opt_out_hb();
         }
     }
 
-    /// Send `append_entries` containing a single log to a server
-    #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)] // log.len() >= 1 because we have a fake log[0], indexing of `next_index` or `match_index` won't panic because we created an entry when initializing the server state
-    async fn send_log_until_succeed(
-        curp: Arc<RawCurp<C>>,
-        connect: Arc<impl ConnectApi>,
-        i: usize,
-        req: AppendEntriesRequest,
-    ) {
-        let (rpc_timeout, retry_timeout) = (curp.cfg().rpc_timeout, curp.cfg().retry_timeout);
-        // send log[i] until succeed
-        loop {
-            let resp = connect.append_entries(req.clone(), rpc_timeout).await;
-
-            #[allow(clippy::unwrap_used)]
-            // indexing of `next_index` or `match_index` won't panic because we created an entry when initializing the server state
-            match resp {
+    /// Send batches of log entries to a follower through its pipeline until there is nothing to
+    /// send, at most `max_inflight_appends` of these tasks are sending to a follower at the same time
+    /// On failures, the leader backs off and calibrates the follower instead
+    #[allow(clippy::integer_arithmetic)] // won't overflow
+    async fn pipeline_logs(curp: Arc<RawCurp<C>>, connect: Arc<dyn ConnectApi>) {
+        while let Ok(Some(ae)) = curp.next_pipelined_batch(connect.id()) {
+            let last_sent_index = ae.prev_log_index + ae.entries.len();
+            let req = match AppendEntriesRequest::new(
+                ae.term,
+                ae.leader_id,
+                ae.prev_log_index,
+                ae.prev_log_term,
+                ae.entries,
+                ae.leader_commit,
+            ) {
                 Err(e) => {
-                    warn!("append_entries error: {e}");
+                    error!("can't serialize log entries, {e}");
+                    curp.finish_pipelined_batch(connect.id());
+                    curp.handle_append_entries_error(connect.id());
+                    return;
                 }
-                Ok(resp) => {
-                    let resp = resp.into_inner();
-
-                    let result = curp.handle_append_entries_resp(
-                        connect.id(),
-                        Some(i),
-                        resp.term,
-                        resp.success,
-                        resp.hint_index.numeric_cast(),
-                    );
-
-                    match result {
-                        Ok(true) | Err(()) => return,
-                        Ok(false) => {
-                            warn!("{} failed to send log {i}, retrying", curp.id());
-                        }
-                    }
+                Ok(req) => req,
+            };
+            let resp = Self::send_append_entries(&curp, connect.as_ref(), req).await;
+            curp.finish_pipelined_batch(connect.id());
+            let resp = match resp {
+                Err(e) => {
+                    warn!("append_entries to {} error: {e}", connect.id());
+                    curp.handle_append_entries_error(connect.id());
+                    return;
                 }
+                Ok(resp) => resp.into_inner(),
+            };
+            let result = curp.handle_append_entries_resp(
+                connect.id(),
+                Some(last_sent_index),
+                resp.term,
+                resp.success,
+                resp.hint_index.numeric_cast(),
+            );
+            if !matches!(result, Ok(true)) {
+                return;
             }
-            // wait for some time until next retry
-            tokio::time::sleep(retry_timeout).await;
         }
     }
 
@@ -579,18 +1054,146 @@ impl<C: 'static + Command> CurpNode<C> {
         self.curp.leader_rx()
     }
 
-    /// Log persist task
+    /// Get a rx for membership changes that have taken effect
+    pub(super) fn conf_change_rx(&self) -> broadcast::Receiver<ConfChange> {
+        self.conf_change_bcast.subscribe()
+    }
+
+    /// Get other members of the cluster
+    pub(super) fn others(&self) -> HashMap<ServerId, Member> {
+        self.curp.others()
+    }
+
+    /// Whether self is a learner
+    pub(super) fn is_learner(&self) -> bool {
+        self.curp.is_learner()
+    }
+
+    /// Get the current term
+    pub(super) fn term(&self) -> u64 {
+        self.curp.leader().1
+    }
+
+    /// Get the leader known by self and the current term
+    #[cfg(test)]
+    pub(super) fn leader(&self) -> (Option<ServerId>, u64) {
+        self.curp.leader()
+    }
+
+    /// Get the registry of the metrics
+    pub(super) fn metrics_registry(&self) -> prometheus::Registry {
+        self.curp.metrics().registry().clone()
+    }
+
+    /// Get the commit index
+    pub(super) fn commit_index(&self) -> LogIndex {
+        self.curp.commit_index().numeric_cast()
+    }
+
+    /// Log persist task, all pending log entries are persisted in one batch
     pub(super) async fn log_persist_task(
+        curp: Arc<RawCurp<C>>,
         mut log_rx: mpsc::UnboundedReceiver<LogEntry<C>>,
         storage: Arc<dyn StorageApi<Command = C>>,
     ) {
         while let Some(e) = log_rx.recv().await {
-            if let Err(err) = storage.put_log_entry(e).await {
-                error!("storage error, {err}");
+            let mut entries = vec![e];
+            while let Ok(e) = log_rx.try_recv() {
+                entries.push(e);
+            }
+            // the batch is retried until it's durable, the persisted index must not skip any entry
+            while let Err(err) = storage.put_log_entries(&entries).await {
+                error!("failed to persist log entries, retry later, {err}");
+                tokio::time::sleep(curp.cfg().retry_timeout).await;
+            }
+            if let Some(last) = entries.last() {
+                curp.on_log_persisted(last.index, last.term);
             }
         }
         error!("log persist task exits unexpectedly");
     }
+
+    /// Install the members carried by the snapshot and update the connections to other servers
+    async fn install_members(
+        &self,
+        index: usize,
+        is_learner: bool,
+        others: HashMap<ServerId, Member>,
+    ) {
+        let prev = self.curp.others();
+        let addrs: HashMap<_, _> = others
+            .iter()
+            .filter(|&(id, member)| {
+                prev.get(id).map_or(true, |prev_member| {
+                    prev_member.address() != member.address()
+                })
+            })
+            .map(|(id, member)| (id.clone(), member.address().to_owned()))
+            .collect();
+        self.connects
+            .write()
+            .retain(|id, _| others.contains_key(id));
+        self.curp.install_members(index, is_learner, others);
+        let new_connects = self.connector.connect(addrs).await;
+        self.connects.write().extend(new_connects);
+    }
+
+    /// Get the next chunk from the `InstallSnapshot` stream
+    async fn next_snapshot_chunk(
+        req_stream: &mut (impl Stream<Item = Result<InstallSnapshotRequest, tonic::Status>> + Unpin),
+    ) -> Result<InstallSnapshotRequest, CurpError> {
+        req_stream
+            .next()
+            .await
+            .ok_or_else(|| {
+                CurpError::Internal("install_snapshot stream ends unexpectedly".to_owned())
+            })?
+            .map_err(|e| CurpError::Internal(format!("receive snapshot chunk error, {e}")))
+    }
+
+    /// Snapshot task, persists snapshots taken by the command executor and compacts the log with them
+    async fn snapshot_task(
+        curp: Arc<RawCurp<C>>,
+        mut snapshot_rx: mpsc::UnboundedReceiver<Snapshot>,
+        storage: Arc<dyn StorageApi<Command = C>>,
+        applied: Arc<AppliedIndex>,
+    ) {
+        while let Some(snapshot) = snapshot_rx.recv().await {
+            // the members must contain the membership changes included in the snapshot, the later ones
+            // will be applied again by the server installing it, which is harmless as they are idempotent
+            applied
+                .wait(snapshot.meta().last_included_index.numeric_cast())
+                .await;
+            let snapshot = match bincode::serialize(&curp.others()) {
+                Ok(members) => snapshot.with_members(members),
+                Err(err) => {
+                    error!("failed to encode members, {err}");
+                    continue;
+                }
+            };
+            // the snapshot must be persisted before the log is compacted
+            if let Err(err) = storage.put_snapshot(&snapshot).await {
+                error!("failed to persist snapshot, {err}");
+                continue;
+            }
+            curp.compact_log(snapshot);
+        }
+        error!("snapshot task exits unexpectedly");
+    }
+}
+
+/// Finishes calibrating a follower when dropped, so the calibration can exit at any point
+struct CalibrationGuard<'a, C: Command> {
+    /// Curp
+    curp: &'a RawCurp<C>,
+    /// Id of the follower being calibrated
+    follower_id: &'a ServerId,
+}
+
+impl<C: Command> Drop for CalibrationGuard<'_, C> {
+    fn drop(&mut self) {
+        self.curp.finish_calibrating(self.follower_id);
+    }
 }
 
 impl<C: Command> Drop for CurpNode<C> {
@@ -614,119 +1217,90 @@ impl<C: Command> Debug for CurpNode<C> {
 #[cfg(test)]
 mod tests {
     use tracing_test::traced_test;
-    use utils::config::default_retry_timeout;
 
     use super::*;
     use crate::{
-        log_entry::LogEntry,
-        rpc::connect::MockConnectApi,
-        server::cmd_worker::MockCEEventTxApi,
-        test_utils::{sleep_millis, test_cmd::TestCommand},
+        rpc::connect::MockConnectApi, server::cmd_worker::MockCEEventTxApi, snapshot::SnapshotMeta,
+        test_utils::test_cmd::TestCommand,
     };
 
-    /*************** tests for send_log_until_succeed **************/
+    /*************** tests for pipeline_logs **************/
 
     #[traced_test]
     #[tokio::test]
-    async fn logs_will_be_resent() {
+    async fn pipeline_will_back_off_after_rpc_error() {
         let curp = Arc::new(RawCurp::new_test(
             3,
             MockCEEventTxApi::<TestCommand>::default(),
         ));
+        curp.push_cmd(Arc::new(TestCommand::default()));
 
         let mut mock_connect = MockConnectApi::default();
         mock_connect
             .expect_append_entries()
-            .times(3..)
+            .times(1)
             .returning(|_, _| Err(ProposeError::RpcStatus("timeout".to_owned())));
         mock_connect.expect_id().return_const("S1".to_owned());
 
-        let handle = tokio::spawn(async move {
-            let req = AppendEntriesRequest::new(
-                1,
-                "S0".to_owned(),
-                0,
-                0,
-                vec![LogEntry::new(1, 1, Arc::new(TestCommand::default()))],
-                0,
-            )
-            .unwrap();
-            CurpNode::send_log_until_succeed(curp, Arc::new(mock_connect), 1, req).await;
-        });
-        sleep_millis(default_retry_timeout().as_millis() as u64 * 4).await;
-        assert!(!handle.is_finished());
-        handle.abort();
+        CurpNode::pipeline_logs(Arc::clone(&curp), Arc::new(mock_connect)).await;
+        // the follower is being calibrated, so the pipeline won't send anything
+        curp.push_cmd(Arc::new(TestCommand::default()));
+        assert!(matches!(
+            curp.next_pipelined_batch(&"S1".to_owned()),
+            Ok(None)
+        ));
     }
 
     #[traced_test]
     #[tokio::test]
-    async fn send_log_will_stop_after_new_election() {
+    async fn pipeline_will_stop_after_new_election() {
         let curp = {
             let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
-            exe_tx.expect_send_reset().returning(|| ());
+            exe_tx.expect_send_reset().returning(|_| ());
             Arc::new(RawCurp::new_test(3, exe_tx))
         };
+        curp.push_cmd(Arc::new(TestCommand::default()));
 
         let mut mock_connect = MockConnectApi::default();
-        mock_connect.expect_append_entries().returning(|_, _| {
-            Ok(tonic::Response::new(AppendEntriesResponse::new_reject(
-                2, 0,
-            )))
-        });
+        mock_connect
+            .expect_append_entries()
+            .times(1)
+            .returning(|_, _| {
+                Ok(tonic::Response::new(AppendEntriesResponse::new_reject(
+                    2, 0,
+                )))
+            });
         mock_connect.expect_id().return_const("S1".to_owned());
 
-        let curp_c = Arc::clone(&curp);
-        let handle = tokio::spawn(async move {
-            let req = AppendEntriesRequest::new(
-                1,
-                "S0".to_owned(),
-                0,
-                0,
-                vec![LogEntry::new(1, 1, Arc::new(TestCommand::default()))],
-                0,
-            )
-            .unwrap();
-            CurpNode::send_log_until_succeed(curp, Arc::new(mock_connect), 1, req).await;
-        });
-
-        assert!(handle.await.is_ok());
-        assert_eq!(curp_c.term(), 2);
+        CurpNode::pipeline_logs(Arc::clone(&curp), Arc::new(mock_connect)).await;
+        assert_eq!(curp.term(), 2);
     }
 
     #[traced_test]
     #[tokio::test]
-    async fn send_log_will_succeed_and_commit() {
+    async fn pipeline_will_batch_logs_and_commit() {
         let curp = {
             let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
-            exe_tx.expect_send_reset().returning(|| ());
+            exe_tx.expect_send_reset().returning(|_| ());
             exe_tx.expect_send_after_sync().returning(|_, _| ());
             Arc::new(RawCurp::new_test(3, exe_tx))
         };
-        let cmd = Arc::new(TestCommand::default());
-        curp.push_cmd(Arc::clone(&cmd));
+        for _ in 0..3 {
+            let index = curp.push_cmd(Arc::new(TestCommand::default()));
+            curp.on_log_persisted(index, curp.term());
+        }
 
         let mut mock_connect = MockConnectApi::default();
         mock_connect
             .expect_append_entries()
+            .times(1)
+            .withf(|req, _| req.entries.len() == 3)
             .returning(|_, _| Ok(tonic::Response::new(AppendEntriesResponse::new_accept(0))));
         mock_connect.expect_id().return_const("S1".to_owned());
-        let curp_c = Arc::clone(&curp);
-        let handle = tokio::spawn(async move {
-            let req = AppendEntriesRequest::new(
-                1,
-                "S0".to_owned(),
-                0,
-                0,
-                vec![LogEntry::new(1, 1, cmd)],
-                0,
-            )
-            .unwrap();
-            CurpNode::send_log_until_succeed(curp, Arc::new(mock_connect), 1, req).await;
-        });
 
-        assert!(handle.await.is_ok());
-        assert_eq!(curp_c.term(), 0);
-        assert_eq!(curp_c.commit_index(), 1);
+        CurpNode::pipeline_logs(Arc::clone(&curp), Arc::new(mock_connect)).await;
+        assert_eq!(curp.term(), 0);
+        assert_eq!(curp.commit_index(), 3);
     }
 
     #[traced_test]
@@ -761,4 +1335,43 @@ mod tests {
 
         CurpNode::leader_calibrates_follower(curp, Arc::new(mock_connect)).await;
     }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn leader_will_install_snapshot_on_lagging_follower() {
+        let curp = {
+            let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+            exe_tx.expect_send_after_sync().returning(|_, _| ());
+            Arc::new(RawCurp::new_test(3, exe_tx))
+        };
+        curp.push_cmd(Arc::new(TestCommand::default()));
+        curp.push_cmd(Arc::new(TestCommand::default()));
+        curp.compact_log(Snapshot::new(
+            SnapshotMeta {
+                last_included_index: 2,
+                last_included_term: 0,
+            },
+            vec![],
+        ));
+
+        let mut mock_connect = MockConnectApi::default();
+        mock_connect
+            .expect_install_snapshot()
+            .times(1)
+            .returning(|term, _, snapshot, _| {
+                assert_eq!(term, 0);
+                assert_eq!(snapshot.meta().last_included_index, 2);
+                Ok(tonic::Response::new(InstallSnapshotResponse::new(0)))
+            });
+        mock_connect
+            .expect_append_entries()
+            .times(1)
+            .returning(|req, _| {
+                assert_eq!(req.prev_log_index, 2);
+                Ok(tonic::Response::new(AppendEntriesResponse::new_accept(0)))
+            });
+        mock_connect.expect_id().return_const("S1".to_owned());
+
+        CurpNode::leader_calibrates_follower(curp, Arc::new(mock_connect)).await;
+    }
 }
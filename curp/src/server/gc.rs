@@ -9,7 +9,7 @@ use crate::{
 };
 
 /// How often we should do gc
-const GC_INTERVAL: Duration = Duration::from_secs(20);
+pub(super) const GC_INTERVAL: Duration = Duration::from_secs(20);
 
 /// Run background GC tasks for Curp server
 pub(super) fn run_gc_tasks<C: Command + 'static>(cmd_board: CmdBoardRef<C>, spec: SpecPoolRef<C>) {
@@ -24,7 +24,7 @@ async fn gc_spec_pool<C: Command + 'static>(sp: SpecPoolRef<C>, interval: Durati
     loop {
         tokio::time::sleep(interval).await;
         let mut sp = sp.lock();
-        sp.pool.retain(|k, _v| !last_check.contains(k));
+        sp.retain(|id| !last_check.contains(id));
 
         last_check = sp.pool.keys().cloned().collect();
     }
@@ -35,6 +35,7 @@ async fn gc_cmd_board<C: Command + 'static>(cmd_board: CmdBoardRef<C>, interval:
     let mut last_check_len_er = 0;
     let mut last_check_len_asr = 0;
     let mut last_check_len_sync = 0;
+    let mut last_check_len_conf = 0;
     loop {
         tokio::time::sleep(interval).await;
         let mut board = cmd_board.write();
@@ -58,6 +59,12 @@ async fn gc_cmd_board<C: Command + 'static>(cmd_board: CmdBoardRef<C>, interval:
             board.sync = new_sync;
             last_check_len_sync = board.sync.len();
         }
+
+        if last_check_len_conf <= board.conf_buffer.len() {
+            let new_conf_buffer = board.conf_buffer.split_off(last_check_len_conf);
+            board.conf_buffer = new_conf_buffer;
+            last_check_len_conf = board.conf_buffer.len();
+        }
     }
 }
 
@@ -0,0 +1,271 @@
+use std::{fmt::Debug, time::Duration};
+
+use clippy_utilities::NumericCast;
+use prometheus::{
+    core::{Collector, Desc},
+    exponential_buckets,
+    proto::MetricFamily,
+    HistogramOpts, HistogramVec, IntCounter, IntGauge, Registry,
+};
+
+/// Metrics of a curp server, they are registered to the registry of the server
+pub(super) struct Metrics {
+    /// The registry which all the metrics are registered to
+    registry: Registry,
+    /// Proposals appended to the log by the leader
+    proposals: IntCounter,
+    /// Proposals rejected by the speculative pool because of conflicts
+    conflicts: IntCounter,
+    /// Elections started by the server
+    elections_started: IntCounter,
+    /// Elections won by the server
+    elections_won: IntCounter,
+    /// Times the term of the server changes
+    term_changes: IntCounter,
+    /// Latency of the successful `AppendEntries`, labelled with the follower
+    append_entries_latency: HistogramVec,
+}
+
+impl Metrics {
+    /// Create the metrics and register them to a new registry
+    pub(super) fn new() -> Self {
+        let registry = Registry::new();
+        let proposals = register(
+            &registry,
+            IntCounter::new(
+                "curp_leader_proposals_total",
+                "Proposals appended to the log by the leader",
+            ),
+        );
+        let conflicts = register(
+            &registry,
+            IntCounter::new(
+                "curp_proposal_conflicts_total",
+                "Proposals rejected by the speculative pool because of conflicts",
+            ),
+        );
+        let elections_started = register(
+            &registry,
+            IntCounter::new("curp_elections_started_total", "Elections started"),
+        );
+        let elections_won = register(
+            &registry,
+            IntCounter::new("curp_elections_won_total", "Elections won"),
+        );
+        let term_changes = register(
+            &registry,
+            IntCounter::new("curp_term_changes_total", "Times the term changes"),
+        );
+        // from 100us to about 3s
+        let buckets = exponential_buckets(0.0001, 2.0, 16)
+            .unwrap_or_else(|e| unreachable!("buckets should be valid, {e}"));
+        let append_entries_latency = register(
+            &registry,
+            HistogramVec::new(
+                HistogramOpts::new(
+                    "curp_append_entries_duration_seconds",
+                    "Latency of the successful append entries, labelled with the follower",
+                )
+                .buckets(buckets),
+                &["peer"],
+            ),
+        );
+        Self {
+            registry,
+            proposals,
+            conflicts,
+            elections_started,
+            elections_won,
+            term_changes,
+            append_entries_latency,
+        }
+    }
+
+    /// Get the registry which all the metrics are registered to
+    pub(super) fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Register the gauges of the server state, the state is sampled when the metrics are
+    /// gathered, `sample` returns `None` if the server has been dropped
+    pub(super) fn register_state(
+        &self,
+        sample: impl Fn() -> Option<StateSample> + Send + Sync + 'static,
+    ) {
+        self.registry
+            .register(Box::new(StateCollector::new(Box::new(sample))))
+            .unwrap_or_else(|e| unreachable!("the state should only be registered once, {e}"));
+    }
+
+    /// Record a proposal appended to the log by the leader, whether it completes on the fast path
+    /// is decided by the client
+    pub(super) fn on_leader_propose(&self) {
+        self.proposals.inc();
+    }
+
+    /// Record a proposal rejected by the speculative pool
+    pub(super) fn on_conflict(&self) {
+        self.conflicts.inc();
+    }
+
+    /// Record an election started by the server
+    pub(super) fn on_election_started(&self) {
+        self.elections_started.inc();
+    }
+
+    /// Record an election won by the server
+    pub(super) fn on_election_won(&self) {
+        self.elections_won.inc();
+    }
+
+    /// Record a term change
+    pub(super) fn on_term_change(&self) {
+        self.term_changes.inc();
+    }
+
+    /// Record the latency of a successful `AppendEntries` to `peer`
+    pub(super) fn on_append_entries(&self, peer: &str, latency: Duration) {
+        self.append_entries_latency
+            .with_label_values(&[peer])
+            .observe(latency.as_secs_f64());
+    }
+}
+
+impl Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics")
+            .field("registry", &self.registry)
+            .finish()
+    }
+}
+
+/// The state of the server, it's sampled when the metrics are gathered
+#[derive(Debug, Clone, Copy)]
+pub(super) struct StateSample {
+    /// Current term
+    pub(super) term: u64,
+    /// Index of the last log entry
+    pub(super) last_log_index: usize,
+    /// Index of the highest log entry known to be committed
+    pub(super) commit_index: usize,
+    /// Index of the highest log entry applied by the command executor
+    pub(super) applied_index: usize,
+    /// Number of the log entries that are not compacted
+    pub(super) log_len: usize,
+}
+
+/// Collector of the gauges of the server state
+struct StateCollector {
+    /// Sample the state of the server
+    sample: Box<dyn Fn() -> Option<StateSample> + Send + Sync>,
+    /// Current term
+    term: IntGauge,
+    /// Commit index
+    commit_index: IntGauge,
+    /// Log entries that are not committed yet
+    commit_lag: IntGauge,
+    /// Applied index
+    applied_index: IntGauge,
+    /// Committed log entries that are not applied yet
+    apply_lag: IntGauge,
+    /// Number of the log entries that are not compacted
+    log_len: IntGauge,
+    /// Descriptions of the gauges
+    descs: Vec<Desc>,
+}
+
+impl StateCollector {
+    /// Create a new `StateCollector`
+    fn new(sample: Box<dyn Fn() -> Option<StateSample> + Send + Sync>) -> Self {
+        let gauge = |name: &str, help: &str| {
+            IntGauge::new(name, help).unwrap_or_else(|e| unreachable!("invalid gauge {name}, {e}"))
+        };
+        let term = gauge("curp_term", "Current term");
+        let commit_index = gauge("curp_commit_index", "Index of the last committed log entry");
+        let commit_lag = gauge(
+            "curp_commit_lag",
+            "Number of the log entries that are not committed yet",
+        );
+        let applied_index = gauge("curp_applied_index", "Index of the last applied log entry");
+        let apply_lag = gauge(
+            "curp_apply_lag",
+            "Number of the committed log entries that are not applied yet",
+        );
+        let log_len = gauge(
+            "curp_log_length",
+            "Number of the log entries kept in memory",
+        );
+        let descs = [
+            &term,
+            &commit_index,
+            &commit_lag,
+            &applied_index,
+            &apply_lag,
+            &log_len,
+        ]
+        .into_iter()
+        .flat_map(Collector::desc)
+        .cloned()
+        .collect();
+        Self {
+            sample,
+            term,
+            commit_index,
+            commit_lag,
+            applied_index,
+            apply_lag,
+            log_len,
+            descs,
+        }
+    }
+}
+
+impl Collector for StateCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        if let Some(sample) = (self.sample)() {
+            self.term.set(sample.term.numeric_cast());
+            self.commit_index.set(sample.commit_index.numeric_cast());
+            self.commit_lag.set(
+                sample
+                    .last_log_index
+                    .saturating_sub(sample.commit_index)
+                    .numeric_cast(),
+            );
+            self.applied_index.set(sample.applied_index.numeric_cast());
+            self.apply_lag.set(
+                sample
+                    .commit_index
+                    .saturating_sub(sample.applied_index)
+                    .numeric_cast(),
+            );
+            self.log_len.set(sample.log_len.numeric_cast());
+        }
+        [
+            &self.term,
+            &self.commit_index,
+            &self.commit_lag,
+            &self.applied_index,
+            &self.apply_lag,
+            &self.log_len,
+        ]
+        .into_iter()
+        .flat_map(Collector::collect)
+        .collect()
+    }
+}
+
+/// Register a newly created metric to the registry
+fn register<M: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: prometheus::Result<M>,
+) -> M {
+    let metric = metric.unwrap_or_else(|e| unreachable!("invalid metric, {e}"));
+    registry
+        .register(Box::new(metric.clone()))
+        .unwrap_or_else(|e| unreachable!("metrics should only be registered once, {e}"));
+    metric
+}
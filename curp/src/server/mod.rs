@@ -1,7 +1,9 @@
 use std::{collections::HashMap, fmt::Debug, sync::Arc};
 
+use prometheus::Registry;
 use tokio::{net::TcpListener, sync::broadcast};
 use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::ClientTlsConfig;
 use tower::filter::FilterLayer;
 use tracing::{info, instrument};
 use utils::{config::CurpConfig, tracing::Extract};
@@ -9,12 +11,15 @@ use utils::{config::CurpConfig, tracing::Extract};
 use self::curp_node::{CurpError, CurpNode};
 use crate::{
     cmd::{Command, CommandExecutor},
-    error::ServerError,
-    message::ServerId,
+    error::{ProposeError, ServerError},
+    members::{ConfChange, Member},
+    message::{LogIndex, ServerId},
     rpc::{
-        AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest, FetchLeaderResponse,
-        ProposeRequest, ProposeResponse, ProtocolServer, VoteRequest, VoteResponse,
-        WaitSyncedRequest, WaitSyncedResponse,
+        connect::TonicConnector, AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest,
+        FetchLeaderResponse, InnerProtocolServer, InstallSnapshotRequest, InstallSnapshotResponse,
+        ProposeConfChangeRequest, ProposeConfChangeResponse, ProposeRequest, ProposeResponse,
+        ProtocolServer, ReadIndexRequest, ReadIndexResponse, TimeoutNowRequest, TimeoutNowResponse,
+        VoteRequest, VoteResponse, WaitSyncedRequest, WaitSyncedResponse,
     },
     TxFilter,
 };
@@ -31,6 +36,12 @@ mod cmd_board;
 /// Speculative pool
 mod spec_pool;
 
+/// Index of commands by their keys for conflict detection
+mod conflict_index;
+
+/// Client sessions for exactly-once proposals
+mod session;
+
 /// Background garbage collection for Curp server
 mod gc;
 
@@ -40,6 +51,26 @@ mod curp_node;
 /// Storage
 mod storage;
 
+/// Witness which only keeps a durable speculative pool
+mod witness;
+
+/// Random numbers of the server, they can be seeded
+mod random;
+
+/// Metrics of the server
+mod metrics;
+
+/// Deterministic simulation of a curp cluster
+#[cfg(test)]
+mod sim;
+
+/// Entry points of the benchmarks to the conflict detection of the server
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+
+pub use self::witness::Witness;
+
 /// Default server serving port
 static DEFAULT_SERVER_PORT: u16 = 12345;
 
@@ -75,13 +106,36 @@ impl<C: 'static + Command> crate::rpc::Protocol for Rpc<C> {
         ))
     }
 
+    #[instrument(skip_all, name = "curp_fetch_leader")]
+    async fn fetch_leader(
+        &self,
+        request: tonic::Request<FetchLeaderRequest>,
+    ) -> Result<tonic::Response<FetchLeaderResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            self.inner.fetch_leader(request.into_inner())?,
+        ))
+    }
+
+    #[instrument(skip_all, name = "curp_read_index")]
+    async fn read_index(
+        &self,
+        request: tonic::Request<ReadIndexRequest>,
+    ) -> Result<tonic::Response<ReadIndexResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            self.inner.read_index(request.into_inner()).await?,
+        ))
+    }
+}
+
+#[tonic::async_trait]
+impl<C: 'static + Command> crate::rpc::InnerProtocol for Rpc<C> {
     #[instrument(skip_all, name = "curp_append_entries")]
     async fn append_entries(
         &self,
         request: tonic::Request<AppendEntriesRequest>,
     ) -> Result<tonic::Response<AppendEntriesResponse>, tonic::Status> {
         Ok(tonic::Response::new(
-            self.inner.append_entries(request.into_inner())?,
+            self.inner.append_entries(request.into_inner()).await?,
         ))
     }
 
@@ -95,19 +149,40 @@ impl<C: 'static + Command> crate::rpc::Protocol for Rpc<C> {
         ))
     }
 
-    #[instrument(skip_all, name = "curp_fetch_leader")]
-    async fn fetch_leader(
+    #[instrument(skip_all, name = "curp_install_snapshot")]
+    async fn install_snapshot(
         &self,
-        request: tonic::Request<FetchLeaderRequest>,
-    ) -> Result<tonic::Response<FetchLeaderResponse>, tonic::Status> {
+        request: tonic::Request<tonic::Streaming<InstallSnapshotRequest>>,
+    ) -> Result<tonic::Response<InstallSnapshotResponse>, tonic::Status> {
         Ok(tonic::Response::new(
-            self.inner.fetch_leader(request.into_inner())?,
+            self.inner.install_snapshot(request.into_inner()).await?,
+        ))
+    }
+
+    #[instrument(skip_all, name = "curp_propose_conf_change")]
+    async fn propose_conf_change(
+        &self,
+        request: tonic::Request<ProposeConfChangeRequest>,
+    ) -> Result<tonic::Response<ProposeConfChangeResponse>, tonic::Status> {
+        request.metadata().extract_span();
+        Ok(tonic::Response::new(
+            self.inner.propose_conf_change(request.into_inner()).await?,
+        ))
+    }
+
+    #[instrument(skip_all, name = "curp_timeout_now")]
+    async fn timeout_now(
+        &self,
+        request: tonic::Request<TimeoutNowRequest>,
+    ) -> Result<tonic::Response<TimeoutNowResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            self.inner.timeout_now(request.into_inner())?,
         ))
     }
 }
 
 impl<C: Command + 'static> Rpc<C> {
-    /// New `Rpc`
+    /// New `Rpc`, the server connects to other servers through tls if `tls_config` is set
     ///
     /// # Panics
     /// Panic if storage creation failed
@@ -119,10 +194,12 @@ impl<C: Command + 'static> Rpc<C> {
         executor: CE,
         curp_cfg: Arc<CurpConfig>,
         tx_filter: Option<Box<dyn TxFilter>>,
+        tls_config: Option<ClientTlsConfig>,
     ) -> Self {
+        let connector = Arc::new(TonicConnector::new(tx_filter, tls_config));
         #[allow(clippy::panic)]
         let curp_node =
-            match CurpNode::new(id, is_leader, others, executor, curp_cfg, tx_filter).await {
+            match CurpNode::new(id, is_leader, others, executor, curp_cfg, connector).await {
                 Ok(n) => n,
                 Err(err) => {
                     panic!("failed to create curp service, {err}");
@@ -163,12 +240,13 @@ impl<C: Command + 'static> Rpc<C> {
     {
         let port = server_port.unwrap_or(DEFAULT_SERVER_PORT);
         info!("RPC server {id} started, listening on port {port}");
-        let server = Self::new(id, is_leader, others, executor, curp_cfg, tx_filter).await;
+        let server = Self::new(id, is_leader, others, executor, curp_cfg, tx_filter, None).await;
 
         if let Some(f) = rx_filter {
             tonic::transport::Server::builder()
                 .layer(f)
-                .add_service(ProtocolServer::new(server))
+                .add_service(ProtocolServer::new(server.clone()))
+                .add_service(InnerProtocolServer::new(server))
                 .serve(
                     format!("0.0.0.0:{port}")
                         .parse()
@@ -177,7 +255,8 @@ impl<C: Command + 'static> Rpc<C> {
                 .await?;
         } else {
             tonic::transport::Server::builder()
-                .add_service(ProtocolServer::new(server))
+                .add_service(ProtocolServer::new(server.clone()))
+                .add_service(InnerProtocolServer::new(server))
                 .serve(
                     format!("0.0.0.0:{port}")
                         .parse()
@@ -215,17 +294,19 @@ impl<C: Command + 'static> Rpc<C> {
             ) -> Result<tonic::codegen::http::Request<tonic::transport::Body>, UE>,
         UE: 'static + Send + Sync + std::error::Error,
     {
-        let server = Self::new(id, is_leader, others, executor, curp_cfg, tx_filter).await;
+        let server = Self::new(id, is_leader, others, executor, curp_cfg, tx_filter, None).await;
 
         if let Some(f) = rx_filter {
             tonic::transport::Server::builder()
                 .layer(f)
-                .add_service(ProtocolServer::new(server))
+                .add_service(ProtocolServer::new(server.clone()))
+                .add_service(InnerProtocolServer::new(server))
                 .serve_with_incoming(TcpListenerStream::new(listener))
                 .await?;
         } else {
             tonic::transport::Server::builder()
-                .add_service(ProtocolServer::new(server))
+                .add_service(ProtocolServer::new(server.clone()))
+                .add_service(InnerProtocolServer::new(server))
                 .serve_with_incoming(TcpListenerStream::new(listener))
                 .await?;
         }
@@ -238,6 +319,72 @@ impl<C: Command + 'static> Rpc<C> {
     pub fn leader_rx(&self) -> broadcast::Receiver<Option<ServerId>> {
         self.inner.leader_rx()
     }
+
+    /// Get a subscriber for membership changes, a change is sent after it takes effect
+    #[inline]
+    #[must_use]
+    pub fn conf_change_rx(&self) -> broadcast::Receiver<ConfChange> {
+        self.inner.conf_change_rx()
+    }
+
+    /// Get other members of the cluster
+    #[inline]
+    #[must_use]
+    pub fn others(&self) -> HashMap<ServerId, Member> {
+        self.inner.others()
+    }
+
+    /// Whether this server is a learner
+    #[inline]
+    #[must_use]
+    pub fn is_learner(&self) -> bool {
+        self.inner.is_learner()
+    }
+
+    /// Get the registry of the metrics of this server, it can be served to prometheus
+    #[inline]
+    #[must_use]
+    pub fn metrics_registry(&self) -> Registry {
+        self.inner.metrics_registry()
+    }
+
+    /// Get the current term of this server
+    #[inline]
+    #[must_use]
+    pub fn term(&self) -> u64 {
+        self.inner.term()
+    }
+
+    /// Get the commit index of this server
+    #[inline]
+    #[must_use]
+    pub fn commit_index(&self) -> LogIndex {
+        self.inner.commit_index()
+    }
+
+    /// Transfer the leadership of this server to `target`, return after `target` becomes the leader
+    /// Proposals are rejected during the transfer, and it will be aborted if `target` can't take
+    /// over in an election timeout
+    ///
+    /// # Errors
+    /// Return `ProposeError::NotLeader` if this server is not the leader
+    /// Return `ProposeError::LeaderTransfer` if `target` is not a voter or the transfer fails
+    #[inline]
+    pub async fn move_leader(&self, target: ServerId) -> Result<(), ProposeError> {
+        self.inner.move_leader(target).await
+    }
+
+    /// Wait until this server can serve a linearizable read locally, it returns after the leader
+    /// has confirmed its leadership and the log entries up to the read index have been applied
+    ///
+    /// # Errors
+    /// Return `ProposeError::ReadIndex` if the leadership can't be confirmed or the log entries
+    /// are not applied in time
+    /// Return `ProposeError::NotLeader` if the leader has changed
+    #[inline]
+    pub async fn read_index(&self) -> Result<(), ProposeError> {
+        self.inner.wait_read_index().await
+    }
 }
 
 impl From<CurpError> for tonic::Status {
@@ -0,0 +1,23 @@
+use std::cell::RefCell;
+
+use rand::{rngs::StdRng, thread_rng, RngCore, SeedableRng};
+
+thread_local! {
+    /// The seeded random number generator of the current thread, it's only set in simulations
+    static SEEDED_RNG: RefCell<Option<StdRng>> = RefCell::new(None);
+}
+
+/// Run `f` with the random number generator of the current thread
+/// The generator is seeded in simulations, so that their runs can be reproduced from the seed
+pub(super) fn with_rng<R>(f: impl FnOnce(&mut dyn RngCore) -> R) -> R {
+    SEEDED_RNG.with(|seeded| match *seeded.borrow_mut() {
+        Some(ref mut rng) => f(rng),
+        None => f(&mut thread_rng()),
+    })
+}
+
+/// Seed the random number generator of the current thread
+#[cfg(test)]
+pub(super) fn seed_thread_rng(seed: u64) {
+    SEEDED_RNG.with(|seeded| *seeded.borrow_mut() = Some(StdRng::seed_from_u64(seed)));
+}
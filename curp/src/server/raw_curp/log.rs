@@ -1,13 +1,21 @@
 #![allow(clippy::integer_arithmetic)] // u64 is large enough and won't overflow
 
-use std::{collections::HashSet, fmt::Debug, sync::Arc};
+use std::{
+    cmp::{max, min},
+    collections::HashSet,
+    fmt::Debug,
+    sync::Arc,
+};
 
+use clippy_utilities::NumericCast;
 use tokio::sync::mpsc;
 use tracing::error;
 
 use crate::{
     cmd::{Command, ProposeId},
-    log_entry::LogEntry,
+    log_entry::{EntryData, LogEntry},
+    members::ConfChangeEntry,
+    snapshot::Snapshot,
 };
 
 /// Curp logs
@@ -18,8 +26,12 @@ pub(super) struct Log<C: Command> {
     entries: Vec<LogEntry<C>>,
     /// Base index in entries, is `1`(if no snapshot) or `last_log_index_in_snapshot + 1`
     base_index: usize,
-    /// Term of log[base_index]
+    /// Term of log[base_index - 1], which is the last log entry included in the snapshot(or the fake log[0])
     base_term: u64,
+    /// The latest snapshot, all log entries before `base_index` are included in it
+    snapshot: Option<Arc<Snapshot>>,
+    /// Index of the last log entry included in the latest requested snapshot
+    pub(super) snapshot_requested_index: usize,
     /// Index of highest log entry known to be committed
     pub(super) commit_index: usize,
     /// Index of highest log entry applied to state machine
@@ -27,6 +39,8 @@ pub(super) struct Log<C: Command> {
     /// in that it means the index of the last log sent to the `cmd_worker`(may not be executed yet)
     /// while the `last_applied` in command executor means index of the last log entry that has been successfully applied to the command executor.
     pub(super) last_applied: usize,
+    /// Index of the last log entry known to be persisted, entries after it may be lost on crash
+    pub(super) persisted_index: usize,
     /// Tx to send log entries to persist task
     log_tx: mpsc::UnboundedSender<LogEntry<C>>,
 }
@@ -36,8 +50,11 @@ impl<C: Command> Debug for Log<C> {
         f.debug_struct("Log")
             .field("entries", &self.entries)
             .field("base_index", &self.base_index)
+            .field("base_term", &self.base_term)
+            .field("snapshot", &self.snapshot.as_ref().map(|s| s.meta()))
             .field("commit_index", &self.commit_index)
             .field("last_applied", &self.last_applied)
+            .field("persisted_index", &self.persisted_index)
             .finish()
     }
 }
@@ -52,12 +69,78 @@ impl<C: 'static + Command> Log<C> {
             entries, // a fake log[0]
             commit_index: 0,
             last_applied: 0,
+            persisted_index: 0,
             base_index: 1,
             base_term: 0,
+            snapshot: None,
+            snapshot_requested_index: 0,
             log_tx,
         }
     }
 
+    /// Restore the log from the snapshot and the log entries after it
+    pub(super) fn restore(
+        log_tx: mpsc::UnboundedSender<LogEntry<C>>,
+        snapshot: Option<Snapshot>,
+        entries: Vec<LogEntry<C>>,
+    ) -> Self {
+        let mut log = Self::new(log_tx, vec![]);
+        if let Some(snapshot) = snapshot {
+            log.compact(snapshot);
+        }
+        assert!(
+            entries
+                .first()
+                .map_or(true, |entry| entry.index == log.base_index),
+            "log entries should start right after the snapshot"
+        );
+        log.entries = entries;
+        // the entries are recovered from the storage
+        log.persisted_index = log.last_log_index();
+        log
+    }
+
+    /// Get the index of the last log entry included in the latest snapshot, 0 if there is no snapshot
+    pub(super) fn snapshot_index(&self) -> usize {
+        self.base_index - 1
+    }
+
+    /// Get the latest snapshot
+    pub(super) fn snapshot(&self) -> Option<Arc<Snapshot>> {
+        self.snapshot.clone()
+    }
+
+    /// Compact the log with the snapshot, log entries included in the snapshot will be removed
+    pub(super) fn compact(&mut self, snapshot: Snapshot) {
+        let meta = snapshot.meta();
+        let last_included_index: usize = meta.last_included_index.numeric_cast();
+        if last_included_index <= self.snapshot_index() {
+            // the snapshot is outdated
+            return;
+        }
+
+        // keep the following entries only if the last included entry matches, otherwise the whole log is discarded
+        if self
+            .get(last_included_index)
+            .map_or(false, |entry| entry.term == meta.last_included_term)
+        {
+            let pi = self.li_to_pi(last_included_index);
+            self.entries = self.entries.split_off(pi + 1);
+        } else {
+            self.entries.clear();
+            self.persisted_index = last_included_index;
+        }
+
+        self.base_index = last_included_index + 1;
+        self.base_term = meta.last_included_term;
+        // entries in the snapshot must have been committed and applied
+        self.commit_index = max(self.commit_index, last_included_index);
+        self.last_applied = max(self.last_applied, last_included_index);
+        self.persisted_index = max(self.persisted_index, last_included_index);
+        self.snapshot_requested_index = max(self.snapshot_requested_index, last_included_index);
+        self.snapshot = Some(Arc::new(snapshot));
+    }
+
     /// Get last log index
     pub(super) fn last_log_index(&self) -> usize {
         self.entries
@@ -97,7 +180,8 @@ impl<C: 'static + Command> Log<C> {
         prev_log_term: u64,
     ) -> Result<(), Vec<LogEntry<C>>> {
         // check if entries can be appended
-        if !(prev_log_index == 0 && prev_log_term == 0)
+        // entries before `base_index` have been committed, so they must match
+        if prev_log_index >= self.base_index
             && self
                 .get(prev_log_index)
                 .map_or(true, |entry| entry.term != prev_log_term)
@@ -109,6 +193,10 @@ impl<C: 'static + Command> Log<C> {
         let mut li = prev_log_index;
         for entry in entries {
             li += 1;
+            if li < self.base_index {
+                // already included in the snapshot
+                continue;
+            }
             let pi = self.li_to_pi(li);
             if self
                 .entries
@@ -119,6 +207,7 @@ impl<C: 'static + Command> Log<C> {
             }
 
             self.entries.truncate(pi);
+            self.persisted_index = min(self.persisted_index, li - 1);
             self.entries.push(entry.clone());
             self.send_persist(entry);
         }
@@ -133,6 +222,17 @@ impl<C: 'static + Command> Log<C> {
         }
     }
 
+    /// Advance `persisted_index` after the log entries up to log[index] are persisted, it's ignored
+    /// if log[index] has been overwritten since it was sent to the persist task
+    pub(super) fn advance_persisted(&mut self, index: usize, term: u64) {
+        if self
+            .get(index)
+            .map_or(index <= self.snapshot_index(), |entry| entry.term == term)
+        {
+            self.persisted_index = max(self.persisted_index, index);
+        }
+    }
+
     /// Check if the candidate's log is up-to-date
     pub(super) fn log_up_to_date(&self, last_log_term: u64, last_log_index: usize) -> bool {
         if last_log_term == self.last_log_term() {
@@ -153,22 +253,58 @@ impl<C: 'static + Command> Log<C> {
         self.last_log_index()
     }
 
-    /// Get a range of log entry
+    /// Pack the membership change into a log entry and push it to the end of the log, return its index
+    pub(super) fn push_conf_change(&mut self, term: u64, change: Arc<ConfChangeEntry>) -> usize {
+        let index = self.last_log_index() + 1;
+        let entry = LogEntry::new_conf_change(index, term, change);
+        self.entries.push(entry.clone());
+        self.send_persist(entry);
+        self.last_log_index()
+    }
+
+    /// Whether there is a membership change entry after log[i]
+    pub(super) fn has_conf_change_after(&self, i: usize) -> bool {
+        self.get_from(max(i + 1, self.base_index))
+            .map_or(false, |entries| {
+                entries
+                    .iter()
+                    .any(|entry| matches!(entry.data, EntryData::ConfChange(_)))
+            })
+    }
+
+    /// Get a range of log entry, return `None` if some of them have been compacted
     pub(super) fn get_from(&self, li: usize) -> Option<&[LogEntry<C>]> {
-        let pi = self.li_to_pi(li);
-        self.entries.get(pi..)
+        (li >= self.base_index)
+            .then(|| self.entries.get(li - self.base_index..))
+            .flatten()
+    }
+
+    /// Get a batch of consecutive log entries starting from log[li], their total size is limited by
+    /// `max_size` except that the first entry is always included
+    /// Return `None` if some of them have been compacted
+    pub(super) fn get_batch(&self, li: usize, max_size: usize) -> Option<&[LogEntry<C>]> {
+        let entries = self.get_from(li)?;
+        let mut size = 0;
+        let len = entries
+            .iter()
+            .take_while(|entry| {
+                size += bincode::serialized_size(entry).map_or(0, |s| s.numeric_cast());
+                size <= max_size
+            })
+            .count();
+        entries.get(..max(len, 1).min(entries.len()))
     }
 
     /// Get existing cmd ids
     pub(super) fn get_cmd_ids(&self) -> HashSet<&ProposeId> {
-        self.entries.iter().map(|entry| entry.cmd.id()).collect()
+        self.entries.iter().map(|entry| entry.id()).collect()
     }
 
     /// Get previous log entry's term and index
     pub(super) fn get_prev_entry_info(&self, i: usize) -> (u64, usize) {
         assert!(i > 0);
-        if i == 1 {
-            (0, 0) // fake log[0]
+        if i <= self.base_index {
+            (self.base_term, self.base_index - 1) // the last entry included in the snapshot or the fake log[0]
         } else {
             let entry = self.get(i - 1).unwrap_or_else(|| {
                 unreachable!(
@@ -186,7 +322,7 @@ mod tests {
     use std::{ops::Index, sync::Arc};
 
     use super::*;
-    use crate::test_utils::test_cmd::TestCommand;
+    use crate::{snapshot::SnapshotMeta, test_utils::test_cmd::TestCommand};
 
     // impl index for test is handy
     impl<C: 'static + Command> Index<usize> for Log<C> {
@@ -247,6 +383,38 @@ mod tests {
         assert_eq!(log[2].term, 2);
     }
 
+    #[test]
+    fn stale_persisted_entries_will_not_advance_persisted_index() {
+        let (log_tx, _log_rx) = mpsc::unbounded_channel();
+        let mut log = Log::<TestCommand>::new(log_tx, vec![]);
+        let result = log.try_append_entries(
+            vec![
+                LogEntry::new(1, 1, Arc::new(TestCommand::default())),
+                LogEntry::new(2, 1, Arc::new(TestCommand::default())),
+                LogEntry::new(3, 1, Arc::new(TestCommand::default())),
+            ],
+            0,
+            0,
+        );
+        assert!(result.is_ok());
+        log.advance_persisted(3, 1);
+        assert_eq!(log.persisted_index, 3);
+
+        let result = log.try_append_entries(
+            vec![LogEntry::new(2, 2, Arc::new(TestCommand::default()))],
+            1,
+            1,
+        );
+        assert!(result.is_ok());
+        assert_eq!(log.persisted_index, 1);
+
+        // log[2] has been overwritten
+        log.advance_persisted(2, 1);
+        assert_eq!(log.persisted_index, 1);
+        log.advance_persisted(2, 2);
+        assert_eq!(log.persisted_index, 2);
+    }
+
     #[test]
     fn try_append_entries_will_not_append() {
         let (log_tx, _log_rx) = mpsc::unbounded_channel();
@@ -278,4 +446,92 @@ mod tests {
         );
         assert!(result.is_err());
     }
+
+    #[test]
+    fn compact_will_remove_entries_in_snapshot() {
+        let (log_tx, _log_rx) = mpsc::unbounded_channel();
+        let mut log = Log::<TestCommand>::new(log_tx, vec![]);
+        let result = log.try_append_entries(
+            (1..=5)
+                .map(|i| LogEntry::new(i, 1, Arc::new(TestCommand::default())))
+                .collect(),
+            0,
+            0,
+        );
+        assert!(result.is_ok());
+        log.commit_index = 3;
+        log.last_applied = 3;
+
+        log.compact(Snapshot::new(
+            SnapshotMeta {
+                last_included_index: 3,
+                last_included_term: 1,
+            },
+            vec![],
+        ));
+        assert_eq!(log.snapshot_index(), 3);
+        assert_eq!(log.last_log_index(), 5);
+        assert!(log.get(3).is_none());
+        assert_eq!(log[4].index, 4);
+        assert_eq!(log.get_prev_entry_info(4), (1, 3));
+        assert!(log.get_from(3).is_none());
+        assert_eq!(log.get_from(4).unwrap().len(), 2);
+
+        // entries after the snapshot can still be appended
+        let result = log.try_append_entries(
+            vec![LogEntry::new(6, 2, Arc::new(TestCommand::default()))],
+            5,
+            1,
+        );
+        assert!(result.is_ok());
+        assert_eq!(log.last_log_index(), 6);
+        assert_eq!(log.last_log_term(), 2);
+    }
+
+    #[test]
+    fn compact_will_discard_whole_log_if_mismatch() {
+        let (log_tx, _log_rx) = mpsc::unbounded_channel();
+        let mut log = Log::<TestCommand>::new(log_tx, vec![]);
+        let result = log.try_append_entries(
+            vec![
+                LogEntry::new(1, 1, Arc::new(TestCommand::default())),
+                LogEntry::new(2, 1, Arc::new(TestCommand::default())),
+            ],
+            0,
+            0,
+        );
+        assert!(result.is_ok());
+
+        log.compact(Snapshot::new(
+            SnapshotMeta {
+                last_included_index: 4,
+                last_included_term: 2,
+            },
+            vec![],
+        ));
+        assert_eq!(log.last_log_index(), 4);
+        assert_eq!(log.last_log_term(), 2);
+        assert_eq!(log.commit_index, 4);
+        assert_eq!(log.last_applied, 4);
+        assert_eq!(log.get_prev_entry_info(5), (2, 4));
+    }
+
+    #[test]
+    fn get_batch_will_limit_the_size() {
+        let (log_tx, _log_rx) = mpsc::unbounded_channel();
+        let mut log = Log::<TestCommand>::new(log_tx, vec![]);
+        for _ in 0..10 {
+            let _ig = log.push_cmd(1, Arc::new(TestCommand::default()));
+        }
+        let size_of =
+            |i: usize| -> usize { bincode::serialized_size(&log[i]).unwrap().numeric_cast() };
+        let size = size_of(1) + size_of(2) + size_of(3);
+
+        assert_eq!(log.get_batch(1, size).unwrap().len(), 3);
+        assert_eq!(log.get_batch(1, size - 1).unwrap().len(), 2);
+        assert_eq!(log.get_batch(9, usize::MAX).unwrap().len(), 2);
+        // the first entry is always included
+        assert_eq!(log.get_batch(1, 0).unwrap().len(), 1);
+        assert!(log.get_batch(11, usize::MAX).unwrap().is_empty());
+    }
 }
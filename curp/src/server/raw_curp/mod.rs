@@ -5,21 +5,23 @@
 //!     1. self.st
 //!     2. self.lst || self.cst (there is no need for grabbing both)
 //!     3. self.log
+//!     4. self.ctx.others (it's a leaf lock, never grab other locks while holding it)
 
 #![allow(clippy::similar_names)] // st, lst, cst is similar but not confusing
 #![allow(clippy::integer_arithmetic)] // u64 is large enough and won't overflow
 
 use std::{
-    cmp::min,
-    collections::{HashMap, HashSet},
+    cmp::{max, min},
+    collections::HashMap,
     fmt::Debug,
     sync::{
-        atomic::{AtomicBool, AtomicU8, Ordering},
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
         Arc,
     },
 };
 
 use clippy_utilities::NumericCast;
+use event_listener::Event;
 use itertools::Itertools;
 use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
 use tokio::sync::{broadcast, mpsc};
@@ -34,15 +36,25 @@ use utils::{
 
 use self::{
     log::Log,
-    state::{CandidateState, LeaderState, State},
+    state::{CandidateState, LeaderState, Pipeline, State},
+};
+use super::{
+    cmd_worker::CEEventTxApi,
+    curp_node::UncommittedPoolRef,
+    metrics::{Metrics, StateSample},
 };
-use super::{cmd_worker::CEEventTxApi, curp_node::UncommittedPoolRef};
 use crate::{
     cmd::{Command, ProposeId},
     error::ProposeError,
-    log_entry::LogEntry,
+    log_entry::{EntryData, LogEntry},
+    members::{ConfChange, ConfChangeEntry, Member},
     message::ServerId,
-    server::{cmd_board::CmdBoardRef, spec_pool::SpecPoolRef},
+    server::{
+        cmd_board::CmdBoardRef,
+        session::{Applied, SessionTable},
+        spec_pool::SpecPoolRef,
+    },
+    snapshot::{Snapshot, SnapshotMeta},
 };
 
 /// Curp state
@@ -70,8 +82,12 @@ pub(super) struct RawCurp<C: Command> {
 pub(super) enum TickAction<C> {
     /// Send out heartbeats
     Heartbeat(HashMap<ServerId, AppendEntries<C>>),
+    /// Send out pre-votes
+    PreVotes(HashMap<ServerId, Vote>),
     /// Send out votes
     Votes(HashMap<ServerId, Vote>),
+    /// Send out heartbeats and `timeout_now` to the transferee, attached with the leader's term
+    TimeoutNow(HashMap<ServerId, AppendEntries<C>>, ServerId, u64),
     /// Do nothing
     Nothing,
 }
@@ -87,6 +103,8 @@ pub(super) struct Vote {
     pub(super) last_log_index: usize,
     /// Candidate's last log term
     pub(super) last_log_term: u64,
+    /// Whether it's a pre-vote, which never changes the voters' state
+    pub(super) is_pre_vote: bool,
 }
 
 /// Invoked by leader to replicate log entries; also used as heartbeat
@@ -105,11 +123,36 @@ pub(super) struct AppendEntries<C> {
     pub(super) entries: Vec<LogEntry<C>>,
 }
 
+/// Read index of a linearizable read, it's valid only after the leadership is confirmed
+pub(super) struct ReadIndex<C> {
+    /// Reads must wait until all log entries up to this index have been applied
+    pub(super) index: usize,
+    /// Number of other voters that need to acknowledge the leadership
+    pub(super) acks: usize,
+    /// Heartbeats to other voters to confirm the leadership
+    pub(super) heartbeats: HashMap<ServerId, AppendEntries<C>>,
+}
+
+/// Actions for the leader to bring a follower up to date
+pub(super) enum SyncAction<C> {
+    /// Send the log entries that the follower needs
+    AppendEntries(AppendEntries<C>),
+    /// The log entries that the follower needs have been compacted, send the snapshot instead
+    Snapshot {
+        /// Leader's term
+        term: u64,
+        /// The latest snapshot of the leader
+        snapshot: Arc<Snapshot>,
+    },
+}
+
 /// Curp Role
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum Role {
     /// Follower
     Follower,
+    /// PreCandidate, it must win a pre-vote before becoming a candidate
+    PreCandidate,
     /// Candidate
     Candidate,
     /// Leader
@@ -120,8 +163,14 @@ enum Role {
 struct Context<C: Command> {
     /// Id of the server
     id: ServerId,
-    /// Other server ids
-    others: HashSet<ServerId>,
+    /// Other servers in the current configuration
+    others: RwLock<HashMap<ServerId, Member>>,
+    /// Whether self is a learner
+    is_learner: AtomicBool,
+    /// Whether self has been removed from the cluster
+    removed: AtomicBool,
+    /// Index of the last membership change entry that has taken effect
+    applied_conf_index: AtomicUsize,
     /// Config
     cfg: Arc<CurpConfig>,
     /// Cmd board for tracking the cmd sync results
@@ -142,6 +191,12 @@ struct Context<C: Command> {
     sync_tx: mpsc::UnboundedSender<usize>,
     /// Tx to send the id of followers that need to be calibrated
     calibrate_tx: mpsc::UnboundedSender<ServerId>,
+    /// Tx to send committed membership changes, attached with their log index
+    conf_change_tx: mpsc::UnboundedSender<(usize, Arc<ConfChangeEntry>)>,
+    /// Notified when log entries are persisted
+    persisted_event: Event,
+    /// Metrics of the server
+    metrics: Metrics,
 }
 
 impl<C: Command> Debug for Context<C> {
@@ -149,12 +204,15 @@ impl<C: Command> Debug for Context<C> {
         f.debug_struct("Context")
             .field("id", &self.id)
             .field("others", &self.others)
+            .field("is_learner", &self.is_learner)
+            .field("removed", &self.removed)
             .field("config", &self.cfg)
             .field("cb", &self.cb)
             .field("sp", &self.sp)
             .field("leader_tx", &self.leader_tx)
             .field("election_tick", &self.election_tick)
             .field("hb_opt", &self.hb_opt)
+            .field("metrics", &self.metrics)
             .finish()
     }
 }
@@ -180,63 +238,109 @@ impl<C: 'static + Command> RawCurp<C> {
         }
     }
 
-    /// Tick election, will generate votes if timeout
+    /// Tick election, will generate pre-votes if timeout
     fn tick_election(&self, timeout: u8) -> TickAction<C> {
+        // learners and removed servers never start elections
+        if self.ctx.is_learner.load(Ordering::Acquire) || self.ctx.removed.load(Ordering::Acquire)
+        {
+            return TickAction::Nothing;
+        }
         let tick = self.ctx.election_tick.fetch_add(1, Ordering::AcqRel);
         if tick < timeout {
             return TickAction::Nothing;
         }
 
-        // start election
+        // start pre-vote, the term won't be increased unless self can win the election
         let vote =
-            self.become_candidate(&mut self.st.write(), &mut self.cst.lock(), &self.log.read());
-        let votes = self
-            .ctx
-            .others
-            .iter()
-            .map(|id| (id.clone(), vote.clone()))
-            .collect();
-        TickAction::Votes(votes)
+            self.become_pre_candidate(&mut self.st.write(), &mut self.cst.lock(), &self.log.read());
+        TickAction::PreVotes(self.votes_to_others(&vote))
     }
 
     /// Tick heartbeat, will generate heartbeat if timeout
     fn tick_heartbeat(&self) -> TickAction<C> {
+        if self.tick_check_quorum() {
+            return TickAction::Nothing;
+        }
+        let timeout_now = self.tick_leader_transfer();
+
         // check if heartbeat has been optimized out
-        if self
+        let hb_optimized = self
             .ctx
             .hb_opt
             .compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
-        {
-            return TickAction::Nothing;
+            .is_ok();
+        let heartbeats = if hb_optimized {
+            HashMap::new()
+        } else {
+            let term = self.st.map_read(|st_r| st_r.term);
+            let lst_r = self.lst.read();
+            let log_r = self.log.read();
+            let ids = self.ctx.others.read().keys().cloned().collect_vec();
+            self.heartbeats(term, &lst_r, &log_r, ids)
+        };
+
+        match timeout_now {
+            Some((transferee, term)) => TickAction::TimeoutNow(heartbeats, transferee, term),
+            None if hb_optimized => TickAction::Nothing,
+            None => TickAction::Heartbeat(heartbeats),
         }
+    }
 
-        let term = self.st.map_read(|st_r| st_r.term);
-        let lst_r = self.lst.read();
-        let log_r = self.log.read();
+    /// Check if the leader has heard from a quorum in an election timeout, it will step down if not
+    /// Return `true` if self steps down
+    fn tick_check_quorum(&self) -> bool {
+        let lost_quorum = self.lst.map_write(|mut lst_w| {
+            lst_w.check_quorum_ticks = lst_w.check_quorum_ticks.saturating_add(1);
+            if lst_w.check_quorum_ticks < self.cfg().follower_timeout_ticks {
+                return false;
+            }
+            lst_w.check_quorum_ticks = 0;
+            let active_voters: u64 = lst_w
+                .active
+                .drain()
+                .filter(|id| self.is_voter(id))
+                .count()
+                .numeric_cast();
+            active_voters + 1 < self.quorum()
+        });
+        if !lost_quorum {
+            return false;
+        }
 
-        let hbs = self
-            .ctx
-            .others
-            .iter()
-            .map(|id| {
-                let next_index = lst_r.get_next_index(id);
-                let (prev_log_term, prev_log_index) = log_r.get_prev_entry_info(next_index);
-                debug!("{} send heartbeat to {}", self.id(), id);
-                (
-                    id.clone(),
-                    AppendEntries {
-                        term,
-                        leader_id: self.id().clone(),
-                        prev_log_index,
-                        prev_log_term,
-                        leader_commit: log_r.commit_index,
-                        entries: vec![],
-                    },
-                )
-            })
-            .collect();
-        TickAction::Heartbeat(hbs)
+        let mut st_w = self.st.write();
+        if st_w.role != Role::Leader {
+            return false;
+        }
+        debug!("{} can't hear from a quorum, steps down", self.id());
+        let term = st_w.term;
+        self.update_to_term_and_become_follower(&mut st_w, term);
+        true
+    }
+
+    /// Tick the leadership transfer in progress, will abort it if the transferee can't take over in an election timeout
+    /// Return `Some((transferee, term))` if the transferee has just caught up with the leader, `timeout_now` is sent only once in a transfer
+    fn tick_leader_transfer(&self) -> Option<(ServerId, u64)> {
+        let term = self.st.map_read(|st_r| st_r.term);
+        let mut lst_w = self.lst.write();
+        let transferee = lst_w.transferee.clone()?;
+        lst_w.transfer_ticks = lst_w.transfer_ticks.saturating_add(1);
+        if lst_w.transfer_ticks > self.cfg().follower_timeout_ticks {
+            debug!(
+                "{} aborts the leadership transfer to {transferee}",
+                self.id()
+            );
+            lst_w.transferee = None;
+            return None;
+        }
+        if lst_w.timeout_now_sent {
+            return None;
+        }
+        let last_log_index = self.log.map_read(|log_r| log_r.last_log_index());
+        if lst_w.get_match_index(&transferee) != last_log_index {
+            return None;
+        }
+        lst_w.timeout_now_sent = true;
+        Some((transferee, term))
     }
 }
 
@@ -251,6 +355,11 @@ impl<C: 'static + Command> RawCurp<C> {
         cmd: Arc<C>,
     ) -> ((Option<ServerId>, u64), Result<bool, ProposeError>) {
         debug!("{} gets proposal for cmd({})", self.id(), cmd.id());
+        // spec pools of learners are never recovered, so they don't accept proposals
+        if self.is_learner() {
+            let info = self.st.map_read(|st_r| (st_r.leader_id.clone(), st_r.term));
+            return (info, Err(ProposeError::NotLeader));
+        }
         let mut conflict = self
             .ctx
             .sp
@@ -261,6 +370,9 @@ impl<C: 'static + Command> RawCurp<C> {
 
         // Non-leader doesn't need to sync or execute
         if st_r.role != Role::Leader {
+            if conflict {
+                self.ctx.metrics.on_conflict();
+            }
             return (
                 info,
                 if conflict {
@@ -271,6 +383,26 @@ impl<C: 'static + Command> RawCurp<C> {
             );
         }
 
+        // the transferee should catch up with the leader, so no more entries can be appended
+        if self.lst.map_read(|lst_r| lst_r.transferee.is_some()) {
+            self.ctx.sp.map_lock(|mut spec_l| spec_l.remove(cmd.id()));
+            return (info, Err(ProposeError::LeaderTransferring));
+        }
+
+        // a retried proposal that has been applied won't be proposed again, its results are reused
+        if let Some(session) = cmd.id().session() {
+            let mut cb_w = self.ctx.cb.write();
+            if let Some(applied) = cb_w.sessions.result(session) {
+                // results of a pending proposal are sent to the cmd board when it's applied
+                if let Applied::Done(result) = applied {
+                    cb_w.insert_applied(cmd.id(), result);
+                }
+                drop(cb_w);
+                self.ctx.sp.map_lock(|mut spec_l| spec_l.remove(cmd.id()));
+                return (info, Err(ProposeError::Duplicated));
+            }
+        }
+
         if !self
             .ctx
             .cb
@@ -292,9 +424,12 @@ impl<C: 'static + Command> RawCurp<C> {
         let mut log_w = self.log.write();
         let index = log_w.push_cmd(st_r.term, Arc::clone(&cmd));
 
-        if !conflict {
+        if conflict {
+            self.ctx.metrics.on_conflict();
+        } else {
             self.ctx.cmd_tx.send_sp_exe(cmd);
         }
+        self.ctx.metrics.on_leader_propose();
 
         if let Err(e) = self.ctx.sync_tx.send(index) {
             error!("send channel error, {e}");
@@ -310,6 +445,62 @@ impl<C: 'static + Command> RawCurp<C> {
         )
     }
 
+    /// Handle `propose_conf_change` request
+    /// Return `((leader_id, term), Ok(()))` if the change has been appended to the leader's log
+    /// Return `((leader_id, term), Err(ProposeError))` if self is not the leader, or the change is invalid or duplicated
+    #[allow(clippy::type_complexity)] // it's clear
+    pub(super) fn handle_propose_conf_change(
+        &self,
+        entry: Arc<ConfChangeEntry>,
+    ) -> ((Option<ServerId>, u64), Result<(), ProposeError>) {
+        debug!(
+            "{} gets proposal for conf change({}): {:?}",
+            self.id(),
+            entry.id(),
+            entry.change()
+        );
+        let st_r = self.st.read();
+        let info = (st_r.leader_id.clone(), st_r.term);
+        if st_r.role != Role::Leader {
+            return (info, Err(ProposeError::NotLeader));
+        }
+        if self.lst.map_read(|lst_r| lst_r.transferee.is_some()) {
+            return (info, Err(ProposeError::LeaderTransferring));
+        }
+
+        let mut log_w = self.log.write();
+        if self
+            .ctx
+            .cb
+            .map_read(|cb_r| cb_r.sync.contains(entry.id()))
+        {
+            return (info, Err(ProposeError::Duplicated));
+        }
+        // only one membership change can be in progress at a time
+        if log_w.has_conf_change_after(self.ctx.applied_conf_index.load(Ordering::Acquire)) {
+            return (
+                info,
+                Err(ProposeError::InvalidConfChange(
+                    "another membership change is in progress".to_owned(),
+                )),
+            );
+        }
+        if let Err(reason) = self.check_conf_change(entry.change()) {
+            return (info, Err(ProposeError::InvalidConfChange(reason)));
+        }
+
+        let _ig = self
+            .ctx
+            .cb
+            .map_write(|mut cb_w| cb_w.sync.insert(entry.id().clone()));
+        let index = log_w.push_conf_change(st_r.term, entry);
+        if let Err(e) = self.ctx.sync_tx.send(index) {
+            error!("send channel error, {e}");
+        }
+
+        (info, Ok(()))
+    }
+
     /// Handle `append_entries`
     /// Return `Ok(term)` if succeeds
     /// Return `Err(term, hint_index)` if fails
@@ -337,10 +528,14 @@ impl<C: 'static + Command> RawCurp<C> {
                 let _ig = self.ctx.leader_tx.send(Some(leader_id)).ok();
             }
             std::cmp::Ordering::Equal => {
-                if st_r.leader_id.is_none() {
+                if st_r.leader_id.is_none() || st_r.role != Role::Follower {
                     let mut st_w = RwLockUpgradableReadGuard::upgrade(st_r);
-                    st_w.leader_id = Some(leader_id.clone());
-                    let _ig = self.ctx.leader_tx.send(Some(leader_id)).ok();
+                    // a (pre-)candidate steps down when the leader of its term shows up
+                    st_w.role = Role::Follower;
+                    if st_w.leader_id.is_none() {
+                        st_w.leader_id = Some(leader_id.clone());
+                        let _ig = self.ctx.leader_tx.send(Some(leader_id)).ok();
+                    }
                 }
             }
             std::cmp::Ordering::Greater => {
@@ -395,6 +590,9 @@ impl<C: 'static + Command> RawCurp<C> {
         if cur_role != Role::Leader {
             return Err(());
         }
+        let _ig = self
+            .lst
+            .map_write(|mut lst_w| lst_w.active.insert(follower_id.clone()));
 
         if !success {
             let mut lst_w = self.lst.write();
@@ -434,8 +632,157 @@ impl<C: 'static + Command> RawCurp<C> {
         Ok(true)
     }
 
+    /// Handle log entries up to log[index] being persisted, the leader may commit them now
+    pub(super) fn on_log_persisted(&self, index: usize, term: u64) {
+        let st_r = self.st.read();
+        let lst_r = self.lst.read();
+        let mut log_w = self.log.write();
+        log_w.advance_persisted(index, term);
+        if st_r.role == Role::Leader {
+            let commit_to = (log_w.commit_index + 1..=log_w.persisted_index)
+                .rev()
+                .find(|&i| self.can_update_commit_index_to(&lst_r, &log_w, i, st_r.term));
+            if let Some(commit_to) = commit_to {
+                log_w.commit_index = commit_to;
+                debug!("{} updates commit index to {commit_to}", self.id());
+                self.apply(&mut *log_w);
+            }
+        }
+        drop(log_w);
+        self.ctx.persisted_event.notify(usize::MAX);
+    }
+
+    /// Wait until log entries up to log[index] are persisted
+    /// Return `false` if log[index] whose term is `term` has been overwritten
+    pub(super) async fn wait_persisted(&self, index: usize, term: u64) -> bool {
+        loop {
+            let listener = self.ctx.persisted_event.listen();
+            {
+                let log_r = self.log.read();
+                if log_r
+                    .get(index)
+                    .map_or(index > log_r.snapshot_index(), |entry| entry.term != term)
+                {
+                    return false;
+                }
+                if log_r.persisted_index >= index {
+                    return true;
+                }
+            }
+            listener.await;
+        }
+    }
+
+    /// Handle `install_snapshot`, it's called before and after the snapshot data is received
+    /// Return `Ok(term)` if the snapshot should be installed
+    /// Return `Err(term)` if the leader is stale or the snapshot is outdated
+    pub(super) fn handle_install_snapshot(
+        &self,
+        term: u64,
+        leader_id: ServerId,
+        meta: SnapshotMeta,
+    ) -> Result<u64, u64> {
+        debug!(
+            "{} received install_snapshot from {}: term({}), last_included_index({}), last_included_term({})",
+            self.id(), leader_id, term, meta.last_included_index, meta.last_included_term
+        );
+
+        // validate term and set leader id
+        let mut st_w = self.st.write();
+        if st_w.term > term {
+            return Err(st_w.term);
+        }
+        if st_w.term < term {
+            self.update_to_term_and_become_follower(&mut st_w, term);
+        }
+        if st_w.leader_id.is_none() {
+            st_w.leader_id = Some(leader_id.clone());
+            let _ig = self.ctx.leader_tx.send(Some(leader_id)).ok();
+        }
+        self.reset_election_tick();
+
+        // entries included in the snapshot have been committed here, no need to install it
+        let last_included_index: usize = meta.last_included_index.numeric_cast();
+        if last_included_index <= self.log.map_read(|log_r| log_r.commit_index) {
+            return Err(term);
+        }
+        Ok(term)
+    }
+
+    /// Get the index of the last persisted log entry to discard when the snapshot is installed, entries after
+    /// the snapshot are kept only if the last included entry matches
+    pub(super) fn snapshot_discard_index(&self, meta: SnapshotMeta) -> usize {
+        let last_included_index: usize = meta.last_included_index.numeric_cast();
+        let log_r = self.log.read();
+        if log_r
+            .get(last_included_index)
+            .map_or(false, |entry| entry.term == meta.last_included_term)
+        {
+            last_included_index
+        } else {
+            max(last_included_index, log_r.last_log_index())
+        }
+    }
+
+    /// Install the snapshot received from the leader, the log and the command executor will be reset to it
+    pub(super) fn install_snapshot(&self, snapshot: Snapshot) {
+        let mut log_w = self.log.write();
+        let meta = snapshot.meta();
+        let last_included_index: usize = meta.last_included_index.numeric_cast();
+        if last_included_index <= log_w.commit_index {
+            return;
+        }
+        log_w.compact(snapshot);
+        // the state is replaced by the snapshot, speculative cmds won't be after synced here
+        self.ctx.sp.lock().clear();
+        self.ctx.ucp.lock().clear();
+        let mut cb_w = self.ctx.cb.write();
+        cb_w.clear();
+        cb_w.sessions = Self::snapshot_sessions(&log_w);
+        drop(cb_w);
+        self.ctx.cmd_tx.send_reset(log_w.snapshot());
+        debug!(
+            "{} installs snapshot, last_included_index({}), last_included_term({})",
+            self.id(),
+            meta.last_included_index,
+            meta.last_included_term
+        );
+    }
+
+    /// Handle `install_snapshot` response
+    /// Return `Err(())` if self is no longer the leader
+    pub(super) fn handle_install_snapshot_resp(
+        &self,
+        follower_id: &ServerId,
+        meta: SnapshotMeta,
+        term: u64,
+    ) -> Result<(), ()> {
+        // validate term
+        let (cur_term, cur_role) = self.st.map_read(|st_r| (st_r.term, st_r.role));
+        if cur_term < term {
+            let mut st_w = self.st.write();
+            self.update_to_term_and_become_follower(&mut st_w, term);
+            return Err(());
+        }
+        if cur_role != Role::Leader {
+            return Err(());
+        }
+
+        self.lst.map_write(|mut lst_w| {
+            lst_w.update_match_index(follower_id, meta.last_included_index.numeric_cast());
+            let _ig = lst_w.active.insert(follower_id.clone());
+        });
+        debug!(
+            "{} has installed snapshot on follower {}, last_included_index({})",
+            self.id(),
+            follower_id,
+            meta.last_included_index
+        );
+        Ok(())
+    }
+
     /// Handle `vote`
-    /// Return `Ok(term, spec_pool)` if the vote is granted
+    /// Return `Ok(term, spec_pool)` if the vote is granted, the spec pool is empty for pre-votes
     /// Return `Err(term)` if the vote is rejected
     pub(super) fn handle_vote(
         &self,
@@ -443,19 +790,40 @@ impl<C: 'static + Command> RawCurp<C> {
         candidate_id: ServerId,
         last_log_index: usize,
         last_log_term: u64,
+        is_pre_vote: bool,
     ) -> Result<(u64, Vec<Arc<C>>), u64> {
         debug!(
-            "{} received vote: term({}), last_log_index({}), last_log_term({}), id({})",
+            "{} received vote: term({}), last_log_index({}), last_log_term({}), id({}), pre_vote({})",
             self.id(),
             term,
             last_log_index,
             last_log_term,
-            candidate_id
+            candidate_id,
+            is_pre_vote
         );
 
         let mut st_w = self.st.write();
         let log_r = self.log.read();
 
+        // only voters in the current configuration can be voted for
+        if !self.is_voter(&candidate_id) {
+            return Err(st_w.term);
+        }
+
+        // a pre-vote never changes the state, and it's rejected if self still hears from the leader
+        if is_pre_vote {
+            let in_lease = st_w.role == Role::Leader
+                || (st_w.leader_id.is_some()
+                    && self.ctx.election_tick.load(Ordering::Acquire)
+                        < self.cfg().follower_timeout_ticks);
+            if term <= st_w.term || in_lease || !log_r.log_up_to_date(last_log_term, last_log_index)
+            {
+                return Err(st_w.term);
+            }
+            debug!("{} grants pre-vote to server {}", self.id(), candidate_id);
+            return Ok((term, vec![]));
+        }
+
         // calibrate term
         if term < st_w.term {
             return Err(st_w.term);
@@ -513,22 +881,32 @@ impl<C: 'static + Command> RawCurp<C> {
         if !vote_granted {
             return Ok(false);
         }
+        // the server may have been removed during the election, votes from learners don't count,
+        // and witnesses only send their spec pools
+        let is_witness = self.is_witness(id);
+        if !self.is_voter(id) && !is_witness {
+            return Ok(false);
+        }
 
         let mut cst_w = self.cst.lock();
-        debug!("{}'s vote is granted by server {}", self.id(), id);
-
-        cst_w.votes_received += 1;
+        if is_witness {
+            debug!("{} receives the spec pool of witness {}", self.id(), id);
+        } else {
+            debug!("{}'s vote is granted by server {}", self.id(), id);
+            cst_w.votes_received += 1;
+        }
         assert!(
             cst_w.sps.insert(id.clone(), spec_pool).is_none(),
             "a server can't vote twice"
         );
 
         let min_granted = self.quorum();
-        if cst_w.votes_received < min_granted {
+        let collected_sps: u64 = cst_w.sps.len().numeric_cast();
+        if cst_w.votes_received < min_granted || collected_sps < self.spec_quorum() {
             return Ok(false);
         }
 
-        // vote is granted by the majority of servers, can become leader
+        // vote is granted by the majority of voters and enough spec pools are collected, can become leader
         let spec_pools = cst_w.sps.drain().collect();
         drop(cst_w);
         let mut lst_w = self.lst.write();
@@ -538,22 +916,180 @@ impl<C: 'static + Command> RawCurp<C> {
         self.recover_from_spec_pools(&mut st_w, &mut log_w, &spec_pools);
         let last_log_index = log_w.last_log_index();
 
+        // retried proposals of the entries that haven't been applied won't be proposed again
+        self.ctx.cb.map_write(|mut cb_w| {
+            for i in (log_w.last_applied + 1)..=last_log_index {
+                if let Some(EntryData::Command(ref cmd)) = log_w.get(i).map(|entry| &entry.data) {
+                    let _ig = cb_w.sync.insert(cmd.id().clone());
+                }
+            }
+        });
+
         self.become_leader(&mut st_w);
+        self.ctx.metrics.on_election_won();
 
         // update next_index for each follower
-        for other in &self.ctx.others {
+        let others_r = self.ctx.others.read();
+        for other in others_r.keys() {
             lst_w.update_next_index(other, last_log_index + 1); // iter from the end to front is more likely to match the follower
         }
         lst_w.calibrating.clear();
+        lst_w.pipelines.clear();
+        lst_w.transferee = None;
+        lst_w.active.clear();
+        lst_w.check_quorum_ticks = 0;
         if prev_last_log_index < last_log_index {
             // if some entries are recovered, calibrate immediately
-            for follower_id in &self.ctx.others {
+            for follower_id in others_r.keys() {
                 self.calibrate(&mut lst_w, follower_id.clone());
             }
         }
 
         Ok(true)
     }
+
+    /// Handle pre-vote responses
+    /// Return `Ok(Some(votes))` if the pre-vote succeeds and self becomes a candidate
+    /// Return `Ok(None)` if the pre-vote hasn't succeeded yet
+    /// Return `Err(())` if self is no longer a pre-candidate
+    pub(super) fn handle_pre_vote_resp(
+        &self,
+        id: &ServerId,
+        term: u64,
+        vote_granted: bool,
+    ) -> Result<Option<HashMap<ServerId, Vote>>, ()> {
+        let mut st_w = self.st.write();
+        if st_w.role != Role::PreCandidate {
+            return Err(());
+        }
+        if !vote_granted {
+            if st_w.term < term {
+                self.update_to_term_and_become_follower(&mut st_w, term);
+                return Err(());
+            }
+            return Ok(None);
+        }
+        // the response may come from a previous pre-vote, and votes from learners don't count
+        if term != st_w.term + 1 || !self.is_voter(id) {
+            return Ok(None);
+        }
+
+        let mut cst_w = self.cst.lock();
+        debug!("{}'s pre-vote is granted by server {}", self.id(), id);
+        let _ig = cst_w.pre_votes.insert(id.clone());
+        let granted: u64 = cst_w.pre_votes.len().numeric_cast();
+        if granted + 1 < self.quorum() {
+            return Ok(None);
+        }
+
+        // pre-vote is granted by the majority of servers, start the election
+        let vote = self.become_candidate(&mut st_w, &mut cst_w, &self.log.read());
+        Ok(Some(self.votes_to_others(&vote)))
+    }
+
+    /// Handle `move_leader`, the leader will stop accepting proposals and bring the transferee up to date
+    /// Return `Ok(true)` if the transferee is already the leader
+    /// Return `Ok(false)` if the transfer starts
+    /// Return `Err(ProposeError)` if self is not the leader or the transferee is not a voter
+    pub(super) fn handle_move_leader(&self, target: &ServerId) -> Result<bool, ProposeError> {
+        debug!("{} receives move leader to {target}", self.id());
+        let st_r = self.st.read();
+        if st_r.role != Role::Leader {
+            return Err(ProposeError::NotLeader);
+        }
+        if target == self.id() {
+            return Ok(true);
+        }
+        if !self.is_voter(target) {
+            return Err(ProposeError::LeaderTransfer(format!(
+                "server {target} is not a voter"
+            )));
+        }
+
+        let mut lst_w = self.lst.write();
+        if let Some(ref transferee) = lst_w.transferee {
+            if transferee != target {
+                return Err(ProposeError::LeaderTransfer(format!(
+                    "the leadership is being transferred to {transferee}"
+                )));
+            }
+        }
+        lst_w.transferee = Some(target.clone());
+        lst_w.transfer_ticks = 0;
+        lst_w.timeout_now_sent = false;
+        self.calibrate(&mut lst_w, target.clone());
+        Ok(false)
+    }
+
+    /// Handle `timeout_now`, the transferee will start an election immediately
+    /// Return `Ok(votes)` if self becomes a candidate
+    /// Return `Err(term)` if the leader is stale or self can't start an election
+    pub(super) fn handle_timeout_now(
+        &self,
+        term: u64,
+        leader_id: &ServerId,
+    ) -> Result<HashMap<ServerId, Vote>, u64> {
+        debug!(
+            "{} received timeout_now from {leader_id}: term({term})",
+            self.id()
+        );
+        let mut st_w = self.st.write();
+        if st_w.term > term {
+            return Err(st_w.term);
+        }
+        if self.ctx.is_learner.load(Ordering::Acquire) || self.ctx.removed.load(Ordering::Acquire)
+        {
+            return Err(st_w.term);
+        }
+        if st_w.term < term {
+            self.update_to_term_and_become_follower(&mut st_w, term);
+        }
+        if st_w.role != Role::Follower {
+            return Err(st_w.term);
+        }
+
+        let vote = self.become_candidate(&mut st_w, &mut self.cst.lock(), &self.log.read());
+        Ok(self.votes_to_others(&vote))
+    }
+
+    /// Handle `timeout_now` response
+    pub(super) fn handle_timeout_now_resp(&self, term: u64) {
+        let mut st_w = self.st.write();
+        if st_w.term < term {
+            self.update_to_term_and_become_follower(&mut st_w, term);
+        }
+    }
+
+    /// Handle `read_index`, the read index should be used after other voters acknowledge the leadership
+    /// Return `Ok(read_index)` if self is the leader
+    /// Return `Err(ProposeError::NotLeader)` if self is not the leader
+    pub(super) fn handle_read_index(&self) -> Result<ReadIndex<C>, ProposeError> {
+        let st_r = self.st.read();
+        if st_r.role != Role::Leader {
+            return Err(ProposeError::NotLeader);
+        }
+        let lst_r = self.lst.read();
+        let log_r = self.log.read();
+
+        // cmds that have completed in the fast path may not be committed yet, so the read index
+        // is the last log index rather than the commit index
+        let index = log_r.last_log_index();
+        let voters = self
+            .ctx
+            .others
+            .read()
+            .iter()
+            .filter(|&(_, member)| member.is_voter())
+            .map(|(id, _)| id.clone())
+            .collect_vec();
+        let acks: usize = (self.quorum() - 1).numeric_cast();
+        debug!("{} handles read index, read_index({index})", self.id());
+        Ok(ReadIndex {
+            index,
+            acks,
+            heartbeats: self.heartbeats(st_r.term, &lst_r, &log_r, voters),
+        })
+    }
 }
 
 /// Other small public interface
@@ -562,7 +1098,8 @@ impl<C: 'static + Command> RawCurp<C> {
     #[allow(clippy::too_many_arguments)] // only called once
     pub(super) fn new(
         id: ServerId,
-        others: HashSet<ServerId>,
+        others: HashMap<ServerId, Member>,
+        is_learner: bool,
         is_leader: bool,
         cmd_board: CmdBoardRef<C>,
         spec_pool: SpecPoolRef<C>,
@@ -571,10 +1108,11 @@ impl<C: 'static + Command> RawCurp<C> {
         cmd_tx: Box<dyn CEEventTxApi<C>>,
         sync_tx: mpsc::UnboundedSender<usize>,
         calibrate_tx: mpsc::UnboundedSender<ServerId>,
+        conf_change_tx: mpsc::UnboundedSender<(usize, Arc<ConfChangeEntry>)>,
         log_tx: mpsc::UnboundedSender<LogEntry<C>>,
     ) -> Self {
-        let next_index = others.iter().map(|o| (o.clone(), 1)).collect();
-        let match_index = others.iter().map(|o| (o.clone(), 0)).collect();
+        let next_index = others.keys().map(|o| (o.clone(), 1)).collect();
+        let match_index = others.keys().map(|o| (o.clone(), 0)).collect();
         let raw_curp = Self {
             st: RwLock::new(State::new(
                 0,
@@ -589,7 +1127,10 @@ impl<C: 'static + Command> RawCurp<C> {
             log: RwLock::new(Log::new(log_tx, vec![])),
             ctx: Context {
                 id,
-                others,
+                others: RwLock::new(others),
+                is_learner: AtomicBool::new(is_learner),
+                removed: AtomicBool::new(false),
+                applied_conf_index: AtomicUsize::new(0),
                 cb: cmd_board,
                 sp: spec_pool,
                 ucp: uncommitted_pool,
@@ -600,6 +1141,9 @@ impl<C: 'static + Command> RawCurp<C> {
                 cmd_tx,
                 sync_tx,
                 calibrate_tx,
+                conf_change_tx,
+                persisted_event: Event::new(),
+                metrics: Metrics::new(),
             },
         };
         if is_leader {
@@ -614,7 +1158,8 @@ impl<C: 'static + Command> RawCurp<C> {
     #[allow(clippy::too_many_arguments)] // only called once
     pub(super) fn recover_from(
         id: ServerId,
-        others: HashSet<ServerId>,
+        others: HashMap<ServerId, Member>,
+        is_learner: bool,
         is_leader: bool,
         cmd_board: CmdBoardRef<C>,
         spec_pool: SpecPoolRef<C>,
@@ -623,14 +1168,18 @@ impl<C: 'static + Command> RawCurp<C> {
         cmd_tx: Box<dyn CEEventTxApi<C>>,
         sync_tx: mpsc::UnboundedSender<usize>,
         calibrate_tx: mpsc::UnboundedSender<ServerId>,
+        conf_change_tx: mpsc::UnboundedSender<(usize, Arc<ConfChangeEntry>)>,
         log_tx: mpsc::UnboundedSender<LogEntry<C>>,
         voted_for: Option<(u64, ServerId)>,
+        snapshot: Option<Snapshot>,
         entries: Vec<LogEntry<C>>,
         last_applied: usize,
+        members_index: usize,
     ) -> Self {
         let mut raw_curp = Self::new(
             id,
             others,
+            is_learner,
             is_leader,
             cmd_board,
             spec_pool,
@@ -639,6 +1188,7 @@ impl<C: 'static + Command> RawCurp<C> {
             cmd_tx,
             sync_tx,
             calibrate_tx,
+            conf_change_tx,
             log_tx.clone(),
         );
 
@@ -650,18 +1200,36 @@ impl<C: 'static + Command> RawCurp<C> {
             // all uncommitted cmds should stay in ucp until they are executed
             raw_curp.ctx.ucp.map_lock(|mut ucp_l| {
                 for e in &entries {
-                    let _ig = ucp_l.insert(e.cmd.id().clone(), Arc::clone(&e.cmd));
+                    if let EntryData::Command(ref cmd) = e.data {
+                        let _ig = ucp_l.insert(cmd.id().clone(), Arc::clone(cmd));
+                    }
                 }
             });
         } else {
         }
 
-        raw_curp.log.map_write(|mut log_w| {
-            log_w.last_applied = last_applied;
-            log_w.commit_index = last_applied;
-        });
-
-        raw_curp.log = RwLock::new(Log::new(log_tx, entries));
+        let mut log = Log::restore(log_tx, snapshot, entries);
+        if last_applied < log.last_applied {
+            // the command executor falls behind the snapshot, reset it to the snapshot
+            raw_curp.ctx.cb.write().sessions = Self::snapshot_sessions(&log);
+            raw_curp.ctx.cmd_tx.send_reset(log.snapshot());
+        } else {
+            raw_curp.recover_sessions(&log, last_applied);
+            log.last_applied = last_applied;
+            log.commit_index = last_applied;
+        }
+        log.snapshot_requested_index = log.last_applied;
+        // membership changes up to `members_index` have been recovered from the persisted members,
+        // the applied ones after it may not have been persisted, so they are applied again
+        *raw_curp.ctx.applied_conf_index.get_mut() = members_index;
+        for i in (members_index.max(log.snapshot_index()) + 1)..=log.last_applied {
+            if let Some(EntryData::ConfChange(ref change)) = log.get(i).map(|e| &e.data) {
+                if let Err(e) = raw_curp.ctx.conf_change_tx.send((i, Arc::clone(change))) {
+                    error!("send channel error, {e}");
+                }
+            }
+        }
+        raw_curp.log = RwLock::new(log);
 
         raw_curp
     }
@@ -681,49 +1249,309 @@ impl<C: 'static + Command> RawCurp<C> {
         self.ctx.leader_tx.subscribe()
     }
 
-    /// Get `append_entries` request for log[i]
-    /// Return `Err(())` if self is no longer the leader
-    pub(super) fn append_entries_single(&self, i: usize) -> Result<AppendEntries<C>, ()> {
-        assert!(i > 0, "can't generate append_entries for fake log[0]");
+    /// Get other servers in the current configuration
+    pub(super) fn others(&self) -> HashMap<ServerId, Member> {
+        self.ctx.others.read().clone()
+    }
+
+    /// Whether self is a learner
+    pub(super) fn is_learner(&self) -> bool {
+        self.ctx.is_learner.load(Ordering::Acquire)
+    }
+
+    /// Get the metrics
+    pub(super) fn metrics(&self) -> &Metrics {
+        &self.ctx.metrics
+    }
+
+    /// Sample the state for the metrics
+    pub(super) fn sample_state(&self, applied_index: usize) -> StateSample {
+        let term = self.st.map_read(|st_r| st_r.term);
+        let log_r = self.log.read();
+        StateSample {
+            term,
+            last_log_index: log_r.last_log_index(),
+            commit_index: log_r.commit_index,
+            applied_index,
+            log_len: log_r.last_log_index() - log_r.snapshot_index(),
+        }
+    }
+
+    /// Get the commit index
+    pub(super) fn commit_index(&self) -> usize {
+        self.log.read().commit_index
+    }
+
+    /// Make a committed membership change take effect, return `false` if the membership has been
+    /// updated beyond `index`, e.g. by an installed snapshot
+    /// The change may be applied more than once after restart, so it must be idempotent
+    pub(super) fn apply_conf_change(&self, index: usize, change: &ConfChange) -> bool {
+        let mut st_w = self.st.write();
+        if index < self.ctx.applied_conf_index.load(Ordering::Acquire) {
+            return false;
+        }
+        let mut lst_w = self.lst.write();
+        let log_r = self.log.read();
+        let mut others_w = self.ctx.others.write();
+        match *change {
+            ConfChange::AddNode { ref id, ref address }
+            | ConfChange::AddLearner { ref id, ref address } => {
+                let is_learner = matches!(*change, ConfChange::AddLearner { .. });
+                if id == self.id() {
+                    self.ctx.is_learner.store(is_learner, Ordering::Release);
+                    self.ctx.removed.store(false, Ordering::Release);
+                } else if others_w
+                    .insert(id.clone(), Member::new(address.clone(), is_learner))
+                    .is_none()
+                {
+                    // the new server starts to replicate from the end of the leader's log
+                    lst_w.add_server(id.clone(), log_r.last_log_index() + 1);
+                } else {
+                }
+            }
+            ConfChange::AddWitness { ref id, ref address } => {
+                // witnesses receive the log to remove the committed commands from their spec pools
+                if id != self.id()
+                    && others_w
+                        .insert(id.clone(), Member::new_witness(address.clone()))
+                        .is_none()
+                {
+                    lst_w.add_server(id.clone(), log_r.last_log_index() + 1);
+                }
+            }
+            ConfChange::PromoteLearner { ref id } => {
+                if id == self.id() {
+                    self.ctx.is_learner.store(false, Ordering::Release);
+                } else if let Some(member) = others_w.get_mut(id) {
+                    member.promote();
+                } else {
+                }
+            }
+            ConfChange::RemoveNode { ref id } => {
+                if id == self.id() {
+                    self.ctx.removed.store(true, Ordering::Release);
+                } else if others_w.remove(id).is_some() {
+                    lst_w.remove_server(id);
+                } else {
+                }
+            }
+            ConfChange::UpdateNode { ref id, ref address } => {
+                if let Some(member) = others_w.get_mut(id) {
+                    member.set_address(address.clone());
+                }
+            }
+            ConfChange::UpdateClientUrls {
+                ref id,
+                ref client_urls,
+            } => {
+                if let Some(member) = others_w.get_mut(id) {
+                    member.set_client_urls(client_urls.clone());
+                }
+            }
+        }
+        let _prev = self
+            .ctx
+            .applied_conf_index
+            .fetch_max(index, Ordering::AcqRel);
+        debug!(
+            "{} applies conf change in log[{index}]: {change:?}",
+            self.id()
+        );
+        drop(others_w);
+        drop(log_r);
+        drop(lst_w);
+
+        // a removed leader should step down
+        if self.ctx.removed.load(Ordering::Acquire) && st_w.role == Role::Leader {
+            let term = st_w.term;
+            self.update_to_term_and_become_follower(&mut st_w, term);
+        }
+        true
+    }
+
+    /// Get whether self is a learner and the other members after the members carried by the snapshot
+    /// from `leader_id` are installed, the snapshot doesn't contain the leader itself
+    pub(super) fn snapshot_members(
+        &self,
+        leader_id: &ServerId,
+        mut members: HashMap<ServerId, Member>,
+    ) -> (bool, HashMap<ServerId, Member>) {
+        let is_learner = members
+            .remove(self.id())
+            .map_or_else(|| self.is_learner(), |member| member.is_learner());
+        if let Some(leader) = self.ctx.others.read().get(leader_id) {
+            let _ig = members
+                .entry(leader_id.clone())
+                .or_insert_with(|| leader.clone());
+        }
+        (is_learner, members)
+    }
+
+    /// Replace the members with those carried by the installed snapshot whose last included index is `index`
+    pub(super) fn install_members(
+        &self,
+        index: usize,
+        is_learner: bool,
+        others: HashMap<ServerId, Member>,
+    ) {
+        let _st_w = self.st.write();
+        let mut lst_w = self.lst.write();
+        let log_r = self.log.read();
+        let mut others_w = self.ctx.others.write();
+        for id in others_w.keys() {
+            if !others.contains_key(id) {
+                lst_w.remove_server(id);
+            }
+        }
+        for id in others.keys() {
+            if !others_w.contains_key(id) {
+                lst_w.add_server(id.clone(), log_r.last_log_index() + 1);
+            }
+        }
+        *others_w = others;
+        self.ctx.is_learner.store(is_learner, Ordering::Release);
+        let _prev = self
+            .ctx
+            .applied_conf_index
+            .fetch_max(index, Ordering::AcqRel);
+        debug!("{} installs members in the snapshot at {index}", self.id());
+    }
+
+    /// Get the next batch of log entries to send to `follower_id` through its pipeline
+    /// Return `Ok(None)` if there is nothing to send, the in-flight window is full or the follower is being calibrated,
+    /// the window shrinks to one batch when the follower lags behind more than `max_follower_lag` entries
+    /// Return `Err(())` if self is no longer the leader or the follower has been removed
+    pub(super) fn next_pipelined_batch(
+        &self,
+        follower_id: &ServerId,
+    ) -> Result<Option<AppendEntries<C>>, ()> {
         let st_r = self.st.read();
         if st_r.role != Role::Leader {
             return Err(());
         }
+        let mut lst_w = self.lst.write();
+        if !lst_w.contains(follower_id) {
+            return Err(());
+        }
+        if lst_w.calibrating.contains(follower_id) {
+            return Ok(None);
+        }
+        let next_index = lst_w.get_next_index(follower_id);
+        let pipeline = lst_w
+            .pipelines
+            .get(follower_id)
+            .copied()
+            .unwrap_or_default();
         let log_r = self.log.read();
-        let (prev_log_term, prev_log_index) = log_r.get_prev_entry_info(i);
-        let entry = log_r.get(i).unwrap_or_else(|| {
-            unreachable!("system corrupted, leader wants to log[{i}] when it doesn't have it")
-        });
-        Ok(AppendEntries {
+        let lag = log_r
+            .last_log_index()
+            .saturating_sub(lst_w.get_match_index(follower_id));
+        // back off when the follower is lagging, one batch at a time until it catches up
+        let window = if lag > self.cfg().max_follower_lag {
+            1
+        } else {
+            self.cfg().max_inflight_appends
+        };
+        if pipeline.inflight >= window {
+            return Ok(None);
+        }
+        let start = max(pipeline.sent_index + 1, next_index);
+        if start > log_r.last_log_index() {
+            return Ok(None);
+        }
+        if start <= log_r.snapshot_index() {
+            // the follower lags behind the snapshot, let the calibration install it
+            self.calibrate(&mut lst_w, follower_id.clone());
+            return Ok(None);
+        }
+        let (prev_log_term, prev_log_index) = log_r.get_prev_entry_info(start);
+        let entries = log_r
+            .get_batch(start, self.cfg().max_append_size)
+            .unwrap_or_else(|| {
+                unreachable!("system corrupted, leader get log[{start}] when it doesn't have one")
+            });
+        let _ig = lst_w.pipelines.insert(
+            follower_id.clone(),
+            Pipeline {
+                sent_index: prev_log_index + entries.len(),
+                inflight: pipeline.inflight + 1,
+            },
+        );
+        Ok(Some(AppendEntries {
             term: st_r.term,
             leader_id: self.id().clone(),
             prev_log_index,
             prev_log_term,
             leader_commit: log_r.commit_index,
-            entries: vec![entry.clone()],
-        })
+            entries: entries.to_vec(),
+        }))
+    }
+
+    /// Mark a batch sent through the pipeline of `follower_id` finished, whether it succeeds or not
+    pub(super) fn finish_pipelined_batch(&self, follower_id: &ServerId) {
+        let mut lst_w = self.lst.write();
+        if let Some(pipeline) = lst_w.pipelines.get_mut(follower_id) {
+            pipeline.inflight = pipeline.inflight.saturating_sub(1);
+        }
     }
 
-    /// Get `append_entries` request for `follower_id` that contains the latest log entries
-    pub(super) fn append_entries(&self, follower_id: &ServerId) -> Result<AppendEntries<C>, ()> {
+    /// Handle a failed `append_entries` rpc, the leader backs off and calibrates the follower,
+    /// which sends log entries one batch at a time with retries until the follower catches up
+    pub(super) fn handle_append_entries_error(&self, follower_id: &ServerId) {
+        if self.st.read().role != Role::Leader {
+            return;
+        }
+        let mut lst_w = self.lst.write();
+        if lst_w.contains(follower_id) {
+            self.calibrate(&mut lst_w, follower_id.clone());
+        }
+    }
+
+    /// Mark the calibration of `follower_id` finished, the pipeline takes over the replication
+    pub(super) fn finish_calibrating(&self, follower_id: &ServerId) {
+        let _ig = self.lst.write().calibrating.remove(follower_id);
+    }
+
+    /// Get a `SyncAction` for `follower_id`, which contains a batch of log entries from its `next_index` or the snapshot if they have been compacted
+    /// Return `Err(())` if self is no longer the leader or the follower has been removed
+    pub(super) fn sync(&self, follower_id: &ServerId) -> Result<SyncAction<C>, ()> {
         let st_r = self.st.read();
         if st_r.role != Role::Leader {
             return Err(());
         }
-        let next_index = self.lst.map_read(|lst_r| lst_r.get_next_index(follower_id));
+        let Some(next_index) = self.lst.map_read(|lst_r| {
+            lst_r
+                .contains(follower_id)
+                .then(|| lst_r.get_next_index(follower_id))
+        }) else {
+            return Err(());
+        };
         let log_r = self.log.read();
+        if next_index <= log_r.snapshot_index() {
+            let snapshot = log_r.snapshot().unwrap_or_else(|| {
+                unreachable!("system corrupted, log[{next_index}] is compacted without a snapshot")
+            });
+            return Ok(SyncAction::Snapshot {
+                term: st_r.term,
+                snapshot,
+            });
+        }
         let (prev_log_term, prev_log_index) = log_r.get_prev_entry_info(next_index);
-        let entries = log_r.get_from(next_index).unwrap_or_else(|| {
-            unreachable!("system corrupted, leader get log[{next_index}] when it doesn't have one")
-        });
-        Ok(AppendEntries {
+        let entries = log_r
+            .get_batch(next_index, self.cfg().max_append_size)
+            .unwrap_or_else(|| {
+                unreachable!(
+                    "system corrupted, leader get log[{next_index}] when it doesn't have one"
+                )
+            });
+        Ok(SyncAction::AppendEntries(AppendEntries {
             term: st_r.term,
             leader_id: self.id().clone(),
             prev_log_index,
             prev_log_term,
             leader_commit: log_r.commit_index,
             entries: entries.to_vec(),
-        })
+        }))
     }
 
     /// Optimize out heartbeat
@@ -735,6 +1563,19 @@ impl<C: 'static + Command> RawCurp<C> {
     pub(super) fn cfg(&self) -> &CurpConfig {
         self.ctx.cfg.as_ref()
     }
+
+    /// Compact the log with a snapshot taken by the command executor
+    pub(super) fn compact_log(&self, snapshot: Snapshot) {
+        let mut log_w = self.log.write();
+        let meta = snapshot.meta();
+        log_w.compact(snapshot);
+        debug!(
+            "{} compacts log to index {}, term {}",
+            self.id(),
+            meta.last_included_index,
+            meta.last_included_term
+        );
+    }
 }
 
 // Utils
@@ -747,6 +1588,8 @@ impl<C: 'static + Command> RawCurp<C> {
 
         st.term += 1;
         st.role = Role::Candidate;
+        self.ctx.metrics.on_election_started();
+        self.ctx.metrics.on_term_change();
         st.voted_for = Some(self.id().clone());
         st.leader_id = None;
         let _ig = self.ctx.leader_tx.send(None).ok();
@@ -759,10 +1602,10 @@ impl<C: 'static + Command> RawCurp<C> {
         cst.votes_received = 1;
         cst.sps = HashMap::from([(self.id().clone(), self_sp)]);
 
-        if prev_role == Role::Follower {
-            debug!("Follower {} starts election", self.id());
-        } else {
+        if prev_role == Role::Candidate {
             debug!("Candidate {} restarts election", self.id());
+        } else {
+            debug!("{} starts election", self.id());
         }
 
         Vote {
@@ -770,6 +1613,32 @@ impl<C: 'static + Command> RawCurp<C> {
             candidate_id: self.id().clone(),
             last_log_index: log.last_log_index(),
             last_log_term: log.last_log_term(),
+            is_pre_vote: false,
+        }
+    }
+
+    /// Server becomes a pre-candidate, its term and vote stay unchanged
+    fn become_pre_candidate(
+        &self,
+        st: &mut State,
+        cst: &mut CandidateState<C>,
+        log: &Log<C>,
+    ) -> Vote {
+        assert!(st.role != Role::Leader, "leader can't start pre-vote");
+
+        st.role = Role::PreCandidate;
+        st.leader_id = None;
+        let _ig = self.ctx.leader_tx.send(None).ok();
+        self.reset_election_tick();
+        cst.pre_votes.clear();
+        debug!("{} starts pre-vote", self.id());
+
+        Vote {
+            term: st.term + 1,
+            candidate_id: self.id().clone(),
+            last_log_index: log.last_log_index(),
+            last_log_term: log.last_log_term(),
+            is_pre_vote: true,
         }
     }
 
@@ -794,9 +1663,13 @@ impl<C: 'static + Command> RawCurp<C> {
         if st.role == Role::Leader {
             self.leader_retires();
         }
+        // the vote must be kept if the term stays the same, otherwise self may vote twice in a term
+        if st.term < term {
+            st.voted_for = None;
+            self.ctx.metrics.on_term_change();
+        }
         st.term = term;
         st.role = Role::Follower;
-        st.voted_for = None;
         st.leader_id = None;
         let _ig = self.ctx.leader_tx.send(None).ok();
         st.randomize_timeout_ticks(); // regenerate timeout ticks
@@ -832,14 +1705,17 @@ impl<C: 'static + Command> RawCurp<C> {
         let replicated_cnt: u64 = self
             .ctx
             .others
+            .read()
             .iter()
-            .filter(|&id| lst.get_match_index(id) >= i)
+            .filter(|&(id, member)| member.is_voter() && lst.get_match_index(id) >= i)
             .count()
             .numeric_cast();
-        replicated_cnt + 1 >= self.quorum()
+        // the leader counts itself only after the entry is persisted
+        let self_cnt = u64::from(log.persisted_index >= i);
+        replicated_cnt + self_cnt >= self.quorum()
     }
 
-    /// Recover from all voter's spec pools
+    /// Recover from the spec pools of all voters and witnesses
     fn recover_from_spec_pools(
         &self,
         st: &mut State,
@@ -857,28 +1733,37 @@ impl<C: 'static + Command> RawCurp<C> {
             debug!("{} collected spec pools:\n{debug_sps:#?}", self.id());
         }
 
+        // only spec pools of voters and witnesses in the current configuration are counted
+        let member_sps = spec_pools
+            .iter()
+            .filter(|&(id, _)| id == self.id() || self.is_voter(id) || self.is_witness(id))
+            .flat_map(|(_, sp)| sp.iter().cloned())
+            .collect_vec();
         let mut cmd_cnt: HashMap<ProposeId, (Arc<C>, u64)> = HashMap::new();
-        for cmd in spec_pools.values().flatten().cloned() {
+        for cmd in member_sps {
             let entry = cmd_cnt.entry(cmd.id().clone()).or_insert((cmd, 0));
             entry.1 += 1;
         }
 
+        let mut cb_w = self.ctx.cb.write();
+        let mut sp_l = self.ctx.sp.lock();
+
         // get all possibly executed(fast path) commands
         let existing_log_ids = log.get_cmd_ids();
         let recovered_cmds = cmd_cnt
             .into_values()
             // only cmds whose cnt >= 3/4 can be recovered
             .filter_map(|(cmd, cnt)| (cnt >= self.superquorum()).then_some(cmd))
-            // dedup in current logs
+            // dedup in current logs, and cmds in client sessions are also deduped in the applied ones
             .filter(|cmd| {
-                // TODO: better dedup mechanism
                 !existing_log_ids.contains(cmd.id())
+                    && !cmd
+                        .id()
+                        .session()
+                        .map_or(false, |session| cb_w.sessions.is_applied(session))
             })
             .collect_vec();
 
-        let mut cb_w = self.ctx.cb.write();
-        let mut sp_l = self.ctx.sp.lock();
-
         let term = st.term;
         for cmd in recovered_cmds {
             let _ig_sync = cb_w.sync.insert(cmd.id().clone()); // may have been inserted before
@@ -893,6 +1778,35 @@ impl<C: 'static + Command> RawCurp<C> {
         }
     }
 
+    /// Restore client sessions from the snapshot in the log
+    fn snapshot_sessions(log: &Log<C>) -> SessionTable<C> {
+        log.snapshot()
+            .map_or(Ok(SessionTable::new()), |s| {
+                SessionTable::restore(s.sessions())
+            })
+            .unwrap_or_else(|e| {
+                error!("failed to restore sessions from the snapshot, {e}");
+                SessionTable::new()
+            })
+    }
+
+    /// Recover client sessions from the snapshot and the log entries that have been applied, results of
+    /// the log entries after the snapshot are lost
+    fn recover_sessions(&self, log: &Log<C>, last_applied: usize) {
+        let mut sessions = Self::snapshot_sessions(log);
+        for i in (log.snapshot_index() + 1)..=last_applied {
+            let Some(entry) = log.get(i) else {
+                continue;
+            };
+            if let EntryData::Command(ref cmd) = entry.data {
+                if let Some(session) = cmd.id().session() {
+                    sessions.apply_lost(session, i);
+                }
+            }
+        }
+        self.ctx.cb.write().sessions = sessions;
+    }
+
     /// Apply new logs
     fn apply(&self, log: &mut Log<C>) {
         for i in (log.last_applied + 1)..=log.commit_index {
@@ -902,9 +1816,41 @@ impl<C: 'static + Command> RawCurp<C> {
                     log.last_log_index()
                 )
             });
-            self.ctx
-                .cmd_tx
-                .send_after_sync(Arc::clone(&entry.cmd), i.numeric_cast());
+            match entry.data {
+                EntryData::Command(ref cmd) => {
+                    // sessions are recorded in the order of the log, the after sync may finish out of order
+                    if let Some(session) = cmd.id().session() {
+                        let mut cb_w = self.ctx.cb.write();
+                        if let Some(applied) = cb_w.sessions.result(session) {
+                            // a retried proposal committed twice is only applied in its first entry,
+                            // results of a pending one are sent to the cmd board when it's applied
+                            if let Applied::Done(result) = applied {
+                                cb_w.insert_applied(cmd.id(), result);
+                            }
+                            drop(cb_w);
+                            self.ctx.sp.map_lock(|mut spec_l| spec_l.remove(cmd.id()));
+                            let _ig = self.ctx.ucp.map_lock(|mut ucp_l| ucp_l.remove(cmd.id()));
+                            self.ctx.cmd_tx.skip_after_sync(i.numeric_cast());
+                            log.last_applied = i;
+                            debug!(
+                                "{} skips the duplicated cmd({}) in log[{i}]",
+                                self.id(),
+                                cmd.id()
+                            );
+                            continue;
+                        }
+                        cb_w.sessions.apply(session, i);
+                    }
+                    self.ctx
+                        .cmd_tx
+                        .send_after_sync(Arc::clone(cmd), i.numeric_cast());
+                }
+                EntryData::ConfChange(ref change) => {
+                    if let Err(e) = self.ctx.conf_change_tx.send((i, Arc::clone(change))) {
+                        error!("send channel error, {e}");
+                    }
+                }
+            }
             log.last_applied = i;
 
             debug!(
@@ -913,44 +1859,183 @@ impl<C: 'static + Command> RawCurp<C> {
                 i
             );
         }
+
+        // take a snapshot if there are too many log entries applied since the last one
+        if log.last_applied - log.snapshot_requested_index >= self.cfg().log_compact_threshold {
+            let last_included_term = log.get(log.last_applied).map_or_else(
+                || unreachable!("log[{}] should exist", log.last_applied),
+                |entry| entry.term,
+            );
+            // sessions idle since the last snapshot are expired, they won't be taken into the new one
+            self.ctx
+                .cb
+                .write()
+                .sessions
+                .expire(log.snapshot_requested_index);
+            self.ctx.cmd_tx.send_snapshot(SnapshotMeta {
+                last_included_index: log.last_applied.numeric_cast(),
+                last_included_term,
+            });
+            log.snapshot_requested_index = log.last_applied;
+        }
+    }
+
+    /// Generate heartbeats to the given servers
+    fn heartbeats(
+        &self,
+        term: u64,
+        lst: &LeaderState,
+        log: &Log<C>,
+        ids: Vec<ServerId>,
+    ) -> HashMap<ServerId, AppendEntries<C>> {
+        ids.into_iter()
+            .map(|id| {
+                let next_index = lst.get_next_index(&id);
+                let (prev_log_term, prev_log_index) = log.get_prev_entry_info(next_index);
+                debug!("{} send heartbeat to {}", self.id(), id);
+                let hb = AppendEntries {
+                    term,
+                    leader_id: self.id().clone(),
+                    prev_log_index,
+                    prev_log_term,
+                    leader_commit: log.commit_index,
+                    entries: vec![],
+                };
+                (id, hb)
+            })
+            .collect()
     }
 
-    /// Get quorum: the smallest number of servers who must be online for the cluster to work
+    /// Get quorum: the smallest number of voters who must be online for the cluster to work
     fn quorum(&self) -> u64 {
-        (self.ctx.others.len() / 2 + 1).numeric_cast()
+        let voters = self
+            .ctx
+            .others
+            .map_read(|others_r| others_r.values().filter(|member| member.is_voter()).count())
+            + 1;
+        (voters / 2 + 1).numeric_cast()
+    }
+
+    /// Get spec quorum: the smallest number of spec pools, from voters and witnesses, that a
+    /// candidate must collect before it recovers the speculatively executed commands
+    fn spec_quorum(&self) -> u64 {
+        let servers = self.ctx.others.map_read(|others_r| {
+            others_r
+                .values()
+                .filter(|member| !member.is_learner())
+                .count()
+        }) + 1;
+        (servers / 2 + 1).numeric_cast()
+    }
+
+    /// Whether the server is a voter in the current configuration
+    fn is_voter(&self, id: &ServerId) -> bool {
+        self.ctx
+            .others
+            .read()
+            .get(id)
+            .map_or(false, Member::is_voter)
+    }
+
+    /// Whether the server is a witness in the current configuration
+    fn is_witness(&self, id: &ServerId) -> bool {
+        self.ctx
+            .others
+            .read()
+            .get(id)
+            .map_or(false, Member::is_witness)
+    }
+
+    /// Send the vote to all other voters, learners neither vote nor have their spec pools recovered
+    /// Witnesses don't vote either, but they receive the votes to send back their spec pools
+    fn votes_to_others(&self, vote: &Vote) -> HashMap<ServerId, Vote> {
+        self.ctx
+            .others
+            .read()
+            .iter()
+            .filter(|&(_, member)| member.is_voter() || (member.is_witness() && !vote.is_pre_vote))
+            .map(|(id, _)| (id.clone(), vote.clone()))
+            .collect()
     }
 
     /// Get superquorum: the smallest number of servers who must contain a command in speculative pool for it to be recovered
     fn superquorum(&self) -> u64 {
-        self.quorum() / 2 + 1
+        self.spec_quorum() / 2 + 1
     }
 
     /// When leader retires, it should reset state
     fn leader_retires(&self) {
         debug!("leader {} retires", self.id());
 
+        let log_r = self.log.read();
+
         // when a leader retires, it should wipe up speculatively executed cmds by resetting and re-executing
-        self.ctx.cmd_tx.send_reset();
+        self.ctx.cmd_tx.send_reset(log_r.snapshot());
 
         let mut cb_w = self.ctx.cb.write();
         cb_w.clear();
+        // the applied cmds are applied again, so are their sessions
+        cb_w.sessions = Self::snapshot_sessions(&log_r);
 
-        let log_r = self.log.read();
-        for i in 1..=log_r.commit_index {
+        for i in (log_r.snapshot_index() + 1)..=log_r.commit_index {
             let entry = log_r.get(i).unwrap_or_else(|| {
                 unreachable!(
                     "system corrupted, apply log[{i}] when we only have {} log entries",
                     log_r.last_log_index()
                 )
             });
-            self.ctx
-                .cmd_tx
-                .send_after_sync(Arc::clone(&entry.cmd), i.numeric_cast());
+            // membership changes have taken effect, only commands need to be re-executed
+            if let EntryData::Command(ref cmd) = entry.data {
+                if let Some(session) = cmd.id().session() {
+                    cb_w.sessions.apply(session, i);
+                }
+                self.ctx
+                    .cmd_tx
+                    .send_after_sync(Arc::clone(cmd), i.numeric_cast());
+            }
         }
     }
 
+    /// Check whether the membership change is valid in the current configuration
+    fn check_conf_change(&self, change: &ConfChange) -> Result<(), String> {
+        let others_r = self.ctx.others.read();
+        let is_member = |id: &str| id == self.id() || others_r.contains_key(id);
+        match *change {
+            ConfChange::AddNode { ref id, .. }
+            | ConfChange::AddLearner { ref id, .. }
+            | ConfChange::AddWitness { ref id, .. } => {
+                if is_member(id) {
+                    return Err(format!("server {id} is already a member"));
+                }
+            }
+            ConfChange::PromoteLearner { ref id } => {
+                if !others_r.get(id).map_or(false, Member::is_learner) {
+                    return Err(format!("server {id} is not a learner"));
+                }
+            }
+            ConfChange::RemoveNode { ref id } => {
+                if id == self.id() {
+                    return Err(format!("can't remove the leader {id}"));
+                }
+                if !is_member(id) {
+                    return Err(format!("server {id} is not a member"));
+                }
+            }
+            ConfChange::UpdateNode { ref id, .. } | ConfChange::UpdateClientUrls { ref id, .. } => {
+                if !is_member(id) {
+                    return Err(format!("server {id} is not a member"));
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Send calibrate task
     fn calibrate(&self, lst: &mut LeaderState, id: ServerId) {
+        // the pipeline restarts from `next_index` after the calibration
+        if let Some(pipeline) = lst.pipelines.get_mut(&id) {
+            pipeline.sent_index = 0;
+        }
         if lst.calibrating.insert(id.clone()) {
             if let Err(e) = self.ctx.calibrate_tx.send(id) {
                 error!("can't send calibrate task {e}");
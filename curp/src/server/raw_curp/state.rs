@@ -3,11 +3,11 @@ use std::{
     sync::Arc,
 };
 
-use madsim::rand::{thread_rng, Rng};
+use rand::Rng;
 use tracing::debug;
 
 use super::Role;
-use crate::message::ServerId;
+use crate::{message::ServerId, server::random::with_rng};
 
 /// Curp state
 #[derive(Debug)]
@@ -42,6 +42,8 @@ pub(super) struct CandidateState<C> {
     pub(super) sps: HashMap<ServerId, Vec<Arc<C>>>,
     /// Votes received in the election
     pub(super) votes_received: u64,
+    /// Servers that have granted the pre-vote
+    pub(super) pre_votes: HashSet<ServerId>,
 }
 
 /// Additional state for the leader, all volatile
@@ -53,6 +55,28 @@ pub(super) struct LeaderState {
     match_index: HashMap<ServerId, usize>,
     /// Servers that are being calibrated by the leader
     pub(super) calibrating: HashSet<ServerId>,
+    /// The server that the leadership is being transferred to
+    pub(super) transferee: Option<ServerId>,
+    /// Ticks elapsed since the leadership transfer started
+    pub(super) transfer_ticks: u8,
+    /// Whether `timeout_now` has been sent to the transferee
+    pub(super) timeout_now_sent: bool,
+    /// Servers that have responded to the leader since the last quorum check
+    pub(super) active: HashSet<ServerId>,
+    /// Ticks elapsed since the last quorum check
+    pub(super) check_quorum_ticks: u8,
+    /// For each server, the pipeline that replicates log entries to it
+    pub(super) pipelines: HashMap<ServerId, Pipeline>,
+}
+
+/// Pipeline that replicates log entries to a follower without waiting for the previous
+/// `append_entries` to be acknowledged
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct Pipeline {
+    /// Index of the last log entry sent through the pipeline, 0 if it needs to restart from `next_index`
+    pub(super) sent_index: usize,
+    /// Number of `append_entries` in flight
+    pub(super) inflight: usize,
 }
 
 impl State {
@@ -81,11 +105,16 @@ impl State {
 
     /// Randomize `follower_timeout_ticks` and `candidate_timeout_ticks` to reduce vote split possibility
     pub(super) fn randomize_timeout_ticks(&mut self) {
-        let mut rng = thread_rng();
-        self.follower_timeout_ticks =
-            rng.gen_range(self.follower_timeout_ticks_base..(self.follower_timeout_ticks_base * 2));
-        self.candidate_timeout_ticks = rng
-            .gen_range(self.candidate_timeout_ticks_base..(self.candidate_timeout_ticks_base * 2));
+        let (follower_base, candidate_base) = (
+            self.follower_timeout_ticks_base,
+            self.candidate_timeout_ticks_base,
+        );
+        (self.follower_timeout_ticks, self.candidate_timeout_ticks) = with_rng(|rng| {
+            (
+                rng.gen_range(follower_base..(follower_base * 2)),
+                rng.gen_range(candidate_base..(candidate_base * 2)),
+            )
+        });
     }
 }
 
@@ -99,6 +128,12 @@ impl LeaderState {
             next_index,
             match_index,
             calibrating: HashSet::new(),
+            transferee: None,
+            transfer_ticks: 0,
+            timeout_now_sent: false,
+            active: HashSet::new(),
+            check_quorum_ticks: 0,
+            pipelines: HashMap::new(),
         }
     }
 
@@ -118,20 +153,19 @@ impl LeaderState {
             .unwrap_or_else(|| unreachable!("no match_index for {id}"))
     }
 
-    /// Update `next_index` for server
+    /// Update `next_index` for server, servers that have been removed are ignored
     pub(super) fn update_next_index(&mut self, id: &ServerId, index: usize) {
-        *self
-            .next_index
-            .get_mut(id)
-            .unwrap_or_else(|| unreachable!("no next_index for {id}")) = index;
+        if let Some(next_index) = self.next_index.get_mut(id) {
+            *next_index = index;
+        }
     }
 
     /// Update `match_index` for server, will update `next_index` if possible
+    /// Servers that have been removed are ignored
     pub(super) fn update_match_index(&mut self, id: &ServerId, index: usize) {
-        let match_index = self
-            .match_index
-            .get_mut(id)
-            .unwrap_or_else(|| unreachable!("no match_index for {id}"));
+        let Some(match_index) = self.match_index.get_mut(id) else {
+            return;
+        };
         if *match_index >= index {
             return;
         }
@@ -145,6 +179,29 @@ impl LeaderState {
             .unwrap_or_else(|| unreachable!("no next_index for {id}"));
         *next_index = *match_index + 1;
     }
+
+    /// Whether the server is tracked by the leader
+    pub(super) fn contains(&self, id: &ServerId) -> bool {
+        self.next_index.contains_key(id)
+    }
+
+    /// Start tracking a new server
+    pub(super) fn add_server(&mut self, id: ServerId, next_index: usize) {
+        let _ig_next = self.next_index.insert(id.clone(), next_index);
+        let _ig_match = self.match_index.insert(id, 0);
+    }
+
+    /// Stop tracking a removed server
+    pub(super) fn remove_server(&mut self, id: &ServerId) {
+        let _ig_next = self.next_index.remove(id);
+        let _ig_match = self.match_index.remove(id);
+        let _ig_calibrating = self.calibrating.remove(id);
+        let _ig_active = self.active.remove(id);
+        let _ig_pipeline = self.pipelines.remove(id);
+        if self.transferee.as_ref() == Some(id) {
+            self.transferee = None;
+        }
+    }
 }
 
 impl<C> CandidateState<C> {
@@ -153,6 +210,7 @@ impl<C> CandidateState<C> {
         Self {
             sps: HashMap::new(),
             votes_received: 0,
+            pre_votes: HashSet::new(),
         }
     }
 }
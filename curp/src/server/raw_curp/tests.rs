@@ -8,6 +8,7 @@ use utils::config::{
 
 use super::*;
 use crate::{
+    cmd::ClientSession,
     server::{
         cmd_board::CommandBoard,
         cmd_worker::{CEEventTxApi, MockCEEventTxApi},
@@ -23,21 +24,21 @@ impl<C: 'static + Command> RawCurp<C> {
         self.st.read().role
     }
 
-    pub(crate) fn commit_index(&self) -> usize {
-        self.log.read().commit_index
-    }
-
     pub(crate) fn new_test<Tx: CEEventTxApi<C>>(n: u64, exe_tx: Tx) -> Self {
-        let others = (1..n).map(|i| format!("S{i}")).collect();
+        let others = (1..n)
+            .map(|i| (format!("S{i}"), Member::new(format!("S{i}"), false)))
+            .collect();
         let cmd_board = Arc::new(RwLock::new(CommandBoard::new()));
         let spec_pool = Arc::new(Mutex::new(SpeculativePool::new()));
         let uncommitted_pool = Arc::new(Mutex::new(UncommittedPool::new()));
         let (sync_tx, _sync_rx) = mpsc::unbounded_channel();
         let (calibrate_tx, _rx) = mpsc::unbounded_channel();
+        let (conf_change_tx, _conf_change_rx) = mpsc::unbounded_channel();
         let (log_tx, _log_rx) = mpsc::unbounded_channel();
         Self::new(
             "S0".to_owned(),
             others,
+            false,
             true,
             cmd_board,
             spec_pool,
@@ -46,6 +47,7 @@ impl<C: 'static + Command> RawCurp<C> {
             Box::new(exe_tx),
             sync_tx,
             calibrate_tx,
+            conf_change_tx,
             log_tx,
         )
     }
@@ -69,6 +71,19 @@ impl<C: 'static + Command> RawCurp<C> {
         let st_r = self.st.read();
         st_r.term
     }
+
+    /// Get the value of a counter, the values of all labels are summed up if `label` is `None`
+    fn counter(&self, name: &str, label: Option<&str>) -> u64 {
+        self.metrics()
+            .registry()
+            .gather()
+            .iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(|family| family.get_metric())
+            .filter(|m| label.map_or(true, |l| m.get_label().iter().any(|p| p.get_value() == l)))
+            .map(|m| m.get_counter().get_value() as u64)
+            .sum()
+    }
 }
 
 /*************** tests for propose **************/
@@ -116,6 +131,26 @@ fn leader_handle_propose_will_reject_conflicted() {
     assert!(matches!(result, Err(ProposeError::KeyConflict)));
 }
 
+#[traced_test]
+#[test]
+fn leader_handle_propose_will_count_proposals_and_conflicts() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_sp_exe().returning(|_| {});
+        RawCurp::new_test(3, exe_tx)
+    };
+
+    let cmd1 = Arc::new(TestCommand::new_put(vec![1], 0));
+    let (_, result) = curp.handle_propose(cmd1);
+    assert!(matches!(result, Ok(true)));
+    let cmd2 = Arc::new(TestCommand::new_put(vec![1, 2], 1));
+    let (_, result) = curp.handle_propose(cmd2);
+    assert!(matches!(result, Err(ProposeError::KeyConflict)));
+
+    assert_eq!(curp.counter("curp_leader_proposals_total", None), 2);
+    assert_eq!(curp.counter("curp_proposal_conflicts_total", None), 1);
+}
+
 #[traced_test]
 #[test]
 fn leader_handle_propose_will_reject_duplicated() {
@@ -152,6 +187,23 @@ fn follower_handle_propose_will_succeed() {
     assert!(matches!(result, Ok(false)));
 }
 
+#[traced_test]
+#[test]
+fn learner_handle_propose_will_reject() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    curp.ctx.is_learner.store(true, Ordering::Release);
+    let cmd = Arc::new(TestCommand::new_get(vec![1]));
+    let ((_, term), result) = curp.handle_propose(Arc::clone(&cmd));
+    assert_eq!(term, 1);
+    assert!(matches!(result, Err(ProposeError::NotLeader)));
+    assert!(!curp.spec_pool().lock().pool.contains_key(cmd.id()));
+}
+
 #[traced_test]
 #[test]
 fn follower_handle_propose_will_reject_conflicted() {
@@ -175,6 +227,150 @@ fn follower_handle_propose_will_reject_conflicted() {
     assert!(matches!(result, Err(ProposeError::KeyConflict)));
 }
 
+/*************** tests for propose_conf_change **************/
+
+#[traced_test]
+#[test]
+fn leader_handle_propose_conf_change_will_reject_invalid_changes() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    let new_entry = |id: &str, change: ConfChange| {
+        Arc::new(ConfChangeEntry::new(ProposeId::new(id.to_owned()), change))
+    };
+
+    let (_, result) = curp.handle_propose_conf_change(new_entry(
+        "id0",
+        ConfChange::AddNode {
+            id: "S1".to_owned(),
+            address: "S1".to_owned(),
+        },
+    ));
+    assert!(matches!(result, Err(ProposeError::InvalidConfChange(_))));
+
+    let (_, result) = curp.handle_propose_conf_change(new_entry(
+        "id1",
+        ConfChange::RemoveNode {
+            id: "S0".to_owned(),
+        },
+    ));
+    assert!(matches!(result, Err(ProposeError::InvalidConfChange(_))));
+
+    let (_, result) = curp.handle_propose_conf_change(new_entry(
+        "id2",
+        ConfChange::AddNode {
+            id: "S3".to_owned(),
+            address: "S3".to_owned(),
+        },
+    ));
+    assert!(result.is_ok());
+
+    // only one change can be in progress
+    let (_, result) = curp.handle_propose_conf_change(new_entry(
+        "id3",
+        ConfChange::RemoveNode {
+            id: "S1".to_owned(),
+        },
+    ));
+    assert!(matches!(result, Err(ProposeError::InvalidConfChange(_))));
+
+    let (_, result) = curp.handle_propose_conf_change(new_entry(
+        "id2",
+        ConfChange::AddNode {
+            id: "S3".to_owned(),
+            address: "S3".to_owned(),
+        },
+    ));
+    assert!(matches!(result, Err(ProposeError::Duplicated)));
+}
+
+#[traced_test]
+#[test]
+fn follower_handle_propose_conf_change_will_reject() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    let (_, result) = curp.handle_propose_conf_change(Arc::new(ConfChangeEntry::new(
+        ProposeId::new("id".to_owned()),
+        ConfChange::RemoveNode {
+            id: "S1".to_owned(),
+        },
+    )));
+    assert!(matches!(result, Err(ProposeError::NotLeader)));
+}
+
+#[traced_test]
+#[test]
+fn apply_conf_change_will_update_members_and_quorum() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    assert_eq!(curp.quorum(), 2);
+
+    curp.apply_conf_change(
+        1,
+        &ConfChange::AddNode {
+            id: "S3".to_owned(),
+            address: "S3".to_owned(),
+        },
+    );
+    assert_eq!(curp.others().len(), 3);
+    assert_eq!(curp.quorum(), 3);
+    assert_eq!(curp.lst.read().get_next_index(&"S3".to_owned()), 1);
+
+    curp.apply_conf_change(
+        2,
+        &ConfChange::UpdateNode {
+            id: "S3".to_owned(),
+            address: "new_addr".to_owned(),
+        },
+    );
+    assert_eq!(
+        curp.others().get("S3").map(Member::address),
+        Some("new_addr")
+    );
+    assert_eq!(
+        curp.others().get("S3").map(|m| m.client_urls().to_vec()),
+        Some(vec![])
+    );
+
+    curp.apply_conf_change(
+        3,
+        &ConfChange::UpdateClientUrls {
+            id: "S3".to_owned(),
+            client_urls: vec!["http://S3:2379".to_owned()],
+        },
+    );
+    assert_eq!(
+        curp.others().get("S3").map(|m| m.client_urls().to_vec()),
+        Some(vec!["http://S3:2379".to_owned()])
+    );
+
+    curp.apply_conf_change(
+        4,
+        &ConfChange::RemoveNode {
+            id: "S1".to_owned(),
+        },
+    );
+    // apply the same change again won't affect the result
+    curp.apply_conf_change(
+        4,
+        &ConfChange::RemoveNode {
+            id: "S1".to_owned(),
+        },
+    );
+    assert_eq!(curp.others().len(), 2);
+    assert_eq!(curp.quorum(), 2);
+    assert!(!curp.lst.read().contains(&"S1".to_owned()));
+    assert_eq!(curp.ctx.applied_conf_index.load(Ordering::Acquire), 4);
+}
+
 /*************** tests for append_entries(heartbeat) **************/
 
 #[traced_test]
@@ -319,6 +515,142 @@ fn handle_ae_will_reject_wrong_log() {
     assert_eq!(result, Err((1, 1)));
 }
 
+#[traced_test]
+#[test]
+fn leader_will_commit_after_its_log_is_persisted() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_after_sync().returning(|_, _| ());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    let index = curp.push_cmd(Arc::new(TestCommand::default()));
+
+    let result = curp.handle_append_entries_resp(&"S1".to_owned(), Some(index), 0, true, index);
+    assert!(result.is_ok());
+    assert_eq!(curp.commit_index(), 0);
+
+    curp.on_log_persisted(index, curp.term());
+    assert_eq!(curp.commit_index(), index);
+}
+
+#[traced_test]
+#[test]
+fn pipeline_will_limit_inflight_batches() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        RawCurp::new_test(3, exe_tx)
+    };
+    let s1_id = "S1".to_owned();
+    for i in 1..=curp.cfg().max_inflight_appends {
+        let _ig = curp.push_cmd(Arc::new(TestCommand::default()));
+        let ae = curp.next_pipelined_batch(&s1_id).unwrap().unwrap();
+        // entries in flight won't be sent again
+        assert_eq!(ae.prev_log_index, i - 1);
+        assert_eq!(ae.entries.len(), 1);
+    }
+    let _ig = curp.push_cmd(Arc::new(TestCommand::default()));
+    assert!(matches!(curp.next_pipelined_batch(&s1_id), Ok(None)));
+
+    curp.finish_pipelined_batch(&s1_id);
+    assert!(matches!(curp.next_pipelined_batch(&s1_id), Ok(Some(_))));
+
+    // the pipeline stops once the follower rejects
+    curp.finish_pipelined_batch(&s1_id);
+    assert!(curp
+        .handle_append_entries_resp(&s1_id, Some(1), 0, false, 1)
+        .is_ok());
+    assert!(matches!(curp.next_pipelined_batch(&s1_id), Ok(None)));
+}
+
+#[traced_test]
+#[test]
+fn pipeline_will_back_off_when_follower_is_lagging() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_after_sync().returning(|_, _| ());
+        RawCurp::new_test(3, exe_tx)
+    };
+    let s1_id = "S1".to_owned();
+    let mut last_index = 0;
+    for _ in 0..=curp.cfg().max_follower_lag {
+        last_index = curp.push_cmd(Arc::new(TestCommand::default()));
+    }
+    // the follower lags behind, only one batch can be in flight
+    let ae = curp.next_pipelined_batch(&s1_id).unwrap().unwrap();
+    assert_eq!(ae.prev_log_index + ae.entries.len(), last_index);
+    let _ig = curp.push_cmd(Arc::new(TestCommand::default()));
+    assert!(matches!(curp.next_pipelined_batch(&s1_id), Ok(None)));
+
+    // the window is restored once the follower catches up
+    curp.finish_pipelined_batch(&s1_id);
+    assert!(curp
+        .handle_append_entries_resp(&s1_id, Some(last_index), 0, true, last_index)
+        .is_ok());
+    assert!(matches!(curp.next_pipelined_batch(&s1_id), Ok(Some(_))));
+    let _ig = curp.push_cmd(Arc::new(TestCommand::default()));
+    assert!(matches!(curp.next_pipelined_batch(&s1_id), Ok(Some(_))));
+}
+
+#[traced_test]
+#[test]
+fn duplicated_entries_of_retried_proposals_will_not_be_applied() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        // only the first entry of a retried proposal is applied
+        exe_tx
+            .expect_send_after_sync()
+            .withf(|_, index| *index == 1 || *index == 2)
+            .times(2)
+            .returning(|_, _| ());
+        exe_tx
+            .expect_skip_after_sync()
+            .withf(|index| *index == 3 || *index == 4)
+            .times(2)
+            .returning(|_| ());
+        RawCurp::new_test(3, exe_tx)
+    };
+    let session = |seq_num, first_incomplete| ClientSession {
+        client_id: 1,
+        seq_num,
+        first_incomplete,
+    };
+    let cmd0 = Arc::new(TestCommand::default().set_session(session(0, 0)));
+    let cmd1 = Arc::new(TestCommand::default().set_session(session(1, 0)));
+    let _ig = curp.push_cmd(Arc::clone(&cmd0));
+    let _ig = curp.push_cmd(Arc::clone(&cmd1));
+    let index = curp.push_cmd(Arc::clone(&cmd0));
+
+    assert!(curp
+        .handle_append_entries_resp(&"S1".to_owned(), Some(index), 0, true, index)
+        .is_ok());
+    curp.on_log_persisted(index, curp.term());
+    assert_eq!(curp.commit_index(), index);
+
+    let cb = curp.cmd_board();
+    assert!(matches!(
+        cb.read().sessions.result(&session(0, 0)),
+        Some(Applied::Pending)
+    ));
+    assert!(matches!(
+        cb.read().sessions.result(&session(1, 0)),
+        Some(Applied::Pending)
+    ));
+    assert!(cb.read().sessions.result(&session(2, 0)).is_none());
+
+    // the results of the first entry are reused by a duplicated entry committed after it's applied
+    cb.write()
+        .sessions
+        .complete(&session(1, 0), Some((Ok(vec![1]), Some(Ok(2)))));
+    let index = curp.push_cmd(Arc::clone(&cmd1));
+    assert!(curp
+        .handle_append_entries_resp(&"S1".to_owned(), Some(index), 0, true, index)
+        .is_ok());
+    curp.on_log_persisted(index, curp.term());
+    assert_eq!(curp.commit_index(), index);
+    assert_eq!(cb.read().er_buffer.get(cmd1.id()), Some(&Ok(vec![1])));
+    assert_eq!(cb.read().asr_buffer.get(cmd1.id()), Some(&Ok(2)));
+}
+
 /*************** tests for election **************/
 
 #[traced_test]
@@ -351,7 +683,7 @@ async fn follower_will_not_start_election_when_heartbeats_are_received() {
 
 #[traced_test]
 #[tokio::test]
-async fn follower_or_candidate_will_start_election_if_timeout() {
+async fn follower_or_pre_candidate_will_start_pre_vote_if_timeout() {
     let curp = {
         let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
         exe_tx.expect_send_reset().return_const(());
@@ -365,7 +697,7 @@ async fn follower_or_candidate_will_start_election_if_timeout() {
         sleep(default_heartbeat_interval()).await;
         let role = curp.role();
         let action = curp.tick();
-        if matches!(action, TickAction::Votes(_)) && role == Role::Follower {
+        if matches!(action, TickAction::PreVotes(_)) && role == Role::Follower {
             let now = Instant::now();
             let dur = now - start;
             assert!(dur >= default_heartbeat_interval() * default_follower_timeout_ticks() as u32);
@@ -375,7 +707,7 @@ async fn follower_or_candidate_will_start_election_if_timeout() {
             );
             follower_election = Some(now);
         }
-        if matches!(action, TickAction::Votes(_)) && role == Role::Candidate {
+        if matches!(action, TickAction::PreVotes(_)) && role == Role::PreCandidate {
             let prev = follower_election.unwrap();
             let now = Instant::now();
 
@@ -399,7 +731,7 @@ fn handle_vote_will_calibrate_term() {
         Arc::new(RawCurp::new_test(3, exe_tx))
     };
 
-    let result = curp.handle_vote(1, "S1".to_owned(), 0, 0).unwrap();
+    let result = curp.handle_vote(1, "S1".to_owned(), 0, 0, false).unwrap();
     assert_eq!(result.0, 1);
 
     assert_eq!(curp.term(), 1);
@@ -416,7 +748,7 @@ fn handle_vote_will_reject_smaller_term() {
     };
     curp.update_to_term_and_become_follower(&mut *curp.st.write(), 2);
 
-    let result = curp.handle_vote(1, "S1".to_owned(), 0, 0);
+    let result = curp.handle_vote(1, "S1".to_owned(), 0, 0, false);
     assert_eq!(result, Err(2));
 }
 
@@ -438,10 +770,25 @@ fn handle_vote_will_reject_outdated_candidate() {
     );
     assert!(result.is_ok());
 
-    let result = curp.handle_vote(3, "S1".to_owned(), 0, 0);
+    let result = curp.handle_vote(3, "S1".to_owned(), 0, 0, false);
     assert_eq!(result, Err(3));
 }
 
+#[traced_test]
+#[test]
+fn handle_vote_will_reject_non_member() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    let result = curp.handle_vote(2, "S5".to_owned(), 0, 0, false);
+    assert_eq!(result, Err(1));
+    assert_eq!(curp.term(), 1);
+}
+
 #[traced_test]
 #[test]
 fn candidate_will_become_leader_after_election_succeeds() {
@@ -452,10 +799,15 @@ fn candidate_will_become_leader_after_election_succeeds() {
     };
     curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
 
-    // tick till election starts
-    while curp.role() != Role::Candidate {
+    // tick till pre-vote starts, then win the pre-vote
+    while curp.role() != Role::PreCandidate {
         let _ig = curp.tick();
     }
+    let votes = curp
+        .handle_pre_vote_resp(&"S1".to_owned(), 2, true)
+        .unwrap();
+    assert!(votes.is_some());
+    assert_eq!(curp.role(), Role::Candidate);
 
     let result = curp
         .handle_vote_resp(&"S1".to_owned(), 2, true, vec![])
@@ -466,6 +818,11 @@ fn candidate_will_become_leader_after_election_succeeds() {
     let result = curp.handle_vote_resp(&"S2".to_owned(), 2, true, vec![]);
     assert!(result.is_err());
     assert_eq!(curp.role(), Role::Leader);
+
+    assert_eq!(curp.counter("curp_elections_started_total", None), 1);
+    assert_eq!(curp.counter("curp_elections_won_total", None), 1);
+    // term 0 -> 1 -> 2
+    assert_eq!(curp.counter("curp_term_changes_total", None), 2);
 }
 
 #[traced_test]
@@ -478,10 +835,15 @@ fn vote_will_calibrate_candidate_term() {
     };
     curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
 
-    // tick till election starts
-    while curp.role() != Role::Candidate {
+    // tick till pre-vote starts, then win the pre-vote
+    while curp.role() != Role::PreCandidate {
         let _ig = curp.tick();
     }
+    let votes = curp
+        .handle_pre_vote_resp(&"S1".to_owned(), 2, true)
+        .unwrap();
+    assert!(votes.is_some());
+    assert_eq!(curp.role(), Role::Candidate);
 
     let result = curp.handle_vote_resp(&"S1".to_owned(), 3, false, vec![]);
     assert!(result.is_err());
@@ -491,6 +853,135 @@ fn vote_will_calibrate_candidate_term() {
     assert_eq!(st_r.role, Role::Follower);
 }
 
+#[traced_test]
+#[test]
+fn handle_pre_vote_will_not_change_state() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    let result = curp.handle_vote(2, "S1".to_owned(), 0, 0, true);
+    assert_eq!(result.map(|(term, _)| term), Ok(2));
+    let st_r = curp.st.read();
+    assert_eq!(st_r.term, 1);
+    assert_eq!(st_r.voted_for, None);
+    assert_eq!(st_r.role, Role::Follower);
+}
+
+#[traced_test]
+#[test]
+fn handle_pre_vote_will_reject_when_leader_is_alive() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    // the leader rejects pre-votes
+    let result = curp.handle_vote(1, "S1".to_owned(), 0, 0, true);
+    assert_eq!(result.map(|(term, _)| term), Err(0));
+
+    // the follower rejects pre-votes if it has heard from the leader recently
+    let result = curp.handle_append_entries(1, "S2".to_owned(), 0, 0, vec![], 0);
+    assert!(result.is_ok());
+    let result = curp.handle_vote(2, "S1".to_owned(), 0, 0, true);
+    assert_eq!(result.map(|(term, _)| term), Err(1));
+
+    // the leader is considered lost after an election timeout
+    curp.ctx
+        .election_tick
+        .store(curp.cfg().follower_timeout_ticks, Ordering::Relaxed);
+    let result = curp.handle_vote(2, "S1".to_owned(), 0, 0, true);
+    assert_eq!(result.map(|(term, _)| term), Ok(2));
+}
+
+#[traced_test]
+#[test]
+fn pre_candidate_will_not_increase_term_until_pre_vote_succeeds() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(5, exe_tx))
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    while curp.role() != Role::PreCandidate {
+        let _ig = curp.tick();
+    }
+    assert_eq!(curp.term(), 1);
+
+    let result = curp.handle_pre_vote_resp(&"S1".to_owned(), 1, false);
+    assert!(matches!(result, Ok(None)));
+    let result = curp.handle_pre_vote_resp(&"S1".to_owned(), 2, true);
+    assert!(matches!(result, Ok(None)));
+    // a server's pre-vote won't be counted twice
+    let result = curp.handle_pre_vote_resp(&"S1".to_owned(), 2, true);
+    assert!(matches!(result, Ok(None)));
+    assert_eq!(curp.term(), 1);
+
+    let votes = curp
+        .handle_pre_vote_resp(&"S2".to_owned(), 2, true)
+        .unwrap()
+        .unwrap();
+    assert_eq!(votes.len(), 4);
+    assert!(votes
+        .values()
+        .all(|vote| vote.term == 2 && !vote.is_pre_vote));
+    assert_eq!(curp.role(), Role::Candidate);
+    assert_eq!(curp.term(), 2);
+}
+
+#[traced_test]
+#[test]
+fn pre_candidate_will_step_down_when_leader_shows_up() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    while curp.role() != Role::PreCandidate {
+        let _ig = curp.tick();
+    }
+    let result = curp.handle_append_entries(1, "S2".to_owned(), 0, 0, vec![], 0);
+    assert!(result.is_ok());
+    assert_eq!(curp.role(), Role::Follower);
+    assert_eq!(curp.leader(), (Some("S2".to_owned()), 1));
+}
+
+#[traced_test]
+#[test]
+fn leader_will_step_down_if_it_cannot_hear_from_quorum() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    curp.st.write().voted_for = Some("S0".to_owned());
+    let timeout = curp.cfg().follower_timeout_ticks;
+
+    // the leader keeps its leadership as long as a quorum responds
+    for _ in 0..timeout {
+        assert!(curp
+            .handle_append_entries_resp(&"S1".to_owned(), None, 0, true, 1)
+            .is_ok());
+        let _ig = curp.tick();
+    }
+    assert_eq!(curp.role(), Role::Leader);
+
+    for _ in 0..timeout {
+        let _ig = curp.tick();
+    }
+    let st_r = curp.st.read();
+    assert_eq!(st_r.role, Role::Follower);
+    assert_eq!(st_r.term, 0);
+    // the vote in the current term is kept
+    assert_eq!(st_r.voted_for, Some("S0".to_owned()));
+}
+
 /*************** tests for recovery **************/
 
 #[traced_test]
@@ -523,12 +1014,83 @@ fn recover_from_spec_pools_will_pick_the_correct_cmds() {
     curp.recover_from_spec_pools(&mut *curp.st.write(), &mut *curp.log.write(), &spec_pools);
 
     curp.log.map_read(|log_r| {
-        assert_eq!(log_r[1].cmd.id(), cmd0.id());
-        assert_eq!(log_r[2].cmd.id(), cmd1.id());
+        assert_eq!(log_r[1].id(), cmd0.id());
+        assert_eq!(log_r[2].id(), cmd1.id());
         assert_eq!(log_r.last_log_index(), 2);
     });
 }
 
+#[traced_test]
+#[test]
+fn recover_from_spec_pools_will_ignore_learners() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    for (index, id) in [(1, "S3"), (2, "S4")] {
+        curp.apply_conf_change(
+            index,
+            &ConfChange::AddLearner {
+                id: id.to_owned(),
+                address: id.to_owned(),
+            },
+        );
+    }
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    assert_eq!(curp.superquorum(), 2);
+
+    // cmd is only stored by the old leader and the learners
+    let cmd = Arc::new(TestCommand::new_put(vec![1], 1));
+    let spec_pools = HashMap::from([
+        ("S0".to_owned(), vec![]),
+        ("S1".to_owned(), vec![Arc::clone(&cmd)]),
+        ("S3".to_owned(), vec![Arc::clone(&cmd)]),
+        ("S4".to_owned(), vec![Arc::clone(&cmd)]),
+    ]);
+
+    curp.recover_from_spec_pools(&mut *curp.st.write(), &mut *curp.log.write(), &spec_pools);
+
+    assert_eq!(curp.log.read().last_log_index(), 0);
+}
+
+#[traced_test]
+#[test]
+fn recover_from_spec_pools_will_count_witnesses() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    for (index, id) in [(1, "S3"), (2, "S4")] {
+        curp.apply_conf_change(
+            index,
+            &ConfChange::AddWitness {
+                id: id.to_owned(),
+                address: id.to_owned(),
+            },
+        );
+    }
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    assert_eq!(curp.superquorum(), 2);
+
+    // cmd is only stored by the witnesses
+    let cmd = Arc::new(TestCommand::new_put(vec![1], 1));
+    let spec_pools = HashMap::from([
+        ("S0".to_owned(), vec![]),
+        ("S1".to_owned(), vec![]),
+        ("S3".to_owned(), vec![Arc::clone(&cmd)]),
+        ("S4".to_owned(), vec![Arc::clone(&cmd)]),
+    ]);
+
+    curp.recover_from_spec_pools(&mut *curp.st.write(), &mut *curp.log.write(), &spec_pools);
+
+    curp.log.map_read(|log_r| {
+        assert_eq!(log_r.last_log_index(), 1);
+        assert_eq!(log_r[1].id(), cmd.id());
+    });
+}
+
 /*************** tests for other small functions **************/
 
 #[traced_test]
@@ -541,4 +1103,448 @@ fn quorum() {
     };
     assert_eq!(curp.quorum(), 3);
     assert_eq!(curp.superquorum(), 2);
+
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(4, exe_tx))
+    };
+    assert_eq!(curp.quorum(), 3);
+}
+
+#[traced_test]
+#[test]
+fn learners_will_not_count_toward_quorum() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    curp.apply_conf_change(
+        1,
+        &ConfChange::AddLearner {
+            id: "S3".to_owned(),
+            address: "S3".to_owned(),
+        },
+    );
+    assert_eq!(curp.quorum(), 2);
+    assert!(curp.others()["S3"].is_learner());
+    assert_eq!(curp.lst.read().get_next_index(&"S3".to_owned()), 1);
+
+    // a learner can't be a candidate
+    let result = curp.handle_vote(2, "S3".to_owned(), 0, 0, false);
+    assert!(result.is_err());
+
+    curp.apply_conf_change(
+        2,
+        &ConfChange::PromoteLearner {
+            id: "S3".to_owned(),
+        },
+    );
+    assert!(!curp.others()["S3"].is_learner());
+    assert_eq!(curp.quorum(), 3);
+}
+
+#[traced_test]
+#[test]
+fn witnesses_will_not_count_toward_quorum() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    for (index, id) in [(1, "S3"), (2, "S4")] {
+        curp.apply_conf_change(
+            index,
+            &ConfChange::AddWitness {
+                id: id.to_owned(),
+                address: id.to_owned(),
+            },
+        );
+    }
+    assert!(curp.others()["S3"].is_witness());
+    assert_eq!(curp.quorum(), 2);
+    assert_eq!(curp.spec_quorum(), 3);
+    assert_eq!(curp.lst.read().get_next_index(&"S3".to_owned()), 1);
+
+    // a witness can't be a candidate
+    let result = curp.handle_vote(2, "S3".to_owned(), 0, 0, false);
+    assert!(result.is_err());
+}
+
+#[traced_test]
+#[test]
+fn candidate_will_wait_for_spec_pools_of_witnesses() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    for (index, id) in [(1, "S3"), (2, "S4")] {
+        curp.apply_conf_change(
+            index,
+            &ConfChange::AddWitness {
+                id: id.to_owned(),
+                address: id.to_owned(),
+            },
+        );
+    }
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    // tick till pre-vote starts, then win the pre-vote without the witnesses
+    while curp.role() != Role::PreCandidate {
+        let _ig = curp.tick();
+    }
+    let votes = curp
+        .handle_pre_vote_resp(&"S1".to_owned(), 2, true)
+        .unwrap()
+        .unwrap();
+    assert!(votes.contains_key("S3") && votes.contains_key("S4"));
+
+    // a quorum of votes is granted, but only 2 of the 5 spec pools are collected
+    let result = curp
+        .handle_vote_resp(&"S1".to_owned(), 2, true, vec![])
+        .unwrap();
+    assert!(!result);
+    assert_eq!(curp.role(), Role::Candidate);
+
+    let result = curp
+        .handle_vote_resp(&"S3".to_owned(), 2, true, vec![])
+        .unwrap();
+    assert!(result);
+    assert_eq!(curp.role(), Role::Leader);
+}
+
+#[traced_test]
+#[test]
+fn check_conf_change_will_only_promote_learners() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        Arc::new(RawCurp::new_test(3, exe_tx))
+    };
+    assert!(curp
+        .check_conf_change(&ConfChange::PromoteLearner {
+            id: "S1".to_owned(),
+        })
+        .is_err());
+    assert!(curp
+        .check_conf_change(&ConfChange::AddLearner {
+            id: "S1".to_owned(),
+            address: "S1".to_owned(),
+        })
+        .is_err());
+
+    curp.apply_conf_change(
+        1,
+        &ConfChange::AddLearner {
+            id: "S3".to_owned(),
+            address: "S3".to_owned(),
+        },
+    );
+    assert!(curp
+        .check_conf_change(&ConfChange::PromoteLearner {
+            id: "S3".to_owned(),
+        })
+        .is_ok());
+}
+
+/*************** tests for install_snapshot **************/
+
+#[traced_test]
+#[test]
+fn follower_will_install_snapshot_and_reset_executor() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().times(2).returning(|_| ());
+        RawCurp::new_test(3, exe_tx)
+    };
+    let meta = SnapshotMeta {
+        last_included_index: 5,
+        last_included_term: 1,
+    };
+
+    // a stale leader's snapshot is rejected
+    curp.st.write().term = 2;
+    assert_eq!(
+        curp.handle_install_snapshot(1, "S1".to_owned(), meta),
+        Err(2)
+    );
+
+    let result = curp.handle_install_snapshot(3, "S1".to_owned(), meta);
+    assert_eq!(result, Ok(3));
+    assert_eq!(curp.role(), Role::Follower);
+
+    curp.install_snapshot(Snapshot::new(meta, vec![]));
+    let log_r = curp.log.read();
+    assert_eq!(log_r.snapshot_index(), 5);
+    assert_eq!(log_r.commit_index, 5);
+    assert_eq!(log_r.last_applied, 5);
+    assert_eq!(log_r.last_log_index(), 5);
+    assert_eq!(log_r.last_log_term(), 1);
+    drop(log_r);
+
+    // the same snapshot won't be installed twice
+    assert_eq!(
+        curp.handle_install_snapshot(3, "S1".to_owned(), meta),
+        Err(3)
+    );
+}
+
+#[traced_test]
+#[test]
+fn follower_will_discard_conflicting_entries_and_speculative_cmds_when_installing_snapshot() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().returning(|_| ());
+        RawCurp::new_test(3, exe_tx)
+    };
+    let term = curp.term();
+    for _ in 0..3 {
+        let _ig = curp.push_cmd(Arc::new(TestCommand::default()));
+    }
+    let cmd = Arc::new(TestCommand::new_put(vec![1], 1));
+    assert!(curp.spec_pool().lock().insert(Arc::clone(&cmd)).is_none());
+    let _ig = curp.ctx.ucp.lock().insert(cmd.id().clone(), cmd);
+
+    // the entries after the snapshot are kept only if the last included entry matches
+    let matched = SnapshotMeta {
+        last_included_index: 2,
+        last_included_term: term,
+    };
+    assert_eq!(curp.snapshot_discard_index(matched), 2);
+    let meta = SnapshotMeta {
+        last_included_index: 2,
+        last_included_term: term + 1,
+    };
+    assert_eq!(curp.snapshot_discard_index(meta), 3);
+
+    assert!(curp
+        .handle_install_snapshot(term + 1, "S1".to_owned(), meta)
+        .is_ok());
+    curp.install_snapshot(Snapshot::new(meta, vec![]));
+    assert_eq!(curp.log.read().last_log_index(), 2);
+    assert!(curp.spec_pool().lock().pool.is_empty());
+    assert!(curp.ctx.ucp.lock().is_empty());
+}
+
+/*************** tests for leader transfer **************/
+
+#[traced_test]
+#[test]
+fn follower_will_install_members_in_snapshot_and_skip_included_conf_changes() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        RawCurp::new_test(3, exe_tx)
+    };
+    // S0 has been added as a learner, S2 has been removed and S3 has been added in the snapshot from S1
+    let members = HashMap::from([
+        ("S0".to_owned(), Member::new("S0".to_owned(), true)),
+        ("S3".to_owned(), Member::new("S3".to_owned(), false)),
+    ]);
+    let (is_learner, others) = curp.snapshot_members(&"S1".to_owned(), members);
+    assert!(is_learner);
+    assert_eq!(others.len(), 2);
+    assert!(others.contains_key("S1") && others.contains_key("S3"));
+
+    curp.install_members(5, is_learner, others);
+    assert!(curp.is_learner());
+    assert!(!curp.lst.read().contains(&"S2".to_owned()));
+    assert!(curp.lst.read().contains(&"S3".to_owned()));
+    assert_eq!(curp.ctx.applied_conf_index.load(Ordering::Acquire), 5);
+
+    // changes included in the snapshot won't be applied again
+    assert!(!curp.apply_conf_change(
+        4,
+        &ConfChange::AddNode {
+            id: "S2".to_owned(),
+            address: "S2".to_owned(),
+        },
+    ));
+    assert!(!curp.others().contains_key("S2"));
+    assert!(curp.apply_conf_change(
+        6,
+        &ConfChange::RemoveNode {
+            id: "S3".to_owned(),
+        },
+    ));
+    assert!(!curp.others().contains_key("S3"));
+}
+
+#[traced_test]
+#[test]
+fn leader_will_reject_proposals_during_leader_transfer() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        RawCurp::new_test(3, exe_tx)
+    };
+    assert!(matches!(
+        curp.handle_move_leader(&"S1".to_owned()),
+        Ok(false)
+    ));
+
+    let cmd = Arc::new(TestCommand::new_put(vec![1], 1));
+    let (_, result) = curp.handle_propose(Arc::clone(&cmd));
+    assert!(matches!(result, Err(ProposeError::LeaderTransferring)));
+    assert!(!curp.spec_pool().lock().pool.contains_key(cmd.id()));
+
+    let (_, result) = curp.handle_propose_conf_change(Arc::new(ConfChangeEntry::new(
+        ProposeId::new("id".to_owned()),
+        ConfChange::RemoveNode {
+            id: "S2".to_owned(),
+        },
+    )));
+    assert!(matches!(result, Err(ProposeError::LeaderTransferring)));
+}
+
+#[traced_test]
+#[test]
+fn handle_move_leader_will_reject_invalid_targets() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        RawCurp::new_test(3, exe_tx)
+    };
+    assert!(matches!(
+        curp.handle_move_leader(&"S0".to_owned()),
+        Ok(true)
+    ));
+    assert!(matches!(
+        curp.handle_move_leader(&"S3".to_owned()),
+        Err(ProposeError::LeaderTransfer(_))
+    ));
+
+    assert!(matches!(
+        curp.handle_move_leader(&"S1".to_owned()),
+        Ok(false)
+    ));
+    assert!(matches!(
+        curp.handle_move_leader(&"S1".to_owned()),
+        Ok(false)
+    ));
+    // only one transfer can be in progress
+    assert!(matches!(
+        curp.handle_move_leader(&"S2".to_owned()),
+        Err(ProposeError::LeaderTransfer(_))
+    ));
+
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+    assert!(matches!(
+        curp.handle_move_leader(&"S1".to_owned()),
+        Err(ProposeError::NotLeader)
+    ));
+}
+
+#[traced_test]
+#[test]
+fn leader_will_send_timeout_now_after_transferee_catches_up() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        RawCurp::new_test(3, exe_tx)
+    };
+    let s1_id = "S1".to_owned();
+    let _ig = curp.push_cmd(Arc::new(TestCommand::default()));
+    assert!(matches!(curp.handle_move_leader(&s1_id), Ok(false)));
+    assert!(matches!(curp.tick(), TickAction::Heartbeat(_)));
+
+    curp.lst.write().update_match_index(&s1_id, 1);
+    assert!(matches!(
+        curp.tick(),
+        TickAction::TimeoutNow(ref hbs, ref id, 0) if id == &s1_id && hbs.len() == 2
+    ));
+    // `timeout_now` is sent only once, the heartbeats keep going
+    assert!(matches!(curp.tick(), TickAction::Heartbeat(ref hbs) if hbs.len() == 2));
+}
+
+#[traced_test]
+#[test]
+fn leader_transfer_will_be_aborted_after_election_timeout() {
+    let curp = {
+        let exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        RawCurp::new_test(3, exe_tx)
+    };
+    let _ig = curp.push_cmd(Arc::new(TestCommand::default()));
+    assert!(matches!(
+        curp.handle_move_leader(&"S1".to_owned()),
+        Ok(false)
+    ));
+    // the transferee is reachable but can't catch up
+    assert!(curp
+        .handle_append_entries_resp(&"S1".to_owned(), None, 0, true, 1)
+        .is_ok());
+    for _ in 0..curp.cfg().follower_timeout_ticks {
+        assert!(matches!(curp.tick(), TickAction::Heartbeat(_)));
+    }
+    assert!(curp.lst.read().transferee.is_some());
+
+    let _ig = curp.tick();
+    assert!(curp.lst.read().transferee.is_none());
+    // the leader can transfer to another server now
+    assert!(matches!(
+        curp.handle_move_leader(&"S2".to_owned()),
+        Ok(false)
+    ));
+}
+
+#[traced_test]
+#[test]
+fn transferee_will_start_election_on_timeout_now() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 2);
+
+    // a stale leader's request is rejected
+    assert_eq!(curp.handle_timeout_now(1, &"S1".to_owned()).err(), Some(2));
+    assert_eq!(curp.role(), Role::Follower);
+
+    let votes = curp.handle_timeout_now(2, &"S1".to_owned()).unwrap();
+    assert_eq!(curp.role(), Role::Candidate);
+    assert_eq!(curp.term(), 3);
+    assert_eq!(votes.len(), 2);
+    assert!(votes.values().all(|vote| vote.term == 3));
+}
+
+/*************** tests for read index **************/
+
+#[traced_test]
+#[test]
+fn handle_read_index_will_return_the_last_log_index() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_sp_exe().returning(|_| {});
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.apply_conf_change(
+        1,
+        &ConfChange::AddLearner {
+            id: "S3".to_owned(),
+            address: "S3".to_owned(),
+        },
+    );
+    // the cmd is not committed, but it may have completed in the fast path
+    let ((_, _), result) = curp.handle_propose(Arc::new(TestCommand::default()));
+    assert!(matches!(result, Ok(true)));
+
+    let read_index = curp.handle_read_index().unwrap();
+    assert_eq!(read_index.index, 1);
+    assert_eq!(read_index.acks, 1);
+    // learners can't acknowledge the leadership
+    assert_eq!(read_index.heartbeats.len(), 2);
+    assert!(!read_index.heartbeats.contains_key("S3"));
+}
+
+#[traced_test]
+#[test]
+fn follower_will_reject_read_index() {
+    let curp = {
+        let mut exe_tx = MockCEEventTxApi::<TestCommand>::default();
+        exe_tx.expect_send_reset().return_const(());
+        RawCurp::new_test(3, exe_tx)
+    };
+    curp.update_to_term_and_become_follower(&mut *curp.st.write(), 1);
+
+    assert!(matches!(
+        curp.handle_read_index(),
+        Err(ProposeError::NotLeader)
+    ));
 }
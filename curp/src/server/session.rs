@@ -0,0 +1,222 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cmd::{ClientSession, Command};
+
+/// Results of a cmd, the after sync result is absent if the execution fails
+pub(super) type CmdResult<C> = (
+    Result<<C as Command>::ER, String>,
+    Option<Result<<C as Command>::ASR, String>>,
+);
+
+/// Error message for an applied cmd whose result has been lost
+const RESULT_LOST: &str = "the cmd has been applied, but its result is lost";
+
+/// Results of an applied proposal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) enum Applied<C: Command> {
+    /// The proposal is being applied, its results will be sent to the cmd board
+    Pending,
+    /// Results of the proposal, the execution result is an error if they have been lost
+    Done(CmdResult<C>),
+}
+
+impl<C: Command> Applied<C> {
+    /// Results that have been lost
+    fn lost() -> Self {
+        Self::Done((Err(RESULT_LOST.to_owned()), None))
+    }
+}
+
+/// Applied proposals of a client
+#[derive(Debug, Serialize, Deserialize)]
+struct Session<C: Command> {
+    /// Proposals whose sequence numbers are smaller than it have completed in the client
+    first_incomplete: u64,
+    /// Results of the applied proposals
+    results: BTreeMap<u64, Applied<C>>,
+    /// Log index of the last applied proposal, the session expires if it's idle for too long
+    last_index: usize,
+}
+
+/// Sessions of all clients, used to dedupe speculative executions and replies of retried proposals.
+/// Proposals are recorded in the order they are applied, so it's part of the state machine, it's
+/// taken into snapshots and rebuilt from the log.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct SessionTable<C: Command> {
+    /// Client id -> session
+    sessions: HashMap<u64, Session<C>>,
+}
+
+impl<C: Command> SessionTable<C> {
+    /// Create an empty session table
+    pub(super) fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Restore the session table from the bytes taken into a snapshot
+    pub(super) fn restore(bytes: &[u8]) -> bincode::Result<Self> {
+        if bytes.is_empty() {
+            return Ok(Self::new());
+        }
+        bincode::deserialize(bytes)
+    }
+
+    /// Encode the session table so that it can be taken into a snapshot
+    pub(super) fn encode(&self) -> bincode::Result<Vec<u8>> {
+        bincode::serialize(self)
+    }
+
+    /// Whether the proposal has been applied or has completed in the client
+    pub(super) fn is_applied(&self, session: &ClientSession) -> bool {
+        self.sessions.get(&session.client_id).map_or(false, |s| {
+            session.seq_num < s.first_incomplete || s.results.contains_key(&session.seq_num)
+        })
+    }
+
+    /// Get the results of a proposal, `None` if the proposal hasn't been applied. The results of
+    /// a proposal that has completed in the client are lost.
+    pub(super) fn result(&self, session: &ClientSession) -> Option<Applied<C>> {
+        let s = self.sessions.get(&session.client_id)?;
+        if session.seq_num < s.first_incomplete {
+            return Some(Applied::lost());
+        }
+        s.results.get(&session.seq_num).cloned()
+    }
+
+    /// Record a proposal when log[index] is applied, its results are pending until `complete` is
+    /// called. Results of the proposals that have completed in the client are discarded.
+    pub(super) fn apply(&mut self, session: &ClientSession, index: usize) {
+        self.record(session, index, Applied::Pending);
+    }
+
+    /// Record a proposal in log[index] that has been applied but whose results are lost
+    pub(super) fn apply_lost(&mut self, session: &ClientSession, index: usize) {
+        self.record(session, index, Applied::lost());
+    }
+
+    /// Expire the sessions that have no proposal applied after log[index]
+    pub(super) fn expire(&mut self, index: usize) {
+        self.sessions.retain(|_, s| s.last_index > index);
+    }
+
+    /// Complete an applied proposal with its results, `None` if they are lost
+    pub(super) fn complete(&mut self, session: &ClientSession, result: Option<CmdResult<C>>) {
+        if let Some(applied) = self
+            .sessions
+            .get_mut(&session.client_id)
+            .and_then(|s| s.results.get_mut(&session.seq_num))
+        {
+            *applied = result.map_or_else(Applied::lost, Applied::Done);
+        }
+    }
+
+    /// Record an applied proposal
+    fn record(&mut self, session: &ClientSession, index: usize, applied: Applied<C>) {
+        let s = self
+            .sessions
+            .entry(session.client_id)
+            .or_insert_with(|| Session {
+                first_incomplete: 0,
+                results: BTreeMap::new(),
+                last_index: 0,
+            });
+        s.last_index = index;
+        if session.first_incomplete > s.first_incomplete {
+            s.first_incomplete = session.first_incomplete;
+            s.results = s.results.split_off(&session.first_incomplete);
+        }
+        if session.seq_num >= s.first_incomplete {
+            let _ig = s.results.insert(session.seq_num, applied);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_cmd::TestCommand;
+
+    fn session(seq_num: u64, first_incomplete: u64) -> ClientSession {
+        ClientSession {
+            client_id: 1,
+            seq_num,
+            first_incomplete,
+        }
+    }
+
+    #[test]
+    fn session_table_will_discard_results_of_completed_proposals() {
+        let mut table = SessionTable::<TestCommand>::new();
+        table.apply(&session(0, 0), 1);
+        table.complete(&session(0, 0), Some((Ok(vec![1]), Some(Ok(1)))));
+        table.apply_lost(&session(1, 0), 2);
+        assert!(table.is_applied(&session(0, 0)));
+        assert!(!table.is_applied(&session(2, 0)));
+        assert!(matches!(
+            table.result(&session(0, 0)),
+            Some(Applied::Done((Ok(er), Some(Ok(1))))) if er == vec![1]
+        ));
+        assert!(matches!(
+            table.result(&session(1, 0)),
+            Some(Applied::Done((Err(_), None)))
+        ));
+
+        table.apply(&session(2, 1), 3);
+        assert!(matches!(
+            table.result(&session(2, 1)),
+            Some(Applied::Pending)
+        ));
+        table.complete(&session(2, 1), Some((Ok(vec![]), Some(Ok(2)))));
+        assert!(table.is_applied(&session(0, 0)));
+        assert!(matches!(
+            table.result(&session(0, 0)),
+            Some(Applied::Done((Err(_), None)))
+        ));
+        assert!(matches!(
+            table.result(&session(2, 1)),
+            Some(Applied::Done((Ok(_), Some(Ok(2)))))
+        ));
+
+        let restored = SessionTable::<TestCommand>::restore(&table.encode().unwrap()).unwrap();
+        assert!(restored.is_applied(&session(2, 1)));
+        assert!(SessionTable::<TestCommand>::restore(&[])
+            .unwrap()
+            .sessions
+            .is_empty());
+    }
+
+    #[test]
+    fn session_table_will_only_complete_applied_proposals() {
+        let mut table = SessionTable::<TestCommand>::new();
+        table.complete(&session(0, 0), Some((Ok(vec![]), Some(Ok(0)))));
+        assert!(table.result(&session(0, 0)).is_none());
+
+        table.apply(&session(1, 0), 1);
+        table.apply(&session(2, 2), 2);
+        table.complete(&session(1, 0), None);
+        assert!(!table.sessions[&1].results.contains_key(&1));
+        assert!(matches!(
+            table.result(&session(2, 2)),
+            Some(Applied::Pending)
+        ));
+    }
+
+    #[test]
+    fn session_table_will_expire_idle_sessions() {
+        let mut table = SessionTable::<TestCommand>::new();
+        let other = ClientSession {
+            client_id: 2,
+            seq_num: 0,
+            first_incomplete: 0,
+        };
+        table.apply(&session(0, 0), 1);
+        table.apply(&other, 2);
+        table.expire(1);
+        assert!(!table.is_applied(&session(0, 0)));
+        assert!(table.is_applied(&other));
+    }
+}
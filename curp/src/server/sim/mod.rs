@@ -0,0 +1,265 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use futures::future::join_all;
+use itertools::Itertools;
+use parking_lot::Mutex;
+use tokio::task::JoinHandle;
+use utils::config::CurpConfig;
+
+use self::network::{NetConfig, SimConnect, SimConnector, SimNetwork};
+use super::{curp_node::CurpNode, random::seed_thread_rng};
+use crate::{
+    cmd::ProposeId,
+    error::ProposeError,
+    message::ServerId,
+    rpc::{connect::ConnectApi, ProposeRequest, ProposeResponse, SyncResult, WaitSyncedRequest},
+    test_utils::{
+        random_id,
+        test_cmd::{TestCESimple, TestCommand},
+    },
+};
+
+/// The simulated network and the in-process connects
+mod network;
+
+/// Seeded simulations of the cluster
+mod tests;
+
+/// Id of the client in the simulated network
+const CLIENT_ID: &str = "client";
+
+/// How often the monitor checks the leaders known by the servers
+const MONITOR_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Number of the trace lines printed when the simulation fails
+const TRACE_TAIL: usize = 100;
+
+/// The servers known as the leader in each term
+type Leaders = Arc<Mutex<BTreeMap<u64, BTreeSet<ServerId>>>>;
+
+/// A curp cluster whose servers talk through a simulated network. The cluster should run on the
+/// paused tokio clock and all randomness is drawn from the seed, so a failure can be reproduced by
+/// running the simulation with the same seed again.
+struct SimCluster {
+    /// The seed of the simulation
+    seed: u64,
+    /// The network
+    net: Arc<SimNetwork<CurpNode<TestCommand>>>,
+    /// Servers that have not crashed
+    nodes: BTreeMap<ServerId, Arc<CurpNode<TestCommand>>>,
+    /// Data dirs of the servers
+    data_dirs: Vec<PathBuf>,
+    /// Leaders known by the servers
+    leaders: Leaders,
+    /// The task that monitors the leaders
+    monitor: JoinHandle<()>,
+}
+
+impl SimCluster {
+    /// Create a cluster of `size` servers, none of them is the leader at the beginning
+    async fn new(seed: u64, size: usize, net_config: NetConfig) -> Self {
+        seed_thread_rng(seed);
+        let net = SimNetwork::new(seed, net_config);
+        let ids = (0..size).map(|i| format!("S{i}")).collect_vec();
+        let mut nodes = BTreeMap::new();
+        let mut data_dirs = vec![];
+        for id in &ids {
+            // the address is not used by the simulated network
+            let others = ids
+                .iter()
+                .filter(|other| *other != id)
+                .map(|other| (other.clone(), other.clone()))
+                .collect();
+            let data_dir = PathBuf::from(format!("/tmp/curp-sim-{}", random_id()));
+            let cfg = Arc::new(CurpConfig {
+                data_dir: data_dir.clone(),
+                ..CurpConfig::default()
+            });
+            let connector = Arc::new(SimConnector::new(id.clone(), Arc::clone(&net)));
+            let ce = TestCESimple::new(id.clone());
+            let node = CurpNode::new(id.clone(), false, others, ce, cfg, connector)
+                .await
+                .unwrap_or_else(|e| panic!("failed to create server {id}, {e}"));
+            let node = Arc::new(node);
+            net.register(id.clone(), Arc::clone(&node));
+            let _ig = nodes.insert(id.clone(), node);
+            data_dirs.push(data_dir);
+        }
+        let leaders = Arc::new(Mutex::new(BTreeMap::new()));
+        let monitor = tokio::spawn(Self::monitor_task(
+            nodes.values().map(Arc::downgrade).collect(),
+            Arc::clone(&leaders),
+        ));
+        Self {
+            seed,
+            net,
+            nodes,
+            data_dirs,
+            leaders,
+            monitor,
+        }
+    }
+
+    /// Record the leaders known by the servers
+    async fn monitor_task(nodes: Vec<Weak<CurpNode<TestCommand>>>, leaders: Leaders) {
+        let mut ticker = tokio::time::interval(MONITOR_INTERVAL);
+        loop {
+            let _now = ticker.tick().await;
+            let mut leaders_l = leaders.lock();
+            for node in nodes.iter().filter_map(Weak::upgrade) {
+                if let (Some(leader), term) = node.leader() {
+                    let _ig = leaders_l.entry(term).or_default().insert(leader);
+                }
+            }
+        }
+    }
+
+    /// Ids of the servers that have not crashed
+    fn ids(&self) -> Vec<ServerId> {
+        self.nodes.keys().cloned().collect()
+    }
+
+    /// Let the simulation run for `duration`
+    async fn run_for(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Get the leader with the highest term, a leader knows itself as the leader
+    fn leader(&self) -> Option<(ServerId, u64)> {
+        self.nodes
+            .iter()
+            .filter_map(|(id, node)| match node.leader() {
+                (Some(leader), term) if leader == *id => Some((leader, term)),
+                _ => None,
+            })
+            .max_by_key(|&(_, term)| term)
+    }
+
+    /// Wait until there is a leader whose term is not less than `min_term`
+    async fn wait_for_leader(&self, min_term: u64, timeout: Duration) -> (ServerId, u64) {
+        let elected = async {
+            loop {
+                match self.leader() {
+                    Some((leader, term)) if term >= min_term => return (leader, term),
+                    _ => {}
+                }
+                tokio::time::sleep(MONITOR_INTERVAL).await;
+            }
+        };
+        match tokio::time::timeout(timeout, elected).await {
+            Ok(leader) => leader,
+            Err(_elapsed) => self.fail(&format!("no leader is elected in {timeout:?}")),
+        }
+    }
+
+    /// Get the commit index of a server
+    fn commit_index(&self, id: &ServerId) -> u64 {
+        self.node(id).commit_index()
+    }
+
+    /// Cut the link from `from` to `to`
+    fn cut(&self, from: &ServerId, to: &ServerId) {
+        self.net.cut(from, to);
+    }
+
+    /// Cut all links between `group` and the other servers
+    fn partition(&self, group: &[ServerId]) {
+        self.net.partition(group);
+    }
+
+    /// Restore all links
+    fn heal(&self) {
+        self.net.heal();
+    }
+
+    /// Crash a server, it stops all its tasks
+    fn crash(&mut self, id: &ServerId) {
+        let _ig = self.net.crash(id);
+        let _ig = self.nodes.remove(id);
+    }
+
+    /// Connect to a server as the client
+    fn client_connect(&self, id: &ServerId) -> SimConnect<TestCommand> {
+        SimConnect::new(CLIENT_ID.to_owned(), id.clone(), Arc::clone(&self.net))
+    }
+
+    /// Propose a command to all servers, return their responses
+    async fn propose(
+        &self,
+        cmd: &TestCommand,
+        timeout: Duration,
+    ) -> BTreeMap<ServerId, Result<ProposeResponse, ProposeError>> {
+        let req = ProposeRequest::new(cmd).unwrap_or_else(|e| panic!("encode error, {e}"));
+        let resps = self.nodes.keys().map(|id| {
+            let (connect, req) = (self.client_connect(id), req.clone());
+            async move {
+                let resp = connect.propose(req, timeout).await;
+                (id.clone(), resp.map(tonic::Response::into_inner))
+            }
+        });
+        join_all(resps).await.into_iter().collect()
+    }
+
+    /// Wait for the command to be synced by the server
+    async fn wait_synced(
+        &self,
+        id: &ServerId,
+        cmd_id: &ProposeId,
+        timeout: Duration,
+    ) -> Result<SyncResult<TestCommand>, ProposeError> {
+        let req = WaitSyncedRequest::new(cmd_id)?;
+        let resp = self
+            .client_connect(id)
+            .wait_synced(req, timeout)
+            .await?
+            .into_inner();
+        Ok(resp.into::<TestCommand>()?)
+    }
+
+    /// Check that no two servers are known as the leader in the same term
+    fn check_election_safety(&self) {
+        let leaders = self.leaders.lock().clone();
+        for (term, ids) in leaders {
+            if ids.len() > 1 {
+                self.fail(&format!(
+                    "{ids:?} are all known as the leader in term {term}"
+                ));
+            }
+        }
+    }
+
+    /// Get a server
+    fn node(&self, id: &ServerId) -> &CurpNode<TestCommand> {
+        self.nodes
+            .get(id)
+            .unwrap_or_else(|| panic!("server {id} doesn't exist"))
+    }
+
+    /// Fail the simulation, print the tail of the trace and the seed to reproduce the failure
+    fn fail(&self, msg: &str) -> ! {
+        let trace = self.net.trace();
+        let tail = trace.iter().skip(trace.len().saturating_sub(TRACE_TAIL));
+        panic!(
+            "simulation with seed {} fails: {msg}\nlast messages:\n{}",
+            self.seed,
+            tail.format("\n")
+        );
+    }
+}
+
+impl Drop for SimCluster {
+    fn drop(&mut self) {
+        self.monitor.abort();
+        self.nodes.clear();
+        // the servers and the network refer to each other
+        self.net.clear();
+        for dir in &self.data_dirs {
+            let _ig = std::fs::remove_dir_all(dir);
+        }
+    }
+}
@@ -0,0 +1,591 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    future::Future,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    sync::{oneshot, Notify},
+    time::Instant,
+};
+
+use crate::{
+    cmd::Command,
+    error::ProposeError,
+    message::ServerId,
+    rpc::{
+        connect::{ConnectApi, Connector, SNAPSHOT_CHUNK_SIZE},
+        AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest, FetchLeaderResponse,
+        InstallSnapshotRequest, InstallSnapshotResponse, ProposeConfChangeRequest,
+        ProposeConfChangeResponse, ProposeRequest, ProposeResponse, ReadIndexRequest,
+        ReadIndexResponse, TimeoutNowRequest, TimeoutNowResponse, VoteRequest, VoteResponse,
+        WaitSyncedRequest, WaitSyncedResponse,
+    },
+    server::curp_node::{CurpError, CurpNode},
+    snapshot::Snapshot,
+};
+
+/// Faults of the simulated network, every message draws its fate from the generator of its link
+#[derive(Debug, Clone, Copy)]
+pub(super) struct NetConfig {
+    /// Min delay of a message
+    pub(super) min_delay: Duration,
+    /// Max delay of a message, messages on a link are reordered when their delays differ
+    pub(super) max_delay: Duration,
+    /// Probability that a message is held back for another `max_delay`, so that the messages
+    /// sent after it overtake it
+    pub(super) reorder_rate: f64,
+    /// Probability that a message is lost
+    pub(super) loss_rate: f64,
+    /// Probability that a message is delivered twice
+    pub(super) dup_rate: f64,
+}
+
+impl NetConfig {
+    /// A network that only delays messages
+    pub(super) fn reliable(min_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            min_delay,
+            max_delay,
+            reorder_rate: 0.0,
+            loss_rate: 0.0,
+            dup_rate: 0.0,
+        }
+    }
+}
+
+/// A directed link between two endpoints
+struct Link {
+    /// The generator of the link, it's derived from the seed and the ends of the link, so the
+    /// fate of a message doesn't depend on the messages sent through other links
+    rng: StdRng,
+    /// Sequence number of the next message
+    seq: u64,
+}
+
+impl Link {
+    /// Create a new link
+    fn new(seed: u64, from: &str, to: &str) -> Self {
+        // `DefaultHasher::new` always uses the same keys, so the hash is stable
+        let mut hasher = DefaultHasher::new();
+        (seed, from, to).hash(&mut hasher);
+        Self {
+            rng: StdRng::seed_from_u64(hasher.finish()),
+            seq: 0,
+        }
+    }
+
+    /// Delays of the arrivals of the next message, a lost message never arrives
+    fn arrivals(&mut self, config: &NetConfig) -> Vec<Duration> {
+        if self.rng.gen_bool(config.loss_rate) {
+            return vec![];
+        }
+        let copies = if self.rng.gen_bool(config.dup_rate) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let delay = self.rng.gen_range(config.min_delay..=config.max_delay);
+                if self.rng.gen_bool(config.reorder_rate) {
+                    delay + config.max_delay
+                } else {
+                    delay
+                }
+            })
+            .collect()
+    }
+
+    /// Get the sequence number of the next message
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+}
+
+/// A message in flight, it's delivered to the receiver when it arrives, the receiver is `None`
+/// if it's not an endpoint, e.g. a client
+type Delivery<T> = Box<dyn FnOnce(Option<Arc<T>>) + Send>;
+
+/// A message in the network
+struct Message<T> {
+    /// Kind of the message
+    kind: &'static str,
+    /// Delivers the message, a lost message is traced when it's sent and is never delivered
+    deliver: Option<Delivery<T>>,
+}
+
+/// Messages are ordered by their arrival time, the ties are broken by their links and sequence
+/// numbers, so the order doesn't depend on how the tasks are scheduled
+type ArrivalKey = (Instant, ServerId, ServerId, u64);
+
+/// Mutable state of the network
+struct NetState<T> {
+    /// Faults of the network
+    config: NetConfig,
+    /// Endpoints that handle requests
+    endpoints: BTreeMap<ServerId, Arc<T>>,
+    /// Endpoints that have crashed, messages to them are dropped
+    crashed: BTreeSet<ServerId>,
+    /// Links that have been used
+    links: HashMap<(ServerId, ServerId), Link>,
+    /// Links that are cut, messages through them are dropped on arrival
+    cut: BTreeSet<(ServerId, ServerId)>,
+    /// Messages in flight
+    in_flight: BTreeMap<ArrivalKey, Message<T>>,
+    /// What happened to the messages, it's printed to tell how a failure is reached
+    trace: Vec<String>,
+}
+
+/// A network which delivers messages between in-process endpoints in a deterministic order.
+/// All randomness is drawn from `seed`, so a run on the paused tokio clock can be reproduced
+/// from its seed.
+pub(super) struct SimNetwork<T> {
+    /// The seed of the network
+    seed: u64,
+    /// When the network starts, times in the trace are relative to it
+    start: Instant,
+    /// The state of the network
+    state: Mutex<NetState<T>>,
+    /// Notified when a message is sent
+    sent: Notify,
+}
+
+impl<T> Debug for SimNetwork<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimNetwork")
+            .field("seed", &self.seed)
+            .field("config", &self.state.lock().config)
+            .finish()
+    }
+}
+
+impl<T: Send + Sync + 'static> SimNetwork<T> {
+    /// Create a new network and start delivering messages
+    pub(super) fn new(seed: u64, config: NetConfig) -> Arc<Self> {
+        let net = Arc::new(Self {
+            seed,
+            start: Instant::now(),
+            state: Mutex::new(NetState {
+                config,
+                endpoints: BTreeMap::new(),
+                crashed: BTreeSet::new(),
+                links: HashMap::new(),
+                cut: BTreeSet::new(),
+                in_flight: BTreeMap::new(),
+                trace: vec![],
+            }),
+            sent: Notify::new(),
+        });
+        let _handle = tokio::spawn(Self::deliver_task(Arc::clone(&net)));
+        net
+    }
+
+    /// Make an endpoint reachable
+    pub(super) fn register(&self, id: ServerId, endpoint: Arc<T>) {
+        let _ig = self.state.lock().endpoints.insert(id, endpoint);
+    }
+
+    /// Crash an endpoint, the messages to it are dropped
+    pub(super) fn crash(&self, id: &ServerId) -> Option<Arc<T>> {
+        let mut state = self.state.lock();
+        let _ig = state.crashed.insert(id.clone());
+        state.endpoints.remove(id)
+    }
+
+    /// Remove all endpoints
+    pub(super) fn clear(&self) {
+        let mut state = self.state.lock();
+        state.endpoints.clear();
+        state.in_flight.clear();
+    }
+
+    /// Cut the link from `from` to `to`
+    pub(super) fn cut(&self, from: &ServerId, to: &ServerId) {
+        let _ig = self.state.lock().cut.insert((from.clone(), to.clone()));
+    }
+
+    /// Cut all links between `group` and the other endpoints
+    pub(super) fn partition(&self, group: &[ServerId]) {
+        let mut state = self.state.lock();
+        let others = state
+            .endpoints
+            .keys()
+            .filter(|id| !group.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for a in group {
+            for b in &others {
+                let _ig = state.cut.insert((a.clone(), b.clone()));
+                let _ig = state.cut.insert((b.clone(), a.clone()));
+            }
+        }
+    }
+
+    /// Restore all links
+    pub(super) fn heal(&self) {
+        self.state.lock().cut.clear();
+    }
+
+    /// Get the trace of the network
+    pub(super) fn trace(&self) -> Vec<String> {
+        self.state.lock().trace.clone()
+    }
+
+    /// Send a message of `kind` through the link from `from` to `to`, `deliver` is called on
+    /// every arrival of the message
+    fn send(
+        &self,
+        kind: &'static str,
+        from: &ServerId,
+        to: &ServerId,
+        deliver: impl Fn(Option<Arc<T>>) + Send + Sync + 'static,
+    ) {
+        let deliver = Arc::new(deliver);
+        let now = Instant::now();
+        let mut state_l = self.state.lock();
+        let state = &mut *state_l;
+        let link = state
+            .links
+            .entry((from.clone(), to.clone()))
+            .or_insert_with(|| Link::new(self.seed, from, to));
+        let arrivals = link.arrivals(&state.config);
+        if arrivals.is_empty() {
+            // the loss is traced in order with the other messages
+            let key = (now, from.clone(), to.clone(), link.next_seq());
+            let _ig = state.in_flight.insert(
+                key,
+                Message {
+                    kind,
+                    deliver: None,
+                },
+            );
+        }
+        for delay in arrivals {
+            let key = (now + delay, from.clone(), to.clone(), link.next_seq());
+            let deliver = Arc::clone(&deliver);
+            let delivery: Delivery<T> = Box::new(move |endpoint| deliver(endpoint));
+            let _ig = state.in_flight.insert(
+                key,
+                Message {
+                    kind,
+                    deliver: Some(delivery),
+                },
+            );
+        }
+        self.sent.notify_one();
+    }
+
+    /// Deliver the messages when they arrive
+    async fn deliver_task(net: Arc<Self>) {
+        loop {
+            let next = net.state.lock().in_flight.keys().next().map(|key| key.0);
+            match next {
+                Some(at) if at <= Instant::now() => net.deliver_next(),
+                Some(at) => {
+                    tokio::select! {
+                        biased;
+                        () = tokio::time::sleep_until(at) => {}
+                        () = net.sent.notified() => {}
+                    }
+                }
+                None => net.sent.notified().await,
+            }
+        }
+    }
+
+    /// Deliver the first message in flight
+    fn deliver_next(&self) {
+        let mut state = self.state.lock();
+        let Some(((at, from, to, _seq), msg)) = state.in_flight.pop_first() else {
+            return;
+        };
+        let receiver = state.endpoints.get(&to).cloned();
+        let outcome = if msg.deliver.is_none() {
+            " lost"
+        } else if state.crashed.contains(&to) {
+            " unreachable"
+        } else if state.cut.contains(&(from.clone(), to.clone())) {
+            " dropped"
+        } else {
+            ""
+        };
+        let elapsed = at - self.start;
+        state
+            .trace
+            .push(format!("{elapsed:?} {from}->{to} {}{outcome}", msg.kind));
+        drop(state);
+        if let (Some(deliver), "") = (msg.deliver, outcome) {
+            deliver(receiver);
+        }
+    }
+
+    /// Send a request to `to` and wait for its response, `handle` is run by the receiver on every
+    /// arrival of the request and the first response that arrives is returned. An error is
+    /// returned if no response arrives in `timeout`.
+    pub(super) async fn call<Req, Resp, Fut>(
+        self: &Arc<Self>,
+        kind: &'static str,
+        from: &ServerId,
+        to: &ServerId,
+        req: Req,
+        timeout: Duration,
+        handle: impl Fn(Arc<T>, Req) -> Fut + Send + Sync + 'static,
+    ) -> Result<Resp, ProposeError>
+    where
+        Req: Clone + Send + Sync + 'static,
+        Resp: Send + 'static,
+        Fut: Future<Output = Result<Resp, ProposeError>> + Send + 'static,
+    {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let net = Arc::clone(self);
+        let (from_c, to_c) = (from.clone(), to.clone());
+        self.send(kind, from, to, move |endpoint| {
+            let Some(endpoint) = endpoint else {
+                return;
+            };
+            let resp_fut = handle(endpoint, req.clone());
+            let (net, from, to, tx) = (
+                Arc::clone(&net),
+                from_c.clone(),
+                to_c.clone(),
+                Arc::clone(&tx),
+            );
+            let _handle = tokio::spawn(async move {
+                let resp = Mutex::new(Some(resp_fut.await));
+                net.send(kind, &to, &from, move |_endpoint| {
+                    if let (Some(tx), Some(resp)) = (tx.lock().take(), resp.lock().take()) {
+                        let _ig = tx.send(resp);
+                    }
+                });
+            });
+        });
+        drop(tx);
+        match tokio::time::timeout_at(deadline, rx).await {
+            Ok(Ok(resp)) => resp,
+            Ok(Err(_lost)) => {
+                // the caller can't tell a lost message from a slow one
+                tokio::time::sleep_until(deadline).await;
+                Err(ProposeError::RpcError(format!("{kind} to {to} timeout")))
+            }
+            Err(_elapsed) => Err(ProposeError::RpcError(format!("{kind} to {to} timeout"))),
+        }
+    }
+}
+
+/// A `ConnectApi` to a `CurpNode` through the simulated network
+pub(super) struct SimConnect<C: Command> {
+    /// Id of the server that sends requests
+    from: ServerId,
+    /// Id of the server that handles requests
+    id: ServerId,
+    /// The network
+    net: Arc<SimNetwork<CurpNode<C>>>,
+}
+
+impl<C: 'static + Command> SimConnect<C> {
+    /// Create a new `SimConnect`
+    pub(super) fn new(from: ServerId, id: ServerId, net: Arc<SimNetwork<CurpNode<C>>>) -> Self {
+        Self { from, id, net }
+    }
+
+    /// Send a request to the server, the response is returned as if it came through tonic
+    async fn call<Req, Resp, Fut>(
+        &self,
+        kind: &'static str,
+        req: Req,
+        timeout: Duration,
+        handle: impl Fn(Arc<CurpNode<C>>, Req) -> Fut + Send + Sync + 'static,
+    ) -> Result<tonic::Response<Resp>, ProposeError>
+    where
+        Req: Clone + Send + Sync + 'static,
+        Resp: Send + 'static,
+        Fut: Future<Output = Result<Resp, CurpError>> + Send + 'static,
+    {
+        self.net
+            .call(
+                kind,
+                &self.from,
+                &self.id,
+                req,
+                timeout,
+                move |node, req| {
+                    let resp_fut = handle(node, req);
+                    async move {
+                        resp_fut
+                            .await
+                            .map_err(|e| ProposeError::from(tonic::Status::from(e)))
+                    }
+                },
+            )
+            .await
+            .map(tonic::Response::new)
+    }
+}
+
+#[async_trait]
+impl<C: 'static + Command> ConnectApi for SimConnect<C> {
+    fn id(&self) -> &ServerId {
+        &self.id
+    }
+
+    async fn propose(
+        &self,
+        request: ProposeRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<ProposeResponse>, ProposeError> {
+        self.call("propose", request, timeout, |node, req| async move {
+            node.propose(req).await
+        })
+        .await
+    }
+
+    async fn wait_synced(
+        &self,
+        request: WaitSyncedRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<WaitSyncedResponse>, ProposeError> {
+        self.call("wait_synced", request, timeout, |node, req| async move {
+            node.wait_synced(req).await
+        })
+        .await
+    }
+
+    async fn append_entries(
+        &self,
+        request: AppendEntriesRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<AppendEntriesResponse>, ProposeError> {
+        self.call("append_entries", request, timeout, |node, req| async move {
+            node.append_entries(req).await
+        })
+        .await
+    }
+
+    async fn vote(
+        &self,
+        request: VoteRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<VoteResponse>, ProposeError> {
+        self.call("vote", request, timeout, |node, req| async move {
+            node.vote(req).await
+        })
+        .await
+    }
+
+    async fn fetch_leader(
+        &self,
+        request: FetchLeaderRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<FetchLeaderResponse>, ProposeError> {
+        self.call("fetch_leader", request, timeout, |node, req| {
+            let resp = node.fetch_leader(req);
+            async move { resp }
+        })
+        .await
+    }
+
+    async fn install_snapshot(
+        &self,
+        term: u64,
+        leader_id: ServerId,
+        snapshot: Arc<Snapshot>,
+        timeout: Duration,
+    ) -> Result<tonic::Response<InstallSnapshotResponse>, ProposeError> {
+        let chunks =
+            InstallSnapshotRequest::new_chunks(term, &leader_id, &snapshot, SNAPSHOT_CHUNK_SIZE);
+        let total_timeout = timeout.saturating_mul(u32::try_from(chunks.len()).unwrap_or(u32::MAX));
+        self.call(
+            "install_snapshot",
+            chunks,
+            total_timeout,
+            |node, chunks| async move {
+                let stream = futures::stream::iter(chunks.into_iter().map(Ok));
+                node.install_snapshot(stream).await
+            },
+        )
+        .await
+    }
+
+    async fn propose_conf_change(
+        &self,
+        request: ProposeConfChangeRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<ProposeConfChangeResponse>, ProposeError> {
+        self.call(
+            "propose_conf_change",
+            request,
+            timeout,
+            |node, req| async move { node.propose_conf_change(req).await },
+        )
+        .await
+    }
+
+    async fn timeout_now(
+        &self,
+        request: TimeoutNowRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<TimeoutNowResponse>, ProposeError> {
+        self.call("timeout_now", request, timeout, |node, req| {
+            let resp = node.timeout_now(req);
+            async move { resp }
+        })
+        .await
+    }
+
+    async fn read_index(
+        &self,
+        request: ReadIndexRequest,
+        timeout: Duration,
+    ) -> Result<tonic::Response<ReadIndexResponse>, ProposeError> {
+        self.call("read_index", request, timeout, |node, req| async move {
+            node.read_index(req).await
+        })
+        .await
+    }
+}
+
+/// Connects a server to the others through the simulated network, the addresses are ignored
+#[derive(Debug)]
+pub(super) struct SimConnector<C: Command> {
+    /// Id of the server that connects to the others
+    id: ServerId,
+    /// The network
+    net: Arc<SimNetwork<CurpNode<C>>>,
+}
+
+impl<C: Command> SimConnector<C> {
+    /// Create a new `SimConnector`
+    pub(super) fn new(id: ServerId, net: Arc<SimNetwork<CurpNode<C>>>) -> Self {
+        Self { id, net }
+    }
+}
+
+#[async_trait]
+impl<C: 'static + Command> Connector for SimConnector<C> {
+    async fn connect(
+        &self,
+        addrs: HashMap<ServerId, String>,
+    ) -> HashMap<ServerId, Arc<dyn ConnectApi>> {
+        addrs
+            .into_keys()
+            .map(|id| {
+                let connect: Arc<dyn ConnectApi> = Arc::new(SimConnect::new(
+                    self.id.clone(),
+                    id.clone(),
+                    Arc::clone(&self.net),
+                ));
+                (id, connect)
+            })
+            .collect()
+    }
+}
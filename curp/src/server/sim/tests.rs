@@ -0,0 +1,148 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::future::join_all;
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use super::{
+    network::{NetConfig, SimNetwork},
+    SimCluster,
+};
+use crate::{cmd::Command, rpc::SyncResult, test_utils::test_cmd::TestCommand};
+
+/// Seeds of the simulations, set `CURP_SIM_SEED` to reproduce a failed one
+fn seeds(n: u64) -> Vec<u64> {
+    match std::env::var("CURP_SIM_SEED") {
+        Ok(seed) => vec![seed.parse().expect("CURP_SIM_SEED should be a u64")],
+        Err(_) => (0..n).collect(),
+    }
+}
+
+/// A network that delays, reorders, loses and duplicates messages
+fn faulty_net() -> NetConfig {
+    NetConfig {
+        min_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(20),
+        reorder_rate: 0.1,
+        loss_rate: 0.05,
+        dup_rate: 0.05,
+    }
+}
+
+/// A network that only delays messages
+fn reliable_net() -> NetConfig {
+    NetConfig::reliable(Duration::from_millis(1), Duration::from_millis(5))
+}
+
+/// Ping between all endpoints through a faulty network, return the trace of the network
+async fn ping_all(seed: u64, reverse: bool) -> Vec<String> {
+    let net = SimNetwork::new(seed, faulty_net());
+    let ids = ["A", "B", "C"].map(str::to_owned);
+    for id in &ids {
+        net.register(id.clone(), Arc::new(id.clone()));
+    }
+    let mut links = ids.iter().cartesian_product(ids.iter()).collect_vec();
+    if reverse {
+        links.reverse();
+    }
+    let pings = links.into_iter().flat_map(|(from, to)| {
+        let net = Arc::clone(&net);
+        (0..10).map(move |i| {
+            let net = Arc::clone(&net);
+            async move {
+                let timeout = Duration::from_millis(30);
+                net.call("ping", from, to, i, timeout, |_to, i| async move { Ok(i) })
+                    .await
+            }
+        })
+    });
+    let _ig = join_all(pings).await;
+    net.trace()
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_seed_will_schedule_same_messages() {
+    let trace = ping_all(1, false).await;
+    // the messages sent through different links don't affect each other
+    assert_eq!(trace, ping_all(1, true).await);
+    assert_ne!(trace, ping_all(2, false).await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn elections_are_safe_under_faults() {
+    for seed in seeds(8) {
+        let cluster = SimCluster::new(seed, 5, faulty_net()).await;
+        let mut rng = StdRng::seed_from_u64(seed);
+        for _ in 0..10 {
+            // isolate a random minority for a while
+            let ids = cluster.ids();
+            let n = rng.gen_range(0..=2);
+            let group = ids.choose_multiple(&mut rng, n).cloned().collect_vec();
+            cluster.partition(&group);
+            let duration = Duration::from_millis(rng.gen_range(100..3000));
+            cluster.run_for(duration).await;
+            cluster.heal();
+            cluster.check_election_safety();
+        }
+        let _ig = cluster.wait_for_leader(0, Duration::from_secs(10)).await;
+        cluster.check_election_safety();
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn new_leader_will_recover_cmds_from_spec_pools() {
+    for seed in seeds(4) {
+        let mut cluster = SimCluster::new(seed, 5, reliable_net()).await;
+        let (leader, term) = cluster.wait_for_leader(0, Duration::from_secs(10)).await;
+
+        // the cmd can't be replicated by the leader, it's only kept in the spec pools
+        for id in cluster.ids() {
+            if id != leader {
+                cluster.cut(&leader, &id);
+            }
+        }
+        let cmd = TestCommand::new_put(vec![1], 1);
+        let resps = cluster.propose(&cmd, Duration::from_secs(1)).await;
+        assert!(resps.values().all(Result::is_ok), "seed {seed}: {resps:?}");
+
+        cluster.crash(&leader);
+        let (new_leader, _) = cluster
+            .wait_for_leader(term + 1, Duration::from_secs(10))
+            .await;
+        let synced = cluster
+            .wait_synced(&new_leader, cmd.id(), Duration::from_secs(5))
+            .await;
+        if !matches!(synced, Ok(SyncResult::Success { .. })) {
+            cluster.fail(&format!("cmd {} is not recovered", cmd.id()));
+        }
+        cluster.check_election_safety();
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn lagging_follower_will_be_calibrated() {
+    for seed in seeds(4) {
+        let cluster = SimCluster::new(seed, 3, reliable_net()).await;
+        let (leader, _) = cluster.wait_for_leader(0, Duration::from_secs(10)).await;
+        let ids = cluster.ids();
+        let follower = ids.iter().find(|&id| *id != leader).unwrap();
+
+        cluster.partition(&[follower.clone()]);
+        for i in 0..10 {
+            let cmd = TestCommand::new_put(vec![i], i);
+            let _ig = cluster.propose(&cmd, Duration::from_secs(1)).await;
+            let synced = cluster
+                .wait_synced(&leader, cmd.id(), Duration::from_secs(5))
+                .await;
+            assert!(matches!(synced, Ok(SyncResult::Success { .. })));
+        }
+        cluster.heal();
+        cluster.run_for(Duration::from_secs(5)).await;
+
+        let commit_indexes = ids.iter().map(|id| cluster.commit_index(id)).collect_vec();
+        if !commit_indexes.iter().all_equal() {
+            cluster.fail(&format!("commit indexes {commit_indexes:?} are not equal"));
+        }
+        cluster.check_election_safety();
+    }
+}
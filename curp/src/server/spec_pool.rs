@@ -3,6 +3,7 @@ use std::{collections::HashMap, sync::Arc};
 use parking_lot::Mutex;
 use tracing::{debug, warn};
 
+use super::conflict_index::ConflictIndex;
 use crate::cmd::{Command, ProposeId};
 
 /// A reference to the speculative pool
@@ -10,9 +11,11 @@ pub(super) type SpecPoolRef<C> = Arc<Mutex<SpeculativePool<C>>>;
 
 /// The speculative pool that stores commands that might be executed speculatively
 #[derive(Debug)]
-pub(super) struct SpeculativePool<C> {
+pub(super) struct SpeculativePool<C: Command> {
     /// Store
     pub(super) pool: HashMap<ProposeId, Arc<C>>,
+    /// Index of the cmds in the pool for conflict detection
+    index: ConflictIndex<C, ProposeId>,
 }
 
 impl<C: Command + 'static> SpeculativePool<C> {
@@ -20,6 +23,7 @@ impl<C: Command + 'static> SpeculativePool<C> {
     pub(super) fn new() -> Self {
         Self {
             pool: HashMap::new(),
+            index: ConflictIndex::new(),
         }
     }
 
@@ -29,6 +33,8 @@ impl<C: Command + 'static> SpeculativePool<C> {
             Some(cmd)
         } else {
             let id = cmd.id().clone();
+            self.index.remove(&id);
+            self.index.insert(id.clone(), Some(cmd.as_ref()));
             let result = self.pool.insert(id.clone(), cmd);
             if result.is_none() {
                 debug!("insert cmd({id}) into spec pool");
@@ -40,12 +46,19 @@ impl<C: Command + 'static> SpeculativePool<C> {
     }
 
     /// Check whether the command pool has conflict with the new command
-    fn has_conflict_with(&self, cmd: &C) -> bool {
-        self.pool.values().any(|spec_cmd| spec_cmd.is_conflict(cmd))
+    pub(super) fn has_conflict_with(&self, cmd: &C) -> bool {
+        match self.index.candidates(cmd) {
+            Some(ids) => ids
+                .iter()
+                .filter_map(|id| self.pool.get(id))
+                .any(|spec_cmd| spec_cmd.is_conflict(cmd)),
+            None => self.pool.values().any(|spec_cmd| spec_cmd.is_conflict(cmd)),
+        }
     }
 
     /// Remove the command from spec pool
     pub(super) fn remove(&mut self, cmd_id: &ProposeId) {
+        self.index.remove(cmd_id);
         if self.pool.remove(cmd_id).is_some() {
             debug!("cmd({cmd_id}) is removed from spec pool");
         } else {
@@ -54,4 +67,22 @@ impl<C: Command + 'static> SpeculativePool<C> {
             debug!("cmd({cmd_id}) is not in spec pool");
         };
     }
+
+    /// Remove all the cmds
+    pub(super) fn clear(&mut self) {
+        self.pool.clear();
+        self.index = ConflictIndex::new();
+    }
+
+    /// Remove the cmds that don't satisfy the predicate
+    pub(super) fn retain(&mut self, mut f: impl FnMut(&ProposeId) -> bool) {
+        let index = &mut self.index;
+        self.pool.retain(|id, _cmd| {
+            let keep = f(id);
+            if !keep {
+                index.remove(id);
+            }
+            keep
+        });
+    }
 }
@@ -1,8 +1,16 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use engine::error::EngineError;
 use thiserror::Error;
 
-use crate::{cmd::Command, log_entry::LogEntry, message::ServerId};
+use crate::{
+    cmd::{Command, ProposeId},
+    log_entry::LogEntry,
+    members::Member,
+    message::ServerId,
+    snapshot::Snapshot,
+};
 
 /// Storage layer error
 #[derive(Error, Debug)]
@@ -24,14 +32,60 @@ pub(super) trait StorageApi: Send + Sync {
     /// Put `voted_for` in storage, must be flushed on disk before returning
     async fn flush_voted_for(&self, term: u64, voted_for: ServerId) -> Result<(), StorageError>;
 
-    /// Put log entries in storage
-    async fn put_log_entry(&self, entry: LogEntry<Self::Command>) -> Result<(), StorageError>;
+    /// Put log entries in storage in one batch, must be flushed on disk before returning
+    async fn put_log_entries(
+        &self,
+        entries: &[LogEntry<Self::Command>],
+    ) -> Result<(), StorageError>;
+
+    /// Put the snapshot in storage and remove all log entries included in it, must be flushed on disk before returning
+    async fn put_snapshot(&self, snapshot: &Snapshot) -> Result<(), StorageError>;
+
+    /// Put the snapshot received from the leader in storage and remove all log entries up to `discard_to`,
+    /// which include the entries in the snapshot and those conflicting with it, must be flushed on disk before returning
+    async fn install_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        discard_to: usize,
+    ) -> Result<(), StorageError>;
+
+    /// Put whether self is a learner and other members of the cluster in storage, along with the index of the
+    /// log entry that they are updated to, must be flushed on disk before returning
+    async fn put_members(
+        &self,
+        is_learner: bool,
+        others: &HashMap<ServerId, Member>,
+        index: usize,
+    ) -> Result<(), StorageError>;
+
+    /// Recover whether self is a learner, other members of the cluster and the index of the log entry that they
+    /// are updated to, `None` if the membership has never been changed
+    async fn recover_members(
+        &self,
+    ) -> Result<Option<(bool, HashMap<ServerId, Member>, usize)>, StorageError>;
+
+    /// Put a cmd in the speculative pool of a witness, must be flushed on disk before returning
+    async fn put_spec_cmd(&self, cmd: &Self::Command) -> Result<(), StorageError>;
+
+    /// Remove cmds from the speculative pool of a witness
+    async fn remove_spec_cmds(&self, ids: &[ProposeId]) -> Result<(), StorageError>;
+
+    /// Recover the speculative pool of a witness
+    async fn recover_spec_pool(&self) -> Result<Vec<Self::Command>, StorageError>;
 
     /// Recover from persisted storage
-    /// Return `voted_for` and all log entries
+    /// Return `voted_for`, the latest snapshot and all log entries after the snapshot
+    #[allow(clippy::type_complexity)] // it's clear
     async fn recover(
         &self,
-    ) -> Result<(Option<(u64, ServerId)>, Vec<LogEntry<Self::Command>>), StorageError>;
+    ) -> Result<
+        (
+            Option<(u64, ServerId)>,
+            Option<Snapshot>,
+            Vec<LogEntry<Self::Command>>,
+        ),
+        StorageError,
+    >;
 }
 
 /// `RocksDB` storage implementation
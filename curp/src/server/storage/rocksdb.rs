@@ -1,17 +1,33 @@
-use std::{marker::PhantomData, path::Path};
+use std::{collections::HashMap, marker::PhantomData, mem::size_of, path::Path};
 
 use async_trait::async_trait;
+use clippy_utilities::NumericCast;
 use engine::{rocksdb_engine::RocksEngine, StorageEngine, WriteOperation};
 
 use super::{StorageApi, StorageError};
-use crate::{cmd::Command, log_entry::LogEntry, message::ServerId};
+use crate::{
+    cmd::{Command, ProposeId},
+    log_entry::LogEntry,
+    members::Member,
+    message::ServerId,
+    snapshot::Snapshot,
+};
 
 /// Key for persisted state
 const VOTE_FOR: &[u8] = b"VoteFor";
 
+/// Key for the latest snapshot
+const SNAPSHOT: &[u8] = b"LatestSnapshot";
+
+/// Key for other members of the cluster
+const MEMBERS: &[u8] = b"Members";
+
 /// Column family name for curp storage
 const CF: &str = "curp";
 
+/// Column family name for the speculative pool of a witness, cmds are keyed by their propose ids
+const SPEC_POOL_CF: &str = "spec_pool";
+
 /// `RocksDB` storage implementation
 pub(in crate::server) struct RocksDBStorage<C> {
     /// DB handle
@@ -33,31 +49,140 @@ impl<C: 'static + Command> StorageApi for RocksDBStorage<C> {
         Ok(())
     }
 
-    async fn put_log_entry(&self, entry: LogEntry<Self::Command>) -> Result<(), StorageError> {
-        let bytes = bincode::serialize(&entry)?;
-        let op = WriteOperation::new_put(CF, entry.index.to_be_bytes().to_vec(), bytes);
-        self.db.write_batch(vec![op], false)?;
+    async fn put_log_entries(
+        &self,
+        entries: &[LogEntry<Self::Command>],
+    ) -> Result<(), StorageError> {
+        let ops = entries
+            .iter()
+            .map(|entry| {
+                let bytes = bincode::serialize(entry)?;
+                Ok(WriteOperation::new_put(
+                    CF,
+                    entry.index.to_be_bytes().to_vec(),
+                    bytes,
+                ))
+            })
+            .collect::<Result<_, StorageError>>()?;
+        self.db.write_batch(ops, true)?;
+
+        Ok(())
+    }
+
+    async fn put_snapshot(&self, snapshot: &Snapshot) -> Result<(), StorageError> {
+        let last_included_index = snapshot.meta().last_included_index.numeric_cast();
+        self.install_snapshot(snapshot, last_included_index).await
+    }
+
+    #[allow(clippy::integer_arithmetic)] // won't overflow
+    async fn install_snapshot(
+        &self,
+        snapshot: &Snapshot,
+        discard_to: usize,
+    ) -> Result<(), StorageError> {
+        let bytes = bincode::serialize(snapshot)?;
+        let ops = vec![
+            WriteOperation::new_delete_range(
+                CF,
+                0_usize.to_be_bytes().to_vec(),
+                (discard_to + 1).to_be_bytes().to_vec(),
+            ),
+            WriteOperation::new_put(CF, SNAPSHOT.to_vec(), bytes),
+        ];
+        self.db.write_batch(ops, true)?;
+
+        Ok(())
+    }
+
+    async fn put_members(
+        &self,
+        is_learner: bool,
+        others: &HashMap<ServerId, Member>,
+        index: usize,
+    ) -> Result<(), StorageError> {
+        let bytes = bincode::serialize(&(is_learner, others, index))?;
+        let op = WriteOperation::new_put(CF, MEMBERS.to_vec(), bytes);
+        self.db.write_batch(vec![op], true)?;
+
+        Ok(())
+    }
+
+    async fn recover_members(
+        &self,
+    ) -> Result<Option<(bool, HashMap<ServerId, Member>, usize)>, StorageError> {
+        self.db
+            .get(CF, MEMBERS)?
+            .map(|bytes| bincode::deserialize(&bytes))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    async fn put_spec_cmd(&self, cmd: &Self::Command) -> Result<(), StorageError> {
+        let key = bincode::serialize(cmd.id())?;
+        let bytes = bincode::serialize(cmd)?;
+        let op = WriteOperation::new_put(SPEC_POOL_CF, key, bytes);
+        self.db.write_batch(vec![op], true)?;
 
         Ok(())
     }
 
+    async fn remove_spec_cmds(&self, ids: &[ProposeId]) -> Result<(), StorageError> {
+        let ops = ids
+            .iter()
+            .map(|id| {
+                let key = bincode::serialize(id)?;
+                Ok(WriteOperation::new_delete(SPEC_POOL_CF, key))
+            })
+            .collect::<Result<_, StorageError>>()?;
+        // a removed cmd that comes back after a crash is harmless, it will be removed by gc
+        self.db.write_batch(ops, false)?;
+
+        Ok(())
+    }
+
+    async fn recover_spec_pool(&self) -> Result<Vec<Self::Command>, StorageError> {
+        self.db
+            .get_all(SPEC_POOL_CF)?
+            .into_iter()
+            .map(|(_, bytes)| bincode::deserialize(&bytes).map_err(Into::into))
+            .collect()
+    }
+
     async fn recover(
         &self,
-    ) -> Result<(Option<(u64, ServerId)>, Vec<LogEntry<Self::Command>>), StorageError> {
+    ) -> Result<
+        (
+            Option<(u64, ServerId)>,
+            Option<Snapshot>,
+            Vec<LogEntry<Self::Command>>,
+        ),
+        StorageError,
+    > {
         let voted_for = self
             .db
             .get(CF, VOTE_FOR)?
             .map(|bytes| bincode::deserialize::<(u64, ServerId)>(&bytes))
             .transpose()?;
+        let snapshot = self
+            .db
+            .get(CF, SNAPSHOT)?
+            .map(|bytes| bincode::deserialize::<Snapshot>(&bytes))
+            .transpose()?;
 
         let mut entries = vec![];
-        let mut prev_index = 0;
+        let mut prev_index = snapshot
+            .as_ref()
+            .map_or(0, |s| s.meta().last_included_index.numeric_cast());
         for (k, v) in self.db.get_all(CF)? {
             // we can identify whether a kv is state or entry by the key length
-            if k.len() == VOTE_FOR.len() {
+            if k.len() != size_of::<usize>() {
                 continue;
             }
             let entry: LogEntry<C> = bincode::deserialize(&v)?;
+            // entries included in the snapshot may be persisted after the snapshot, skip them
+            if entry.index <= prev_index && entries.is_empty() {
+                continue;
+            }
             #[allow(clippy::integer_arithmetic)] // won't overflow
             if entry.index != prev_index + 1 {
                 // break when logs are no longer consistent
@@ -67,14 +192,14 @@ impl<C: 'static + Command> StorageApi for RocksDBStorage<C> {
             entries.push(entry);
         }
 
-        Ok((voted_for, entries))
+        Ok((voted_for, snapshot, entries))
     }
 }
 
 impl<C> RocksDBStorage<C> {
     /// Create a new `RocksDBStorage`
     pub(in crate::server) fn new(dir: impl AsRef<Path>) -> Result<Self, StorageError> {
-        let db = RocksEngine::new(dir, &[CF])?;
+        let db = RocksEngine::new(dir, &[CF, SPEC_POOL_CF])?;
         Ok(Self {
             db,
             phantom: PhantomData,
@@ -89,7 +214,10 @@ mod tests {
     use tokio::fs::remove_dir_all;
 
     use super::*;
-    use crate::test_utils::{random_id, sleep_secs, test_cmd::TestCommand};
+    use crate::{
+        snapshot::SnapshotMeta,
+        test_utils::{random_id, sleep_secs, test_cmd::TestCommand},
+    };
 
     #[tokio::test]
     async fn create_and_recover() -> Result<(), Box<dyn Error>> {
@@ -102,16 +230,15 @@ mod tests {
             let entry0 = LogEntry::new(1, 3, Arc::new(TestCommand::default()));
             let entry1 = LogEntry::new(2, 3, Arc::new(TestCommand::default()));
             let entry2 = LogEntry::new(3, 3, Arc::new(TestCommand::default()));
-            s.put_log_entry(entry0).await?;
-            s.put_log_entry(entry1).await?;
-            s.put_log_entry(entry2).await?;
-            sleep_secs(2).await;
+            s.put_log_entries(&[entry0, entry1]).await?;
+            s.put_log_entries(&[entry2]).await?;
         }
 
         {
             let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
-            let (voted_for, entries) = s.recover().await?;
+            let (voted_for, snapshot, entries) = s.recover().await?;
             assert_eq!(voted_for, Some((3, "S1".to_string())));
+            assert!(snapshot.is_none());
             assert_eq!(entries[0].index, 1);
             assert_eq!(entries[1].index, 2);
             assert_eq!(entries[2].index, 3);
@@ -121,4 +248,140 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn put_and_recover_members() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            assert!(s.recover_members().await?.is_none());
+            let others = HashMap::from([
+                (
+                    "S1".to_owned(),
+                    Member::new("127.0.0.1:2380".to_owned(), false),
+                ),
+                (
+                    "S2".to_owned(),
+                    Member::new("127.0.0.1:2381".to_owned(), true),
+                ),
+            ]);
+            s.put_members(false, &others, 3).await?;
+        }
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let (is_learner, others, index) = s.recover_members().await?.unwrap();
+            assert!(!is_learner);
+            assert_eq!(index, 3);
+            assert_eq!(others.len(), 2);
+            assert_eq!(others["S2"].address(), "127.0.0.1:2381");
+            assert!(others["S2"].is_learner());
+            // members are not mistaken for log entries
+            let (_, _, entries) = s.recover().await?;
+            assert!(entries.is_empty());
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_snapshot_will_remove_included_entries() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let entries: Vec<_> = (1..=5)
+                .map(|i| LogEntry::new(i, 1, Arc::new(TestCommand::default())))
+                .collect();
+            s.put_log_entries(&entries).await?;
+            let snapshot = Snapshot::new(
+                SnapshotMeta {
+                    last_included_index: 3,
+                    last_included_term: 1,
+                },
+                vec![1, 2, 3],
+            );
+            s.put_snapshot(&snapshot).await?;
+            sleep_secs(2).await;
+        }
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let (voted_for, snapshot, entries) = s.recover().await?;
+            assert!(voted_for.is_none());
+            let snapshot = snapshot.unwrap();
+            assert_eq!(snapshot.meta().last_included_index, 3);
+            assert_eq!(snapshot.data(), &[1, 2, 3]);
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].index, 4);
+            assert_eq!(entries[1].index, 5);
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn install_snapshot_will_remove_conflicting_entries() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let entries: Vec<_> = (1..=5)
+                .map(|i| LogEntry::new(i, 1, Arc::new(TestCommand::default())))
+                .collect();
+            s.put_log_entries(&entries).await?;
+            let snapshot = Snapshot::new(
+                SnapshotMeta {
+                    last_included_index: 3,
+                    last_included_term: 2,
+                },
+                vec![1, 2, 3],
+            );
+            s.install_snapshot(&snapshot, 5).await?;
+        }
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let (_, snapshot, entries) = s.recover().await?;
+            assert_eq!(snapshot.unwrap().meta().last_included_term, 2);
+            assert!(entries.is_empty());
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_and_recover_spec_pool() -> Result<(), Box<dyn Error>> {
+        let db_dir = format!("/tmp/curp-{}", random_id());
+        let cmd0 = TestCommand::new_put(vec![1], 1);
+        let cmd1 = TestCommand::new_put(vec![2], 2);
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            s.put_spec_cmd(&cmd0).await?;
+            s.put_spec_cmd(&cmd1).await?;
+            s.remove_spec_cmds(&[cmd0.id().clone()]).await?;
+        }
+
+        {
+            let s = RocksDBStorage::<TestCommand>::new(&db_dir)?;
+            let spec_pool = s.recover_spec_pool().await?;
+            assert_eq!(spec_pool.len(), 1);
+            assert_eq!(spec_pool[0].id(), cmd1.id());
+            // spec cmds are not mistaken for log entries
+            let (_, _, entries) = s.recover().await?;
+            assert!(entries.is_empty());
+        }
+
+        remove_dir_all(db_dir).await?;
+
+        Ok(())
+    }
 }
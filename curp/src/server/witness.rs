@@ -0,0 +1,488 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
+    mem,
+    sync::Arc,
+};
+
+use clippy_utilities::NumericCast;
+use futures::{pin_mut, Stream, StreamExt};
+use itertools::Itertools;
+use parking_lot::Mutex;
+use tracing::{debug, error, info, instrument};
+use utils::{config::CurpConfig, parking_lot_lock::MutexMap, tracing::Extract};
+
+use super::{
+    curp_node::CurpError,
+    gc::GC_INTERVAL,
+    spec_pool::{SpecPoolRef, SpeculativePool},
+    storage::{rocksdb::RocksDBStorage, StorageApi},
+};
+use crate::{
+    cmd::{Command, ProposeId},
+    error::ProposeError,
+    log_entry::EntryData,
+    message::ServerId,
+    rpc::{
+        AppendEntriesRequest, AppendEntriesResponse, FetchLeaderRequest, FetchLeaderResponse,
+        InstallSnapshotRequest, InstallSnapshotResponse, ProposeConfChangeRequest,
+        ProposeConfChangeResponse, ProposeRequest, ProposeResponse, ReadIndexRequest,
+        ReadIndexResponse, TimeoutNowRequest, TimeoutNowResponse, VoteRequest, VoteResponse,
+        WaitSyncedRequest, WaitSyncedResponse,
+    },
+};
+
+/// The term and the leader followed by a witness
+#[derive(Debug, Default)]
+struct WitnessState {
+    /// Current term
+    term: u64,
+    /// Leader of the current term
+    leader_id: Option<ServerId>,
+    /// Cmds replicated by the leader whose log entries are not known to be committed, keyed by
+    /// the indexes of the entries
+    uncommitted: BTreeMap<usize, ProposeId>,
+}
+
+impl WitnessState {
+    /// Follow the leader of `term`, a witness only learns the leader from its requests
+    fn follow(&mut self, term: u64, leader_id: Option<ServerId>) {
+        if term > self.term {
+            self.term = term;
+            self.leader_id = None;
+        }
+        if leader_id.is_some() {
+            self.leader_id = leader_id;
+        }
+    }
+
+    /// Take the cmds whose log entries are committed
+    fn take_committed(&mut self, commit_index: usize) -> Vec<ProposeId> {
+        let uncommitted = self.uncommitted.split_off(&commit_index.saturating_add(1));
+        mem::replace(&mut self.uncommitted, uncommitted)
+            .into_values()
+            .collect()
+    }
+}
+
+/// A witness only keeps a durable speculative pool for the fast path of Curp. It never votes
+/// or executes commands, candidates recover the speculatively executed commands from its spec
+/// pool in elections, and the commands are removed once the leader commits them.
+#[derive(Clone)]
+pub struct Witness<C: Command + 'static> {
+    /// Id of the witness
+    id: ServerId,
+    /// The followed term and leader
+    st: Arc<Mutex<WitnessState>>,
+    /// The speculative pool, every cmd in it is also persisted
+    spec_pool: SpecPoolRef<C>,
+    /// Storage
+    storage: Arc<dyn StorageApi<Command = C>>,
+}
+
+#[tonic::async_trait]
+impl<C: 'static + Command> crate::rpc::Protocol for Witness<C> {
+    #[instrument(skip_all, name = "witness_propose")]
+    async fn propose(
+        &self,
+        request: tonic::Request<ProposeRequest>,
+    ) -> Result<tonic::Response<ProposeResponse>, tonic::Status> {
+        request.metadata().extract_span();
+        Ok(tonic::Response::new(
+            self.handle_propose(request.into_inner()).await?,
+        ))
+    }
+
+    async fn wait_synced(
+        &self,
+        _request: tonic::Request<WaitSyncedRequest>,
+    ) -> Result<tonic::Response<WaitSyncedResponse>, tonic::Status> {
+        Err(tonic::Status::failed_precondition(
+            "a witness never executes commands",
+        ))
+    }
+
+    async fn fetch_leader(
+        &self,
+        _request: tonic::Request<FetchLeaderRequest>,
+    ) -> Result<tonic::Response<FetchLeaderResponse>, tonic::Status> {
+        let (leader_id, term) = self.leader();
+        Ok(tonic::Response::new(FetchLeaderResponse::new(
+            leader_id, term,
+        )))
+    }
+
+    async fn read_index(
+        &self,
+        _request: tonic::Request<ReadIndexRequest>,
+    ) -> Result<tonic::Response<ReadIndexResponse>, tonic::Status> {
+        let (leader_id, term) = self.leader();
+        let resp = ReadIndexResponse::new_error(leader_id, term, &ProposeError::NotLeader)
+            .map_err(CurpError::from)?;
+        Ok(tonic::Response::new(resp))
+    }
+}
+
+#[tonic::async_trait]
+impl<C: 'static + Command> crate::rpc::InnerProtocol for Witness<C> {
+    #[instrument(skip_all, name = "witness_append_entries")]
+    async fn append_entries(
+        &self,
+        request: tonic::Request<AppendEntriesRequest>,
+    ) -> Result<tonic::Response<AppendEntriesResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            self.handle_append_entries(request.into_inner()).await?,
+        ))
+    }
+
+    #[instrument(skip_all, name = "witness_vote")]
+    async fn vote(
+        &self,
+        request: tonic::Request<VoteRequest>,
+    ) -> Result<tonic::Response<VoteResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            self.handle_vote(request.into_inner())?,
+        ))
+    }
+
+    #[instrument(skip_all, name = "witness_install_snapshot")]
+    async fn install_snapshot(
+        &self,
+        request: tonic::Request<tonic::Streaming<InstallSnapshotRequest>>,
+    ) -> Result<tonic::Response<InstallSnapshotResponse>, tonic::Status> {
+        Ok(tonic::Response::new(
+            self.handle_install_snapshot(request.into_inner()).await?,
+        ))
+    }
+
+    async fn propose_conf_change(
+        &self,
+        _request: tonic::Request<ProposeConfChangeRequest>,
+    ) -> Result<tonic::Response<ProposeConfChangeResponse>, tonic::Status> {
+        let (leader_id, term) = self.leader();
+        let resp = ProposeConfChangeResponse::new_error(leader_id, term, &ProposeError::NotLeader)
+            .map_err(CurpError::from)?;
+        Ok(tonic::Response::new(resp))
+    }
+
+    async fn timeout_now(
+        &self,
+        _request: tonic::Request<TimeoutNowRequest>,
+    ) -> Result<tonic::Response<TimeoutNowResponse>, tonic::Status> {
+        // a witness can't be the leader, so the transfer will time out
+        Ok(tonic::Response::new(TimeoutNowResponse::new(
+            self.leader().1,
+        )))
+    }
+}
+
+impl<C: Command + 'static> Witness<C> {
+    /// New `Witness`, its spec pool is recovered from the storage in `curp_cfg.data_dir`
+    ///
+    /// # Panics
+    /// Panic if storage creation or recovery failed
+    #[inline]
+    pub async fn new(id: ServerId, curp_cfg: Arc<CurpConfig>) -> Self {
+        #[allow(clippy::panic)]
+        match Self::recover(id, &curp_cfg).await {
+            Ok(witness) => witness,
+            Err(err) => {
+                panic!("failed to create curp witness, {err}");
+            }
+        }
+    }
+
+    /// Recover the witness from the storage and start its gc task
+    async fn recover(id: ServerId, curp_cfg: &CurpConfig) -> Result<Self, CurpError> {
+        let storage = Arc::new(RocksDBStorage::new(&curp_cfg.data_dir)?);
+        let mut spec_pool = SpeculativePool::new();
+        for cmd in storage.recover_spec_pool().await? {
+            // the persisted cmds didn't conflict with each other when they were proposed
+            let _ig = spec_pool.insert(Arc::new(cmd));
+        }
+        info!(
+            "witness {id} recovered {} cmds in its spec pool",
+            spec_pool.pool.len()
+        );
+        let spec_pool = Arc::new(Mutex::new(spec_pool));
+        let _gc = tokio::spawn(Self::gc_spec_pool(
+            Arc::clone(&spec_pool),
+            Arc::clone(&storage),
+        ));
+
+        Ok(Self {
+            id,
+            st: Arc::new(Mutex::new(WitnessState::default())),
+            spec_pool,
+            storage,
+        })
+    }
+
+    /// Get the followed leader and term
+    fn leader(&self) -> (Option<ServerId>, u64) {
+        self.st.map_lock(|st| (st.leader_id.clone(), st.term))
+    }
+
+    /// Handle `Propose` requests, a proposal is acknowledged after the cmd is persisted
+    async fn handle_propose(&self, req: ProposeRequest) -> Result<ProposeResponse, CurpError> {
+        let cmd: Arc<C> = Arc::new(req.cmd()?);
+        let (leader_id, term) = self.leader();
+        debug!("witness {} gets proposal for cmd({})", self.id, cmd.id());
+
+        let conflict = self
+            .spec_pool
+            .map_lock(|mut sp_l| sp_l.insert(Arc::clone(&cmd)).is_some());
+        if conflict {
+            return Ok(ProposeResponse::new_error(
+                leader_id,
+                term,
+                &ProposeError::KeyConflict,
+            )?);
+        }
+        if let Err(err) = self.storage.put_spec_cmd(cmd.as_ref()).await {
+            self.spec_pool.lock().remove(cmd.id());
+            return Err(err.into());
+        }
+
+        Ok(ProposeResponse::new_empty(leader_id, term)?)
+    }
+
+    /// Handle `AppendEntries` requests, the entries are only used to remove the committed cmds
+    async fn handle_append_entries(
+        &self,
+        req: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse, CurpError> {
+        let entries = req.entries::<C>()?;
+        let (term, committed) = {
+            let mut st = self.st.lock();
+            if req.term < st.term {
+                return Ok(AppendEntriesResponse::new_reject(st.term, 0));
+            }
+            st.follow(req.term, Some(req.leader_id));
+            for entry in entries {
+                match entry.data {
+                    EntryData::Command(ref cmd) => {
+                        let _ig = st.uncommitted.insert(entry.index, cmd.id().clone());
+                    }
+                    // the entry may overwrite a cmd from a previous term
+                    EntryData::ConfChange(_) => {
+                        let _ig = st.uncommitted.remove(&entry.index);
+                    }
+                }
+            }
+            (st.term, st.take_committed(req.leader_commit.numeric_cast()))
+        };
+        self.remove_committed(&committed).await?;
+
+        Ok(AppendEntriesResponse::new_accept(term))
+    }
+
+    /// Handle `Vote` requests, a witness doesn't vote, it sends its spec pool to the candidates
+    /// of new terms so that they can recover the speculatively executed cmds
+    #[allow(clippy::needless_pass_by_value)] // To keep type consistent with other request handlers
+    fn handle_vote(&self, req: VoteRequest) -> Result<VoteResponse, CurpError> {
+        let mut st = self.st.lock();
+        if req.is_pre_vote || req.term < st.term {
+            return Ok(VoteResponse::new_reject(st.term));
+        }
+        st.follow(req.term, None);
+        let spec_pool = self
+            .spec_pool
+            .map_lock(|sp_l| sp_l.pool.values().cloned().collect_vec());
+        debug!(
+            "witness {} sends {} cmds in its spec pool to candidate {}",
+            self.id,
+            spec_pool.len(),
+            req.candidate_id
+        );
+
+        Ok(VoteResponse::new_accept(st.term, spec_pool)?)
+    }
+
+    /// Handle `InstallSnapshot` stream, a witness has no state machine, so the snapshot is
+    /// dropped and only the cmds included in it are removed
+    async fn handle_install_snapshot(
+        &self,
+        req_stream: impl Stream<Item = Result<InstallSnapshotRequest, tonic::Status>>,
+    ) -> Result<InstallSnapshotResponse, CurpError> {
+        pin_mut!(req_stream);
+        while let Some(chunk) = req_stream.next().await {
+            let chunk = chunk
+                .map_err(|e| CurpError::Internal(format!("receive snapshot chunk error, {e}")))?;
+            if !chunk.done {
+                continue;
+            }
+            let committed = {
+                let mut st = self.st.lock();
+                if chunk.term < st.term {
+                    return Ok(InstallSnapshotResponse::new(st.term));
+                }
+                st.follow(chunk.term, Some(chunk.leader_id.clone()));
+                st.take_committed(chunk.last_included_index.numeric_cast())
+            };
+            self.remove_committed(&committed).await?;
+            break;
+        }
+
+        Ok(InstallSnapshotResponse::new(self.leader().1))
+    }
+
+    /// Remove the committed cmds from the spec pool and the storage
+    async fn remove_committed(&self, committed: &[ProposeId]) -> Result<(), CurpError> {
+        if committed.is_empty() {
+            return Ok(());
+        }
+        self.spec_pool.map_lock(|mut sp_l| {
+            for id in committed {
+                sp_l.remove(id);
+            }
+        });
+        self.storage.remove_spec_cmds(committed).await?;
+        Ok(())
+    }
+
+    /// Remove the cmds that stay in the spec pool for more than a gc interval, they may never be
+    /// committed, or the witness may have missed their commits
+    async fn gc_spec_pool(sp: SpecPoolRef<C>, storage: Arc<dyn StorageApi<Command = C>>) {
+        let mut last_check: HashSet<ProposeId> =
+            sp.map_lock(|sp_l| sp_l.pool.keys().cloned().collect());
+        loop {
+            tokio::time::sleep(GC_INTERVAL).await;
+            let mut removed = vec![];
+            sp.map_lock(|mut sp_l| {
+                sp_l.retain(|id| {
+                    let stale = last_check.contains(id);
+                    if stale {
+                        removed.push(id.clone());
+                    }
+                    !stale
+                });
+                last_check = sp_l.pool.keys().cloned().collect();
+            });
+            if let Err(err) = storage.remove_spec_cmds(&removed).await {
+                error!("failed to remove stale cmds from the spec pool, {err}");
+            }
+        }
+    }
+}
+
+impl<C: Command> Debug for Witness<C> {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Witness")
+            .field("id", &self.id)
+            .field("st", &self.st)
+            .field("spec_pool", &self.spec_pool)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs::remove_dir_all;
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::{
+        log_entry::LogEntry,
+        test_utils::{random_id, test_cmd::TestCommand},
+    };
+
+    fn witness_cfg() -> Arc<CurpConfig> {
+        Arc::new(CurpConfig {
+            data_dir: format!("/tmp/curp-witness-{}", random_id()).into(),
+            ..CurpConfig::default()
+        })
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn witness_will_recover_its_spec_pool() {
+        let cfg = witness_cfg();
+        let cmd = TestCommand::new_put(vec![1], 1);
+        {
+            let witness = Witness::<TestCommand>::new("W0".to_owned(), Arc::clone(&cfg)).await;
+            let resp = witness
+                .handle_propose(ProposeRequest::new(&cmd).unwrap())
+                .await
+                .unwrap();
+            assert!(resp.exe_result.is_none());
+            // a conflicting cmd is rejected
+            let resp = witness
+                .handle_propose(ProposeRequest::new(&TestCommand::new_put(vec![1], 2)).unwrap())
+                .await
+                .unwrap();
+            assert!(resp.exe_result.is_some());
+        }
+
+        let witness = Witness::<TestCommand>::new("W0".to_owned(), Arc::clone(&cfg)).await;
+        let resp = witness
+            .handle_vote(VoteRequest::new(1, "S0".to_owned(), 0, 0, false))
+            .unwrap();
+        assert!(resp.vote_granted);
+        let spec_pool = resp.spec_pool::<TestCommand>().unwrap();
+        assert_eq!(spec_pool.len(), 1);
+        assert_eq!(spec_pool[0].id(), cmd.id());
+
+        remove_dir_all(&cfg.data_dir).await.unwrap();
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn witness_will_remove_committed_cmds() {
+        let cfg = witness_cfg();
+        let witness = Witness::<TestCommand>::new("W0".to_owned(), Arc::clone(&cfg)).await;
+        let cmd0 = Arc::new(TestCommand::new_put(vec![1], 1));
+        let cmd1 = Arc::new(TestCommand::new_put(vec![2], 2));
+        for cmd in [&cmd0, &cmd1] {
+            let _ig = witness
+                .handle_propose(ProposeRequest::new(cmd.as_ref()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        // both cmds are replicated, but only cmd0 is committed
+        let entries = vec![
+            LogEntry::new(1, 1, Arc::clone(&cmd0)),
+            LogEntry::new(2, 1, Arc::clone(&cmd1)),
+        ];
+        let req = AppendEntriesRequest::new(1, "S0".to_owned(), 0, 0, entries, 1).unwrap();
+        let resp = witness.handle_append_entries(req).await.unwrap();
+        assert!(resp.success);
+        assert_eq!(witness.leader(), (Some("S0".to_owned()), 1));
+        assert!(!witness.spec_pool.lock().pool.contains_key(cmd0.id()));
+        assert!(witness.spec_pool.lock().pool.contains_key(cmd1.id()));
+
+        let req = AppendEntriesRequest::new_heartbeat(1, "S0".to_owned(), 2, 1, 2);
+        let _ig = witness.handle_append_entries(req).await.unwrap();
+        assert!(witness.spec_pool.lock().pool.is_empty());
+        let persisted = witness.storage.recover_spec_pool().await.unwrap();
+        assert!(persisted.is_empty());
+
+        remove_dir_all(&cfg.data_dir).await.unwrap();
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn witness_will_not_grant_pre_votes_or_stale_votes() {
+        let cfg = witness_cfg();
+        let witness = Witness::<TestCommand>::new("W0".to_owned(), Arc::clone(&cfg)).await;
+
+        let resp = witness
+            .handle_vote(VoteRequest::new(2, "S0".to_owned(), 0, 0, true))
+            .unwrap();
+        assert!(!resp.vote_granted);
+
+        let resp = witness
+            .handle_vote(VoteRequest::new(2, "S0".to_owned(), 0, 0, false))
+            .unwrap();
+        assert!(resp.vote_granted);
+        let resp = witness
+            .handle_vote(VoteRequest::new(1, "S1".to_owned(), 0, 0, false))
+            .unwrap();
+        assert!(!resp.vote_granted);
+        assert_eq!(resp.term, 2);
+
+        remove_dir_all(&cfg.data_dir).await.unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+use crate::LogIndex;
+
+/// Snapshot of the command executor, it contains all the changes made by the log entries up to
+/// `meta.last_included_index`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Snapshot meta
+    meta: SnapshotMeta,
+    /// Snapshot data, generated by `CommandExecutor::snapshot`
+    data: Vec<u8>,
+    /// Encoded client sessions at the time the snapshot is taken
+    sessions: Vec<u8>,
+    /// Encoded members of the cluster except the server taking the snapshot, they contain the
+    /// membership changes in the log entries up to `meta.last_included_index`
+    members: Vec<u8>,
+}
+
+/// Meta of a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::exhaustive_structs)] // the meta is stable
+pub struct SnapshotMeta {
+    /// Index of the last log entry included in the snapshot
+    pub last_included_index: LogIndex,
+    /// Term of the last log entry included in the snapshot
+    pub last_included_term: u64,
+}
+
+impl Snapshot {
+    /// Create a new snapshot
+    #[inline]
+    #[must_use]
+    pub fn new(meta: SnapshotMeta, data: Vec<u8>) -> Self {
+        Self {
+            meta,
+            data,
+            sessions: vec![],
+            members: vec![],
+        }
+    }
+
+    /// Attach the encoded client sessions to the snapshot
+    #[must_use]
+    pub(crate) fn with_sessions(mut self, sessions: Vec<u8>) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Get the encoded client sessions
+    pub(crate) fn sessions(&self) -> &[u8] {
+        &self.sessions
+    }
+
+    /// Attach the encoded members of the cluster to the snapshot
+    #[must_use]
+    pub(crate) fn with_members(mut self, members: Vec<u8>) -> Self {
+        self.members = members;
+        self
+    }
+
+    /// Get the encoded members of the cluster, empty if they are not attached
+    pub(crate) fn members(&self) -> &[u8] {
+        &self.members
+    }
+
+    /// Get the meta of the snapshot
+    #[inline]
+    #[must_use]
+    pub fn meta(&self) -> SnapshotMeta {
+        self.meta
+    }
+
+    /// Get the data of the snapshot
+    #[inline]
+    #[must_use]
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Consume the snapshot and get the data
+    #[inline]
+    #[must_use]
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
@@ -19,8 +19,9 @@ use tokio::{sync::mpsc, time::sleep};
 use tracing::debug;
 
 use crate::{
-    cmd::{Command, CommandExecutor, ConflictCheck, ProposeId},
+    cmd::{ClientSession, Command, CommandExecutor, ConflictCheck, ProposeId},
     message::ServerId,
+    snapshot::Snapshot,
     LogIndex,
 };
 
@@ -96,6 +97,10 @@ impl TestCommand {
         self.as_should_fail = true;
         self
     }
+    pub(crate) fn set_session(mut self, session: ClientSession) -> Self {
+        self.id = ProposeId::new_in_session(session);
+        self
+    }
 }
 
 impl Command for TestCommand {
@@ -112,6 +117,10 @@ impl Command for TestCommand {
     fn id(&self) -> &ProposeId {
         &self.id
     }
+
+    fn is_keyed(&self) -> bool {
+        !self.keys.is_empty()
+    }
 }
 
 impl ConflictCheck for TestCommand {
@@ -197,8 +206,22 @@ impl CommandExecutor<TestCommand> for TestCE {
         Ok(index)
     }
 
-    async fn reset(&self) {
-        self.store.lock().clear();
+    async fn reset(&self, snapshot: Option<&Snapshot>) -> Result<(), ExecuteError> {
+        let Some(snapshot) = snapshot else {
+            self.store.lock().clear();
+            self.last_applied.store(0, Ordering::Relaxed);
+            return Ok(());
+        };
+        let store: HashMap<u32, u32> = bincode::deserialize(snapshot.data())
+            .map_err(|e| ExecuteError(e.to_string()))?;
+        *self.store.lock() = store;
+        self.last_applied
+            .store(snapshot.meta().last_included_index, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn snapshot(&self) -> Result<Vec<u8>, ExecuteError> {
+        bincode::serialize(&*self.store.lock()).map_err(|e| ExecuteError(e.to_string()))
     }
 
     fn last_applied(&self) -> Result<LogIndex, ExecuteError> {
@@ -273,8 +296,22 @@ impl CommandExecutor<TestCommand> for TestCESimple {
         Ok(index)
     }
 
-    async fn reset(&self) {
-        self.store.lock().clear();
+    async fn reset(&self, snapshot: Option<&Snapshot>) -> Result<(), ExecuteError> {
+        let Some(snapshot) = snapshot else {
+            self.store.lock().clear();
+            self.last_applied.store(0, Ordering::Relaxed);
+            return Ok(());
+        };
+        let store: HashMap<u32, u32> = bincode::deserialize(snapshot.data())
+            .map_err(|e| ExecuteError(e.to_string()))?;
+        *self.store.lock() = store;
+        self.last_applied
+            .store(snapshot.meta().last_included_index, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn snapshot(&self) -> Result<Vec<u8>, ExecuteError> {
+        bincode::serialize(&*self.store.lock()).map_err(|e| ExecuteError(e.to_string()))
     }
 
     fn last_applied(&self) -> Result<LogIndex, ExecuteError> {
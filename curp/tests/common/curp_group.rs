@@ -12,7 +12,7 @@ use std::{
     time::Duration,
 };
 
-use curp::{client::Client, server::Rpc, LogIndex, ProtocolServer, TxFilter};
+use curp::{client::Client, server::Rpc, LogIndex, ProtocolServer, RpcKind, TxAction, TxFilter};
 use futures::future::join_all;
 use itertools::Itertools;
 use parking_lot::Mutex;
@@ -21,8 +21,9 @@ use tokio_stream::wrappers::TcpListenerStream;
 use tracing::debug;
 use utils::config::{
     default_candidate_timeout_ticks, default_follower_timeout_ticks, default_heartbeat_interval,
-    default_retry_timeout, default_rpc_timeout, default_server_wait_synced_timeout, ClientTimeout,
-    CurpConfig,
+    default_log_compact_threshold, default_max_append_size, default_max_follower_lag,
+    default_max_inflight_appends, default_retry_timeout, default_rpc_timeout,
+    default_server_wait_synced_timeout, ClientTimeout, CurpConfig,
 };
 
 use crate::common::{
@@ -35,7 +36,10 @@ pub type ServerId = String;
 pub mod proto {
     tonic::include_proto!("messagepb");
 }
-pub use proto::{protocol_client::ProtocolClient, ProposeRequest, ProposeResponse};
+pub use proto::{
+    inner_protocol_client::InnerProtocolClient, protocol_client::ProtocolClient, ProposeRequest,
+    ProposeResponse,
+};
 
 use self::proto::{FetchLeaderRequest, FetchLeaderResponse};
 
@@ -54,26 +58,44 @@ impl Error for NotReachable {
     }
 }
 
-#[derive(Debug)]
+// Decide what to do with a request of the kind to the target, `None` if the rule doesn't apply
+pub type TxRule = Arc<dyn Fn(RpcKind, &str, &[u8]) -> Option<TxAction> + Send + Sync>;
+
+#[derive(Clone)]
 struct TestTxFilter {
     reachable: Arc<AtomicBool>,
+    rules: Arc<Mutex<Vec<TxRule>>>,
 }
 
 impl TestTxFilter {
-    fn new(reachable: Arc<AtomicBool>) -> Self {
-        Self { reachable }
+    fn new(reachable: Arc<AtomicBool>, rules: Arc<Mutex<Vec<TxRule>>>) -> Self {
+        Self { reachable, rules }
+    }
+}
+
+impl std::fmt::Debug for TestTxFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TestTxFilter")
+            .field("reachable", &self.reachable)
+            .field("rules", &self.rules.lock().len())
+            .finish()
     }
 }
 
 impl TxFilter for TestTxFilter {
-    fn filter(&self) -> Option<()> {
-        self.reachable.load(Ordering::Acquire).then_some(())
+    fn filter(&self, kind: RpcKind, target: &str, request: &[u8]) -> TxAction {
+        if !self.reachable.load(Ordering::Acquire) {
+            return TxAction::Drop;
+        }
+        self.rules
+            .lock()
+            .iter()
+            .find_map(|rule| rule(kind, target, request))
+            .unwrap_or(TxAction::Pass)
     }
 
     fn boxed_clone(&self) -> Box<dyn TxFilter> {
-        Box::new(Self {
-            reachable: Arc::clone(&self.reachable),
-        })
+        Box::new(self.clone())
     }
 }
 
@@ -85,6 +107,7 @@ pub struct CurpNode {
     pub store: Arc<Mutex<HashMap<u32, u32>>>,
     pub rt: Runtime,
     pub switch: Arc<AtomicBool>,
+    pub tx_rules: Arc<Mutex<Vec<TxRule>>>,
     pub storage_path: String,
 }
 
@@ -149,8 +172,10 @@ impl CurpGroup {
                     }
                 });
 
+                let tx_rules = Arc::new(Mutex::new(vec![]));
+                let tx_filter = TestTxFilter::new(Arc::clone(&switch), Arc::clone(&tx_rules));
+
                 let id_c = id.clone();
-                let switch_c = Arc::clone(&switch);
                 let storage_path_c = storage_path.clone();
                 thread::spawn(move || {
                     handle.spawn(Rpc::run_from_listener(
@@ -167,8 +192,12 @@ impl CurpGroup {
                             default_follower_timeout_ticks(),
                             default_candidate_timeout_ticks(),
                             PathBuf::from(storage_path_c),
+                            default_log_compact_threshold(),
+                            default_max_inflight_appends(),
+                            default_max_append_size(),
+                            default_max_follower_lag(),
                         )),
-                        Some(Box::new(TestTxFilter::new(Arc::clone(&switch_c)))),
+                        Some(Box::new(tx_filter)),
                         Some(reachable_layer),
                     ));
                 });
@@ -183,6 +212,7 @@ impl CurpGroup {
                         store,
                         rt,
                         switch,
+                        tx_rules,
                         storage_path,
                     },
                 )
@@ -208,7 +238,7 @@ impl CurpGroup {
             .iter()
             .map(|(id, node)| (id.clone(), node.addr.clone()))
             .collect();
-        Client::<TestCommand>::new(addrs, timeout).await
+        Client::<TestCommand>::new(addrs, timeout, None).await
     }
 
     pub fn exe_rxs(
@@ -276,8 +306,10 @@ impl CurpGroup {
             }
         });
 
+        let tx_rules = Arc::new(Mutex::new(vec![]));
+        let tx_filter = TestTxFilter::new(Arc::clone(&switch), Arc::clone(&tx_rules));
+
         let id_c = id.clone();
-        let switch_c = Arc::clone(&switch);
         let storage_path = crashed.storage_path.clone();
         thread::spawn(move || {
             handle.spawn(Rpc::run_from_listener(
@@ -294,8 +326,12 @@ impl CurpGroup {
                     default_follower_timeout_ticks(),
                     default_candidate_timeout_ticks(),
                     PathBuf::from(storage_path),
+                    default_log_compact_threshold(),
+                    default_max_inflight_appends(),
+                    default_max_append_size(),
+                    default_max_follower_lag(),
                 )),
-                Some(Box::new(TestTxFilter::new(Arc::clone(&switch_c)))),
+                Some(Box::new(tx_filter)),
                 Some(reachable_layer),
             ));
         });
@@ -308,6 +344,7 @@ impl CurpGroup {
             store,
             rt,
             switch,
+            tx_rules,
             storage_path: crashed.storage_path,
         };
         self.nodes.insert(id.clone(), new_node);
@@ -387,13 +424,44 @@ impl CurpGroup {
         node.switch.store(true, Ordering::Relaxed);
     }
 
+    // Apply the rule to the requests sent by the node
+    pub fn add_tx_rule(&self, id: &ServerId, rule: TxRule) {
+        self.nodes[id].tx_rules.lock().push(rule);
+    }
+
+    // Remove all the rules applied to the requests sent by the node
+    pub fn clear_tx_rules(&self, id: &ServerId) {
+        self.nodes[id].tx_rules.lock().clear();
+    }
+
+    // Drop all the requests sent from `from` to `to`, the other direction is not affected
+    pub fn cut(&self, from: &ServerId, to: &ServerId) {
+        let to = to.clone();
+        self.add_tx_rule(
+            from,
+            Arc::new(move |_, target, _| (target == to).then_some(TxAction::Drop)),
+        );
+    }
+
     pub async fn get_connect(&self, id: &ServerId) -> ProtocolClient<tonic::transport::Channel> {
+        ProtocolClient::connect(self.addr_of(id)).await.unwrap()
+    }
+
+    pub async fn get_inner_connect(
+        &self,
+        id: &ServerId,
+    ) -> InnerProtocolClient<tonic::transport::Channel> {
+        InnerProtocolClient::connect(self.addr_of(id))
+            .await
+            .unwrap()
+    }
+
+    fn addr_of(&self, id: &ServerId) -> String {
         let addr = self
             .all
             .iter()
             .find_map(|(node_id, addr)| (node_id == id).then_some(addr))
             .unwrap();
-        let addr = format!("http://{}", addr);
-        ProtocolClient::connect(addr.clone()).await.unwrap()
+        format!("http://{}", addr)
     }
 }
@@ -12,6 +12,7 @@ use async_trait::async_trait;
 use clippy_utilities::NumericCast;
 use curp::{
     cmd::{Command, CommandExecutor, ConflictCheck, ProposeId},
+    snapshot::Snapshot,
     LogIndex,
 };
 use itertools::Itertools;
@@ -116,6 +117,10 @@ impl Command for TestCommand {
     fn id(&self) -> &ProposeId {
         &self.id
     }
+
+    fn is_keyed(&self) -> bool {
+        !self.keys.is_empty()
+    }
 }
 
 impl ConflictCheck for TestCommand {
@@ -190,8 +195,22 @@ impl CommandExecutor<TestCommand> for TestCE {
         Ok(index)
     }
 
-    async fn reset(&self) {
-        self.store.lock().clear();
+    async fn reset(&self, snapshot: Option<&Snapshot>) -> Result<(), ExecuteError> {
+        let Some(snapshot) = snapshot else {
+            self.store.lock().clear();
+            self.last_applied.store(0, Ordering::Relaxed);
+            return Ok(());
+        };
+        let store: HashMap<u32, u32> = bincode::deserialize(snapshot.data())
+            .map_err(|e| ExecuteError(e.to_string()))?;
+        *self.store.lock() = store;
+        self.last_applied
+            .store(snapshot.meta().last_included_index, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn snapshot(&self) -> Result<Vec<u8>, ExecuteError> {
+        bincode::serialize(&*self.store.lock()).map_err(|e| ExecuteError(e.to_string()))
     }
 
     fn last_applied(&self) -> Result<LogIndex, ExecuteError> {
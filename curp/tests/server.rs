@@ -5,7 +5,10 @@ use std::{sync::Arc, time::Duration};
 use utils::config::ClientTimeout;
 
 use crate::common::{
-    curp_group::{proto::propose_response::ExeResult, CurpGroup, ProposeRequest, ProposeResponse},
+    curp_group::{
+        proto::{propose_response::ExeResult, ReadIndexRequest},
+        CurpGroup, ProposeRequest, ProposeResponse,
+    },
     init_logger, sleep_millis, sleep_secs,
     test_cmd::TestCommand,
 };
@@ -188,3 +191,48 @@ async fn concurrent_cmd_order() {
 
     group.stop();
 }
+
+#[tokio::test]
+async fn read_index_will_cover_cmds_completed_in_fast_path() {
+    init_logger();
+
+    let group = CurpGroup::new(3).await;
+    let client = group.new_client(ClientTimeout::default()).await;
+    let (leader, _term) = group.get_leader().await;
+
+    assert_eq!(
+        client
+            .propose(TestCommand::new_put(vec![0], 0))
+            .await
+            .unwrap(),
+        vec![]
+    );
+
+    let resp = group
+        .get_connect(&leader)
+        .await
+        .read_index(ReadIndexRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(resp.error.is_none());
+    assert_eq!(resp.read_index, 1); // log[0] is a fake one
+
+    // only the leader can confirm the read index
+    let follower = group
+        .nodes
+        .keys()
+        .find(|id| **id != leader)
+        .unwrap()
+        .clone();
+    let resp = group
+        .get_connect(&follower)
+        .await
+        .read_index(ReadIndexRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(resp.error.is_some());
+
+    group.stop();
+}
@@ -3,7 +3,11 @@ use std::time::Duration;
 use madsim::time::sleep;
 use utils::config::ClientTimeout;
 
-use crate::common::{curp_group::CurpGroup, init_logger, test_cmd::TestCommand};
+use crate::common::{
+    curp_group::{proto::TimeoutNowRequest, CurpGroup},
+    init_logger,
+    test_cmd::TestCommand,
+};
 
 mod common;
 
@@ -124,3 +128,35 @@ async fn propose_after_reelect() {
 
     group.stop();
 }
+
+// Leadership transfer
+#[tokio::test]
+async fn timeout_now_will_make_the_transferee_the_leader() {
+    init_logger();
+
+    let group = CurpGroup::new(3).await;
+    let (leader, term) = group.get_leader().await;
+    let transferee = group
+        .nodes
+        .keys()
+        .find(|id| **id != leader)
+        .unwrap()
+        .clone();
+
+    let mut connect = group.get_inner_connect(&transferee).await;
+    connect
+        .timeout_now(TimeoutNowRequest {
+            term,
+            leader_id: leader,
+        })
+        .await
+        .unwrap();
+
+    // the transferee doesn't wait for an election timeout
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let (new_leader, new_term) = group.get_leader().await;
+    assert_eq!(new_leader, transferee);
+    assert_eq!(new_term, term + 1);
+
+    group.stop();
+}
@@ -0,0 +1,93 @@
+//! Integration test for the curp server under asymmetric partitions and message loss
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use curp::{RpcKind, TxAction};
+use utils::config::ClientTimeout;
+
+use crate::common::{curp_group::CurpGroup, init_logger, sleep_secs, test_cmd::TestCommand};
+
+mod common;
+
+// A follower that can't hear from the leader should not disrupt the cluster, because the others
+// still hear from the leader and reject its pre-votes
+#[tokio::test]
+async fn follower_cut_off_from_leader_will_not_disrupt_cluster() {
+    init_logger();
+
+    let mut group = CurpGroup::new(3).await;
+    let (leader, term) = group.get_leader().await;
+    let follower = group.all.keys().find(|&id| id != &leader).unwrap().clone();
+
+    // the follower can still send requests to the leader
+    group.cut(&leader, &follower);
+    sleep_secs(3).await;
+
+    assert_eq!(group.get_leader().await, (leader.clone(), term));
+    assert_eq!(group.get_term_checked().await, term);
+
+    // the follower catches up after the link is restored
+    group.clear_tx_rules(&leader);
+    let client = group.new_client(ClientTimeout::default()).await;
+    assert_eq!(
+        client
+            .propose(TestCommand::new_put(vec![0], 0))
+            .await
+            .unwrap(),
+        vec![]
+    );
+    let as_rx = &mut group.nodes.get_mut(&follower).unwrap().as_rx;
+    let (cmd, _) = tokio::time::timeout(Duration::from_secs(5), as_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(cmd, TestCommand::new_put(vec![0], 0));
+
+    group.stop();
+}
+
+// Commands should be committed on every node even if half of the `AppendEntries` are lost
+#[tokio::test]
+async fn cmds_will_be_synced_when_append_entries_are_lost() {
+    init_logger();
+
+    let mut group = CurpGroup::new(3).await;
+    let leader = group.get_leader().await.0;
+    let sent = Arc::new(AtomicUsize::new(0));
+    group.add_tx_rule(
+        &leader,
+        Arc::new(move |kind, _, _| {
+            (kind == RpcKind::AppendEntries && sent.fetch_add(1, Ordering::Relaxed) % 2 == 0)
+                .then_some(TxAction::Drop)
+        }),
+    );
+
+    let client = group.new_client(ClientTimeout::default()).await;
+    for i in 0..3 {
+        assert_eq!(
+            client
+                .propose(TestCommand::new_put(vec![i], i))
+                .await
+                .unwrap(),
+            vec![]
+        );
+    }
+
+    for as_rx in group.as_rxs() {
+        for i in 0..3 {
+            let (cmd, _) = tokio::time::timeout(Duration::from_secs(5), as_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(cmd, TestCommand::new_put(vec![i], i));
+        }
+    }
+
+    group.stop();
+}
@@ -110,4 +110,10 @@ pub trait StorageEngine: Send + Sync + 'static + std::fmt::Debug {
     /// Return `EngineError::TableNotFound` if the given table does not exist
     /// Return `EngineError` if met some errors
     fn write_batch(&self, wr_ops: Vec<WriteOperation>, sync: bool) -> Result<(), EngineError>;
+
+    /// Get the size of the data held by the storage engine in bytes
+    ///
+    /// # Errors
+    /// Return `EngineError` if met some errors
+    fn size(&self) -> Result<u64, EngineError>;
 }
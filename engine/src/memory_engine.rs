@@ -113,6 +113,18 @@ impl StorageEngine for MemoryEngine {
         }
         Ok(())
     }
+
+    #[inline]
+    fn size(&self) -> Result<u64, EngineError> {
+        let size = self
+            .inner
+            .read()
+            .values()
+            .flat_map(HashMap::iter)
+            .map(|(key, value)| key.len().saturating_add(value.len()))
+            .fold(0_usize, usize::saturating_add);
+        Ok(u64::try_from(size).unwrap_or(u64::MAX))
+    }
 }
 
 #[cfg(test)]
@@ -208,4 +220,16 @@ mod test {
             .collect::<Vec<(Vec<u8>, Vec<u8>)>>();
         assert_eq!(res_3.sort(), expected_all_values.sort());
     }
+
+    #[test]
+    fn size_should_count_keys_and_values() {
+        let engine = MemoryEngine::new(&TESTTABLES).unwrap();
+        assert_eq!(engine.size().unwrap(), 0);
+        let batch = vec![
+            WriteOperation::new_put("kv", "key", "value"),
+            WriteOperation::new_put("lease", "id", "lease"),
+        ];
+        engine.write_batch(batch, false).unwrap();
+        assert_eq!(engine.size().unwrap(), 15);
+    }
 }
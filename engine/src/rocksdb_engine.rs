@@ -36,11 +36,19 @@ impl From<RocksError> for EngineError {
     }
 }
 
+/// Property of the total size of the sst files of a column family
+const TOTAL_SST_FILES_SIZE: &str = "rocksdb.total-sst-files-size";
+
+/// Property of the approximate size of the memtables of a column family
+const CUR_SIZE_ALL_MEM_TABLES: &str = "rocksdb.cur-size-all-mem-tables";
+
 /// `RocksDB` Storage Engine
 #[derive(Debug, Clone)]
 pub struct RocksEngine {
     /// The inner storage engine of `RocksDB`
     inner: Arc<DB>,
+    /// Tables of the engine, one column family for each
+    tables: Vec<&'static str>,
 }
 
 impl RocksEngine {
@@ -56,6 +64,7 @@ impl RocksEngine {
         db_opts.create_if_missing(true);
         Ok(Self {
             inner: Arc::new(DB::open_cf(&db_opts, data_dir, tables)?),
+            tables: tables.to_vec(),
         })
     }
 }
@@ -135,6 +144,23 @@ impl StorageEngine for RocksEngine {
         opt.set_sync(sync);
         self.inner.write_opt(batch, &opt).map_err(EngineError::from)
     }
+
+    #[inline]
+    fn size(&self) -> Result<u64, EngineError> {
+        // the properties are kept in memory by rocksdb, so the files are never touched
+        let mut size = 0_u64;
+        for table in &self.tables {
+            let cf = self
+                .inner
+                .cf_handle(table)
+                .ok_or(EngineError::TableNotFound((*table).to_owned()))?;
+            for property in [TOTAL_SST_FILES_SIZE, CUR_SIZE_ALL_MEM_TABLES] {
+                let value = self.inner.property_int_value_cf(&cf, property)?;
+                size = size.saturating_add(value.unwrap_or(0));
+            }
+        }
+        Ok(size)
+    }
 }
 
 /// destroy will remove the db file. It's test only
@@ -253,4 +279,18 @@ mod test {
         drop(engine);
         destroy(&data_dir);
     }
+
+    #[test]
+    fn size_should_grow_with_the_data() {
+        let data_dir = PathBuf::from("/tmp/size_should_grow_with_the_data");
+        let engine = RocksEngine::new(&data_dir, &TESTTABLES).unwrap();
+        let empty_size = engine.size().unwrap();
+        let puts = (0..100_u32)
+            .map(|i| WriteOperation::new_put("kv", i.to_be_bytes().to_vec(), vec![0; 1024]))
+            .collect();
+        engine.write_batch(puts, false).unwrap();
+        assert!(engine.size().unwrap() > empty_size);
+        drop(engine);
+        destroy(&data_dir);
+    }
 }
@@ -0,0 +1,59 @@
+//! Compare the conflict lookup of the `IntervalMap` with a linear scan at thousands of in-flight keys
+
+#![allow(missing_docs)] // criterion macros generate undocumented items
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use utils::interval_map::{Interval, IntervalMap, UpperBound};
+
+/// Generate in-flight keys, one in ten of them is a range covering the next 100 keys
+fn in_flight_keys(n: usize) -> Vec<Interval<Vec<u8>>> {
+    (0..n)
+        .map(|i| {
+            let key = format!("key{i:08}").into_bytes();
+            if i % 10 == 0 {
+                let end = format!("key{:08}", i + 100).into_bytes();
+                Interval::new(key, UpperBound::Included(end)).unwrap()
+            } else {
+                Interval::point(key)
+            }
+        })
+        .collect()
+}
+
+fn bench_conflict_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("conflict_lookup");
+    for n in [1_000, 4_000, 16_000] {
+        let keys = in_flight_keys(n);
+        let mut map = IntervalMap::new();
+        for (id, key) in keys.iter().enumerate() {
+            map.insert(key.clone(), id);
+        }
+        let queries: Vec<_> = (0..100)
+            .map(|i| Interval::point(format!("key{:08}", i * n / 100 + 5).into_bytes()))
+            .collect();
+
+        let _ig = group.bench_with_input(BenchmarkId::new("linear_scan", n), &n, |b, _| {
+            b.iter(|| {
+                for query in &queries {
+                    let conflicts = keys
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, key)| key.overlaps(query))
+                        .count();
+                    let _ig = black_box(conflicts);
+                }
+            });
+        });
+        let _ig = group.bench_with_input(BenchmarkId::new("interval_map", n), &n, |b, _| {
+            b.iter(|| {
+                for query in &queries {
+                    let _ig = black_box(map.find_overlaps(query).len());
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_conflict_lookup);
+criterion_main!(benches);
@@ -23,6 +23,22 @@ pub struct XlineServerConfig {
     /// auth configuration object
     #[getset(get = "pub")]
     auth: AuthConfig,
+    /// compact configuration object
+    #[getset(get = "pub")]
+    #[serde(default)]
+    compact: CompactConfig,
+    /// metrics configuration object
+    #[getset(get = "pub")]
+    #[serde(default)]
+    metrics: MetricsConfig,
+    /// tls configuration object
+    #[getset(get = "pub")]
+    #[serde(default)]
+    tls: TlsConfig,
+    /// quota configuration object
+    #[getset(get = "pub")]
+    #[serde(default)]
+    quota: QuotaConfig,
 }
 
 // TODO: support persistent storage configuration in the future
@@ -65,6 +81,10 @@ pub struct ClusterConfig {
     /// Leader node.
     #[getset(get = "pub")]
     is_leader: bool,
+    /// Whether the node is a curp witness, which only keeps a durable speculative pool
+    #[getset(get = "pub")]
+    #[serde(default)]
+    is_witness: bool,
     /// Curp server timeout settings
     #[getset(get = "pub")]
     #[serde(default = "CurpConfig::default")]
@@ -73,27 +93,63 @@ pub struct ClusterConfig {
     #[getset(get = "pub")]
     #[serde(default = "ClientTimeout::default")]
     client_timeout: ClientTimeout,
+    /// Urls to serve the peer traffic, the address of the node in `members` is used if it is empty
+    #[getset(get = "pub")]
+    #[serde(default)]
+    listen_peer_urls: Vec<String>,
+    /// Urls to serve the client traffic, the clients are served on the peer urls if it is empty
+    #[getset(get = "pub")]
+    #[serde(default)]
+    listen_client_urls: Vec<String>,
+    /// Peer urls advertised to the rest of the cluster, the address of the node in `members` is
+    /// used if it is empty. Other servers connect to the node by its address in `members`, so
+    /// the urls must address it
+    #[getset(get = "pub")]
+    #[serde(default)]
+    advertise_peer_urls: Vec<String>,
+    /// Client urls advertised to the clients, `listen_client_urls` is used if it is empty
+    #[getset(get = "pub")]
+    #[serde(default)]
+    advertise_client_urls: Vec<String>,
 }
 
 impl ClusterConfig {
     /// Generate a new `ClusterConfig` object
     #[must_use]
     #[inline]
+    #[allow(clippy::too_many_arguments)] // it's a config constructor
     pub fn new(
         name: String,
         members: HashMap<String, String>,
         is_leader: bool,
+        is_witness: bool,
         curp: CurpConfig,
         client_timeout: ClientTimeout,
+        listen_peer_urls: Vec<String>,
+        listen_client_urls: Vec<String>,
+        advertise_peer_urls: Vec<String>,
+        advertise_client_urls: Vec<String>,
     ) -> Self {
         Self {
             name,
             members,
             is_leader,
+            is_witness,
             curp_config: curp,
             client_timeout,
+            listen_peer_urls,
+            listen_client_urls,
+            advertise_peer_urls,
+            advertise_client_urls,
         }
     }
+
+    /// Set the name of the server, a server added to the cluster without a name adopts the name
+    /// the cluster knows it by
+    #[inline]
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
 }
 
 /// Curp server timeout settings
@@ -133,6 +189,22 @@ pub struct CurpConfig {
     /// Curp storage path
     #[serde(default = "default_curp_data_dir")]
     pub data_dir: PathBuf,
+
+    /// How many applied log entries can be kept before a snapshot is taken to compact the log
+    #[serde(default = "default_log_compact_threshold")]
+    pub log_compact_threshold: usize,
+
+    /// How many `append_entries` can be in flight to a follower at the same time
+    #[serde(default = "default_max_inflight_appends")]
+    pub max_inflight_appends: usize,
+
+    /// Max size in bytes of the log entries sent in one `append_entries`, at least one entry is sent
+    #[serde(default = "default_max_append_size")]
+    pub max_append_size: usize,
+
+    /// How many log entries a follower can lag behind before only one `append_entries` is in flight to it
+    #[serde(default = "default_max_follower_lag")]
+    pub max_follower_lag: usize,
 }
 
 /// default heartbeat interval
@@ -198,10 +270,39 @@ pub fn default_curp_data_dir() -> PathBuf {
     PathBuf::from("/var/lib/curp")
 }
 
+/// default log compact threshold
+#[must_use]
+#[inline]
+pub fn default_log_compact_threshold() -> usize {
+    10_000
+}
+
+/// default max inflight appends
+#[must_use]
+#[inline]
+pub fn default_max_inflight_appends() -> usize {
+    16
+}
+
+/// default max append size
+#[must_use]
+#[inline]
+pub fn default_max_append_size() -> usize {
+    1024 * 1024
+}
+
+/// default max follower lag
+#[must_use]
+#[inline]
+pub fn default_max_follower_lag() -> usize {
+    1000
+}
+
 impl CurpConfig {
     /// Create a new server timeout
     #[must_use]
     #[inline]
+    #[allow(clippy::too_many_arguments)] // it's a config constructor
     pub fn new(
         heartbeat_interval: Duration,
         wait_synced_timeout: Duration,
@@ -210,6 +311,10 @@ impl CurpConfig {
         follower_timeout_ticks: u8,
         candidate_timeout_ticks: u8,
         data_dir: PathBuf,
+        log_compact_threshold: usize,
+        max_inflight_appends: usize,
+        max_append_size: usize,
+        max_follower_lag: usize,
     ) -> Self {
         Self {
             heartbeat_interval,
@@ -219,6 +324,10 @@ impl CurpConfig {
             follower_timeout_ticks,
             candidate_timeout_ticks,
             data_dir,
+            log_compact_threshold,
+            max_inflight_appends,
+            max_append_size,
+            max_follower_lag,
         }
     }
 }
@@ -234,6 +343,10 @@ impl Default for CurpConfig {
             follower_timeout_ticks: default_follower_timeout_ticks(),
             candidate_timeout_ticks: default_candidate_timeout_ticks(),
             data_dir: default_curp_data_dir(),
+            log_compact_threshold: default_log_compact_threshold(),
+            max_inflight_appends: default_max_inflight_appends(),
+            max_append_size: default_max_append_size(),
+            max_follower_lag: default_max_follower_lag(),
         }
     }
 }
@@ -481,7 +594,6 @@ pub struct AuthConfig {
     /// The private key file
     #[getset(get = "pub")]
     auth_private_key: Option<PathBuf>,
-    // TODO: support SSL/TLS configuration in the future
 }
 
 impl AuthConfig {
@@ -496,16 +608,225 @@ impl AuthConfig {
     }
 }
 
+/// Compaction configuration object
+#[allow(clippy::module_name_repetitions)]
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq, Getters)]
+pub struct CompactConfig {
+    /// The auto compaction policy, auto compaction is disabled if it is not set
+    #[getset(get = "pub")]
+    #[serde(default)]
+    auto_compact_config: Option<AutoCompactConfig>,
+}
+
+impl CompactConfig {
+    /// Generate a new `CompactConfig` object
+    #[must_use]
+    #[inline]
+    pub fn new(auto_compact_config: Option<AutoCompactConfig>) -> Self {
+        Self {
+            auto_compact_config,
+        }
+    }
+}
+
+/// Metrics configuration object
+#[allow(clippy::module_name_repetitions)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Getters)]
+pub struct MetricsConfig {
+    /// Serve the metrics in the prometheus text format at `/metrics`
+    #[getset(get = "pub")]
+    #[serde(default)]
+    enable: bool,
+    /// The address to serve the metrics
+    #[getset(get = "pub")]
+    #[serde(default = "default_metrics_listen_addr")]
+    listen_addr: String,
+}
+
+/// default metrics listen address
+#[must_use]
+#[inline]
+pub fn default_metrics_listen_addr() -> String {
+    "0.0.0.0:9100".to_owned()
+}
+
+impl MetricsConfig {
+    /// Generate a new `MetricsConfig` object
+    #[must_use]
+    #[inline]
+    pub fn new(enable: bool, listen_addr: String) -> Self {
+        Self {
+            enable,
+            listen_addr,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            listen_addr: default_metrics_listen_addr(),
+        }
+    }
+}
+
+/// Quota configuration object
+#[allow(clippy::module_name_repetitions)]
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq, Getters)]
+pub struct QuotaConfig {
+    /// Max size in bytes of the backend, the `NOSPACE` alarm is raised and the requests that take
+    /// more space are rejected once it is exceeded
+    #[getset(get = "pub")]
+    #[serde(default = "default_quota_backend_bytes")]
+    quota_backend_bytes: u64,
+}
+
+/// default quota of the backend, the same as etcd
+#[must_use]
+#[inline]
+pub fn default_quota_backend_bytes() -> u64 {
+    // 2GiB
+    2 * 1024 * 1024 * 1024
+}
+
+impl QuotaConfig {
+    /// Generate a new `QuotaConfig` object
+    #[must_use]
+    #[inline]
+    pub fn new(quota_backend_bytes: u64) -> Self {
+        Self {
+            quota_backend_bytes,
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            quota_backend_bytes: default_quota_backend_bytes(),
+        }
+    }
+}
+
+/// Tls configuration object, all the certificates and keys are PEM encoded files
+#[allow(clippy::module_name_repetitions)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Getters)]
+pub struct TlsConfig {
+    /// Certificate of the server, the client-facing services are served through tls if both the
+    /// certificate and the key are set
+    #[getset(get = "pub")]
+    #[serde(default)]
+    server_cert_path: Option<PathBuf>,
+    /// Private key of the server
+    #[getset(get = "pub")]
+    #[serde(default)]
+    server_key_path: Option<PathBuf>,
+    /// CA certificate used to verify the clients, the server requires clients to present a
+    /// certificate signed by it if it is set
+    #[getset(get = "pub")]
+    #[serde(default)]
+    client_ca_cert_path: Option<PathBuf>,
+    /// Certificate of the server presented to other servers, the curp protocol is served through
+    /// tls if both the certificate and the key are set. The client-facing settings are used by
+    /// the peer listeners if neither of them is set
+    #[getset(get = "pub")]
+    #[serde(default)]
+    peer_server_cert_path: Option<PathBuf>,
+    /// Private key of the certificate presented to other servers
+    #[getset(get = "pub")]
+    #[serde(default)]
+    peer_server_key_path: Option<PathBuf>,
+    /// CA certificate used to verify other servers connecting to this one, the server requires
+    /// peers to present a certificate signed by it if it is set
+    #[getset(get = "pub")]
+    #[serde(default)]
+    peer_client_ca_cert_path: Option<PathBuf>,
+    /// CA certificate used to verify other servers, peers are connected through tls if it is set
+    #[getset(get = "pub")]
+    #[serde(default)]
+    server_ca_cert_path: Option<PathBuf>,
+    /// Certificate presented to other servers when connecting to them
+    #[getset(get = "pub")]
+    #[serde(default)]
+    client_cert_path: Option<PathBuf>,
+    /// Private key of the certificate presented to other servers
+    #[getset(get = "pub")]
+    #[serde(default)]
+    client_key_path: Option<PathBuf>,
+    /// Domain name checked against the certificates of other servers, it must be set if the
+    /// members are addressed by ip, since the ip addresses can't be verified
+    #[getset(get = "pub")]
+    #[serde(default)]
+    peer_domain_name: Option<String>,
+}
+
+impl TlsConfig {
+    /// Generate a new `TlsConfig` object
+    #[must_use]
+    #[inline]
+    #[allow(clippy::too_many_arguments)] // it's a config constructor
+    pub fn new(
+        server_cert_path: Option<PathBuf>,
+        server_key_path: Option<PathBuf>,
+        client_ca_cert_path: Option<PathBuf>,
+        peer_server_cert_path: Option<PathBuf>,
+        peer_server_key_path: Option<PathBuf>,
+        peer_client_ca_cert_path: Option<PathBuf>,
+        server_ca_cert_path: Option<PathBuf>,
+        client_cert_path: Option<PathBuf>,
+        client_key_path: Option<PathBuf>,
+        peer_domain_name: Option<String>,
+    ) -> Self {
+        Self {
+            server_cert_path,
+            server_key_path,
+            client_ca_cert_path,
+            peer_server_cert_path,
+            peer_server_key_path,
+            peer_client_ca_cert_path,
+            server_ca_cert_path,
+            client_cert_path,
+            client_key_path,
+            peer_domain_name,
+        }
+    }
+}
+
+/// Auto compaction policy, the same as the `periodic` and `revision` modes of etcd
+#[non_exhaustive]
+#[allow(clippy::module_name_repetitions)]
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(
+    tag = "mode",
+    content = "retention",
+    rename_all(deserialize = "lowercase")
+)]
+pub enum AutoCompactConfig {
+    /// Retain the history of the given duration, e.g. '1h'
+    #[serde(with = "duration_format")]
+    Periodic(Duration),
+    /// Retain the given number of the latest revisions
+    Revision(u64),
+}
+
 impl XlineServerConfig {
     /// Generates a new `XlineServerConfig` object
     #[must_use]
     #[inline]
+    #[allow(clippy::too_many_arguments)] // it's a config constructor
     pub fn new(
         cluster: ClusterConfig,
         storage: StorageConfig,
         log: LogConfig,
         trace: TraceConfig,
         auth: AuthConfig,
+        compact: CompactConfig,
+        metrics: MetricsConfig,
+        tls: TlsConfig,
+        quota: QuotaConfig,
     ) -> Self {
         Self {
             cluster,
@@ -513,6 +834,10 @@ impl XlineServerConfig {
             log,
             trace,
             auth,
+            compact,
+            metrics,
+            tls,
+            quota,
         }
     }
 }
@@ -528,6 +853,9 @@ mod tests {
             r#"[cluster]
             name = 'node1'
             is_leader = true
+            listen_peer_urls = ['http://0.0.0.0:2380']
+            listen_client_urls = ['http://0.0.0.0:2379']
+            advertise_client_urls = ['http://10.0.0.1:2379']
 
             [cluster.members]
             node1 = '127.0.0.1:2379'
@@ -539,6 +867,9 @@ mod tests {
             wait_synced_timeout = '100ms'
             rpc_timeout = '100ms'
             retry_timeout = '100us'
+            log_compact_threshold = 100
+            max_inflight_appends = 8
+            max_follower_lag = 500
 
             [cluster.client_timeout]
             retry_timeout = '5s'
@@ -557,7 +888,28 @@ mod tests {
             jaeger_output_dir = './jaeger_jsons'
             jaeger_level = 'info'
 
-            [auth]"#,
+            [auth]
+
+            [compact.auto_compact_config]
+            mode = 'periodic'
+            retention = '1h'
+
+            [metrics]
+            enable = true
+            listen_addr = '127.0.0.1:9101'
+
+            [tls]
+            server_cert_path = '/etc/xline/server.crt'
+            server_key_path = '/etc/xline/server.key'
+            client_ca_cert_path = '/etc/xline/ca.crt'
+            peer_server_cert_path = '/etc/xline/peer.crt'
+            peer_server_key_path = '/etc/xline/peer.key'
+            peer_client_ca_cert_path = '/etc/xline/peer-ca.crt'
+            server_ca_cert_path = '/etc/xline/peer-ca.crt'
+            peer_domain_name = 'xline.local'
+
+            [quota]
+            quota_backend_bytes = 1024"#,
         )
         .unwrap();
 
@@ -569,6 +921,10 @@ mod tests {
             default_follower_timeout_ticks(),
             default_candidate_timeout_ticks(),
             default_curp_data_dir(),
+            100,
+            8,
+            default_max_append_size(),
+            500,
         );
 
         let client_timeout = ClientTimeout::new(
@@ -587,8 +943,13 @@ mod tests {
                     ("node3".to_owned(), "127.0.0.1:2381".to_owned()),
                 ]),
                 true,
+                false,
                 curp_config,
-                client_timeout
+                client_timeout,
+                vec!["http://0.0.0.0:2380".to_owned()],
+                vec!["http://0.0.0.0:2379".to_owned()],
+                vec![],
+                vec!["http://10.0.0.1:2379".to_owned()]
             )
         );
 
@@ -611,6 +972,30 @@ mod tests {
                 LevelConfig::INFO
             )
         );
+        assert_eq!(
+            config.compact,
+            CompactConfig::new(Some(AutoCompactConfig::Periodic(Duration::from_secs(3600))))
+        );
+        assert_eq!(
+            config.metrics,
+            MetricsConfig::new(true, "127.0.0.1:9101".to_owned())
+        );
+        assert_eq!(
+            config.tls,
+            TlsConfig::new(
+                Some(PathBuf::from("/etc/xline/server.crt")),
+                Some(PathBuf::from("/etc/xline/server.key")),
+                Some(PathBuf::from("/etc/xline/ca.crt")),
+                Some(PathBuf::from("/etc/xline/peer.crt")),
+                Some(PathBuf::from("/etc/xline/peer.key")),
+                Some(PathBuf::from("/etc/xline/peer-ca.crt")),
+                Some(PathBuf::from("/etc/xline/peer-ca.crt")),
+                None,
+                None,
+                Some("xline.local".to_owned())
+            )
+        );
+        assert_eq!(config.quota, QuotaConfig::new(1024));
     }
 
     #[allow(clippy::unwrap_used)]
@@ -655,8 +1040,13 @@ mod tests {
                     ("node3".to_owned(), "127.0.0.1:2381".to_owned()),
                 ]),
                 true,
+                false,
                 CurpConfig::default(),
-                ClientTimeout::default()
+                ClientTimeout::default(),
+                vec![],
+                vec![],
+                vec![],
+                vec![]
             )
         );
 
@@ -683,5 +1073,21 @@ mod tests {
                 LevelConfig::INFO
             )
         );
+        assert_eq!(config.compact, CompactConfig::default());
+        assert_eq!(config.metrics, MetricsConfig::default());
+        assert_eq!(config.tls, TlsConfig::default());
+        assert_eq!(config.quota, QuotaConfig::default());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn test_auto_compact_config_should_be_loaded() {
+        let config: CompactConfig = toml::from_str(
+            r#"[auto_compact_config]
+            mode = 'revision'
+            retention = 10000"#,
+        )
+        .unwrap();
+        assert_eq!(config, CompactConfig::new(Some(AutoCompactConfig::Revision(10000))));
     }
 }
@@ -0,0 +1,446 @@
+use std::cmp::{max, Ordering};
+
+/// The upper end of an interval
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[allow(clippy::exhaustive_enums)] // an upper end is either a point or the infinity
+pub enum UpperBound<P> {
+    /// The interval ends at the point, the point is included
+    Included(P),
+    /// The interval has no upper end
+    Unbounded,
+}
+
+impl<P: Ord> UpperBound<P> {
+    /// Whether the `point` is not greater than the upper bound
+    fn is_above(&self, point: &P) -> bool {
+        match *self {
+            UpperBound::Included(ref high) => point <= high,
+            UpperBound::Unbounded => true,
+        }
+    }
+}
+
+/// A closed interval `[low, high]`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Interval<P> {
+    /// The lower end
+    low: P,
+    /// The upper end
+    high: UpperBound<P>,
+}
+
+impl<P: Ord> Interval<P> {
+    /// Create a new interval, return `None` if `low` is greater than `high`
+    #[inline]
+    #[must_use]
+    pub fn new(low: P, high: UpperBound<P>) -> Option<Self> {
+        high.is_above(&low).then_some(Self { low, high })
+    }
+
+    /// Create an interval that only contains the point
+    #[inline]
+    #[must_use]
+    pub fn point(point: P) -> Self
+    where
+        P: Clone,
+    {
+        Self {
+            low: point.clone(),
+            high: UpperBound::Included(point),
+        }
+    }
+
+    /// Create an interval that contains all points from `low`
+    #[inline]
+    #[must_use]
+    pub fn starting_at(low: P) -> Self {
+        Self {
+            low,
+            high: UpperBound::Unbounded,
+        }
+    }
+
+    /// Get the lower end
+    #[inline]
+    #[must_use]
+    pub fn low(&self) -> &P {
+        &self.low
+    }
+
+    /// Get the upper end
+    #[inline]
+    #[must_use]
+    pub fn high(&self) -> &UpperBound<P> {
+        &self.high
+    }
+
+    /// Whether the two intervals share at least one point
+    #[inline]
+    #[must_use]
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.high.is_above(&other.low) && other.high.is_above(&self.low)
+    }
+}
+
+/// A subtree of the AVL tree
+type Link<P, V> = Option<Box<Node<P, V>>>;
+
+/// A node of the AVL tree, ordered by its interval
+#[derive(Debug)]
+struct Node<P, V> {
+    /// Interval of the node
+    interval: Interval<P>,
+    /// Values inserted with the interval
+    values: Vec<V>,
+    /// The greatest upper end of the intervals in the subtree
+    max_high: UpperBound<P>,
+    /// Height of the subtree
+    height: u32,
+    /// Left child
+    left: Link<P, V>,
+    /// Right child
+    right: Link<P, V>,
+}
+
+impl<P: Ord + Clone, V> Node<P, V> {
+    /// Create a leaf node
+    fn new(interval: Interval<P>, value: V) -> Self {
+        Self {
+            max_high: interval.high.clone(),
+            interval,
+            values: vec![value],
+            height: 1,
+            left: None,
+            right: None,
+        }
+    }
+
+    /// Recalculate the height and the greatest upper end after the children changed
+    fn update(&mut self) {
+        self.height = max(height(&self.left), height(&self.right)).saturating_add(1);
+        self.max_high = [&self.left, &self.right]
+            .into_iter()
+            .flatten()
+            .map(|child| &child.max_high)
+            .fold(&self.interval.high, max)
+            .clone();
+    }
+}
+
+/// Height of a subtree
+fn height<P, V>(node: &Link<P, V>) -> u32 {
+    node.as_ref().map_or(0, |n| n.height)
+}
+
+/// Rotate the subtree to the right, the left child becomes the root
+fn rotate_right<P: Ord + Clone, V>(mut node: Box<Node<P, V>>) -> Box<Node<P, V>> {
+    let Some(mut left) = node.left.take() else {
+        return node;
+    };
+    node.left = left.right.take();
+    node.update();
+    left.right = Some(node);
+    left.update();
+    left
+}
+
+/// Rotate the subtree to the left, the right child becomes the root
+fn rotate_left<P: Ord + Clone, V>(mut node: Box<Node<P, V>>) -> Box<Node<P, V>> {
+    let Some(mut right) = node.right.take() else {
+        return node;
+    };
+    node.right = right.left.take();
+    node.update();
+    right.left = Some(node);
+    right.update();
+    right
+}
+
+/// Restore the AVL property of a subtree whose children are balanced
+fn balance<P: Ord + Clone, V>(mut node: Box<Node<P, V>>) -> Box<Node<P, V>> {
+    node.update();
+    let (left_height, right_height) = (height(&node.left), height(&node.right));
+    if left_height > right_height.saturating_add(1) {
+        node.left = node.left.take().map(|left| {
+            if height(&left.right) > height(&left.left) {
+                rotate_left(left)
+            } else {
+                left
+            }
+        });
+        return rotate_right(node);
+    }
+    if right_height > left_height.saturating_add(1) {
+        node.right = node.right.take().map(|right| {
+            if height(&right.left) > height(&right.right) {
+                rotate_right(right)
+            } else {
+                right
+            }
+        });
+        return rotate_left(node);
+    }
+    node
+}
+
+/// Insert a value into the subtree
+fn insert<P: Ord + Clone, V>(node: Link<P, V>, interval: Interval<P>, value: V) -> Box<Node<P, V>> {
+    let Some(mut node) = node else {
+        return Box::new(Node::new(interval, value));
+    };
+    match interval.cmp(&node.interval) {
+        Ordering::Less => node.left = Some(insert(node.left.take(), interval, value)),
+        Ordering::Greater => node.right = Some(insert(node.right.take(), interval, value)),
+        Ordering::Equal => {
+            node.values.push(value);
+            return node;
+        }
+    }
+    balance(node)
+}
+
+/// Detach the node with the smallest interval from the subtree, return the rest of the subtree and the node
+fn take_min<P: Ord + Clone, V>(mut node: Box<Node<P, V>>) -> (Link<P, V>, Box<Node<P, V>>) {
+    match node.left.take() {
+        Some(left) => {
+            let (rest, min) = take_min(left);
+            node.left = rest;
+            (Some(balance(node)), min)
+        }
+        None => (node.right.take(), node),
+    }
+}
+
+/// Remove a value from the subtree, return the new subtree and whether the value is removed
+fn remove<P: Ord + Clone, V: PartialEq>(
+    node: Link<P, V>,
+    interval: &Interval<P>,
+    value: &V,
+) -> (Link<P, V>, bool) {
+    let Some(mut node) = node else {
+        return (None, false);
+    };
+    let removed = match interval.cmp(&node.interval) {
+        Ordering::Less => {
+            let (left, removed) = remove(node.left.take(), interval, value);
+            node.left = left;
+            removed
+        }
+        Ordering::Greater => {
+            let (right, removed) = remove(node.right.take(), interval, value);
+            node.right = right;
+            removed
+        }
+        Ordering::Equal => {
+            let Some(pos) = node.values.iter().position(|v| v == value) else {
+                return (Some(node), false);
+            };
+            let _ig = node.values.swap_remove(pos);
+            if !node.values.is_empty() {
+                return (Some(node), true);
+            }
+            let (left, right) = (node.left.take(), node.right.take());
+            let subtree = match (left, right) {
+                (None, None) => None,
+                (Some(child), None) | (None, Some(child)) => Some(child),
+                (Some(left), Some(right)) => {
+                    let (rest, mut min) = take_min(right);
+                    min.left = Some(left);
+                    min.right = rest;
+                    Some(balance(min))
+                }
+            };
+            return (subtree, true);
+        }
+    };
+    (Some(balance(node)), removed)
+}
+
+/// Collect the values whose intervals overlap with the `interval` in the subtree
+fn collect_overlaps<'a, P: Ord, V>(
+    node: &'a Link<P, V>,
+    interval: &Interval<P>,
+    overlaps: &mut Vec<&'a V>,
+) {
+    let Some(node) = node.as_ref() else {
+        return;
+    };
+    // all intervals in the subtree end before the `interval` starts
+    if !node.max_high.is_above(&interval.low) {
+        return;
+    }
+    collect_overlaps(&node.left, interval, overlaps);
+    // the node and the intervals in the right subtree start after the `interval` ends
+    if !interval.high.is_above(&node.interval.low) {
+        return;
+    }
+    if node.interval.overlaps(interval) {
+        overlaps.extend(node.values.iter());
+    }
+    collect_overlaps(&node.right, interval, overlaps);
+}
+
+/// A map from intervals to values, which finds the values whose intervals overlap with a given
+/// interval in `O(log(n) + k)` time. It is an AVL tree ordered by the intervals, in which every
+/// node also records the greatest upper end in its subtree, so that subtrees that can't overlap
+/// are skipped. An interval can be mapped to multiple values.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)] // the name is ok even with repetitions
+pub struct IntervalMap<P, V> {
+    /// Root of the AVL tree
+    root: Link<P, V>,
+    /// Number of values in the map
+    len: usize,
+}
+
+impl<P, V> Default for IntervalMap<P, V> {
+    #[inline]
+    fn default() -> Self {
+        Self { root: None, len: 0 }
+    }
+}
+
+impl<P: Ord + Clone, V: PartialEq> IntervalMap<P, V> {
+    /// Create an empty map
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a value with the interval
+    #[inline]
+    pub fn insert(&mut self, interval: Interval<P>, value: V) {
+        self.root = Some(insert(self.root.take(), interval, value));
+        self.len = self.len.saturating_add(1);
+    }
+
+    /// Remove a value that was inserted with the interval, return whether it was in the map
+    #[inline]
+    pub fn remove(&mut self, interval: &Interval<P>, value: &V) -> bool {
+        let (root, removed) = remove(self.root.take(), interval, value);
+        self.root = root;
+        if removed {
+            self.len = self.len.saturating_sub(1);
+        }
+        removed
+    }
+
+    /// Find all values whose intervals overlap with the `interval`
+    #[inline]
+    #[must_use]
+    pub fn find_overlaps(&self, interval: &Interval<P>) -> Vec<&V> {
+        let mut overlaps = vec![];
+        collect_overlaps(&self.root, interval, &mut overlaps);
+        overlaps
+    }
+
+    /// Number of values in the map
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the map is empty
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Generate `n` pseudo random intervals in `[0, 1000]`
+    fn intervals(n: u64) -> Vec<Interval<u64>> {
+        let mut seed = 42_u64;
+        let mut next = move || {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (seed >> 33) % 1000
+        };
+        (0..n)
+            .map(|i| {
+                let low = next();
+                let high = match i % 10 {
+                    0 => UpperBound::Unbounded,
+                    1..=4 => UpperBound::Included(low),
+                    _ => UpperBound::Included(low + next() % 50),
+                };
+                Interval::new(low, high).unwrap()
+            })
+            .collect()
+    }
+
+    fn brute_force(entries: &[(Interval<u64>, usize)], interval: &Interval<u64>) -> Vec<usize> {
+        let mut overlaps: Vec<_> = entries
+            .iter()
+            .filter(|&&(ref i, _)| i.overlaps(interval))
+            .map(|&(_, v)| v)
+            .collect();
+        overlaps.sort_unstable();
+        overlaps
+    }
+
+    fn find(map: &IntervalMap<u64, usize>, interval: &Interval<u64>) -> Vec<usize> {
+        let mut overlaps: Vec<_> = map.find_overlaps(interval).into_iter().copied().collect();
+        overlaps.sort_unstable();
+        overlaps
+    }
+
+    #[test]
+    fn interval_should_tell_overlaps() {
+        let interval = Interval::new(3, UpperBound::Included(5)).unwrap();
+        assert!(interval.overlaps(&Interval::point(3)));
+        assert!(interval.overlaps(&Interval::point(5)));
+        assert!(!interval.overlaps(&Interval::point(6)));
+        assert!(interval.overlaps(&Interval::new(0, UpperBound::Unbounded).unwrap()));
+        assert!(!interval.overlaps(&Interval::new(6, UpperBound::Unbounded).unwrap()));
+        assert!(Interval::new(6, UpperBound::Included(5)).is_none());
+    }
+
+    #[test]
+    fn find_overlaps_should_match_brute_force() {
+        let mut entries: Vec<_> = intervals(2000).into_iter().zip(0..).collect();
+        let mut map = IntervalMap::new();
+        for &(ref interval, value) in &entries {
+            map.insert(interval.clone(), value);
+        }
+        assert_eq!(map.len(), 2000);
+        for query in intervals(100) {
+            assert_eq!(find(&map, &query), brute_force(&entries, &query));
+        }
+
+        // remove every other value, including the ones that share an interval with others
+        let removed: Vec<_> = entries.iter().step_by(2).cloned().collect();
+        for &(ref interval, value) in &removed {
+            assert!(map.remove(interval, &value));
+            assert!(!map.remove(interval, &value));
+        }
+        entries.retain(|&(_, v)| v % 2 == 1);
+        assert_eq!(map.len(), 1000);
+        for query in intervals(100) {
+            assert_eq!(find(&map, &query), brute_force(&entries, &query));
+        }
+
+        for &(ref interval, value) in &entries {
+            assert!(map.remove(interval, &value));
+        }
+        assert!(map.is_empty());
+        assert!(map.root.is_none());
+    }
+
+    #[test]
+    fn tree_should_stay_balanced() {
+        let mut map = IntervalMap::new();
+        for i in 0..1024_u64 {
+            map.insert(Interval::point(i), i);
+        }
+        // an AVL tree with n nodes is at most 1.44 * log2(n) high
+        assert!(height(&map.root) <= 14);
+    }
+}
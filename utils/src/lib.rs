@@ -134,6 +134,8 @@ use crate::config::{ClusterRange, LevelConfig, RotationConfig};
 
 /// configuration
 pub mod config;
+/// interval map which finds overlapping intervals in logarithmic time
+pub mod interval_map;
 /// utils of `parking_lot` lock
 #[cfg(feature = "parking_lot")]
 pub mod parking_lot_lock;
@@ -179,6 +181,16 @@ pub fn parse_members(s: &str) -> Result<HashMap<String, String>, ConfigParseErro
     Ok(map)
 }
 
+/// Get the address from a url, the scheme and the trailing slash are stripped
+#[must_use]
+#[inline]
+pub fn address_from_url(url: &str) -> String {
+    url.trim_start_matches("http://")
+        .trim_start_matches("https://")
+        .trim_end_matches('/')
+        .to_owned()
+}
+
 /// Parse `ClusterRange` from the given string
 /// # Errors
 /// Return error when parsing the given string to `ClusterRange` failed
@@ -225,9 +237,25 @@ pub fn parse_duration(s: &str) -> Result<Duration, ConfigParseError> {
                 "the value of time should not be empty ({s})"
             )))
         }
+    } else if s.ends_with('m') {
+        if let Some(dur) = s.strip_suffix('m') {
+            Ok(Duration::from_secs(dur.parse::<u64>()?.saturating_mul(60)))
+        } else {
+            Err(ConfigParseError::InvalidValue(format!(
+                "the value of time should not be empty ({s})"
+            )))
+        }
+    } else if s.ends_with('h') {
+        if let Some(dur) = s.strip_suffix('h') {
+            Ok(Duration::from_secs(dur.parse::<u64>()?.saturating_mul(3600)))
+        } else {
+            Err(ConfigParseError::InvalidValue(format!(
+                "the value of time should not be empty ({s})"
+            )))
+        }
     } else {
         Err(ConfigParseError::InvalidUnit(format!(
-            "the unit of time should be one of 'us', 'ms' or 's'({s})"
+            "the unit of time should be one of 'us', 'ms', 's', 'm' or 'h'({s})"
         )))
     }
 }
@@ -274,6 +302,8 @@ mod test {
         assert_eq!(parse_duration("5s").unwrap(), Duration::from_secs(5));
         assert_eq!(parse_duration("3ms").unwrap(), Duration::from_millis(3));
         assert_eq!(parse_duration("1us").unwrap(), Duration::from_micros(1));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
         let results = vec![
             parse_duration("hello world"),
             parse_duration("5x"),
@@ -316,6 +346,13 @@ mod test {
         assert!(parse_members(s4).is_err());
     }
 
+    #[test]
+    fn test_address_from_url() {
+        assert_eq!(address_from_url("http://127.0.0.1:2380"), "127.0.0.1:2380");
+        assert_eq!(address_from_url("https://10.0.0.1:2380/"), "10.0.0.1:2380");
+        assert_eq!(address_from_url("127.0.0.1:2380"), "127.0.0.1:2380");
+    }
+
     #[allow(clippy::unwrap_used)]
     #[test]
     fn test_parse_log_level() {
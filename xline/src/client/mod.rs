@@ -1,13 +1,15 @@
 use std::{collections::HashMap, fmt::Debug};
 
-use curp::{client::Client as CurpClient, cmd::ProposeId};
+use curp::{
+    client::Client as CurpClient,
+    cmd::{AccessedKey, ProposeId},
+};
 use etcd_client::{
-    AuthClient, Client as EtcdClient, KvClient, LeaseClient, LeaseKeepAliveStream, LeaseKeeper,
-    LockClient, WatchClient,
+    AuthClient, Client as EtcdClient, ConnectOptions, KvClient, LeaseClient, LeaseKeepAliveStream,
+    LeaseKeeper, LockClient, WatchClient,
 };
 use itertools::Itertools;
 use utils::config::ClientTimeout;
-use uuid::Uuid;
 
 use crate::{
     client::{
@@ -16,6 +18,7 @@ use crate::{
             DeleteRangeRequest, LeaseGrantRequest, LeaseKeepAliveRequest, LeaseRevokeRequest,
             LeaseTimeToLiveRequest, PutRequest, RangeRequest,
         },
+        tls::TlsOptions,
     },
     rpc::{
         self, DeleteRangeResponse, LeaseGrantResponse, LeaseLeasesResponse, LeaseRevokeResponse,
@@ -30,6 +33,8 @@ mod convert;
 pub mod errors;
 /// Requests used by Client
 pub mod kv_types;
+/// Tls options of Client
+pub mod tls;
 
 /// Xline client
 pub struct Client {
@@ -55,7 +60,7 @@ impl Debug for Client {
 }
 
 impl Client {
-    /// New `Client`
+    /// New `Client`, the servers are connected through tls if `tls_options` is set
     ///
     /// # Errors
     ///
@@ -65,10 +70,20 @@ impl Client {
         all_members: HashMap<String, String>,
         use_curp_client: bool,
         timeout: ClientTimeout,
+        tls_options: Option<TlsOptions>,
     ) -> Result<Self, ClientError> {
+        let connect_options = tls_options
+            .as_ref()
+            .map(|tls| ConnectOptions::new().with_tls(tls.etcd_tls_options()));
         let etcd_client =
-            EtcdClient::connect(all_members.values().cloned().collect_vec(), None).await?;
-        let curp_client = CurpClient::new(all_members, timeout).await;
+            EtcdClient::connect(all_members.values().cloned().collect_vec(), connect_options)
+                .await?;
+        let curp_client = CurpClient::new(
+            all_members,
+            timeout,
+            tls_options.as_ref().map(TlsOptions::curp_tls_config),
+        )
+        .await;
         Ok(Self {
             name: String::from("client"),
             curp_client,
@@ -85,7 +100,7 @@ impl Client {
 
     /// Generate a new `ProposeId`
     fn generate_propose_id(&self) -> ProposeId {
-        ProposeId::new(format!("{}-{}", self.name, Uuid::new_v4()))
+        self.curp_client.gen_propose_id()
     }
 
     /// Send `PutRequest` by `CurpClient` or `EtcdClient`
@@ -96,10 +111,10 @@ impl Client {
     #[inline]
     pub async fn put(&mut self, request: PutRequest) -> Result<PutResponse, ClientError> {
         if self.use_curp_client {
-            let key_ranges = vec![KeyRange {
+            let key_ranges = vec![AccessedKey::write(KeyRange {
                 start: request.key().to_vec(),
                 end: vec![],
-            }];
+            })];
             let propose_id = self.generate_propose_id();
             let request = RequestWithToken::new(rpc::PutRequest::from(request).into());
             let cmd = Command::new(key_ranges, request, propose_id);
@@ -123,10 +138,10 @@ impl Client {
     #[inline]
     pub async fn range(&mut self, request: RangeRequest) -> Result<RangeResponse, ClientError> {
         if self.use_curp_client {
-            let key_ranges = vec![KeyRange {
+            let key_ranges = vec![AccessedKey::read(KeyRange {
                 start: request.key().to_vec(),
                 end: request.range_end().to_vec(),
-            }];
+            })];
             let propose_id = self.generate_propose_id();
             let request = RequestWithToken::new(rpc::RangeRequest::from(request).into());
             let cmd = Command::new(key_ranges, request, propose_id);
@@ -150,10 +165,10 @@ impl Client {
         request: DeleteRangeRequest,
     ) -> Result<DeleteRangeResponse, ClientError> {
         if self.use_curp_client {
-            let key_ranges = vec![KeyRange {
+            let key_ranges = vec![AccessedKey::write(KeyRange {
                 start: request.key().to_vec(),
                 end: request.range_end().to_vec(),
-            }];
+            })];
             let propose_id = self.generate_propose_id();
             let request = RequestWithToken::new(rpc::DeleteRangeRequest::from(request).into());
             let cmd = Command::new(key_ranges, request, propose_id);
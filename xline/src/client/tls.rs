@@ -0,0 +1,78 @@
+/// Tls options of the `Client`, the certificates and the key are PEM encoded
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// CA certificate used to verify the servers
+    ca_cert: Option<Vec<u8>>,
+    /// Certificate and key presented to the servers
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    /// Domain name checked against the certificates of the servers
+    domain_name: Option<String>,
+}
+
+impl TlsOptions {
+    /// New `TlsOptions`, the servers are verified by the system roots by default
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the CA certificate used to verify the servers
+    #[inline]
+    #[must_use]
+    pub fn with_ca_cert(mut self, ca_cert: impl Into<Vec<u8>>) -> Self {
+        self.ca_cert = Some(ca_cert.into());
+        self
+    }
+
+    /// Set the certificate and the key presented to the servers, they are required if the
+    /// servers verify client certificates
+    #[inline]
+    #[must_use]
+    pub fn with_identity(mut self, cert: impl Into<Vec<u8>>, key: impl Into<Vec<u8>>) -> Self {
+        self.identity = Some((cert.into(), key.into()));
+        self
+    }
+
+    /// Set the domain name checked against the certificates of the servers
+    #[inline]
+    #[must_use]
+    pub fn with_domain_name(mut self, domain_name: impl Into<String>) -> Self {
+        self.domain_name = Some(domain_name.into());
+        self
+    }
+
+    /// Tls config of the curp client
+    pub(super) fn curp_tls_config(&self) -> tonic::transport::ClientTlsConfig {
+        use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+
+        let mut config = ClientTlsConfig::new();
+        if let Some(ref ca_cert) = self.ca_cert {
+            config = config.ca_certificate(Certificate::from_pem(ca_cert));
+        }
+        if let Some((ref cert, ref key)) = self.identity {
+            config = config.identity(Identity::from_pem(cert, key));
+        }
+        if let Some(ref domain_name) = self.domain_name {
+            config = config.domain_name(domain_name);
+        }
+        config
+    }
+
+    /// Tls options of the etcd client, `etcd_client` depends on another version of tonic
+    pub(super) fn etcd_tls_options(&self) -> etcd_client::TlsOptions {
+        use etcd_client::{Certificate, Identity, TlsOptions};
+
+        let mut options = TlsOptions::new();
+        if let Some(ref ca_cert) = self.ca_cert {
+            options = options.ca_certificate(Certificate::from_pem(ca_cert));
+        }
+        if let Some((ref cert, ref key)) = self.identity {
+            options = options.identity(Identity::from_pem(cert, key));
+        }
+        if let Some(ref domain_name) = self.domain_name {
+            options = options.domain_name(domain_name);
+        }
+        options
+    }
+}
@@ -110,7 +110,13 @@
     clippy::multiple_crate_versions, // caused by the dependency, can't be fixed
 )]
 
-use std::{collections::HashMap, env, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
@@ -118,21 +124,29 @@ use jsonwebtoken::{DecodingKey, EncodingKey};
 use opentelemetry::{global, runtime::Tokio, sdk::propagation::TraceContextPropagator};
 use opentelemetry_contrib::trace::exporter::jaeger_json::JaegerJsonExporter;
 use tokio::fs;
-use tracing::{debug, error};
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+use tracing::{debug, error, info};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{fmt::format, prelude::*};
 use utils::{
+    address_from_url,
     config::{
         default_candidate_timeout_ticks, default_client_wait_synced_timeout,
-        default_follower_timeout_ticks, default_heartbeat_interval, default_log_level,
-        default_propose_timeout, default_retry_timeout, default_rotation, default_rpc_timeout,
-        default_server_wait_synced_timeout, file_appender, AuthConfig, ClientTimeout,
-        ClusterConfig, CurpConfig, LevelConfig, LogConfig, RotationConfig, StorageConfig,
-        TraceConfig, XlineServerConfig,
+        default_follower_timeout_ticks, default_heartbeat_interval, default_log_compact_threshold,
+        default_log_level, default_max_append_size, default_max_follower_lag,
+        default_max_inflight_appends, default_metrics_listen_addr, default_propose_timeout,
+        default_quota_backend_bytes, default_retry_timeout, default_rotation, default_rpc_timeout,
+        default_server_wait_synced_timeout, file_appender, AuthConfig, AutoCompactConfig,
+        ClientTimeout, ClusterConfig, CompactConfig, CurpConfig, LevelConfig, LogConfig,
+        MetricsConfig, QuotaConfig, RotationConfig, StorageConfig, TlsConfig, TraceConfig,
+        XlineServerConfig,
     },
     parse_duration, parse_log_level, parse_members, parse_rotation,
 };
-use xline::{server::XlineServer, storage::db::DBProxy};
+use xline::{
+    server::{start_witness, ServerTls, XlineServer},
+    storage::db::DBProxy,
+};
 
 /// Command line arguments
 #[derive(Parser)]
@@ -144,9 +158,24 @@ struct ServerArgs {
     /// Cluster peers. eg: 192.168.x.x:8080 192.168.x.x:8080
     #[clap(long,value_parser = parse_members)]
     members: HashMap<String, String>,
+    /// Urls to serve the peer traffic, the address of the node in `members` is used by default
+    #[clap(long, value_delimiter = ',')]
+    listen_peer_urls: Vec<String>,
+    /// Urls to serve the client traffic, the clients are served on the peer urls by default
+    #[clap(long, value_delimiter = ',')]
+    listen_client_urls: Vec<String>,
+    /// Peer urls advertised to the rest of the cluster, they must address the node in `members`
+    #[clap(long, value_delimiter = ',')]
+    advertise_peer_urls: Vec<String>,
+    /// Client urls advertised to the clients, `listen_client_urls` is used by default
+    #[clap(long, value_delimiter = ',')]
+    advertise_client_urls: Vec<String>,
     /// If node is leader
     #[clap(long)]
     is_leader: bool,
+    /// If node is a curp witness, which only keeps a durable speculative pool for the fast path
+    #[clap(long, conflicts_with = "is_leader")]
+    is_witness: bool,
     /// Private key used to sign the token
     #[clap(long)]
     auth_private_key: Option<PathBuf>,
@@ -209,6 +238,65 @@ struct ServerArgs {
     data_dir: PathBuf,
     /// Curp directory
     curp_dir: Option<PathBuf>,
+    /// How many applied log entries can be kept before the curp log is compacted
+    #[clap(long, default_value_t = default_log_compact_threshold())]
+    log_compact_threshold: usize,
+    /// How many `append_entries` can be in flight to a follower at the same time
+    #[clap(long, default_value_t = default_max_inflight_appends())]
+    max_inflight_appends: usize,
+    /// Max size in bytes of the log entries sent to a follower in one `append_entries`
+    #[clap(long, default_value_t = default_max_append_size())]
+    max_append_size: usize,
+    /// How many log entries a follower can lag behind before only one `append_entries` is in flight to it
+    #[clap(long, default_value_t = default_max_follower_lag())]
+    max_follower_lag: usize,
+    /// Enable periodic auto compaction and retain the history of the given duration, eg: 1h
+    #[clap(long, value_parser = parse_duration, conflicts_with = "auto_revision_retention")]
+    auto_periodic_retention: Option<Duration>,
+    /// Enable revision auto compaction and retain the given number of the latest revisions
+    #[clap(long)]
+    auto_revision_retention: Option<u64>,
+    /// Serve the prometheus metrics at `/metrics`
+    #[clap(long)]
+    metrics_enable: bool,
+    /// The address to serve the metrics
+    #[clap(long, default_value_t = default_metrics_listen_addr())]
+    metrics_listen_addr: String,
+    /// Certificate of the server, the client-facing services are served through tls if it is set
+    #[clap(long, requires = "server_key_path")]
+    server_cert_path: Option<PathBuf>,
+    /// Private key of the server
+    #[clap(long, requires = "server_cert_path")]
+    server_key_path: Option<PathBuf>,
+    /// CA certificate used to verify the clients, client certificates are required if it is set
+    #[clap(long, requires = "server_cert_path")]
+    client_ca_cert_path: Option<PathBuf>,
+    /// Certificate presented to other servers, the curp protocol is served through tls if it is
+    /// set. The client-facing certificate and key are used if it's not set
+    #[clap(long, requires = "peer_server_key_path")]
+    peer_server_cert_path: Option<PathBuf>,
+    /// Private key of the certificate presented to other servers
+    #[clap(long, requires = "peer_server_cert_path")]
+    peer_server_key_path: Option<PathBuf>,
+    /// CA certificate used to verify the servers connecting to this one, their certificates are
+    /// required if it is set
+    #[clap(long, requires = "peer_server_cert_path")]
+    peer_client_ca_cert_path: Option<PathBuf>,
+    /// CA certificate used to verify other servers, peers are connected through tls if it is set
+    #[clap(long)]
+    server_ca_cert_path: Option<PathBuf>,
+    /// Certificate presented to other servers
+    #[clap(long, requires_all = &["client_key_path", "server_ca_cert_path"])]
+    client_cert_path: Option<PathBuf>,
+    /// Private key of the certificate presented to other servers
+    #[clap(long, requires = "client_cert_path")]
+    client_key_path: Option<PathBuf>,
+    /// Domain name in the certificates of other servers, required if members are addressed by ip
+    #[clap(long, requires = "server_ca_cert_path")]
+    peer_domain_name: Option<String>,
+    /// Max size in bytes of the backend, the `NOSPACE` alarm is raised once it is exceeded
+    #[clap(long, default_value_t = default_quota_backend_bytes())]
+    quota_backend_bytes: u64,
 }
 
 impl From<ServerArgs> for XlineServerConfig {
@@ -227,6 +315,10 @@ impl From<ServerArgs> for XlineServerConfig {
                 path.push("curp");
                 path
             }),
+            args.log_compact_threshold,
+            args.max_inflight_appends,
+            args.max_append_size,
+            args.max_follower_lag,
         );
 
         let storage = match args.storage_engine.as_str() {
@@ -247,8 +339,13 @@ impl From<ServerArgs> for XlineServerConfig {
             args.name,
             args.members,
             args.is_leader,
+            args.is_witness,
             curp_config,
             client_timeout,
+            args.listen_peer_urls,
+            args.listen_client_urls,
+            args.advertise_peer_urls,
+            args.advertise_client_urls,
         );
         let log = LogConfig::new(args.log_file, args.log_rotate, args.log_level);
         let trace = TraceConfig::new(
@@ -258,7 +355,31 @@ impl From<ServerArgs> for XlineServerConfig {
             args.jaeger_level,
         );
         let auth = AuthConfig::new(args.auth_public_key, args.auth_private_key);
-        XlineServerConfig::new(cluster, storage, log, trace, auth)
+        let auto_compact_config = args
+            .auto_periodic_retention
+            .map(AutoCompactConfig::Periodic)
+            .or_else(|| {
+                args.auto_revision_retention
+                    .map(AutoCompactConfig::Revision)
+            });
+        let compact = CompactConfig::new(auto_compact_config);
+        let metrics = MetricsConfig::new(args.metrics_enable, args.metrics_listen_addr);
+        let tls = TlsConfig::new(
+            args.server_cert_path,
+            args.server_key_path,
+            args.client_ca_cert_path,
+            args.peer_server_cert_path,
+            args.peer_server_key_path,
+            args.peer_client_ca_cert_path,
+            args.server_ca_cert_path,
+            args.client_cert_path,
+            args.client_key_path,
+            args.peer_domain_name,
+        );
+        let quota = QuotaConfig::new(args.quota_backend_bytes);
+        XlineServerConfig::new(
+            cluster, storage, log, trace, auth, compact, metrics, tls, quota,
+        )
     }
 }
 
@@ -351,6 +472,149 @@ async fn read_key_pair(
     Some((encoding_key, decoding_key))
 }
 
+/// Parse the listen urls into socket addresses
+fn listen_addrs(urls: &[String]) -> Result<Vec<SocketAddr>> {
+    urls.iter()
+        .map(|url| Ok(address_from_url(url).parse()?))
+        .collect()
+}
+
+/// Resolve the name and the address of the node in `members`. A member added without a name is
+/// named after its address by the cluster, so the node adopts the name of the member addressed
+/// by its advertised peer urls if its own name is not found
+fn resolve_name<'a>(
+    name: &str,
+    members: &'a HashMap<String, String>,
+    advertise_peer_urls: &[String],
+) -> Result<(String, &'a String)> {
+    if let Some(address) = members.get(name) {
+        return Ok((name.to_owned(), address));
+    }
+    let adopted = advertise_peer_urls.first().and_then(|url| {
+        let address = address_from_url(url);
+        members
+            .iter()
+            .find(|&(_, member_address)| *member_address == address)
+    });
+    let Some((adopted, address)) = adopted else {
+        return Err(anyhow!("node name {name} not found in cluster peers"));
+    };
+    info!("node name {name} not found in cluster peers, adopt the name {adopted}");
+    Ok((adopted.clone(), address))
+}
+
+/// Check that the advertised peer urls address the member itself, since other servers connect to
+/// it by its address in `members` rather than the advertised urls
+fn check_advertise_peer_urls(urls: &[String], self_address: &str) -> Result<()> {
+    if let Some(url) = urls
+        .iter()
+        .find(|url| address_from_url(url) != self_address)
+    {
+        return Err(anyhow!(
+            "advertised peer url {url} doesn't address the member address {self_address}"
+        ));
+    }
+    Ok(())
+}
+
+/// Read the config of a tls server from files, the server serves through tls if both the
+/// certificate and the key are set, and it verifies the clients if the CA certificate is set
+async fn read_server_tls_config(
+    cert_path: Option<&Path>,
+    key_path: Option<&Path>,
+    ca_path: Option<&Path>,
+) -> Result<Option<ServerTlsConfig>> {
+    let (Some(cert_path), Some(key_path)) = (cert_path, key_path) else {
+        return Ok(None);
+    };
+    let identity = Identity::from_pem(fs::read(cert_path).await?, fs::read(key_path).await?);
+    let mut config = ServerTlsConfig::new().identity(identity);
+    if let Some(ca_path) = ca_path {
+        config = config.client_ca_root(Certificate::from_pem(fs::read(ca_path).await?));
+    }
+    Ok(Some(config))
+}
+
+/// Read the tls configs of the client-facing services, of the curp protocol and of the
+/// connections to other servers from files
+async fn read_tls_config(
+    tls_config: &TlsConfig,
+    members: &HashMap<String, String>,
+) -> Result<(
+    Option<ServerTlsConfig>,
+    Option<ServerTlsConfig>,
+    Option<ClientTlsConfig>,
+)> {
+    if tls_config.server_cert_path().is_some() != tls_config.server_key_path().is_some() {
+        return Err(anyhow!(
+            "server_cert_path and server_key_path should be set together"
+        ));
+    }
+    let peer_server_cert_path = tls_config.peer_server_cert_path().as_deref();
+    if peer_server_cert_path.is_some() != tls_config.peer_server_key_path().is_some() {
+        return Err(anyhow!(
+            "peer_server_cert_path and peer_server_key_path should be set together"
+        ));
+    }
+    let server_tls_config = read_server_tls_config(
+        tls_config.server_cert_path().as_deref(),
+        tls_config.server_key_path().as_deref(),
+        tls_config.client_ca_cert_path().as_deref(),
+    )
+    .await?;
+    // the peers are served with the client-facing settings if the peer ones are absent
+    let peer_server_tls_config = if peer_server_cert_path.is_some() {
+        read_server_tls_config(
+            peer_server_cert_path,
+            tls_config.peer_server_key_path().as_deref(),
+            tls_config.peer_client_ca_cert_path().as_deref(),
+        )
+        .await?
+    } else {
+        server_tls_config.clone()
+    };
+    let Some(ca_path) = tls_config.server_ca_cert_path().as_ref() else {
+        return Ok((server_tls_config, peer_server_tls_config, None));
+    };
+    let mut client_tls_config =
+        ClientTlsConfig::new().ca_certificate(Certificate::from_pem(fs::read(ca_path).await?));
+    match (
+        tls_config.client_cert_path().as_ref(),
+        tls_config.client_key_path().as_ref(),
+    ) {
+        (Some(cert_path), Some(key_path)) => {
+            let identity =
+                Identity::from_pem(fs::read(cert_path).await?, fs::read(key_path).await?);
+            client_tls_config = client_tls_config.identity(identity);
+        }
+        (None, None) => {}
+        (Some(_), None) | (None, Some(_)) => {
+            return Err(anyhow!(
+                "client_cert_path and client_key_path should be set together"
+            ))
+        }
+    }
+    // the peers are verified by the host in their addresses, which can't be an ip address
+    if tls_config.peer_domain_name().is_none() {
+        if let Some(addr) = members
+            .values()
+            .find(|addr| address_from_url(addr).parse::<SocketAddr>().is_ok())
+        {
+            return Err(anyhow!(
+                "peer_domain_name should be set when the members are addressed by ip, found {addr}"
+            ));
+        }
+    }
+    if let Some(domain_name) = tls_config.peer_domain_name().as_ref() {
+        client_tls_config = client_tls_config.domain_name(domain_name);
+    }
+    Ok((
+        server_tls_config,
+        peer_server_tls_config,
+        Some(client_tls_config),
+    ))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     global::set_text_map_propagator(TraceContextPropagator::new());
@@ -369,6 +633,10 @@ async fn main() -> Result<()> {
     let trace_config = config.trace();
     let cluster_config = config.cluster();
     let auth_config = config.auth();
+    let compact_config = config.compact();
+    let metrics_config = config.metrics();
+    let tls_config = config.tls();
+    let quota_config = config.quota();
 
     let _guard = init_subscriber(cluster_config.name(), log_config, trace_config)?;
 
@@ -377,36 +645,68 @@ async fn main() -> Result<()> {
         auth_config.auth_public_key().clone(),
     )
     .await;
+    let (server_tls_config, peer_server_tls_config, client_tls_config) =
+        read_tls_config(tls_config, cluster_config.members()).await?;
 
-    let self_addr = cluster_config
-        .members()
-        .get(cluster_config.name())
-        .ok_or_else(|| {
-            anyhow!(
-                "node name {} not found in cluster peers",
-                cluster_config.name()
-            )
-        })?
-        .parse()?;
+    let (name, self_address) = resolve_name(
+        cluster_config.name(),
+        cluster_config.members(),
+        cluster_config.advertise_peer_urls(),
+    )?;
+    check_advertise_peer_urls(cluster_config.advertise_peer_urls(), self_address)?;
+    let self_addr = self_address.parse()?;
+
+    let peer_addrs = if cluster_config.listen_peer_urls().is_empty() {
+        vec![self_addr]
+    } else {
+        listen_addrs(cluster_config.listen_peer_urls())?
+    };
+    let client_addrs = listen_addrs(cluster_config.listen_client_urls())?;
 
-    let is_leader = cluster_config.is_leader();
-    debug!("name = {:?}", cluster_config.name());
+    debug!("name = {:?}", name);
     debug!("server_addr = {:?}", self_addr);
+    debug!("peer_addrs = {:?}", peer_addrs);
+    debug!("client_addrs = {:?}", client_addrs);
     debug!("cluster_peers = {:?}", cluster_config.members());
 
+    if *cluster_config.is_witness() {
+        debug!("{} starts as a witness", name);
+        start_witness(
+            name,
+            cluster_config.curp_config().clone(),
+            &peer_addrs,
+            peer_server_tls_config,
+        )
+        .await?;
+        global::shutdown_tracer_provider();
+        return Ok(());
+    }
+
     let db_proxy = DBProxy::open(storage_config)?;
+    let mut cluster_config = cluster_config.clone();
+    cluster_config.set_name(name);
     let server = XlineServer::new(
-        cluster_config.name().clone(),
-        cluster_config.members().clone(),
-        *is_leader,
+        cluster_config,
         key_pair,
-        cluster_config.curp_config().clone(),
-        *cluster_config.client_timeout(),
+        *compact_config.auto_compact_config(),
+        *quota_config.quota_backend_bytes(),
         db_proxy,
+        ServerTls {
+            server: server_tls_config,
+            peer_server: peer_server_tls_config,
+            client: client_tls_config,
+        },
     )
     .await;
     debug!("{:?}", server);
-    server.start(self_addr).await?;
+    let metrics_addr = if *metrics_config.enable() {
+        Some(metrics_config.listen_addr().parse()?)
+    } else {
+        None
+    };
+    server
+        .start(&peer_addrs, &client_addrs, metrics_addr)
+        .await?;
     global::shutdown_tracer_provider();
     Ok(())
 }
@@ -38,34 +38,41 @@ pub use self::etcdserverpb::range_request::{SortOrder, SortTarget};
 pub(crate) use self::{
     authpb::{permission::Type, Permission, Role, User},
     etcdserverpb::{
+        alarm_request::AlarmAction,
         auth_server::{Auth, AuthServer},
+        cluster_server::{Cluster, ClusterServer},
         compare::{CompareResult, CompareTarget, TargetUnion},
-        kv_client::KvClient,
         kv_server::{Kv, KvServer},
         lease_client::LeaseClient,
         lease_server::{Lease, LeaseServer},
+        maintenance_server::{Maintenance, MaintenanceServer},
         request_op::Request,
         response_op::Response,
         watch_client::WatchClient,
         watch_request::RequestUnion,
         watch_server::{Watch, WatchServer},
-        AuthDisableRequest, AuthDisableResponse, AuthEnableRequest, AuthEnableResponse,
-        AuthRoleAddRequest, AuthRoleAddResponse, AuthRoleDeleteRequest, AuthRoleDeleteResponse,
-        AuthRoleGetRequest, AuthRoleGetResponse, AuthRoleGrantPermissionRequest,
-        AuthRoleGrantPermissionResponse, AuthRoleListRequest, AuthRoleListResponse,
-        AuthRoleRevokePermissionRequest, AuthRoleRevokePermissionResponse, AuthStatusRequest,
-        AuthStatusResponse, AuthUserAddRequest, AuthUserAddResponse, AuthUserChangePasswordRequest,
-        AuthUserChangePasswordResponse, AuthUserDeleteRequest, AuthUserDeleteResponse,
-        AuthUserGetRequest, AuthUserGetResponse, AuthUserGrantRoleRequest,
-        AuthUserGrantRoleResponse, AuthUserListRequest, AuthUserListResponse,
-        AuthUserRevokeRoleRequest, AuthUserRevokeRoleResponse, AuthenticateRequest,
-        AuthenticateResponse, CompactionRequest, CompactionResponse, Compare, DeleteRangeRequest,
-        DeleteRangeResponse, LeaseGrantRequest, LeaseGrantResponse, LeaseKeepAliveRequest,
+        AlarmMember, AlarmRequest, AlarmResponse, AlarmType, AuthDisableRequest,
+        AuthDisableResponse, AuthEnableRequest, AuthEnableResponse, AuthRoleAddRequest,
+        AuthRoleAddResponse, AuthRoleDeleteRequest, AuthRoleDeleteResponse, AuthRoleGetRequest,
+        AuthRoleGetResponse, AuthRoleGrantPermissionRequest, AuthRoleGrantPermissionResponse,
+        AuthRoleListRequest, AuthRoleListResponse, AuthRoleRevokePermissionRequest,
+        AuthRoleRevokePermissionResponse, AuthStatusRequest, AuthStatusResponse, AuthUserAddRequest,
+        AuthUserAddResponse, AuthUserChangePasswordRequest, AuthUserChangePasswordResponse,
+        AuthUserDeleteRequest, AuthUserDeleteResponse, AuthUserGetRequest, AuthUserGetResponse,
+        AuthUserGrantRoleRequest, AuthUserGrantRoleResponse, AuthUserListRequest,
+        AuthUserListResponse, AuthUserRevokeRoleRequest, AuthUserRevokeRoleResponse,
+        AuthenticateRequest, AuthenticateResponse, CompactionRequest, CompactionResponse, Compare,
+        DefragmentRequest, DefragmentResponse, DeleteRangeRequest, DeleteRangeResponse,
+        DowngradeRequest, DowngradeResponse, HashKvRequest, HashKvResponse, HashRequest,
+        HashResponse, LeaseGrantRequest, LeaseGrantResponse, LeaseKeepAliveRequest,
         LeaseKeepAliveResponse, LeaseLeasesRequest, LeaseLeasesResponse, LeaseRevokeRequest,
-        LeaseRevokeResponse, LeaseStatus, LeaseTimeToLiveRequest, LeaseTimeToLiveResponse,
-        PutRequest, PutResponse, RangeRequest, RangeResponse, RequestOp, ResponseHeader,
-        ResponseOp, TxnRequest, TxnResponse, WatchCancelRequest, WatchCreateRequest, WatchRequest,
-        WatchResponse,
+        LeaseRevokeResponse, LeaseStatus, LeaseTimeToLiveRequest, LeaseTimeToLiveResponse, Member,
+        MemberAddRequest, MemberAddResponse, MemberListRequest, MemberListResponse,
+        MemberPromoteRequest, MemberPromoteResponse, MemberRemoveRequest, MemberRemoveResponse,
+        MemberUpdateRequest, MemberUpdateResponse, MoveLeaderRequest, MoveLeaderResponse,
+        PutRequest, PutResponse, RangeRequest, RangeResponse, RequestOp, ResponseHeader, ResponseOp,
+        SnapshotRequest, SnapshotResponse, StatusRequest, StatusResponse, TxnRequest, TxnResponse,
+        WatchCancelRequest, WatchCreateRequest, WatchRequest, WatchResponse,
     },
     leasepb::Lease as PbLease,
     mvccpb::{event::EventType, Event, KeyValue},
@@ -143,6 +150,8 @@ pub(crate) enum RequestWrapper {
     LeaseGrantRequest(LeaseGrantRequest),
     /// `LeaseRevokeRequest`
     LeaseRevokeRequest(LeaseRevokeRequest),
+    /// `AlarmRequest`
+    AlarmRequest(AlarmRequest),
 }
 
 /// Wrapper for responses
@@ -197,6 +206,8 @@ pub(crate) enum ResponseWrapper {
     LeaseGrantResponse(LeaseGrantResponse),
     /// `LeaseRevokeResponse`
     LeaseRevokeResponse(LeaseRevokeResponse),
+    /// `AlarmResponse`
+    AlarmResponse(AlarmResponse),
 }
 
 impl ResponseWrapper {
@@ -227,6 +238,7 @@ impl ResponseWrapper {
             ResponseWrapper::AuthenticateResponse(ref mut resp) => &mut resp.header,
             ResponseWrapper::LeaseGrantResponse(ref mut resp) => &mut resp.header,
             ResponseWrapper::LeaseRevokeResponse(ref mut resp) => &mut resp.header,
+            ResponseWrapper::AlarmResponse(ref mut resp) => &mut resp.header,
         };
         if let Some(ref mut header) = *header {
             header.revision = revision;
@@ -243,6 +255,8 @@ pub(crate) enum RequestBackend {
     Auth,
     /// Lease backend
     Lease,
+    /// Alarm backend
+    Alarm,
 }
 
 impl RequestWrapper {
@@ -274,6 +288,7 @@ impl RequestWrapper {
             RequestWrapper::LeaseGrantRequest(_) | RequestWrapper::LeaseRevokeRequest(_) => {
                 RequestBackend::Lease
             }
+            RequestWrapper::AlarmRequest(_) => RequestBackend::Alarm,
         }
     }
 
@@ -303,6 +318,34 @@ impl RequestWrapper {
     pub(crate) fn is_lease_request(&self) -> bool {
         self.backend() == RequestBackend::Lease
     }
+
+    /// Check if this request is an alarm request
+    pub(crate) fn is_alarm_request(&self) -> bool {
+        self.backend() == RequestBackend::Alarm
+    }
+
+    /// Check if this request may take more space of the backend, such requests are rejected
+    /// while the `NOSPACE` alarm is raised
+    pub(crate) fn consumes_space(&self) -> bool {
+        #[allow(clippy::wildcard_enum_match_arm)]
+        match *self {
+            RequestWrapper::PutRequest(_) | RequestWrapper::LeaseGrantRequest(_) => true,
+            RequestWrapper::TxnRequest(ref req) => !is_read_only_txn(req),
+            _ => false,
+        }
+    }
+}
+
+/// Check if all the operations of a txn are range requests
+fn is_read_only_txn(req: &TxnRequest) -> bool {
+    req.success
+        .iter()
+        .chain(req.failure.iter())
+        .all(|op| match op.request {
+            Some(Request::RequestRange(_)) | None => true,
+            Some(Request::RequestTxn(ref txn)) => is_read_only_txn(txn),
+            Some(Request::RequestPut(_) | Request::RequestDeleteRange(_)) => false,
+        })
 }
 
 /// impl `From` trait for all request types
@@ -373,7 +416,8 @@ impl_from_requests!(
     AuthUserRevokeRoleRequest,
     AuthenticateRequest,
     LeaseGrantRequest,
-    LeaseRevokeRequest
+    LeaseRevokeRequest,
+    AlarmRequest
 );
 
 impl_from_responses!(
@@ -400,7 +444,8 @@ impl_from_responses!(
     AuthUserRevokeRoleResponse,
     AuthenticateResponse,
     LeaseGrantResponse,
-    LeaseRevokeResponse
+    LeaseRevokeResponse,
+    AlarmResponse
 );
 
 impl From<RequestOp> for RequestWrapper {
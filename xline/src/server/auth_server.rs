@@ -7,7 +7,6 @@ use pbkdf2::{
 };
 use tonic::metadata::MetadataMap;
 use tracing::debug;
-use uuid::Uuid;
 
 use super::command::{Command, CommandResponse, SyncResponse};
 use crate::{
@@ -37,38 +36,50 @@ where
     storage: Arc<AuthStore<S>>,
     /// Consensus client
     client: Arc<Client<Command>>,
-    /// Server name
-    name: String,
 }
 
 /// Get token from metadata
-pub(crate) fn get_token(metadata: &MetadataMap) -> Option<String> {
+fn token_from_metadata(metadata: &MetadataMap) -> Option<String> {
     metadata
         .get("token")
         .or_else(|| metadata.get("authorization"))
         .and_then(|v| v.to_str().map(String::from).ok())
 }
 
+/// Get the common name of the verified certificate presented by the client
+fn common_name<T>(request: &tonic::Request<T>) -> Option<String> {
+    let certs = request.peer_certs()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(certs.first()?.get_ref()).ok()?;
+    let cn = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(cn.to_owned())
+}
+
+/// Get token of the request. Like etcd, if there is no token in the metadata but the client
+/// presents a verified certificate, the client is authenticated as the user named by the common
+/// name of the certificate
+pub(crate) fn get_token<T, S>(
+    request: &tonic::Request<T>,
+    auth_store: &AuthStore<S>,
+) -> Option<String>
+where
+    S: StorageApi,
+{
+    token_from_metadata(request.metadata())
+        .or_else(|| auth_store.assign_cert_user(&common_name(request)?))
+}
+
 impl<S> AuthServer<S>
 where
     S: StorageApi,
 {
     /// New `AuthServer`
-    pub(crate) fn new(
-        storage: Arc<AuthStore<S>>,
-        client: Arc<Client<Command>>,
-        name: String,
-    ) -> Self {
-        Self {
-            storage,
-            client,
-            name,
-        }
+    pub(crate) fn new(storage: Arc<AuthStore<S>>, client: Arc<Client<Command>>) -> Self {
+        Self { storage, client }
     }
 
     /// Generate propose id
     fn generate_propose_id(&self) -> ProposeId {
-        ProposeId::new(format!("{}-{}", self.name, Uuid::new_v4()))
+        self.client.gen_propose_id()
     }
 
     /// Generate `Command` proposal from `Request`
@@ -85,7 +96,7 @@ where
     where
         T: Into<RequestWrapper>,
     {
-        let wrapper = match get_token(request.metadata()) {
+        let wrapper = match get_token(&request, &self.storage) {
             Some(token) => RequestWithToken::new_with_token(request.into_inner().into(), token),
             None => RequestWithToken::new(request.into_inner().into()),
         };
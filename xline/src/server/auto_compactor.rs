@@ -0,0 +1,219 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clippy_utilities::OverflowArithmetic;
+use curp::{client::Client, cmd::AccessedKey};
+use tokio::time;
+use tracing::{debug, warn};
+use utils::config::AutoCompactConfig;
+
+use super::command::{Command, KeyRange};
+use crate::{
+    rpc::{CompactionRequest, RequestWithToken},
+    state::State,
+    storage::{storage_api::StorageApi, AuthStore, KvStore},
+};
+
+/// Interval between two checks of the revision auto compaction, the same as etcd
+const REVISION_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Minimum interval between two checks of the periodic auto compaction
+const MIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many revisions are sampled in a retention window of the periodic auto compaction
+const PERIODIC_SAMPLES_PER_RETENTION: u32 = 10;
+
+/// Revisions sampled over time, used to find the revision of a point in the past
+#[derive(Debug)]
+struct RevisionWindow {
+    /// How long the history is retained
+    retention: Duration,
+    /// Sampled revisions and the time they are sampled, the oldest sample is at the front
+    samples: VecDeque<(Instant, i64)>,
+}
+
+impl RevisionWindow {
+    /// New `RevisionWindow`
+    fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record the revision at `now`, return the latest revision that has been retained for
+    /// at least `retention`
+    fn sample(&mut self, now: Instant, revision: i64) -> Option<i64> {
+        self.samples.push_back((now, revision));
+        let mut expired = None;
+        while let Some(&(sampled_at, rev)) = self.samples.front() {
+            if now.saturating_duration_since(sampled_at) < self.retention {
+                break;
+            }
+            expired = Some(rev);
+            let _ignore = self.samples.pop_front();
+        }
+        expired
+    }
+
+    /// Drop all samples
+    fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+/// Policy to decide the revision to compact to
+#[derive(Debug)]
+enum CompactPolicy {
+    /// Compact to the revision sampled a retention ago
+    Periodic(RevisionWindow),
+    /// Compact to the revision that is the given number of revisions behind the current one
+    Revision(i64),
+}
+
+impl CompactPolicy {
+    /// Get the revision to compact to based on the current revision, return `None` if there
+    /// is nothing to compact
+    fn target(&mut self, revision: i64) -> Option<i64> {
+        match *self {
+            CompactPolicy::Periodic(ref mut window) => window.sample(Instant::now(), revision),
+            CompactPolicy::Revision(retained) => {
+                (revision > retained).then(|| revision.overflow_sub(retained))
+            }
+        }
+    }
+
+    /// Reset the policy when the current node is not the leader
+    fn reset(&mut self) {
+        if let CompactPolicy::Periodic(ref mut window) = *self {
+            window.clear();
+        }
+    }
+}
+
+/// Auto compactor, the leader periodically proposes compactions so that the kv history
+/// will not grow indefinitely
+#[derive(Debug)]
+pub(crate) struct AutoCompactor<S>
+where
+    S: StorageApi,
+{
+    /// Compaction policy
+    policy: CompactPolicy,
+    /// Interval between two checks
+    interval: Duration,
+    /// Kv storage
+    kv_storage: Arc<KvStore<S>>,
+    /// Auth storage
+    auth_storage: Arc<AuthStore<S>>,
+    /// Consensus client
+    client: Arc<Client<Command>>,
+    /// State of current node
+    state: Arc<State>,
+}
+
+impl<S> AutoCompactor<S>
+where
+    S: StorageApi,
+{
+    /// New `AutoCompactor`
+    pub(crate) fn new(
+        config: AutoCompactConfig,
+        kv_storage: Arc<KvStore<S>>,
+        auth_storage: Arc<AuthStore<S>>,
+        client: Arc<Client<Command>>,
+        state: Arc<State>,
+    ) -> Self {
+        #[allow(clippy::wildcard_enum_match_arm)] // `AutoCompactConfig` is non-exhaustive
+        let (policy, interval) = match config {
+            AutoCompactConfig::Periodic(retention) => (
+                CompactPolicy::Periodic(RevisionWindow::new(retention)),
+                (retention / PERIODIC_SAMPLES_PER_RETENTION).max(MIN_CHECK_INTERVAL),
+            ),
+            AutoCompactConfig::Revision(retention) => (
+                CompactPolicy::Revision(i64::try_from(retention).unwrap_or(i64::MAX)),
+                REVISION_CHECK_INTERVAL,
+            ),
+            _ => unreachable!("unknown auto compaction config: {config:?}"),
+        };
+        Self {
+            policy,
+            interval,
+            kv_storage,
+            auth_storage,
+            client,
+            state,
+        }
+    }
+
+    /// Run the auto compactor, only the leader will propose compactions
+    pub(crate) async fn run(mut self) {
+        loop {
+            if !self.state.is_leader() {
+                // revisions sampled in the previous term may be stale
+                self.policy.reset();
+                self.state.leader_listener().await;
+                continue;
+            }
+            let target = self.policy.target(self.kv_storage.revision());
+            if let Some(revision) = target.filter(|r| *r > self.kv_storage.compacted_revision()) {
+                self.compact(revision).await;
+            }
+            time::sleep(self.interval).await;
+        }
+    }
+
+    /// Propose a compaction to the given revision
+    async fn compact(&self, revision: i64) {
+        debug!("auto compact to revision {revision}");
+        let request = CompactionRequest {
+            revision,
+            physical: false,
+        };
+        let wrapper = match self.auth_storage.root_token() {
+            Ok(token) => RequestWithToken::new_with_token(request.into(), token),
+            Err(_) => RequestWithToken::new(request.into()),
+        };
+        let cmd = Command::new(
+            vec![AccessedKey::write(KeyRange::new(vec![0], vec![0]))],
+            wrapper,
+            self.client.gen_propose_id(),
+        );
+        if let Err(e) = self.client.propose(cmd).await {
+            warn!("failed to auto compact to revision {revision}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn revision_window_will_return_the_latest_expired_revision() {
+        let retention = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut window = RevisionWindow::new(retention);
+        assert_eq!(window.sample(start, 1), None);
+        assert_eq!(window.sample(start + Duration::from_secs(5), 5), None);
+        assert_eq!(window.sample(start + Duration::from_secs(8), 8), None);
+        assert_eq!(window.sample(start + retention, 10), Some(1));
+        assert_eq!(window.sample(start + Duration::from_secs(20), 20), Some(10));
+        assert_eq!(window.sample(start + Duration::from_secs(21), 21), None);
+        window.clear();
+        assert_eq!(window.sample(start + Duration::from_secs(40), 40), None);
+    }
+
+    #[test]
+    fn revision_policy_will_retain_the_latest_revisions() {
+        let mut policy = CompactPolicy::Revision(100);
+        assert_eq!(policy.target(150), Some(50));
+        assert_eq!(policy.target(101), Some(1));
+        assert_eq!(policy.target(100), None);
+        assert_eq!(policy.target(1), None);
+        assert_eq!(policy.target(i64::MIN), None);
+    }
+}
@@ -0,0 +1,15 @@
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+
+/// Connect to the xline server at `addr`, the connection uses tls if `tls_config` is set
+pub(super) async fn connect(
+    addr: &str,
+    tls_config: Option<&ClientTlsConfig>,
+) -> Result<Channel, tonic::transport::Error> {
+    let Some(tls_config) = tls_config else {
+        return Endpoint::from_shared(format!("http://{addr}"))?.connect().await;
+    };
+    Endpoint::from_shared(format!("https://{addr}"))?
+        .tls_config(tls_config.clone())?
+        .connect()
+        .await
+}
@@ -0,0 +1,280 @@
+use std::{hash::Hasher, sync::Arc};
+
+use curp::{
+    client::Client,
+    cmd::ProposeId,
+    error::ProposeError,
+    members::{ConfChange, Member as CurpMember},
+    server::Rpc,
+};
+use siphasher::sip::SipHasher24;
+use tracing::debug;
+use utils::address_from_url;
+
+use super::command::Command;
+use crate::{
+    header_gen::HeaderGenerator,
+    rpc::{
+        Cluster, Member, MemberAddRequest, MemberAddResponse, MemberListRequest,
+        MemberListResponse, MemberPromoteRequest, MemberPromoteResponse, MemberRemoveRequest,
+        MemberRemoveResponse, MemberUpdateRequest, MemberUpdateResponse,
+    },
+    state::State,
+};
+
+/// Keys of the hasher deriving member ids, they must never change, or the ids of the members
+/// will differ between servers of different versions
+const MEMBER_ID_KEYS: (u64, u64) = (0, 0);
+
+/// Get the member id of a server from its name, all servers derive the same id from the same name
+pub(crate) fn member_id(name: &str) -> u64 {
+    let mut hasher = SipHasher24::new_with_keys(MEMBER_ID_KEYS.0, MEMBER_ID_KEYS.1);
+    hasher.write(name.as_bytes());
+    hasher.finish()
+}
+
+/// Derive the name of a member added without a name from its peer address, the member adopts it
+/// on startup by finding its address in the members
+pub(crate) fn member_name(address: &str) -> String {
+    format!("member-{:016x}", member_id(address))
+}
+
+/// Cluster Server
+#[derive(Debug)]
+pub(crate) struct ClusterServer {
+    /// Consensus client
+    client: Arc<Client<Command>>,
+    /// Curp server, the source of the membership
+    curp_server: Rpc<Command>,
+    /// State of current node
+    state: Arc<State>,
+    /// Header generator
+    header_gen: Arc<HeaderGenerator>,
+    /// Peer urls advertised by the current node
+    peer_urls: Vec<String>,
+    /// Client urls advertised by the current node
+    client_urls: Vec<String>,
+}
+
+impl ClusterServer {
+    /// New `ClusterServer`
+    pub(crate) fn new(
+        client: Arc<Client<Command>>,
+        curp_server: Rpc<Command>,
+        state: Arc<State>,
+        header_gen: Arc<HeaderGenerator>,
+        peer_urls: Vec<String>,
+        client_urls: Vec<String>,
+    ) -> Self {
+        Self {
+            client,
+            curp_server,
+            state,
+            header_gen,
+            peer_urls,
+            client_urls,
+        }
+    }
+
+    /// Generate propose id
+    fn generate_propose_id(&self) -> ProposeId {
+        self.client.gen_propose_id()
+    }
+
+    /// Propose a membership change and wait for it to take effect on the leader
+    async fn propose_conf_change(&self, change: ConfChange) -> Result<(), tonic::Status> {
+        self.client
+            .propose_conf_change(self.generate_propose_id(), change)
+            .await
+            .map_err(|err| {
+                if let ProposeError::InvalidConfChange(e) = err {
+                    tonic::Status::failed_precondition(e)
+                } else {
+                    tonic::Status::internal(err.to_string())
+                }
+            })
+    }
+
+    /// Build a `Member` of another server from its name and curp membership, the client urls
+    /// are empty until the server publishes them
+    fn other_member(name: String, member: &CurpMember) -> Member {
+        Member {
+            id: member_id(&name),
+            name,
+            peer_ur_ls: vec![member.address().to_owned()],
+            client_ur_ls: member.client_urls().to_vec(),
+            is_learner: member.is_learner(),
+            is_witness: member.is_witness(),
+        }
+    }
+
+    /// All members known to the current node, including itself
+    fn members(&self) -> Vec<Member> {
+        let name = self.state.id().to_owned();
+        let this = Member {
+            id: member_id(&name),
+            name,
+            peer_ur_ls: self.peer_urls.clone(),
+            client_ur_ls: self.client_urls.clone(),
+            is_learner: self.curp_server.is_learner(),
+            is_witness: false,
+        };
+        self.curp_server
+            .others()
+            .into_iter()
+            .map(|(name, member)| Self::other_member(name, &member))
+            .chain([this])
+            .collect()
+    }
+
+    /// Find the name of a member by its member id
+    fn find_name(&self, id: u64) -> Result<String, tonic::Status> {
+        if member_id(self.state.id()) == id {
+            return Ok(self.state.id().to_owned());
+        }
+        self.curp_server
+            .others()
+            .into_keys()
+            .find(|name| member_id(name) == id)
+            .ok_or_else(|| tonic::Status::not_found(format!("member {id:x} not found")))
+    }
+}
+
+#[tonic::async_trait]
+impl Cluster for ClusterServer {
+    /// The new member is identified by its name, and the first peer url is used as its address.
+    /// The name of a member added without a name is derived from its address
+    async fn member_add(
+        &self,
+        request: tonic::Request<MemberAddRequest>,
+    ) -> Result<tonic::Response<MemberAddResponse>, tonic::Status> {
+        debug!("Receive MemberAddRequest {:?}", request);
+        let req = request.into_inner();
+        let Some(url) = req.peer_ur_ls.first() else {
+            return Err(tonic::Status::invalid_argument("member peerURLs are empty"));
+        };
+        let address = address_from_url(url);
+        let name = if req.name.is_empty() {
+            member_name(&address)
+        } else {
+            req.name
+        };
+        let change = if req.is_learner && req.is_witness {
+            return Err(tonic::Status::invalid_argument(
+                "a member can't be both a learner and a witness",
+            ));
+        } else if req.is_learner {
+            ConfChange::AddLearner {
+                id: name.clone(),
+                address: address.clone(),
+            }
+        } else if req.is_witness {
+            ConfChange::AddWitness {
+                id: name.clone(),
+                address: address.clone(),
+            }
+        } else {
+            ConfChange::AddNode {
+                id: name.clone(),
+                address: address.clone(),
+            }
+        };
+        self.propose_conf_change(change).await?;
+        let curp_member = if req.is_witness {
+            CurpMember::new_witness(address)
+        } else {
+            CurpMember::new(address, req.is_learner)
+        };
+        let member = Self::other_member(name, &curp_member);
+        let mut members = self.members();
+        // the change may not have taken effect on the current node yet
+        if members.iter().all(|m| m.id != member.id) {
+            members.push(member.clone());
+        }
+        Ok(tonic::Response::new(MemberAddResponse {
+            header: Some(self.header_gen.gen_header()),
+            member: Some(member),
+            members,
+        }))
+    }
+
+    async fn member_remove(
+        &self,
+        request: tonic::Request<MemberRemoveRequest>,
+    ) -> Result<tonic::Response<MemberRemoveResponse>, tonic::Status> {
+        debug!("Receive MemberRemoveRequest {:?}", request);
+        let name = self.find_name(request.into_inner().id)?;
+        self.propose_conf_change(ConfChange::RemoveNode { id: name })
+            .await?;
+        Ok(tonic::Response::new(MemberRemoveResponse {
+            header: Some(self.header_gen.gen_header()),
+            members: self.members(),
+        }))
+    }
+
+    async fn member_update(
+        &self,
+        request: tonic::Request<MemberUpdateRequest>,
+    ) -> Result<tonic::Response<MemberUpdateResponse>, tonic::Status> {
+        debug!("Receive MemberUpdateRequest {:?}", request);
+        let req = request.into_inner();
+        let name = self.find_name(req.id)?;
+        let Some(url) = req.peer_ur_ls.first() else {
+            return Err(tonic::Status::invalid_argument("member peerURLs are empty"));
+        };
+        self.propose_conf_change(ConfChange::UpdateNode {
+            id: name,
+            address: address_from_url(url),
+        })
+        .await?;
+        Ok(tonic::Response::new(MemberUpdateResponse {
+            header: Some(self.header_gen.gen_header()),
+            members: self.members(),
+        }))
+    }
+
+    /// The members are served from the view of the current node, `linearizable` is ignored
+    async fn member_list(
+        &self,
+        request: tonic::Request<MemberListRequest>,
+    ) -> Result<tonic::Response<MemberListResponse>, tonic::Status> {
+        debug!("Receive MemberListRequest {:?}", request);
+        Ok(tonic::Response::new(MemberListResponse {
+            header: Some(self.header_gen.gen_header()),
+            members: self.members(),
+        }))
+    }
+
+    async fn member_promote(
+        &self,
+        request: tonic::Request<MemberPromoteRequest>,
+    ) -> Result<tonic::Response<MemberPromoteResponse>, tonic::Status> {
+        debug!("Receive MemberPromoteRequest {:?}", request);
+        let name = self.find_name(request.into_inner().id)?;
+        self.propose_conf_change(ConfChange::PromoteLearner { id: name })
+            .await?;
+        Ok(tonic::Response::new(MemberPromoteResponse {
+            header: Some(self.header_gen.gen_header()),
+            members: self.members(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn member_id_is_stable() {
+        // the ids must be the same on all platforms and versions
+        assert_eq!(member_id("node1"), 0x5e9b_e232_75d9_08a8);
+        assert_eq!(member_id("node2"), 0xe7fe_c442_8b45_d188);
+    }
+
+    #[test]
+    fn member_name_is_derived_from_address() {
+        assert_eq!(member_name("127.0.0.1:2380"), member_name("127.0.0.1:2380"));
+        assert_ne!(member_name("127.0.0.1:2380"), member_name("127.0.0.1:2381"));
+        assert!(member_name("127.0.0.1:2380").starts_with("member-"));
+    }
+}
@@ -5,16 +5,25 @@ use std::{
 
 use curp::{
     cmd::{
-        Command as CurpCommand, CommandExecutor as CurpCommandExecutor, ConflictCheck, ProposeId,
+        AccessedKey, Command as CurpCommand, CommandExecutor as CurpCommandExecutor, ConflictCheck,
+        IntervalKey, ProposeId,
     },
+    snapshot::Snapshot,
     LogIndex,
 };
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use utils::interval_map::{Interval, UpperBound};
 
 use crate::{
-    rpc::{RequestBackend, RequestWithToken, RequestWrapper, ResponseWrapper},
-    storage::{db::WriteOp, storage_api::StorageApi, AuthStore, ExecuteError, KvStore, LeaseStore},
+    rpc::{
+        AlarmAction, AlarmRequest, AlarmType, Request, RequestBackend, RequestWithToken,
+        RequestWrapper, ResponseWrapper, TxnRequest,
+    },
+    storage::{
+        db::WriteOp, storage_api::StorageApi, AlarmStore, AuthStore, ExecuteError, KvStore,
+        LeaseStore,
+    },
 };
 
 /// Meta table name
@@ -167,6 +176,44 @@ impl RangeBounds<[u8]> for KeyRange {
     }
 }
 
+/// Get the keys accessed by a `TxnRequest`, the compared keys and the ranged keys are read
+/// while the put and deleted keys are written
+pub(crate) fn txn_keys(req: &TxnRequest) -> Vec<AccessedKey<KeyRange>> {
+    let mut keys: Vec<_> = req
+        .compare
+        .iter()
+        .map(|cmp| AccessedKey::read(KeyRange::new(cmp.key.as_slice(), cmp.range_end.as_slice())))
+        .collect();
+    for op in req.success.iter().chain(req.failure.iter()) {
+        match op.request {
+            Some(Request::RequestRange(ref r)) => keys.push(AccessedKey::read(KeyRange::new(
+                r.key.as_slice(),
+                r.range_end.as_slice(),
+            ))),
+            Some(Request::RequestPut(ref r)) => {
+                keys.push(AccessedKey::write(KeyRange::new(r.key.as_slice(), "")));
+            }
+            Some(Request::RequestDeleteRange(ref r)) => keys.push(AccessedKey::write(
+                KeyRange::new(r.key.as_slice(), r.range_end.as_slice()),
+            )),
+            Some(Request::RequestTxn(ref r)) => keys.extend(txn_keys(r)),
+            None => {}
+        }
+    }
+    keys
+}
+
+/// Get the keys accessed by an `AlarmRequest`. Alarms affect the requests on all keys, so an
+/// alarm request reads or writes all the keys to be ordered with the kv requests
+pub(crate) fn alarm_keys(req: &AlarmRequest) -> Vec<AccessedKey<KeyRange>> {
+    let all_keys = KeyRange::new(UNBOUNDED, UNBOUNDED);
+    if req.action == i32::from(AlarmAction::Get) {
+        vec![AccessedKey::read(all_keys)]
+    } else {
+        vec![AccessedKey::write(all_keys)]
+    }
+}
+
 /// Command Executor
 #[derive(Debug, Clone)]
 pub(crate) struct CommandExecutor<S>
@@ -179,6 +226,8 @@ where
     auth_storage: Arc<AuthStore<S>>,
     /// Lease Storage
     lease_storage: Arc<LeaseStore<S>>,
+    /// Alarm Storage
+    alarm_storage: Arc<AlarmStore<S>>,
     /// persistent storage
     persistent: Arc<S>,
 }
@@ -192,12 +241,14 @@ where
         kv_storage: Arc<KvStore<S>>,
         auth_storage: Arc<AuthStore<S>>,
         lease_storage: Arc<LeaseStore<S>>,
+        alarm_storage: Arc<AlarmStore<S>>,
         persistent: Arc<S>,
     ) -> Self {
         Self {
             kv_storage,
             auth_storage,
             lease_storage,
+            alarm_storage,
             persistent,
         }
     }
@@ -213,10 +264,15 @@ where
     async fn execute(&self, cmd: &Command) -> Result<CommandResponse, ExecuteError> {
         let wrapper = cmd.request();
         self.auth_storage.check_permission(wrapper).await?;
+        // a failed command is not after synced, so the rejected request takes no space
+        if wrapper.request.consumes_space() && self.alarm_storage.is_raised(AlarmType::Nospace) {
+            return Err(ExecuteError::no_space());
+        }
         match wrapper.request.backend() {
             RequestBackend::Kv => self.kv_storage.execute(wrapper),
             RequestBackend::Auth => self.auth_storage.execute(wrapper),
             RequestBackend::Lease => self.lease_storage.execute(wrapper),
+            RequestBackend::Alarm => self.alarm_storage.execute(wrapper),
         }
     }
 
@@ -235,15 +291,27 @@ where
             RequestBackend::Kv => self.kv_storage.after_sync(id, wrapper).await?,
             RequestBackend::Auth => self.auth_storage.after_sync(id, wrapper)?,
             RequestBackend::Lease => self.lease_storage.after_sync(id, wrapper).await?,
+            RequestBackend::Alarm => self.alarm_storage.after_sync(id, wrapper),
         };
         self.persistent.flush(id)?;
         Ok(res)
     }
 
-    async fn reset(&self) {
-        self.persistent
-            .reset()
-            .unwrap_or_else(|e| panic!("reset backend failed, {e:?}"));
+    async fn reset(&self, snapshot: Option<&Snapshot>) -> Result<(), ExecuteError> {
+        if let Some(snapshot) = snapshot {
+            self.persistent.restore(snapshot.data())?;
+        } else {
+            self.persistent.reset()?;
+        }
+        // the in-memory states are rebuilt from the storage, lease storage must recover before kv storage
+        self.lease_storage.recover()?;
+        self.kv_storage.recover().await?;
+        self.auth_storage.recover()?;
+        self.alarm_storage.recover()
+    }
+
+    async fn snapshot(&self) -> Result<Vec<u8>, ExecuteError> {
+        self.persistent.snapshot()
     }
 
     fn last_applied(&self) -> Result<LogIndex, ExecuteError> {
@@ -261,7 +329,7 @@ where
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct Command {
     /// Keys of request
-    keys: Vec<KeyRange>,
+    keys: Vec<AccessedKey<KeyRange>>,
     /// Request data
     request: RequestWithToken,
     /// Propose id
@@ -288,6 +356,17 @@ impl ConflictCheck for Command {
             return true;
         }
 
+        // alarm requests are ordered with the kv requests by their keys, lease grants are
+        // rejected while the `NOSPACE` alarm is raised so they are ordered with alarm requests too
+        let is_lease_grant =
+            |req: &RequestWrapper| matches!(*req, RequestWrapper::LeaseGrantRequest(_));
+        if (this_req.is_alarm_request()
+            && (other_req.is_alarm_request() || is_lease_grant(other_req)))
+            || (other_req.is_alarm_request() && is_lease_grant(this_req))
+        {
+            return true;
+        }
+
         if (this_req.is_lease_request()) && (other_req.is_lease_request()) {
             #[allow(clippy::wildcard_enum_match_arm)]
             let lease_id1 = match *this_req {
@@ -309,7 +388,7 @@ impl ConflictCheck for Command {
         self.keys()
             .iter()
             .cartesian_product(other.keys().iter())
-            .any(|(k1, k2)| k1.is_conflicted(k2))
+            .any(|(k1, k2)| k1.is_conflict(k2))
     }
 }
 
@@ -319,9 +398,31 @@ impl ConflictCheck for KeyRange {
     }
 }
 
+impl IntervalKey for KeyRange {
+    type Point = Vec<u8>;
+
+    /// The excluded end is included in the interval, which may only report some extra candidates
+    fn interval(&self) -> Interval<Self::Point> {
+        let low = match self.start_bound() {
+            Bound::Included(start) => start.to_vec(),
+            Bound::Excluded(_) | Bound::Unbounded => vec![],
+        };
+        let high = match self.end_bound() {
+            Bound::Included(end) | Bound::Excluded(end) => UpperBound::Included(end.to_vec()),
+            Bound::Unbounded => UpperBound::Unbounded,
+        };
+        // a range that ends before its start can only conflict with the ranges covering its start
+        Interval::new(low.clone(), high).unwrap_or_else(|| Interval::starting_at(low))
+    }
+}
+
 impl Command {
     /// New `Command`
-    pub(crate) fn new(keys: Vec<KeyRange>, request: RequestWithToken, id: ProposeId) -> Self {
+    pub(crate) fn new(
+        keys: Vec<AccessedKey<KeyRange>>,
+        request: RequestWithToken,
+        id: ProposeId,
+    ) -> Self {
         Self { keys, request, id }
     }
 
@@ -370,7 +471,7 @@ impl SyncResponse {
 
 #[async_trait::async_trait]
 impl CurpCommand for Command {
-    type K = KeyRange;
+    type K = AccessedKey<KeyRange>;
     type ER = CommandResponse;
     type ASR = SyncResponse;
 
@@ -381,4 +482,258 @@ impl CurpCommand for Command {
     fn id(&self) -> &ProposeId {
         &self.id
     }
+
+    /// Kv requests only conflict with each other through their keys
+    fn is_keyed(&self) -> bool {
+        self.request.request.is_kv_request() && !self.keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use curp::snapshot::SnapshotMeta;
+    use tokio::sync::mpsc;
+    use utils::config::StorageConfig;
+
+    use super::*;
+    use crate::{
+        header_gen::HeaderGenerator,
+        rpc::{Compare, LeaseGrantRequest, PutRequest, RangeRequest, RequestOp},
+        state::State,
+        storage::{db::DBProxy, index::Index},
+    };
+
+    fn command(keys: Vec<AccessedKey<KeyRange>>, request: RequestWrapper, id: &str) -> Command {
+        Command::new(
+            keys,
+            RequestWithToken::new(request),
+            ProposeId::new(id.to_owned()),
+        )
+    }
+
+    fn init_executor() -> CommandExecutor<DBProxy> {
+        let db = DBProxy::open(&StorageConfig::Memory).unwrap();
+        let header_gen = Arc::new(HeaderGenerator::new(0, 0));
+        let index = Arc::new(Index::new());
+        let (lease_cmd_tx, lease_cmd_rx) = mpsc::channel(128);
+        let kv_storage = Arc::new(KvStore::new(
+            lease_cmd_tx.clone(),
+            Arc::clone(&header_gen),
+            Arc::clone(&db),
+            Arc::clone(&index),
+        ));
+        let lease_storage = Arc::new(LeaseStore::new(
+            lease_cmd_rx,
+            Arc::new(State::default()),
+            Arc::clone(&header_gen),
+            Arc::clone(&db),
+            index,
+            kv_storage.kv_update_tx(),
+        ));
+        let auth_storage = Arc::new(AuthStore::new(
+            lease_cmd_tx,
+            None,
+            Arc::clone(&header_gen),
+            Arc::clone(&db),
+        ));
+        let alarm_storage = Arc::new(AlarmStore::new(header_gen, Arc::clone(&db)));
+        CommandExecutor::new(kv_storage, auth_storage, lease_storage, alarm_storage, db)
+    }
+
+    async fn exe_and_sync(
+        ce: &CommandExecutor<DBProxy>,
+        request: RequestWrapper,
+        index: LogIndex,
+    ) -> ResponseWrapper {
+        let cmd = command(vec![], request, &format!("cmd-{index}"));
+        let res = ce.execute(&cmd).await.unwrap();
+        let _ig = ce.after_sync(&cmd, index).await.unwrap();
+        res.decode()
+    }
+
+    fn put(key: &str, value: &str) -> RequestWrapper {
+        PutRequest {
+            key: key.into(),
+            value: value.into(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn range_all() -> RequestWrapper {
+        RangeRequest {
+            key: vec![0],
+            range_end: vec![0],
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn nospace_alarm(action: AlarmAction) -> AlarmRequest {
+        AlarmRequest {
+            action: action.into(),
+            member_id: 1,
+            alarm: AlarmType::Nospace.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reset_to_snapshot_will_rebuild_the_stores() {
+        let leader = init_executor();
+        exe_and_sync(&leader, put("a", "1"), 1).await;
+        exe_and_sync(&leader, put("b", "2"), 2).await;
+        let data = leader.snapshot().await.unwrap();
+
+        // the follower has diverged from the snapshot
+        let follower = init_executor();
+        exe_and_sync(&follower, put("a", "stale"), 1).await;
+        exe_and_sync(&follower, put("c", "3"), 2).await;
+        exe_and_sync(&follower, put("c", "4"), 3).await;
+
+        let meta = SnapshotMeta {
+            last_included_index: 2,
+            last_included_term: 1,
+        };
+        follower
+            .reset(Some(&Snapshot::new(meta, data)))
+            .await
+            .unwrap();
+        assert_eq!(follower.last_applied().unwrap(), 2);
+        assert_eq!(
+            exe_and_sync(&follower, range_all(), 3).await,
+            exe_and_sync(&leader, range_all(), 3).await
+        );
+
+        // later writes continue from the revision of the snapshot
+        let ResponseWrapper::PutResponse(resp) = exe_and_sync(&follower, put("b", "5"), 4).await
+        else {
+            panic!("unexpected response");
+        };
+        assert_eq!(resp.header.unwrap().revision, 4);
+        let ResponseWrapper::RangeResponse(resp) = exe_and_sync(&follower, range_all(), 5).await
+        else {
+            panic!("unexpected response");
+        };
+        let kvs = resp
+            .kvs
+            .iter()
+            .map(|kv| (kv.key.as_slice(), kv.version, kv.mod_revision))
+            .collect_vec();
+        assert_eq!(kvs, vec![(b"a".as_slice(), 1, 2), (b"b".as_slice(), 2, 4)]);
+    }
+
+    #[tokio::test]
+    async fn writes_will_be_rejected_while_nospace_alarm_is_raised() {
+        let ce = init_executor();
+        exe_and_sync(&ce, put("a", "1"), 1).await;
+        exe_and_sync(&ce, nospace_alarm(AlarmAction::Activate).into(), 2).await;
+
+        let put_cmd = command(vec![], put("a", "2"), "put");
+        assert!(ce.execute(&put_cmd).await.is_err());
+        let grant_cmd = command(vec![], LeaseGrantRequest { ttl: 10, id: 1 }.into(), "grant");
+        assert!(ce.execute(&grant_cmd).await.is_err());
+        let ResponseWrapper::RangeResponse(resp) = exe_and_sync(&ce, range_all(), 3).await else {
+            panic!("unexpected response");
+        };
+        assert_eq!(resp.kvs[0].value, b"1");
+
+        exe_and_sync(&ce, nospace_alarm(AlarmAction::Deactivate).into(), 4).await;
+        assert!(ce.execute(&put_cmd).await.is_ok());
+    }
+
+    #[test]
+    fn alarm_requests_will_conflict_with_writes_and_lease_grants() {
+        let alarm = |action, id| {
+            let req = nospace_alarm(action);
+            command(alarm_keys(&req), req.into(), id)
+        };
+        let activate = alarm(AlarmAction::Activate, "activate");
+        let get = alarm(AlarmAction::Get, "get");
+        let put_cmd = command(
+            vec![AccessedKey::write(KeyRange::new("a", ""))],
+            put("a", "1"),
+            "put",
+        );
+        let range_cmd = command(
+            vec![AccessedKey::read(KeyRange::new("a", ""))],
+            RangeRequest::default().into(),
+            "range",
+        );
+        let grant = command(vec![], LeaseGrantRequest::default().into(), "grant");
+        assert!(activate.is_conflict(&put_cmd));
+        assert!(put_cmd.is_conflict(&activate));
+        assert!(activate.is_conflict(&range_cmd));
+        assert!(activate.is_conflict(&grant));
+        assert!(grant.is_conflict(&activate));
+        assert!(activate.is_conflict(&get));
+        assert!(!get.is_conflict(&range_cmd));
+    }
+
+    #[test]
+    fn reads_of_the_same_key_will_not_conflict() {
+        let range = |id| {
+            command(
+                vec![AccessedKey::read(KeyRange::new("a", "c"))],
+                RangeRequest::default().into(),
+                id,
+            )
+        };
+        let put = command(
+            vec![AccessedKey::write(KeyRange::new("b", ""))],
+            PutRequest::default().into(),
+            "put",
+        );
+        assert!(!range("range1").is_conflict(&range("range2")));
+        assert!(range("range1").is_conflict(&put));
+        assert!(put.is_conflict(&range("range2")));
+        assert!(put.is_conflict(&put));
+    }
+
+    #[test]
+    fn overlapping_key_ranges_should_have_overlapping_intervals() {
+        let ranges = [
+            KeyRange::new("a", ""),
+            KeyRange::new("a", "c"),
+            KeyRange::new("b", "c"),
+            KeyRange::new("c", ""),
+            KeyRange::new("c", vec![0]),
+            KeyRange::new(vec![0], vec![0]),
+            KeyRange::new("d", "b"),
+        ];
+        for (r1, r2) in ranges.iter().cartesian_product(ranges.iter()) {
+            if r1.is_conflicted(r2) {
+                assert!(r1.interval().overlaps(&r2.interval()), "{r1:?} {r2:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn txn_keys_will_write_the_put_keys() {
+        let txn = TxnRequest {
+            compare: vec![Compare {
+                key: b"a".to_vec(),
+                ..Default::default()
+            }],
+            success: vec![RequestOp {
+                request: Some(Request::RequestPut(PutRequest {
+                    key: b"b".to_vec(),
+                    ..Default::default()
+                })),
+            }],
+            failure: vec![RequestOp {
+                request: Some(Request::RequestRange(RangeRequest {
+                    key: b"c".to_vec(),
+                    ..Default::default()
+                })),
+            }],
+        };
+        assert_eq!(
+            txn_keys(&txn),
+            vec![
+                AccessedKey::read(KeyRange::new("a", "")),
+                AccessedKey::write(KeyRange::new("b", "")),
+                AccessedKey::read(KeyRange::new("c", "")),
+            ]
+        );
+    }
 }
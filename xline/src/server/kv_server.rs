@@ -1,22 +1,25 @@
 use std::{collections::HashSet, fmt::Debug, sync::Arc};
 
-use curp::{client::Client, cmd::ProposeId, error::ProposeError};
+use curp::{
+    client::Client,
+    cmd::{AccessedKey, ProposeId},
+    error::ProposeError,
+    server::Rpc,
+};
 use tracing::{debug, instrument};
-use uuid::Uuid;
 
 use super::{
     auth_server::get_token,
-    command::{Command, CommandResponse, KeyRange, SyncResponse},
+    command::{txn_keys, Command, CommandResponse, KeyRange, SyncResponse},
+    quota_checker::QuotaChecker,
 };
 use crate::{
     rpc::{
         CompactionRequest, CompactionResponse, DeleteRangeRequest, DeleteRangeResponse, Kv,
-        KvClient, PutRequest, PutResponse, RangeRequest, RangeResponse, Request, RequestOp,
-        RequestWithToken, RequestWrapper, Response, ResponseOp, SortOrder, SortTarget, TxnRequest,
-        TxnResponse,
+        PutRequest, PutResponse, RangeRequest, RangeResponse, Request, RequestOp, RequestWithToken,
+        RequestWrapper, Response, ResponseOp, SortOrder, SortTarget, TxnRequest, TxnResponse,
     },
-    state::State,
-    storage::{storage_api::StorageApi, AuthStore, KvStore},
+    storage::{execute_error::execution_error_status, storage_api::StorageApi, AuthStore, KvStore},
 };
 
 /// Default max txn ops
@@ -32,12 +35,12 @@ where
     kv_storage: Arc<KvStore<S>>,
     /// Auth storage
     auth_storage: Arc<AuthStore<S>>,
+    /// Quota checker of the backend
+    quota_checker: Arc<QuotaChecker<S>>,
     /// Consensus client
     client: Arc<Client<Command>>,
-    /// Server name
-    name: String,
-    /// State of current node
-    state: Arc<State>,
+    /// Curp server
+    curp_server: Rpc<Command>,
 }
 
 impl<S> KvServer<S>
@@ -48,16 +51,16 @@ where
     pub(crate) fn new(
         kv_storage: Arc<KvStore<S>>,
         auth_storage: Arc<AuthStore<S>>,
-        state: Arc<State>,
+        quota_checker: Arc<QuotaChecker<S>>,
         client: Arc<Client<Command>>,
-        name: String,
+        curp_server: Rpc<Command>,
     ) -> Self {
         Self {
             kv_storage,
             auth_storage,
+            quota_checker,
             client,
-            name,
-            state,
+            curp_server,
         }
     }
 
@@ -74,26 +77,23 @@ where
     fn command_from_request_wrapper(propose_id: ProposeId, wrapper: RequestWithToken) -> Command {
         #[allow(clippy::wildcard_enum_match_arm)]
         let key_ranges = match wrapper.request {
-            RequestWrapper::RangeRequest(ref req) => vec![KeyRange {
+            RequestWrapper::RangeRequest(ref req) => vec![AccessedKey::read(KeyRange {
                 start: req.key.clone(),
                 end: req.range_end.clone(),
-            }],
-            RequestWrapper::PutRequest(ref req) => vec![KeyRange {
+            })],
+            RequestWrapper::PutRequest(ref req) => vec![AccessedKey::write(KeyRange {
                 start: req.key.clone(),
                 end: vec![],
-            }],
-            RequestWrapper::DeleteRangeRequest(ref req) => vec![KeyRange {
+            })],
+            RequestWrapper::DeleteRangeRequest(ref req) => vec![AccessedKey::write(KeyRange {
                 start: req.key.clone(),
                 end: req.range_end.clone(),
-            }],
-            RequestWrapper::TxnRequest(ref req) => req
-                .compare
-                .iter()
-                .map(|cmp| KeyRange {
-                    start: cmp.key.clone(),
-                    end: cmp.range_end.clone(),
-                })
-                .collect(),
+            })],
+            RequestWrapper::TxnRequest(ref req) => txn_keys(req),
+            // compaction affects all the keys
+            RequestWrapper::CompactionRequest(_) => {
+                vec![AccessedKey::write(KeyRange::new(vec![0], vec![0]))]
+            }
             _ => unreachable!("Other request should not be sent to this store"),
         };
         Command::new(key_ranges, wrapper, propose_id)
@@ -104,7 +104,7 @@ where
         &self,
         request: tonic::Request<RangeRequest>,
     ) -> Result<tonic::Response<RangeResponse>, tonic::Status> {
-        let wrapper = match get_token(request.metadata()) {
+        let wrapper = match get_token(&request, &self.auth_storage) {
             Some(token) => RequestWithToken::new_with_token(request.into_inner().into(), token),
             None => RequestWithToken::new(request.into_inner().into()),
         };
@@ -115,7 +115,7 @@ where
         let cmd_res = self
             .kv_storage
             .execute(&wrapper)
-            .map_err(|e| tonic::Status::internal(format!("Execute failed: {e:?}")))?;
+            .map_err(tonic::Status::from)?;
         let res = Self::parse_response_op(cmd_res.decode().into());
         if let Response::ResponseRange(response) = res {
             Ok(tonic::Response::new(response))
@@ -134,16 +134,17 @@ where
     where
         T: Into<RequestWrapper> + Debug,
     {
-        let wrapper = match get_token(request.metadata()) {
+        let wrapper = match get_token(&request, &self.auth_storage) {
             Some(token) => RequestWithToken::new_with_token(request.into_inner().into(), token),
             None => RequestWithToken::new(request.into_inner().into()),
         };
+        self.quota_checker.check(&wrapper.request).await?;
         let propose_id = self.generate_propose_id();
         let cmd = Self::command_from_request_wrapper(propose_id, wrapper);
         if use_fast_path {
             let cmd_res = self.client.propose(cmd).await.map_err(|err| {
                 if let ProposeError::ExecutionError(e) = err {
-                    tonic::Status::invalid_argument(e)
+                    execution_error_status(e)
                 } else {
                     panic!("propose err {err:?}")
                 }
@@ -152,7 +153,7 @@ where
         } else {
             let (cmd_res, sync_res) = self.client.propose_indexed(cmd).await.map_err(|err| {
                 if let ProposeError::ExecutionError(e) = err {
-                    tonic::Status::invalid_argument(e)
+                    execution_error_status(e)
                 } else {
                     panic!("propose err {err:?}")
                 }
@@ -194,7 +195,7 @@ where
 
     /// Generate propose id
     fn generate_propose_id(&self) -> ProposeId {
-        ProposeId::new(format!("{}-{}", self.name, Uuid::new_v4()))
+        self.client.gen_propose_id()
     }
 
     /// Validate range request before handle
@@ -345,11 +346,6 @@ where
         }
         Ok((puts, dels))
     }
-
-    /// Check if the current node is leader
-    fn is_leader(&self) -> bool {
-        self.state.is_leader()
-    }
 }
 
 #[tonic::async_trait]
@@ -366,15 +362,18 @@ where
         debug!("Receive RangeRequest {:?}", request);
         let range_req = request.get_ref();
         Self::check_range_request(range_req)?;
-        if range_req.serializable || self.is_leader() {
-            self.serializable_range(request).await
-        } else {
-            let leader_addr = self.state.wait_leader().await?;
-            let mut kv_client = KvClient::connect(format!("http://{leader_addr}"))
-                .await
-                .map_err(|e| tonic::Status::internal(format!("Connect to leader error: {e}")))?;
-            kv_client.range(request).await
+        if !range_req.serializable {
+            // a linearizable read is served locally once the read index has been applied
+            #[allow(clippy::wildcard_enum_match_arm)] // `ProposeError` is non-exhaustive
+            let map_err = |err: ProposeError| match err {
+                ProposeError::NotLeader | ProposeError::ReadIndex(_) => {
+                    tonic::Status::unavailable(err.to_string())
+                }
+                _ => tonic::Status::internal(err.to_string()),
+            };
+            self.curp_server.read_index().await.map_err(map_err)?;
         }
+        self.serializable_range(request).await
     }
 
     /// Put puts the given key into the key-value store.
@@ -465,10 +464,17 @@ where
         request: tonic::Request<CompactionRequest>,
     ) -> Result<tonic::Response<CompactionResponse>, tonic::Status> {
         debug!("Receive CompactionRequest {:?}", request);
-        Err(tonic::Status::new(
-            tonic::Code::Unimplemented,
-            "Not Implemented".to_owned(),
-        ))
+        // a physical compaction returns after the compacted revisions are removed from storage
+        let is_fast_path = !request.get_ref().physical;
+        let (cmd_res, sync_res) = self.propose(request, is_fast_path).await?;
+
+        let mut res = cmd_res.decode();
+        if let Some(sync_res) = sync_res {
+            let revision = sync_res.revision();
+            debug!("Get revision {:?} for CompactionRequest", revision);
+            res.update_revision(revision);
+        }
+        Ok(tonic::Response::new(res.into()))
     }
 }
 
@@ -1,15 +1,21 @@
 use std::{sync::Arc, time::Duration};
 
 use clippy_utilities::Cast;
-use curp::{client::Client, cmd::ProposeId, error::ProposeError};
+use curp::{
+    client::Client,
+    cmd::{AccessedKey, ProposeId},
+    error::ProposeError,
+};
 use tokio::{sync::mpsc, time};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::transport::ClientTlsConfig;
 use tracing::{debug, info, warn};
-use uuid::Uuid;
 
 use super::{
     auth_server::get_token,
+    channel,
     command::{Command, CommandResponse, KeyRange, SyncResponse},
+    quota_checker::QuotaChecker,
 };
 use crate::{
     id_gen::IdGenerator,
@@ -38,14 +44,16 @@ where
     lease_storage: Arc<LeaseStore<S>>,
     /// Auth storage
     auth_storage: Arc<AuthStore<S>>,
+    /// Quota checker of the backend
+    quota_checker: Arc<QuotaChecker<S>>,
     /// Consensus client
     client: Arc<Client<Command>>,
-    /// Server name
-    name: String,
     /// State of current node
     state: Arc<State>,
     /// Id generator
     id_gen: Arc<IdGenerator>,
+    /// The tls config used to connect to the leader
+    tls_config: Option<ClientTlsConfig>,
 }
 
 impl<S> LeaseServer<S>
@@ -56,18 +64,20 @@ where
     pub(crate) fn new(
         lease_storage: Arc<LeaseStore<S>>,
         auth_storage: Arc<AuthStore<S>>,
+        quota_checker: Arc<QuotaChecker<S>>,
         client: Arc<Client<Command>>,
-        name: String,
         state: Arc<State>,
         id_gen: Arc<IdGenerator>,
+        tls_config: Option<ClientTlsConfig>,
     ) -> Arc<Self> {
         let lease_server = Arc::new(Self {
             lease_storage,
             auth_storage,
+            quota_checker,
             client,
-            name,
             state,
             id_gen,
+            tls_config,
         });
         let _h = tokio::spawn(Self::revoke_expired_leases_task(Arc::clone(&lease_server)));
         lease_server
@@ -109,7 +119,7 @@ where
 
     /// Generate propose id
     fn generate_propose_id(&self) -> ProposeId {
-        ProposeId::new(format!("{}-{}", self.name, Uuid::new_v4()))
+        self.client.gen_propose_id()
     }
 
     /// Generate `Command` proposal from `Request`
@@ -122,7 +132,7 @@ where
             self.lease_storage
                 .get_keys(req.id)
                 .into_iter()
-                .map(|k| KeyRange::new(k, ""))
+                .map(|k| AccessedKey::write(KeyRange::new(k, "")))
                 .collect()
         } else {
             vec![]
@@ -144,10 +154,11 @@ where
     where
         T: Into<RequestWrapper>,
     {
-        let wrapper = match get_token(request.metadata()) {
+        let wrapper = match get_token(&request, &self.auth_storage) {
             Some(token) => RequestWithToken::new_with_token(request.into_inner().into(), token),
             None => RequestWithToken::new(request.into_inner().into()),
         };
+        self.quota_checker.check(&wrapper.request).await?;
         let propose_id = self.generate_propose_id();
         let cmd = self.command_from_request_wrapper(propose_id, wrapper);
         if use_fast_path {
@@ -220,9 +231,10 @@ where
     ) -> Result<ReceiverStream<Result<LeaseKeepAliveResponse, tonic::Status>>, tonic::Status> {
         // TODO: refactor stream forward in a easy way
         let leader_addr = self.state.wait_leader().await?;
-        let mut lease_client = LeaseClient::connect(format!("http://{leader_addr}"))
+        let channel = channel::connect(leader_addr.as_str(), self.tls_config.as_ref())
             .await
             .map_err(|_e| tonic::Status::internal("Connect to leader error: {e}"))?;
+        let mut lease_client = LeaseClient::new(channel);
 
         let (request_tx, request_rx) = mpsc::channel(CHANNEL_SIZE);
         let (response_tx, response_rx) = mpsc::channel(CHANNEL_SIZE);
@@ -348,9 +360,10 @@ where
             Ok(tonic::Response::new(res))
         } else {
             let leader_addr = self.state.wait_leader().await?;
-            let mut lease_client = LeaseClient::connect(format!("http://{leader_addr}"))
+            let channel = channel::connect(leader_addr.as_str(), self.tls_config.as_ref())
                 .await
                 .map_err(|e| tonic::Status::internal(format!("Connect to leader error: {e}")))?;
+            let mut lease_client = LeaseClient::new(channel);
             lease_client.lease_time_to_live(request).await
         }
     }
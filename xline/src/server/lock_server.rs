@@ -5,17 +5,22 @@ use std::{
 };
 
 use clippy_utilities::{Cast, OverflowArithmetic};
-use curp::{client::Client, cmd::ProposeId, error::ProposeError};
+use curp::{
+    client::Client,
+    cmd::{AccessedKey, ProposeId},
+    error::ProposeError,
+};
 use etcd_client::{EventType, WatchOptions};
 use parking_lot::Mutex;
 use tokio::{sync::mpsc, time::Duration};
 use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::ClientTlsConfig;
 use tracing::debug;
-use uuid::Uuid;
 
 use super::{
     auth_server::get_token,
-    command::{Command, CommandResponse, KeyRange, SyncResponse},
+    channel,
+    command::{txn_keys, Command, CommandResponse, KeyRange, SyncResponse},
     kv_server::KvServer,
 };
 use crate::{
@@ -27,8 +32,7 @@ use crate::{
         RequestWrapper, Response, ResponseHeader, SortOrder, SortTarget, TargetUnion, TxnRequest,
         TxnResponse, UnlockRequest, UnlockResponse, WatchClient, WatchCreateRequest, WatchRequest,
     },
-    state::State,
-    storage::{storage_api::StorageApi, KvStore},
+    storage::{storage_api::StorageApi, AuthStore, KvStore},
 };
 
 /// Default session ttl
@@ -42,12 +46,14 @@ where
 {
     /// KV storage
     storage: Arc<KvStore<S>>,
+    /// Auth storage
+    auth_storage: Arc<AuthStore<S>>,
     /// Consensus client
     client: Arc<Client<Command>>,
-    /// State of current node
-    state: Arc<State>,
-    /// Server name
-    name: String,
+    /// Address of the client services of the current node, keys are watched through it
+    client_address: String,
+    /// The tls config used to connect to the servers
+    tls_config: Option<ClientTlsConfig>,
 }
 
 impl<S> LockServer<S>
@@ -57,21 +63,23 @@ where
     /// New `LockServer`
     pub(crate) fn new(
         storage: Arc<KvStore<S>>,
+        auth_storage: Arc<AuthStore<S>>,
         client: Arc<Client<Command>>,
-        state: Arc<State>,
-        name: String,
+        client_address: String,
+        tls_config: Option<ClientTlsConfig>,
     ) -> Self {
         Self {
             storage,
+            auth_storage,
             client,
-            state,
-            name,
+            client_address,
+            tls_config,
         }
     }
 
     /// Generate propose id
     fn generate_propose_id(&self) -> ProposeId {
-        ProposeId::new(format!("{}-{}", self.name, Uuid::new_v4()))
+        self.client.gen_propose_id()
     }
 
     /// Generate `Command` proposal from `Request`
@@ -79,16 +87,15 @@ where
         #[allow(clippy::wildcard_enum_match_arm)]
         let keys = match wrapper.request {
             RequestWrapper::DeleteRangeRequest(ref req) => {
-                vec![KeyRange::new(req.key.as_slice(), "")]
+                vec![AccessedKey::write(KeyRange::new(req.key.as_slice(), ""))]
             }
             RequestWrapper::RangeRequest(ref req) => {
-                vec![KeyRange::new(req.key.as_slice(), req.range_end.as_slice())]
+                vec![AccessedKey::read(KeyRange::new(
+                    req.key.as_slice(),
+                    req.range_end.as_slice(),
+                ))]
             }
-            RequestWrapper::TxnRequest(ref req) => req
-                .compare
-                .iter()
-                .map(|cmp| KeyRange::new(cmp.key.as_slice(), cmp.range_end.as_slice()))
-                .collect(),
+            RequestWrapper::TxnRequest(ref req) => txn_keys(req),
             _ => vec![],
         };
         Command::new(keys, wrapper, propose_id)
@@ -183,10 +190,10 @@ where
         token: Option<&String>,
     ) -> Result<(), tonic::Status> {
         let rev = my_rev.overflow_sub(1);
-        let self_addr = self.state.self_address();
-        let mut watch_client = WatchClient::connect(format!("http://{self_addr}"))
+        let channel = channel::connect(&self.client_address, self.tls_config.as_ref())
             .await
             .map_err(|e| tonic::Status::internal(format!("Connect error: {e}")))?;
+        let mut watch_client = WatchClient::new(channel);
         loop {
             let range_end = KeyRange::get_prefix(pfx.as_bytes());
             #[allow(clippy::as_conversions)] // this cast is always safe
@@ -283,7 +290,7 @@ where
         request: tonic::Request<LockRequest>,
     ) -> Result<tonic::Response<LockResponse>, tonic::Status> {
         debug!("Receive LockRequest {:?}", request);
-        let token = get_token(request.metadata());
+        let token = get_token(&request, &self.auth_storage);
         let lock_req = request.into_inner();
         let lease_id = if lock_req.lease == 0 {
             self.lease_grant(token.clone()).await?
@@ -357,7 +364,7 @@ where
         request: tonic::Request<UnlockRequest>,
     ) -> Result<tonic::Response<UnlockResponse>, tonic::Status> {
         debug!("Receive UnlockRequest {:?}", request);
-        let token = get_token(request.metadata());
+        let token = get_token(&request, &self.auth_storage);
         let header = self.delete_key(&request.get_ref().key, token).await?;
         Ok(tonic::Response::new(UnlockResponse { header }))
     }
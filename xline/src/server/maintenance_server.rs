@@ -0,0 +1,352 @@
+use std::{iter, sync::Arc, vec};
+
+use clippy_utilities::{Cast, OverflowArithmetic};
+use curp::{client::Client, cmd::ProposeId, error::ProposeError, server::Rpc};
+use tracing::debug;
+
+use super::{
+    auth_server::get_token,
+    cluster_server::member_id,
+    command::{alarm_keys, Command, APPLIED_INDEX_KEY, META_TABLE},
+};
+use crate::{
+    header_gen::HeaderGenerator,
+    rpc::{
+        AlarmAction, AlarmMember, AlarmRequest, AlarmResponse, AlarmType, DefragmentRequest,
+        DefragmentResponse, DowngradeRequest, DowngradeResponse, HashKvRequest, HashKvResponse,
+        HashRequest, HashResponse, Maintenance, MoveLeaderRequest, MoveLeaderResponse,
+        RequestWithToken, ResponseHeader, SnapshotRequest, SnapshotResponse, StatusRequest,
+        StatusResponse,
+    },
+    state::State,
+    storage::{
+        kv_store::KV_TABLE, storage_api::StorageApi, AlarmStore, AuthStore, ExecuteError, KvStore,
+        Revision,
+    },
+};
+
+/// Size of each chunk of the snapshot stream
+const SNAPSHOT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Stream of snapshot chunks
+type SnapshotChunkStream =
+    tokio_stream::Iter<vec::IntoIter<Result<SnapshotResponse, tonic::Status>>>;
+
+/// Maintenance Server
+#[derive(Debug)]
+pub(crate) struct MaintenanceServer<S>
+where
+    S: StorageApi,
+{
+    /// Kv storage
+    kv_storage: Arc<KvStore<S>>,
+    /// Alarm storage
+    alarm_storage: Arc<AlarmStore<S>>,
+    /// Auth storage
+    auth_storage: Arc<AuthStore<S>>,
+    /// Persistent storage
+    persistent: Arc<S>,
+    /// Consensus client
+    client: Arc<Client<Command>>,
+    /// Curp server
+    curp_server: Rpc<Command>,
+    /// State of current node
+    state: Arc<State>,
+    /// Header generator
+    header_gen: Arc<HeaderGenerator>,
+}
+
+impl<S> MaintenanceServer<S>
+where
+    S: StorageApi,
+{
+    /// New `MaintenanceServer`
+    #[allow(clippy::too_many_arguments)] // the server is only created by `XlineServer`
+    pub(crate) fn new(
+        kv_storage: Arc<KvStore<S>>,
+        alarm_storage: Arc<AlarmStore<S>>,
+        auth_storage: Arc<AuthStore<S>>,
+        persistent: Arc<S>,
+        client: Arc<Client<Command>>,
+        curp_server: Rpc<Command>,
+        state: Arc<State>,
+        header_gen: Arc<HeaderGenerator>,
+    ) -> Self {
+        Self {
+            kv_storage,
+            alarm_storage,
+            auth_storage,
+            persistent,
+            client,
+            curp_server,
+            state,
+            header_gen,
+        }
+    }
+
+    /// Generate propose id
+    fn generate_propose_id(&self) -> ProposeId {
+        self.client.gen_propose_id()
+    }
+
+    /// Propose an alarm request, only the `GET` request takes the fast path
+    async fn propose_alarm(
+        &self,
+        request: tonic::Request<AlarmRequest>,
+    ) -> Result<AlarmResponse, tonic::Status> {
+        let use_fast_path = request.get_ref().action == i32::from(AlarmAction::Get);
+        let keys = alarm_keys(request.get_ref());
+        let wrapper = match get_token(&request, &self.auth_storage) {
+            Some(token) => RequestWithToken::new_with_token(request.into_inner().into(), token),
+            None => RequestWithToken::new(request.into_inner().into()),
+        };
+        let cmd = Command::new(keys, wrapper, self.generate_propose_id());
+        let map_err = |err| {
+            if let ProposeError::ExecutionError(e) = err {
+                tonic::Status::invalid_argument(e)
+            } else {
+                tonic::Status::internal(err.to_string())
+            }
+        };
+        let cmd_res = if use_fast_path {
+            self.client.propose(cmd).await.map_err(map_err)?
+        } else {
+            self.client.propose_indexed(cmd).await.map_err(map_err)?.0
+        };
+        Ok(cmd_res.decode().into())
+    }
+
+    /// Get the applied index of the storage
+    fn applied_index(&self) -> Result<u64, tonic::Status> {
+        let Some(index_bytes) = self
+            .persistent
+            .get_value(META_TABLE, APPLIED_INDEX_KEY)
+            .map_err(|e| tonic::Status::internal(e.to_string()))?
+        else {
+            return Ok(0);
+        };
+        let buf: [u8; 8] = index_bytes
+            .try_into()
+            .map_err(|_ignore| tonic::Status::data_loss("cannot decode applied index"))?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// Describe an alarm in the format of etcd, e.g. `memberID:1 alarm:NOSPACE`
+fn alarm_description(alarm: &AlarmMember) -> String {
+    let alarm_type = match AlarmType::from_i32(alarm.alarm) {
+        Some(AlarmType::None) => "NONE",
+        Some(AlarmType::Nospace) => "NOSPACE",
+        Some(AlarmType::Corrupt) => "CORRUPT",
+        None => "UNKNOWN",
+    };
+    format!("memberID:{} alarm:{alarm_type}", alarm.member_id)
+}
+
+/// Split the snapshot into chunks, the header is only attached to the first chunk
+fn snapshot_chunks(
+    snapshot: &[u8],
+    header: ResponseHeader,
+) -> Vec<Result<SnapshotResponse, tonic::Status>> {
+    let mut header = Some(header);
+    let mut remaining_bytes = snapshot.len();
+    snapshot
+        .chunks(SNAPSHOT_CHUNK_SIZE)
+        .map(|chunk| {
+            remaining_bytes = remaining_bytes.overflow_sub(chunk.len());
+            Ok(SnapshotResponse {
+                header: header.take(),
+                remaining_bytes: remaining_bytes.cast(),
+                blob: chunk.to_vec(),
+            })
+        })
+        .collect()
+}
+
+#[tonic::async_trait]
+impl<S> Maintenance for MaintenanceServer<S>
+where
+    S: StorageApi,
+{
+    /// Alarms are raised and cleared through consensus, so every member sees the same alarms
+    async fn alarm(
+        &self,
+        request: tonic::Request<AlarmRequest>,
+    ) -> Result<tonic::Response<AlarmResponse>, tonic::Status> {
+        debug!("Receive AlarmRequest {:?}", request);
+        self.propose_alarm(request).await.map(tonic::Response::new)
+    }
+
+    async fn status(
+        &self,
+        request: tonic::Request<StatusRequest>,
+    ) -> Result<tonic::Response<StatusResponse>, tonic::Status> {
+        debug!("Receive StatusRequest {:?}", request);
+        let db_size = self
+            .persistent
+            .size()
+            .map_err(|e| tonic::Status::internal(e.to_string()))?
+            .cast();
+        Ok(tonic::Response::new(StatusResponse {
+            header: Some(self.header_gen.gen_header()),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            db_size,
+            leader: self.state.leader_id().map_or(0, |id| member_id(&id)),
+            raft_index: self.curp_server.commit_index(),
+            raft_term: self.curp_server.term(),
+            raft_applied_index: self.applied_index()?,
+            errors: self
+                .alarm_storage
+                .alarms()
+                .iter()
+                .map(alarm_description)
+                .collect(),
+            db_size_in_use: db_size,
+            is_learner: self.curp_server.is_learner(),
+        }))
+    }
+
+    /// The storage engine compacts itself, so there is nothing to do here
+    async fn defragment(
+        &self,
+        request: tonic::Request<DefragmentRequest>,
+    ) -> Result<tonic::Response<DefragmentResponse>, tonic::Status> {
+        debug!("Receive DefragmentRequest {:?}", request);
+        Ok(tonic::Response::new(DefragmentResponse {
+            header: Some(self.header_gen.gen_header()),
+        }))
+    }
+
+    async fn hash(
+        &self,
+        request: tonic::Request<HashRequest>,
+    ) -> Result<tonic::Response<HashResponse>, tonic::Status> {
+        debug!("Receive HashRequest {:?}", request);
+        let snapshot = self
+            .persistent
+            .snapshot()
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+        Ok(tonic::Response::new(HashResponse {
+            header: Some(self.header_gen.gen_header()),
+            hash: crc32fast::hash(&snapshot),
+        }))
+    }
+
+    async fn hash_kv(
+        &self,
+        request: tonic::Request<HashKvRequest>,
+    ) -> Result<tonic::Response<HashKvResponse>, tonic::Status> {
+        debug!("Receive HashKvRequest {:?}", request);
+        let current_revision = self.header_gen.revision();
+        let compact_revision = self.kv_storage.compacted_revision();
+        let revision = match request.into_inner().revision {
+            0 => current_revision,
+            rev if rev > current_revision => {
+                return Err(ExecuteError::revision_too_large(rev, current_revision).into());
+            }
+            rev if rev < compact_revision => {
+                return Err(ExecuteError::revision_compacted(rev, compact_revision).into());
+            }
+            rev => rev,
+        };
+        let mut hasher = crc32fast::Hasher::new();
+        for (key, value) in self
+            .persistent
+            .get_all(KV_TABLE)
+            .map_err(|e| tonic::Status::internal(e.to_string()))?
+        {
+            if Revision::decode(&key).revision() <= revision {
+                hasher.update(&key);
+                hasher.update(&value);
+            }
+        }
+        Ok(tonic::Response::new(HashKvResponse {
+            header: Some(self.header_gen.gen_header()),
+            hash: hasher.finalize(),
+            compact_revision,
+        }))
+    }
+
+    type SnapshotStream = SnapshotChunkStream;
+
+    async fn snapshot(
+        &self,
+        request: tonic::Request<SnapshotRequest>,
+    ) -> Result<tonic::Response<Self::SnapshotStream>, tonic::Status> {
+        debug!("Receive SnapshotRequest {:?}", request);
+        let snapshot = self
+            .persistent
+            .snapshot()
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
+        let chunks = snapshot_chunks(&snapshot, self.header_gen.gen_header());
+        Ok(tonic::Response::new(tokio_stream::iter(chunks)))
+    }
+
+    async fn move_leader(
+        &self,
+        request: tonic::Request<MoveLeaderRequest>,
+    ) -> Result<tonic::Response<MoveLeaderResponse>, tonic::Status> {
+        debug!("Receive MoveLeaderRequest {:?}", request);
+        let target_id = request.into_inner().target_id;
+        let target = iter::once(self.state.id().to_owned())
+            .chain(self.curp_server.others().into_keys())
+            .find(|name| member_id(name) == target_id)
+            .ok_or_else(|| tonic::Status::not_found(format!("member {target_id} not found")))?;
+        #[allow(clippy::wildcard_enum_match_arm)] // `ProposeError` is non-exhaustive
+        let map_err = |err: ProposeError| match err {
+            ProposeError::NotLeader | ProposeError::LeaderTransfer(_) => {
+                tonic::Status::failed_precondition(err.to_string())
+            }
+            _ => tonic::Status::internal(err.to_string()),
+        };
+        self.curp_server
+            .move_leader(target)
+            .await
+            .map_err(map_err)?;
+        Ok(tonic::Response::new(MoveLeaderResponse {
+            header: Some(self.header_gen.gen_header()),
+        }))
+    }
+
+    async fn downgrade(
+        &self,
+        request: tonic::Request<DowngradeRequest>,
+    ) -> Result<tonic::Response<DowngradeResponse>, tonic::Status> {
+        debug!("Receive DowngradeRequest {:?}", request);
+        Err(tonic::Status::unimplemented("downgrade is not supported"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshot_chunks_will_count_down_remaining_bytes() {
+        let snapshot = vec![0_u8; SNAPSHOT_CHUNK_SIZE * 2 + 1];
+        let chunks = snapshot_chunks(&snapshot, ResponseHeader::default())
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.iter().filter(|c| c.header.is_some()).count(), 1);
+        assert!(chunks.first().unwrap().header.is_some());
+        assert_eq!(
+            chunks.iter().map(|c| c.remaining_bytes).collect::<Vec<_>>(),
+            vec![65537, 1, 0]
+        );
+        assert_eq!(
+            chunks.iter().map(|c| c.blob.len()).sum::<usize>(),
+            snapshot.len()
+        );
+    }
+
+    #[test]
+    fn alarm_description_should_be_etcd_compatible() {
+        let alarm = AlarmMember {
+            member_id: 1,
+            alarm: AlarmType::Nospace.into(),
+        };
+        assert_eq!(alarm_description(&alarm), "memberID:1 alarm:NOSPACE");
+    }
+}
@@ -0,0 +1,73 @@
+use std::{convert::Infallible, future::Future};
+
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    server::conn::AddrIncoming,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{Encoder, Registry, TextEncoder};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+/// The path where the metrics are served
+const METRICS_PATH: &str = "/metrics";
+
+/// Serve the metrics in the registries at `/metrics` in the prometheus text format until `signal` resolves
+pub(super) async fn serve(
+    listener: TcpListener,
+    registries: Vec<Registry>,
+    signal: impl Future<Output = ()>,
+) {
+    let incoming = match AddrIncoming::from_listener(listener) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            error!("failed to start the metrics server, {e}");
+            return;
+        }
+    };
+    let make_svc = make_service_fn(move |_conn| {
+        let registries = registries.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let resp = handle(&req, &registries);
+                async move { Ok::<_, Infallible>(resp) }
+            }))
+        }
+    });
+    info!(
+        "metrics server started, listening on {}",
+        incoming.local_addr()
+    );
+    let server = Server::builder(incoming).serve(make_svc);
+    if let Err(e) = server.with_graceful_shutdown(signal).await {
+        error!("metrics server error, {e}");
+    }
+}
+
+/// Handle a request to the metrics server
+fn handle(req: &Request<Body>, registries: &[Registry]) -> Response<Body> {
+    if req.method() != Method::GET || req.uri().path() != METRICS_PATH {
+        return response(StatusCode::NOT_FOUND, Body::empty());
+    }
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    let families: Vec<_> = registries.iter().flat_map(Registry::gather).collect();
+    if let Err(e) = encoder.encode(&families, &mut buf) {
+        error!("failed to encode the metrics, {e}");
+        return response(StatusCode::INTERNAL_SERVER_ERROR, Body::empty());
+    }
+    let mut resp = response(StatusCode::OK, Body::from(buf));
+    let _prev = resp.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(prometheus::TEXT_FORMAT),
+    );
+    resp
+}
+
+/// Build a response with the status
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    resp
+}
@@ -1,5 +1,11 @@
 /// Xline auth server
 mod auth_server;
+/// Auto compactor of the kv history
+mod auto_compactor;
+/// Channels to other xline servers
+mod channel;
+/// Xline cluster server
+mod cluster_server;
 /// Command to be executed
 pub(crate) mod command;
 /// Xline kv server
@@ -8,9 +14,15 @@ mod kv_server;
 mod lease_server;
 /// Xline lock server
 mod lock_server;
+/// Xline maintenance server
+mod maintenance_server;
+/// Server of the prometheus metrics
+mod metrics;
+/// Checker of the backend quota
+mod quota_checker;
 /// Xline watch server
 mod watch_server;
 /// Xline server
 mod xline_server;
 
-pub use self::xline_server::XlineServer;
+pub use self::xline_server::{start_witness, ServerTls, XlineServer};
@@ -0,0 +1,132 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use curp::{client::Client, error::ProposeError};
+use tracing::warn;
+
+use super::command::{alarm_keys, Command};
+use crate::{
+    rpc::{AlarmAction, AlarmRequest, AlarmType, RequestWithToken, RequestWrapper},
+    storage::{storage_api::StorageApi, AlarmStore, AuthStore, ExecuteError},
+};
+
+/// Interval to sample the size of the backend
+const SIZE_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Checks the size of the backend against the quota before the requests taking more space are
+/// proposed, the `NOSPACE` alarm is raised through consensus once the quota is exceeded. The
+/// size is sampled in the background, so the requests never read it from the backend.
+#[derive(Debug)]
+pub(crate) struct QuotaChecker<S>
+where
+    S: StorageApi,
+{
+    /// Max size in bytes of the backend
+    quota: u64,
+    /// Id of current member, the alarm is raised on it
+    member_id: u64,
+    /// Persistent storage
+    persistent: Arc<S>,
+    /// Auth storage
+    auth_storage: Arc<AuthStore<S>>,
+    /// Alarm storage
+    alarm_storage: Arc<AlarmStore<S>>,
+    /// Consensus client
+    client: Arc<Client<Command>>,
+    /// Size of the backend in the last sample
+    size: AtomicU64,
+    /// Whether a proposal of the `NOSPACE` alarm is in flight
+    alarm_proposing: AtomicBool,
+}
+
+impl<S> QuotaChecker<S>
+where
+    S: StorageApi,
+{
+    /// New `QuotaChecker`
+    pub(crate) fn new(
+        quota: u64,
+        member_id: u64,
+        persistent: Arc<S>,
+        auth_storage: Arc<AuthStore<S>>,
+        alarm_storage: Arc<AlarmStore<S>>,
+        client: Arc<Client<Command>>,
+    ) -> Self {
+        Self {
+            quota,
+            member_id,
+            persistent,
+            auth_storage,
+            alarm_storage,
+            client,
+            size: AtomicU64::new(0),
+            alarm_proposing: AtomicBool::new(false),
+        }
+    }
+
+    /// Sample the size of the backend periodically
+    pub(crate) async fn sample_size_task(checker: Arc<Self>) {
+        let mut interval = tokio::time::interval(SIZE_SAMPLE_INTERVAL);
+        loop {
+            let _ig = interval.tick().await;
+            match checker.persistent.size() {
+                Ok(size) => checker.size.store(size, Ordering::Relaxed),
+                Err(e) => warn!("failed to sample the backend size: {e}"),
+            }
+        }
+    }
+
+    /// Check if the request can be proposed, the request is rejected if it takes more space
+    /// while the `NOSPACE` alarm is raised or the backend exceeds the quota. The alarm is raised
+    /// once the quota is exceeded.
+    pub(crate) async fn check(&self, request: &RequestWrapper) -> Result<(), tonic::Status> {
+        if !request.consumes_space() {
+            return Ok(());
+        }
+        if self.alarm_storage.is_raised(AlarmType::Nospace) {
+            return Err(Self::no_space());
+        }
+        let size = self.size.load(Ordering::Relaxed);
+        if size <= self.quota {
+            return Ok(());
+        }
+        // the requests arriving while the alarm is being raised don't propose it again
+        if !self.alarm_proposing.swap(true, Ordering::AcqRel) {
+            warn!(
+                "backend size {size} exceeds the quota {}, raise the NOSPACE alarm",
+                self.quota
+            );
+            if let Err(e) = self.propose_alarm().await {
+                warn!("failed to raise the NOSPACE alarm: {e}");
+            }
+            self.alarm_proposing.store(false, Ordering::Release);
+        }
+        Err(Self::no_space())
+    }
+
+    /// Propose the `NOSPACE` alarm of current member
+    async fn propose_alarm(&self) -> Result<(), ProposeError> {
+        let alarm = AlarmRequest {
+            action: AlarmAction::Activate.into(),
+            member_id: self.member_id,
+            alarm: AlarmType::Nospace.into(),
+        };
+        let keys = alarm_keys(&alarm);
+        let wrapper = match self.auth_storage.root_token() {
+            Ok(token) => RequestWithToken::new_with_token(alarm.into(), token),
+            Err(_) => RequestWithToken::new(alarm.into()),
+        };
+        let cmd = Command::new(keys, wrapper, self.client.gen_propose_id());
+        self.client.propose_indexed(cmd).await.map(|_| ())
+    }
+
+    /// Status of the rejected requests
+    fn no_space() -> tonic::Status {
+        tonic::Status::resource_exhausted(ExecuteError::no_space().to_string())
+    }
+}
@@ -167,13 +167,35 @@ where
             start: req.key,
             end: req.range_end,
         };
-        let (events, revision) = self.kv_watcher.watch(
+        let watch_res = self.kv_watcher.watch(
             watch_id,
             key_range,
             req.start_revision,
             req.filters,
+            req.progress_notify,
+            req.prev_kv,
             self.event_tx.clone(),
         );
+        let (events, revision) = match watch_res {
+            Ok(res) => res,
+            Err(e) => {
+                // the watch is canceled at once, the client can retry from the compacted revision
+                let response = WatchResponse {
+                    watch_id,
+                    created: true,
+                    canceled: true,
+                    compact_revision: self.kv_watcher.compacted_revision(),
+                    cancel_reason: e.to_string(),
+                    ..WatchResponse::default()
+                };
+                if self.response_tx.send(Ok(response)).await.is_err() {
+                    self.stop_tx.send(()).unwrap_or_else(|e| {
+                        warn!("failed to send stop signal: {}", e);
+                    });
+                }
+                return;
+            }
+        };
         assert!(
             self.active_watch_ids.insert(watch_id),
             "WatchId {watch_id} already exists in watcher_map",
@@ -252,17 +274,17 @@ where
                     self.handle_watch_cancel(req).await;
                 }
                 RequestUnion::ProgressRequest(_req) => {
-                    panic!("Don't support ProgressRequest yet");
+                    self.kv_watcher.request_progress(self.event_tx.clone());
                 }
             }
         }
     }
 
-    /// Handle watch event
+    /// Handle watch event, a progress notification is sent as a response without events
     async fn handle_watch_event(&mut self, mut event: WatchEvent) {
         let watch_id = event.watch_id();
         let events = event.take_events();
-        if events.is_empty() {
+        if events.is_empty() && !event.is_progress() {
             return;
         }
         let response = WatchResponse {
@@ -337,7 +359,7 @@ mod test {
         let _ = mock_watcher
             .expect_watch()
             .times(1)
-            .return_const((vec![], 0));
+            .return_const(Ok((vec![], 0)));
         let _ = mock_watcher.expect_cancel().times(1).returning(move |_| 0);
         let watcher = Arc::new(mock_watcher);
         let handle = tokio::spawn(WatchServer::<DB<MemoryEngine>>::task(
@@ -1,42 +1,121 @@
-use std::{collections::HashMap, future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    future::{self, Future},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Result;
-use curp::{client::Client, server::Rpc, ProtocolServer};
+use curp::{
+    client::Client,
+    error::ProposeError,
+    members::ConfChange,
+    server::{Rpc, Witness},
+    InnerProtocolServer, ProtocolServer,
+};
+use futures::{stream::select_all, FutureExt};
 use jsonwebtoken::{DecodingKey, EncodingKey};
 use tokio::{
     net::TcpListener,
     sync::{broadcast, mpsc},
 };
 use tokio_stream::wrappers::TcpListenerStream;
-use tonic::transport::Server;
-use tracing::info;
-use utils::config::{ClientTimeout, CurpConfig};
+use tonic::transport::{ClientTlsConfig, Server, ServerTlsConfig};
+use tracing::{debug, info, warn};
+use utils::{
+    address_from_url,
+    config::{AutoCompactConfig, ClusterConfig, CurpConfig},
+};
 
 use super::{
     auth_server::AuthServer,
+    auto_compactor::AutoCompactor,
+    cluster_server::{member_id, ClusterServer},
     command::{Command, CommandExecutor},
     kv_server::KvServer,
     lease_server::LeaseServer,
     lock_server::LockServer,
+    maintenance_server::MaintenanceServer,
+    metrics,
+    quota_checker::QuotaChecker,
     watch_server::WatchServer,
 };
 use crate::{
     header_gen::HeaderGenerator,
     id_gen::IdGenerator,
     rpc::{
-        AuthServer as RpcAuthServer, KvServer as RpcKvServer, LeaseServer as RpcLeaseServer,
-        LockServer as RpcLockServer, WatchServer as RpcWatchServer,
+        AuthServer as RpcAuthServer, ClusterServer as RpcClusterServer, KvServer as RpcKvServer,
+        LeaseServer as RpcLeaseServer, LockServer as RpcLockServer,
+        MaintenanceServer as RpcMaintenanceServer, WatchServer as RpcWatchServer,
     },
     state::State,
-    storage::{index::Index, storage_api::StorageApi, AuthStore, KvStore, LeaseStore},
+    storage::{index::Index, storage_api::StorageApi, AlarmStore, AuthStore, KvStore, LeaseStore},
 };
 
 /// Default channel size
 const CHANNEL_SIZE: usize = 128;
 
+/// Interval to retry publishing the client urls
+const PUBLISH_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
 /// Rpc Server of curp protocol
 type CurpServer = Rpc<Command>;
 
+/// Start a curp witness, it only keeps a durable speculative pool for the fast path of curp and
+/// serves nothing but the curp protocol to the peers at `addrs`, the protocol is served through
+/// tls if `tls_config` is set
+///
+/// # Errors
+///
+/// Will return `Err` when the tls config is invalid, the addresses can't be bound or
+/// `tonic::Server` serve return an error
+#[inline]
+pub async fn start_witness(
+    name: String,
+    curp_config: CurpConfig,
+    addrs: &[SocketAddr],
+    tls_config: Option<ServerTlsConfig>,
+) -> Result<()> {
+    let witness = Witness::<Command>::new(name, Arc::new(curp_config)).await;
+    let incoming = select_all(bind(addrs).await?.into_iter().map(TcpListenerStream::new));
+    Ok(server_builder(tls_config)?
+        .add_service(ProtocolServer::new(witness.clone()))
+        .add_service(InnerProtocolServer::new(witness))
+        .serve_with_incoming(incoming)
+        .await?)
+}
+
+/// Create a `tonic::Server` builder, the server serves through tls if `tls_config` is set
+fn server_builder(tls_config: Option<ServerTlsConfig>) -> Result<Server> {
+    let builder = Server::builder();
+    Ok(match tls_config {
+        Some(tls_config) => builder.tls_config(tls_config)?,
+        None => builder,
+    })
+}
+
+/// Bind listeners to the addresses
+async fn bind(addrs: &[SocketAddr]) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        listeners.push(TcpListener::bind(addr).await?);
+    }
+    Ok(listeners)
+}
+
+/// Tls configs of `XlineServer`, the connections are made without tls if a config is not set
+#[derive(Clone, Debug, Default)]
+#[allow(clippy::exhaustive_structs)] // it's a config
+pub struct ServerTls {
+    /// Tls config of the client-facing services
+    pub server: Option<ServerTlsConfig>,
+    /// Tls config of the curp protocol served to the peers
+    pub peer_server: Option<ServerTlsConfig>,
+    /// Tls config used to connect to other servers
+    pub client: Option<ClientTlsConfig>,
+}
+
 /// Xline server
 #[derive(Debug)]
 pub struct XlineServer<S>
@@ -51,21 +130,41 @@ where
     auth_storage: Arc<AuthStore<S>>,
     /// Lease storage
     lease_storage: Arc<LeaseStore<S>>,
+    /// Alarm storage
+    alarm_storage: Arc<AlarmStore<S>>,
+    /// Quota checker of the backend
+    quota_checker: Arc<QuotaChecker<S>>,
     /// persistent storage
     persistent: Arc<S>,
     /// Consensus client
     client: Arc<Client<Command>>,
     /// Curp server timeout
     curp_cfg: Arc<CurpConfig>,
+    /// Auto compaction policy, auto compaction is disabled if it is `None`
+    auto_compact_cfg: Option<AutoCompactConfig>,
     /// Id generator
     id_gen: Arc<IdGenerator>,
+    /// Header generator
+    header_gen: Arc<HeaderGenerator>,
+    /// Tls config of the client-facing services, they are served through tls if it is set
+    server_tls_config: Option<ServerTlsConfig>,
+    /// Tls config of the curp protocol served to the peers, it's served through tls if it is set
+    peer_server_tls_config: Option<ServerTlsConfig>,
+    /// Tls config used to connect to other servers, the connections use tls if it is set
+    client_tls_config: Option<ClientTlsConfig>,
+    /// Peer urls advertised to the rest of the cluster, the member address is advertised if it
+    /// is empty
+    advertise_peer_urls: Vec<String>,
+    /// Client urls advertised to the clients, the peer urls are advertised if it is empty
+    advertise_client_urls: Vec<String>,
 }
 
 impl<S> XlineServer<S>
 where
     S: StorageApi,
 {
-    /// New `XlineServer`
+    /// New `XlineServer` of the member `cluster_config.name()`, the services are served through
+    /// tls according to `tls`
     ///
     /// # Errors
     ///
@@ -76,16 +175,32 @@ where
     /// panic when peers do not contain leader address
     #[inline]
     pub async fn new(
-        name: String,
-        all_members: HashMap<String, String>,
-        is_leader: bool,
+        cluster_config: ClusterConfig,
         key_pair: Option<(EncodingKey, DecodingKey)>,
-        curp_config: CurpConfig,
-        client_timeout: ClientTimeout,
+        auto_compact_config: Option<AutoCompactConfig>,
+        quota_backend_bytes: u64,
         persistent: Arc<S>,
+        tls: ServerTls,
     ) -> Self {
-        // TODO: temporary solution, need real cluster id and member id
-        let header_gen = Arc::new(HeaderGenerator::new(0, 0));
+        let name = cluster_config.name().clone();
+        let all_members = cluster_config.members().clone();
+        let is_leader = *cluster_config.is_leader();
+        let curp_config = cluster_config.curp_config().clone();
+        let client_timeout = *cluster_config.client_timeout();
+        let advertise_peer_urls = cluster_config.advertise_peer_urls().clone();
+        let advertise_client_urls = if cluster_config.advertise_client_urls().is_empty() {
+            cluster_config.listen_client_urls().clone()
+        } else {
+            cluster_config.advertise_client_urls().clone()
+        };
+        let ServerTls {
+            server: server_tls_config,
+            peer_server: peer_server_tls_config,
+            client: client_tls_config,
+        } = tls;
+        // TODO: temporary solution, need real cluster id
+        let self_member_id = member_id(&name);
+        let header_gen = Arc::new(HeaderGenerator::new(0, self_member_id));
         let id_gen = Arc::new(IdGenerator::new(0));
         let leader_id = is_leader.then(|| name.clone());
         let state = Arc::new(State::new(name, leader_id, all_members.clone()));
@@ -110,19 +225,47 @@ where
         let auth_storage = Arc::new(AuthStore::new(
             lease_cmd_tx,
             key_pair,
-            header_gen,
+            Arc::clone(&header_gen),
             Arc::clone(&persistent),
         ));
-        let client = Arc::new(Client::<Command>::new(all_members.clone(), client_timeout).await);
+        let alarm_storage = Arc::new(AlarmStore::new(
+            Arc::clone(&header_gen),
+            Arc::clone(&persistent),
+        ));
+        let client = Arc::new(
+            Client::<Command>::new(
+                all_members.clone(),
+                client_timeout,
+                client_tls_config.clone(),
+            )
+            .await,
+        );
+        let quota_checker = Arc::new(QuotaChecker::new(
+            quota_backend_bytes,
+            self_member_id,
+            Arc::clone(&persistent),
+            Arc::clone(&auth_storage),
+            Arc::clone(&alarm_storage),
+            Arc::clone(&client),
+        ));
         Self {
             state,
             kv_storage,
             auth_storage,
             lease_storage,
+            alarm_storage,
+            quota_checker,
             persistent,
             client,
             curp_cfg: curp_config,
+            auto_compact_cfg: auto_compact_config,
             id_gen,
+            header_gen,
+            server_tls_config,
+            peer_server_tls_config,
+            client_tls_config,
+            advertise_peer_urls,
+            advertise_client_urls,
         }
     }
 
@@ -136,55 +279,139 @@ where
         self.state.is_leader()
     }
 
-    /// Start `XlineServer`
+    /// Start `XlineServer`, the peers are served at `peer_addrs` and the clients at
+    /// `client_addrs`. The clients are served at `peer_addrs` as well if `client_addrs` is empty
+    /// or the same as `peer_addrs`. The metrics are served at `metrics_addr` if it's given
     ///
     /// # Errors
     ///
-    /// Will return `Err` when `tonic::Server` serve return an error
+    /// Will return `Err` when the tls config is invalid, the addresses can't be bound or
+    /// `tonic::Server` serve return an error
     #[inline]
-    pub async fn start(&self, addr: SocketAddr) -> Result<()> {
+    pub async fn start(
+        &self,
+        peer_addrs: &[SocketAddr],
+        client_addrs: &[SocketAddr],
+        metrics_addr: Option<SocketAddr>,
+    ) -> Result<()> {
         // lease storage must recover before kv storage
         self.lease_storage.recover()?;
         self.kv_storage.recover().await?;
         self.auth_storage.recover()?;
-        let (kv_server, lock_server, lease_server, auth_server, watch_server, curp_server) =
-            self.init_servers().await;
-        Ok(Server::builder()
-            .add_service(RpcLockServer::new(lock_server))
-            .add_service(RpcKvServer::new(kv_server))
-            .add_service(RpcLeaseServer::from_arc(lease_server))
-            .add_service(RpcAuthServer::new(auth_server))
-            .add_service(RpcWatchServer::new(watch_server))
-            .add_service(ProtocolServer::new(curp_server))
-            .serve(addr)
-            .await?)
+        self.alarm_storage.recover()?;
+        let peer_listeners = bind(peer_addrs).await?;
+        let client_listeners = if client_addrs.is_empty() || client_addrs == peer_addrs {
+            None
+        } else {
+            Some(bind(client_addrs).await?)
+        };
+        let metrics_listener = match metrics_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        self.serve(
+            peer_listeners,
+            client_listeners,
+            metrics_listener,
+            future::pending(),
+        )
+        .await
     }
 
-    /// Start `XlineServer` from listeners
+    /// Start `XlineServer` from listeners, the clients are served by `peer_listener` as well if
+    /// `client_listener` is `None`. The metrics are served by `metrics_listener` if it's given
     ///
     /// # Errors
     ///
-    /// Will return `Err` when `tonic::Server` serve return an error
+    /// Will return `Err` when the tls config is invalid or `tonic::Server` serve return an error
     #[inline]
     pub async fn start_from_listener_shutdown<F>(
         &self,
-        xline_listener: TcpListener,
+        peer_listener: TcpListener,
+        client_listener: Option<TcpListener>,
+        metrics_listener: Option<TcpListener>,
+        signal: F,
+    ) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.serve(
+            vec![peer_listener],
+            client_listener.map(|l| vec![l]),
+            metrics_listener,
+            signal,
+        )
+        .await
+    }
+
+    /// Serve the services until `signal` resolves. The peer listeners serve the whole curp
+    /// protocol, and the lease service to which the followers forward lease requests. The client
+    /// listeners serve the client-facing services and the part of the curp protocol used by the
+    /// curp clients, the rpcs between the servers are never served to the clients. All services
+    /// are served by the peer listeners if there are no client listeners
+    async fn serve<F>(
+        &self,
+        peer_listeners: Vec<TcpListener>,
+        client_listeners: Option<Vec<TcpListener>>,
+        metrics_listener: Option<TcpListener>,
         signal: F,
     ) -> Result<()>
     where
-        F: Future<Output = ()>,
+        F: Future<Output = ()> + Send + 'static,
     {
-        let (kv_server, lock_server, lease_server, auth_server, watch_server, curp_server) =
-            self.init_servers().await;
-        Ok(Server::builder()
+        let (
+            kv_server,
+            lock_server,
+            lease_server,
+            auth_server,
+            watch_server,
+            cluster_server,
+            maintenance_server,
+            curp_server,
+        ) = self.init_servers().await;
+        let signal = signal.shared();
+        if let Some(metrics_listener) = metrics_listener {
+            let registries = vec![
+                curp_server.metrics_registry(),
+                self.client.metrics_registry(),
+            ];
+            let _handle =
+                tokio::spawn(metrics::serve(metrics_listener, registries, signal.clone()));
+        }
+        let peer_incoming = select_all(peer_listeners.into_iter().map(TcpListenerStream::new));
+        let peer_server = server_builder(self.peer_server_tls_config.clone())?
+            .add_service(InnerProtocolServer::new(curp_server.clone()));
+        let Some(client_listeners) = client_listeners else {
+            // the peer listeners serve the clients as well
+            return Ok(peer_server
+                .add_service(RpcLockServer::new(lock_server))
+                .add_service(RpcKvServer::new(kv_server))
+                .add_service(RpcLeaseServer::from_arc(lease_server))
+                .add_service(RpcAuthServer::new(auth_server))
+                .add_service(RpcWatchServer::new(watch_server))
+                .add_service(RpcClusterServer::new(cluster_server))
+                .add_service(RpcMaintenanceServer::new(maintenance_server))
+                .add_service(ProtocolServer::new(curp_server))
+                .serve_with_incoming_shutdown(peer_incoming, signal)
+                .await?);
+        };
+        let peer_serve = peer_server
+            .add_service(RpcLeaseServer::from_arc(Arc::clone(&lease_server)))
+            .add_service(ProtocolServer::new(curp_server.clone()))
+            .serve_with_incoming_shutdown(peer_incoming, signal.clone());
+        let client_incoming = select_all(client_listeners.into_iter().map(TcpListenerStream::new));
+        let client_serve = server_builder(self.server_tls_config.clone())?
             .add_service(RpcLockServer::new(lock_server))
             .add_service(RpcKvServer::new(kv_server))
             .add_service(RpcLeaseServer::from_arc(lease_server))
             .add_service(RpcAuthServer::new(auth_server))
             .add_service(RpcWatchServer::new(watch_server))
+            .add_service(RpcClusterServer::new(cluster_server))
+            .add_service(RpcMaintenanceServer::new(maintenance_server))
             .add_service(ProtocolServer::new(curp_server))
-            .serve_with_incoming_shutdown(TcpListenerStream::new(xline_listener), signal)
-            .await?)
+            .serve_with_incoming_shutdown(client_incoming, signal);
+        let _ig = tokio::try_join!(peer_serve, client_serve)?;
+        Ok(())
     }
 
     /// Leader change task
@@ -207,8 +434,83 @@ where
         }
     }
 
-    /// Init `KvServer`, `LockServer`, `LeaseServer`, `WatchServer` and `CurpServer`
-    /// for the Xline Server.
+    /// Membership change task, keeps the members in `State` and the connects of the client up to date
+    async fn member_change_task(
+        mut rx: broadcast::Receiver<ConfChange>,
+        curp_server: CurpServer,
+        state: Arc<State>,
+        client: Arc<Client<Command>>,
+    ) {
+        loop {
+            // members may have been recovered from the storage, so sync them before any change
+            let others = curp_server.others();
+            let mut learners: HashSet<String> = others
+                .iter()
+                .filter(|&(_, member)| member.is_learner())
+                .map(|(id, _)| id.clone())
+                .collect();
+            if curp_server.is_learner() {
+                let _ig = learners.insert(state.id().to_owned());
+            }
+            let others: HashMap<String, String> = others
+                .into_iter()
+                .map(|(id, member)| (id, member.address().to_owned()))
+                .collect();
+            let prev_others = state.others();
+            for id in prev_others.keys().filter(|id| !others.contains_key(*id)) {
+                client.remove_server(id);
+            }
+            for (id, address) in &others {
+                if prev_others.get(id) != Some(address) {
+                    client.add_server(id.clone(), address.clone()).await;
+                }
+            }
+            client.set_learners(learners);
+            state.set_others(others);
+
+            match rx.recv().await {
+                Ok(change) => debug!("receive membership change: {change:?}"),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("{n} membership changes are skipped, resync the members");
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+
+    /// Publish the client urls of current node to the cluster, so other members can serve them
+    /// in the member list. It retries until the change is committed
+    async fn publish_client_urls_task(
+        client: Arc<Client<Command>>,
+        id: String,
+        client_urls: Vec<String>,
+    ) {
+        let mut interval = tokio::time::interval(PUBLISH_RETRY_INTERVAL);
+        loop {
+            let _ig = interval.tick().await;
+            let change = ConfChange::UpdateClientUrls {
+                id: id.clone(),
+                client_urls: client_urls.clone(),
+            };
+            match client
+                .propose_conf_change(client.gen_propose_id(), change)
+                .await
+            {
+                Ok(()) => {
+                    info!("published client urls {client_urls:?} of {id}");
+                    return;
+                }
+                Err(ProposeError::InvalidConfChange(e)) => {
+                    warn!("failed to publish client urls of {id}: {e}");
+                    return;
+                }
+                Err(e) => debug!("failed to publish client urls of {id}, retry: {e}"),
+            }
+        }
+    }
+
+    /// Init `KvServer`, `LockServer`, `LeaseServer`, `WatchServer`, `ClusterServer`,
+    /// `MaintenanceServer` and `CurpServer` for the Xline Server.
     #[allow(clippy::type_complexity)] // it is easy to read
     async fn init_servers(
         &self,
@@ -218,6 +520,8 @@ where
         Arc<LeaseServer<S>>,
         AuthServer<S>,
         WatchServer<S>,
+        ClusterServer,
+        MaintenanceServer<S>,
         CurpServer,
     ) {
         let curp_server = CurpServer::new(
@@ -228,10 +532,12 @@ where
                 Arc::clone(&self.kv_storage),
                 Arc::clone(&self.auth_storage),
                 Arc::clone(&self.lease_storage),
+                Arc::clone(&self.alarm_storage),
                 Arc::clone(&self.persistent),
             ),
             Arc::clone(&self.curp_cfg),
             None,
+            self.client_tls_config.clone(),
         )
         .await;
         let _handle = tokio::spawn({
@@ -240,34 +546,89 @@ where
             let rx = curp_server.leader_rx();
             Self::leader_change_task(rx, state, lease_storage)
         });
+        let _handle = tokio::spawn(Self::member_change_task(
+            curp_server.conf_change_rx(),
+            curp_server.clone(),
+            Arc::clone(&self.state),
+            Arc::clone(&self.client),
+        ));
+        let _handle = tokio::spawn(QuotaChecker::sample_size_task(Arc::clone(
+            &self.quota_checker,
+        )));
+        if let Some(config) = self.auto_compact_cfg {
+            let _handle = tokio::spawn(
+                AutoCompactor::new(
+                    config,
+                    Arc::clone(&self.kv_storage),
+                    Arc::clone(&self.auth_storage),
+                    Arc::clone(&self.client),
+                    Arc::clone(&self.state),
+                )
+                .run(),
+            );
+        }
+        let peer_urls = if self.advertise_peer_urls.is_empty() {
+            vec![self.state.self_address()]
+        } else {
+            self.advertise_peer_urls.clone()
+        };
+        let client_urls = if self.advertise_client_urls.is_empty() {
+            peer_urls.clone()
+        } else {
+            self.advertise_client_urls.clone()
+        };
+        let _handle = tokio::spawn(Self::publish_client_urls_task(
+            Arc::clone(&self.client),
+            self.id(),
+            client_urls.clone(),
+        ));
+        let client_address = client_urls
+            .first()
+            .map_or_else(|| self.state.self_address(), |url| address_from_url(url));
         (
             KvServer::new(
                 Arc::clone(&self.kv_storage),
                 Arc::clone(&self.auth_storage),
-                Arc::clone(&self.state),
+                Arc::clone(&self.quota_checker),
                 Arc::clone(&self.client),
-                self.id(),
+                curp_server.clone(),
             ),
             LockServer::new(
                 Arc::clone(&self.kv_storage),
+                Arc::clone(&self.auth_storage),
                 Arc::clone(&self.client),
-                Arc::clone(&self.state),
-                self.id(),
+                client_address,
+                self.client_tls_config.clone(),
             ),
             LeaseServer::new(
                 Arc::clone(&self.lease_storage),
                 Arc::clone(&self.auth_storage),
+                Arc::clone(&self.quota_checker),
                 Arc::clone(&self.client),
-                self.id(),
                 Arc::clone(&self.state),
                 Arc::clone(&self.id_gen),
+                self.client_tls_config.clone(),
+            ),
+            AuthServer::new(Arc::clone(&self.auth_storage), Arc::clone(&self.client)),
+            WatchServer::new(self.kv_storage.kv_watcher()),
+            ClusterServer::new(
+                Arc::clone(&self.client),
+                curp_server.clone(),
+                Arc::clone(&self.state),
+                Arc::clone(&self.header_gen),
+                peer_urls,
+                client_urls,
             ),
-            AuthServer::new(
+            MaintenanceServer::new(
+                Arc::clone(&self.kv_storage),
+                Arc::clone(&self.alarm_storage),
                 Arc::clone(&self.auth_storage),
+                Arc::clone(&self.persistent),
                 Arc::clone(&self.client),
-                self.id(),
+                curp_server.clone(),
+                Arc::clone(&self.state),
+                Arc::clone(&self.header_gen),
             ),
-            WatchServer::new(self.kv_storage.kv_watcher()),
             curp_server,
         )
     }
@@ -10,8 +10,8 @@ pub(crate) struct State {
     id: String,
     /// Leader id
     leader_id: RwLock<Option<String>>,
-    /// Address of all members
-    members: HashMap<String, String>,
+    /// Address of all members, updated on membership changes
+    members: RwLock<HashMap<String, String>>,
     /// leader change event, notify when get new leader_id
     event: Event,
 }
@@ -26,7 +26,7 @@ impl State {
         Self {
             id,
             leader_id: RwLock::new(leader_id),
-            members,
+            members: RwLock::new(members),
             event: Event::new(),
         }
     }
@@ -37,21 +37,27 @@ impl State {
     }
 
     /// Get self address
-    pub(crate) fn self_address(&self) -> &str {
-        self.members.get(&self.id).unwrap_or_else(|| {
+    pub(crate) fn self_address(&self) -> String {
+        let members = self.members.read();
+        members.get(&self.id).cloned().unwrap_or_else(|| {
             panic!(
                 "Self address not found, id: {}, members: {:?}",
-                self.id, self.members
+                self.id, *members
             )
         })
     }
 
+    /// Get leader id
+    pub(crate) fn leader_id(&self) -> Option<String> {
+        self.leader_id.read().clone()
+    }
+
     /// Get leader address
-    pub(crate) fn leader_address(&self) -> Option<&str> {
+    pub(crate) fn leader_address(&self) -> Option<String> {
         self.leader_id
             .read()
             .as_ref()
-            .and_then(|id| self.members.get(id).map(String::as_str))
+            .and_then(|id| self.members.read().get(id).cloned())
     }
 
     /// listener of leader change
@@ -82,24 +88,32 @@ impl State {
 
     /// Get address of other members
     pub(crate) fn others(&self) -> HashMap<String, String> {
-        let mut members = self.members.clone();
+        let mut members = self.members.read().clone();
         let _ignore = members.remove(&self.id);
         members
     }
 
+    /// Replace address of other members, the address of self is kept
+    pub(crate) fn set_others(&self, others: HashMap<String, String>) {
+        let mut members_w = self.members.write();
+        let self_address = members_w.remove(&self.id);
+        *members_w = others;
+        if let Some(addr) = self_address {
+            let _ignore = members_w.insert(self.id.clone(), addr);
+        }
+    }
+
     /// Wait leader until current node has a leader
     pub(crate) async fn wait_leader(&self) -> Result<String, tonic::Status> {
         let listener = {
             if let Some(leader_addr) = self.leader_address() {
-                return Ok(leader_addr.to_owned());
+                return Ok(leader_addr);
             }
             self.leader_listener()
         };
 
         listener.await;
-        self.leader_address()
-            .map(str::to_owned)
-            .ok_or_else(|| tonic::Status::internal("Get leader address error"))
+        self.leader_address().ok_or_else(|| tonic::Status::internal("Get leader address error"))
     }
 }
 
@@ -133,13 +147,19 @@ mod test {
         assert!(!state.set_leader_id(Some("2".to_owned())));
         assert_eq!(state.id(), "1");
         assert_eq!(state.self_address(), "1");
-        assert_eq!(state.leader_address(), Some("2"));
+        assert_eq!(state.leader_address().as_deref(), Some("2"));
         assert!(!state.is_leader());
         assert_eq!(
             state.others(),
             vec![("2".to_owned(), "2".to_owned())].into_iter().collect()
         );
         timeout(Duration::from_secs(1), handle).await??;
+        state.set_others(vec![("3".to_owned(), "3".to_owned())].into_iter().collect());
+        assert_eq!(state.self_address(), "1");
+        assert_eq!(
+            state.others(),
+            vec![("3".to_owned(), "3".to_owned())].into_iter().collect()
+        );
         Ok(())
     }
 }
@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use curp::cmd::ProposeId;
+use log::debug;
+use parking_lot::RwLock;
+use prost::Message;
+
+use super::{db::WriteOp, storage_api::StorageApi, ExecuteError};
+use crate::{
+    header_gen::HeaderGenerator,
+    rpc::{
+        AlarmAction, AlarmMember, AlarmRequest, AlarmResponse, AlarmType, RequestWithToken,
+        RequestWrapper,
+    },
+    server::command::{CommandResponse, SyncResponse},
+};
+
+/// Alarm table name
+pub(crate) const ALARM_TABLE: &str = "alarm";
+
+/// Get the key of an alarm in the alarm table
+pub(crate) fn alarm_key(alarm: &AlarmMember) -> Vec<u8> {
+    let mut key = alarm.member_id.to_be_bytes().to_vec();
+    key.extend_from_slice(&alarm.alarm.to_be_bytes());
+    key
+}
+
+/// Alarm store, alarms are raised and cleared through consensus so that all members agree on them
+#[derive(Debug)]
+pub(crate) struct AlarmStore<DB>
+where
+    DB: StorageApi,
+{
+    /// Raised alarms
+    alarms: RwLock<Vec<AlarmMember>>,
+    /// Header generator
+    header_gen: Arc<HeaderGenerator>,
+    /// DB to store alarms
+    db: Arc<DB>,
+}
+
+impl<DB> AlarmStore<DB>
+where
+    DB: StorageApi,
+{
+    /// New `AlarmStore`
+    pub(crate) fn new(header_gen: Arc<HeaderGenerator>, db: Arc<DB>) -> Self {
+        Self {
+            alarms: RwLock::new(Vec::new()),
+            header_gen,
+            db,
+        }
+    }
+
+    /// Get all raised alarms
+    pub(crate) fn alarms(&self) -> Vec<AlarmMember> {
+        self.alarms.read().clone()
+    }
+
+    /// Check if an alarm of the given type is raised by any member
+    pub(crate) fn is_raised(&self, alarm: AlarmType) -> bool {
+        self.alarms
+            .read()
+            .iter()
+            .any(|a| a.alarm == i32::from(alarm))
+    }
+
+    /// Execute an alarm request
+    pub(crate) fn execute(
+        &self,
+        request: &RequestWithToken,
+    ) -> Result<CommandResponse, ExecuteError> {
+        #[allow(clippy::wildcard_enum_match_arm)]
+        let alarms = match request.request {
+            RequestWrapper::AlarmRequest(ref req) => {
+                debug!("Receive AlarmRequest {:?}", req);
+                self.handle_alarm_request(req)?
+            }
+            _ => unreachable!("Other request should not be sent to this store"),
+        };
+        Ok(CommandResponse::new(
+            AlarmResponse {
+                header: Some(self.header_gen.gen_header_without_revision()),
+                alarms,
+            }
+            .into(),
+        ))
+    }
+
+    /// Handle `AlarmRequest`, return the alarms affected by the request
+    fn handle_alarm_request(&self, req: &AlarmRequest) -> Result<Vec<AlarmMember>, ExecuteError> {
+        let alarms_r = self.alarms.read();
+        let alarms = match AlarmAction::from_i32(req.action) {
+            Some(AlarmAction::Get) => alarms_r.clone(),
+            Some(AlarmAction::Activate) => {
+                if req.alarm == i32::from(AlarmType::None) {
+                    vec![]
+                } else {
+                    vec![AlarmMember {
+                        member_id: req.member_id,
+                        alarm: req.alarm,
+                    }]
+                }
+            }
+            Some(AlarmAction::Deactivate) => alarms_r
+                .iter()
+                .filter(|alarm| alarm.member_id == req.member_id && alarm.alarm == req.alarm)
+                .cloned()
+                .collect(),
+            None => return Err(ExecuteError::invalid_alarm_action(req.action)),
+        };
+        Ok(alarms)
+    }
+
+    /// Sync an alarm request
+    pub(crate) fn after_sync(&self, id: &ProposeId, request: &RequestWithToken) -> SyncResponse {
+        #[allow(clippy::wildcard_enum_match_arm)]
+        let req = match request.request {
+            RequestWrapper::AlarmRequest(ref req) => req,
+            _ => unreachable!("Other request should not be sent to this store"),
+        };
+        debug!("Sync AlarmRequest {:?}", req);
+        let alarm = AlarmMember {
+            member_id: req.member_id,
+            alarm: req.alarm,
+        };
+        match AlarmAction::from_i32(req.action) {
+            Some(AlarmAction::Activate) if req.alarm != i32::from(AlarmType::None) => {
+                let mut alarms_w = self.alarms.write();
+                if !alarms_w.contains(&alarm) {
+                    alarms_w.push(alarm.clone());
+                    self.db.buffer_op(id, WriteOp::PutAlarm(alarm));
+                }
+            }
+            Some(AlarmAction::Deactivate) => {
+                let mut alarms_w = self.alarms.write();
+                if alarms_w.contains(&alarm) {
+                    alarms_w.retain(|a| a != &alarm);
+                    self.db.buffer_op(id, WriteOp::DeleteAlarm(alarm));
+                }
+            }
+            Some(AlarmAction::Get | AlarmAction::Activate) | None => {}
+        }
+        SyncResponse::new(self.header_gen.revision())
+    }
+
+    /// Recover alarms from persistent storage
+    pub(crate) fn recover(&self) -> Result<(), ExecuteError> {
+        let alarms = self
+            .db
+            .get_all(ALARM_TABLE)?
+            .into_iter()
+            .map(|(_, v)| {
+                AlarmMember::decode(v.as_slice()).map_err(|e| {
+                    ExecuteError::DbError(format!("Failed to decode alarm, error: {e}"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        *self.alarms.write() = alarms;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use utils::config::StorageConfig;
+
+    use super::*;
+    use crate::{rpc::ResponseWrapper, storage::db::DBProxy};
+
+    fn alarm_request(action: AlarmAction, alarm: AlarmType) -> RequestWithToken {
+        RequestWithToken::new(
+            AlarmRequest {
+                action: action.into(),
+                member_id: 1,
+                alarm: alarm.into(),
+            }
+            .into(),
+        )
+    }
+
+    fn exe_and_sync_req(
+        store: &AlarmStore<DBProxy>,
+        req: &RequestWithToken,
+    ) -> Result<Vec<AlarmMember>, ExecuteError> {
+        let cmd_res = store.execute(req)?;
+        let id = ProposeId::new("test-id".to_owned());
+        let _ignore = store.after_sync(&id, req);
+        store.db.flush(&id)?;
+        let ResponseWrapper::AlarmResponse(resp) = cmd_res.decode() else {
+            panic!("alarm request should get an alarm response");
+        };
+        Ok(resp.alarms)
+    }
+
+    #[test]
+    fn activate_and_deactivate_alarms() -> Result<(), ExecuteError> {
+        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let store = AlarmStore::new(Arc::new(HeaderGenerator::new(0, 0)), Arc::clone(&db));
+
+        let activated = exe_and_sync_req(
+            &store,
+            &alarm_request(AlarmAction::Activate, AlarmType::Nospace),
+        )?;
+        assert_eq!(activated.len(), 1);
+        let _ignore = exe_and_sync_req(
+            &store,
+            &alarm_request(AlarmAction::Activate, AlarmType::Nospace),
+        )?;
+        let alarms = exe_and_sync_req(&store, &alarm_request(AlarmAction::Get, AlarmType::None))?;
+        assert_eq!(alarms, activated);
+
+        let new_store = AlarmStore::new(Arc::new(HeaderGenerator::new(0, 0)), Arc::clone(&db));
+        new_store.recover()?;
+        assert_eq!(new_store.alarms(), activated);
+
+        let deactivated = exe_and_sync_req(
+            &store,
+            &alarm_request(AlarmAction::Deactivate, AlarmType::Nospace),
+        )?;
+        assert_eq!(deactivated, activated);
+        assert!(store.alarms().is_empty());
+        Ok(())
+    }
+}
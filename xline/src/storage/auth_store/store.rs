@@ -11,7 +11,7 @@ use clippy_utilities::Cast;
 use curp::cmd::ProposeId;
 use itertools::Itertools;
 use jsonwebtoken::{DecodingKey, EncodingKey};
-use log::debug;
+use log::{debug, warn};
 use parking_lot::RwLock;
 use pbkdf2::{
     password_hash::{PasswordHash, PasswordVerifier},
@@ -125,6 +125,18 @@ where
         }
     }
 
+    /// Assign a token to the user named by the common name of a verified client certificate, so
+    /// the request of the client is checked as if it carried the token of the user. `None` is
+    /// returned if auth is disabled, where no token is needed
+    pub(crate) fn assign_cert_user(&self, username: &str) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+        self.assign(username)
+            .map_err(|e| warn!("failed to assign token to the certificate user {username}: {e}"))
+            .ok()
+    }
+
     /// verify token
     pub(crate) fn verify_token(&self, token: &str) -> Result<TokenClaims, ExecuteError> {
         match self.token_manager {
@@ -956,6 +968,7 @@ where
                 | RequestWrapper::AuthRoleDeleteRequest(_)
                 | RequestWrapper::AuthUserListRequest(_)
                 | RequestWrapper::AuthRoleListRequest(_)
+                | RequestWrapper::AlarmRequest(_)
         )
     }
 
@@ -1195,9 +1208,7 @@ where
     /// Recover data from persistent storage
     pub(crate) fn recover(&self) -> Result<(), ExecuteError> {
         let enabled = self.backend.get_enable()?;
-        if enabled {
-            self.enabled.store(true, AtomicOrdering::Relaxed);
-        }
+        self.enabled.store(enabled, AtomicOrdering::Relaxed);
         let revision = self.backend.get_revision()?;
         self.revision.set(revision);
         self.create_permission_cache()?;
@@ -1372,6 +1383,19 @@ mod test {
         assert!(!store.is_enabled());
     }
 
+    #[test]
+    fn test_assign_cert_user() {
+        let db = DBProxy::open(&StorageConfig::Memory).unwrap();
+        let store = init_auth_store(db);
+        assert!(store.assign_cert_user("u").is_none());
+
+        store.enabled.store(true, AtomicOrdering::Relaxed);
+        let token = store.assign_cert_user("u").unwrap();
+        let claims = store.verify_token(&token).unwrap();
+        assert_eq!(claims.username, "u");
+        assert_eq!(claims.revision, store.revision());
+    }
+
     #[test]
     fn test_recover() -> Result<(), ExecuteError> {
         let db = DBProxy::open(&StorageConfig::Memory).unwrap();
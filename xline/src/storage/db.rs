@@ -10,26 +10,28 @@ use prost::Message;
 use utils::config::StorageConfig;
 
 use crate::{
-    rpc::{PbLease, Role, User},
+    rpc::{AlarmMember, PbLease, Role, User},
     server::command::{APPLIED_INDEX_KEY, META_TABLE},
 };
 
 use super::{
+    alarm_store::{alarm_key, ALARM_TABLE},
     auth_store::{AUTH_ENABLE_KEY, AUTH_REVISION_KEY, AUTH_TABLE, ROLE_TABLE, USER_TABLE},
-    kv_store::KV_TABLE,
+    kv_store::{COMPACT_REVISION_KEY, KV_TABLE},
     lease_store::LEASE_TABLE,
     storage_api::StorageApi,
     ExecuteError, Revision,
 };
 
 /// Xline Server Storage Table
-const XLINE_TABLES: [&str; 6] = [
+const XLINE_TABLES: [&str; 7] = [
     META_TABLE,
     KV_TABLE,
     LEASE_TABLE,
     AUTH_TABLE,
     USER_TABLE,
     ROLE_TABLE,
+    ALARM_TABLE,
 ];
 
 /// Database to store revision to kv mapping
@@ -54,6 +56,14 @@ where
             buffer: Mutex::new(HashMap::new()),
         }
     }
+
+    /// Operations that delete all the data in xline tables
+    fn delete_all_ops() -> Vec<WriteOperation> {
+        XLINE_TABLES
+            .iter()
+            .map(|table| WriteOperation::new_delete_range(table, vec![], vec![0xff]))
+            .collect()
+    }
 }
 
 impl<S> StorageApi for DB<S>
@@ -96,17 +106,49 @@ where
     }
 
     fn reset(&self) -> Result<(), ExecuteError> {
-        let start = vec![];
-        let end = vec![0xff];
-        let ops = XLINE_TABLES
-            .iter()
-            .map(|table| WriteOperation::new_delete_range(table, start.as_slice(), end.as_slice()))
-            .collect();
+        let ops = Self::delete_all_ops();
         self.engine
             .write_batch(ops, true)
             .map_err(|e| ExecuteError::DbError(format!("Failed to reset database, error: {e}")))
     }
 
+    fn snapshot(&self) -> Result<Vec<u8>, ExecuteError> {
+        let tables = XLINE_TABLES
+            .iter()
+            .map(|table| self.get_all(table))
+            .collect::<Result<Vec<_>, _>>()?;
+        bincode::serialize(&tables)
+            .map_err(|e| ExecuteError::DbError(format!("Failed to serialize snapshot: {e}")))
+    }
+
+    fn restore(&self, snapshot: &[u8]) -> Result<(), ExecuteError> {
+        let tables: Vec<Vec<(Vec<u8>, Vec<u8>)>> = bincode::deserialize(snapshot)
+            .map_err(|e| ExecuteError::DbError(format!("Failed to deserialize snapshot: {e}")))?;
+        if tables.len() != XLINE_TABLES.len() {
+            return Err(ExecuteError::DbError(format!(
+                "Snapshot contains {} tables, expected {}",
+                tables.len(),
+                XLINE_TABLES.len()
+            )));
+        }
+        let mut ops = Self::delete_all_ops();
+        for (table, kvs) in XLINE_TABLES.into_iter().zip(tables) {
+            ops.extend(
+                kvs.into_iter()
+                    .map(|(key, value)| WriteOperation::new_put(table, key, value)),
+            );
+        }
+        self.engine
+            .write_batch(ops, true)
+            .map_err(|e| ExecuteError::DbError(format!("Failed to restore database, error: {e}")))
+    }
+
+    fn size(&self) -> Result<u64, ExecuteError> {
+        self.engine
+            .size()
+            .map_err(|e| ExecuteError::DbError(format!("Failed to get database size: {e}")))
+    }
+
     fn buffer_op(&self, propose_id: &ProposeId, op: WriteOp) {
         let mut buffer = self.buffer.lock();
         if let Some(ops) = buffer.get_mut(propose_id) {
@@ -186,6 +228,27 @@ impl StorageApi for DBProxy {
         }
     }
 
+    fn snapshot(&self) -> Result<Vec<u8>, ExecuteError> {
+        match *self {
+            DBProxy::MemDB(ref inner_db) => inner_db.snapshot(),
+            DBProxy::RocksDB(ref inner_db) => inner_db.snapshot(),
+        }
+    }
+
+    fn restore(&self, snapshot: &[u8]) -> Result<(), ExecuteError> {
+        match *self {
+            DBProxy::MemDB(ref inner_db) => inner_db.restore(snapshot),
+            DBProxy::RocksDB(ref inner_db) => inner_db.restore(snapshot),
+        }
+    }
+
+    fn size(&self) -> Result<u64, ExecuteError> {
+        match *self {
+            DBProxy::MemDB(ref inner_db) => inner_db.size(),
+            DBProxy::RocksDB(ref inner_db) => inner_db.size(),
+        }
+    }
+
     fn buffer_op(&self, id: &ProposeId, op: WriteOp) {
         match *self {
             DBProxy::MemDB(ref inner_db) => inner_db.buffer_op(id, op),
@@ -231,8 +294,12 @@ impl DBProxy {
 pub enum WriteOp {
     /// Put a key-value pair to kv table
     PutKeyValue(Revision, Vec<u8>),
+    /// Delete the key-value pairs in the range `[from, to)` from kv table
+    DeleteKeyValueRange(Revision, Revision),
     /// Put the applied index to meta table
     PutAppliedIndex(u64),
+    /// Put the compacted revision to meta table
+    PutCompactRevision(i64),
     /// Put a lease to lease table
     PutLease(PbLease),
     /// Delete a lease from lease table
@@ -249,6 +316,10 @@ pub enum WriteOp {
     PutRole(Role),
     /// Delete a role from role table
     DeleteRole(String),
+    /// Put an alarm to alarm table
+    PutAlarm(AlarmMember),
+    /// Delete an alarm from alarm table
+    DeleteAlarm(AlarmMember),
 }
 
 impl From<WriteOp> for WriteOperation {
@@ -259,9 +330,15 @@ impl From<WriteOp> for WriteOperation {
                 let key = rev.encode_to_vec();
                 WriteOperation::new_put(KV_TABLE, key, value)
             }
+            WriteOp::DeleteKeyValueRange(from, to) => {
+                WriteOperation::new_delete_range(KV_TABLE, from.encode_to_vec(), to.encode_to_vec())
+            }
             WriteOp::PutAppliedIndex(index) => {
                 WriteOperation::new_put(META_TABLE, APPLIED_INDEX_KEY, index.to_le_bytes())
             }
+            WriteOp::PutCompactRevision(rev) => {
+                WriteOperation::new_put(META_TABLE, COMPACT_REVISION_KEY, rev.to_le_bytes())
+            }
             WriteOp::PutLease(lease) => WriteOperation::new_put(
                 LEASE_TABLE,
                 lease.id.encode_to_vec(),
@@ -284,6 +361,12 @@ impl From<WriteOp> for WriteOperation {
                 WriteOperation::new_put(ROLE_TABLE, role.name, value)
             }
             WriteOp::DeleteRole(name) => WriteOperation::new_delete(ROLE_TABLE, name),
+            WriteOp::PutAlarm(alarm) => {
+                WriteOperation::new_put(ALARM_TABLE, alarm_key(&alarm), alarm.encode_to_vec())
+            }
+            WriteOp::DeleteAlarm(alarm) => {
+                WriteOperation::new_delete(ALARM_TABLE, alarm_key(&alarm))
+            }
         }
     }
 }
@@ -315,4 +398,30 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_snapshot_and_restore() -> Result<(), ExecuteError> {
+        let origin_db = DBProxy::open(&StorageConfig::Memory)?;
+        let revision = Revision::new(1, 1);
+        let key = revision.encode_to_vec();
+        let id = ProposeId::new("test-id".to_owned());
+        origin_db.buffer_op(&id, WriteOp::PutKeyValue(revision, "value1".into()));
+        origin_db.buffer_op(&id, WriteOp::PutAppliedIndex(5));
+        origin_db.flush(&id)?;
+        let snapshot = origin_db.snapshot()?;
+
+        let new_db = DBProxy::open(&StorageConfig::Memory)?;
+        let revision = Revision::new(2, 1);
+        let id = ProposeId::new("test-id-2".to_owned());
+        new_db.buffer_op(&id, WriteOp::PutKeyValue(revision, "value2".into()));
+        new_db.flush(&id)?;
+        new_db.restore(&snapshot)?;
+
+        let res = new_db.get_all(KV_TABLE)?;
+        assert_eq!(res, vec![(key, "value1".as_bytes().to_vec())]);
+        let res = new_db.get_value(META_TABLE, APPLIED_INDEX_KEY)?;
+        assert_eq!(res, Some(5_u64.to_le_bytes().to_vec()));
+
+        Ok(())
+    }
 }
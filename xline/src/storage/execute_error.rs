@@ -1,5 +1,11 @@
 use thiserror::Error;
 
+/// Message of `ExecuteError::RevisionCompacted`, the same as etcd
+const REVISION_COMPACTED: &str = "etcdserver: mvcc: required revision has been compacted";
+
+/// Message of `ExecuteError::RevisionTooLarge`, the same as etcd
+const REVISION_TOO_LARGE: &str = "etcdserver: mvcc: required revision is a future revision";
+
 /// Error met when executing commands
 #[derive(Error, Debug, Clone)]
 pub enum ExecuteError {
@@ -12,12 +18,31 @@ pub enum ExecuteError {
     /// Auth error
     #[error("auth error: {0}")]
     AuthError(String),
+    /// Alarm error
+    #[error("alarm error: {0}")]
+    AlarmError(String),
     /// Db error
     #[error("db error: {0}")]
     DbError(String),
     /// Permission denied
     #[error("permission denied")]
     PermissionDenied,
+    /// Required revision has been compacted
+    #[error("{}", REVISION_COMPACTED)]
+    RevisionCompacted {
+        /// Required revision
+        revision: i64,
+        /// Compacted revision
+        compacted_revision: i64,
+    },
+    /// Required revision is a future revision
+    #[error("{}", REVISION_TOO_LARGE)]
+    RevisionTooLarge {
+        /// Required revision
+        revision: i64,
+        /// Current revision
+        current_revision: i64,
+    },
 }
 
 impl ExecuteError {
@@ -26,6 +51,22 @@ impl ExecuteError {
         Self::KvError("key not found".to_owned())
     }
 
+    /// Required revision has been compacted
+    pub(crate) fn revision_compacted(revision: i64, compacted_revision: i64) -> Self {
+        Self::RevisionCompacted {
+            revision,
+            compacted_revision,
+        }
+    }
+
+    /// Required revision is a future revision
+    pub(crate) fn revision_too_large(revision: i64, current_revision: i64) -> Self {
+        Self::RevisionTooLarge {
+            revision,
+            current_revision,
+        }
+    }
+
     /// Lease not found
     pub(crate) fn lease_not_found(lease_id: i64) -> Self {
         Self::LeaseError(format!("lease {lease_id} not found"))
@@ -134,4 +175,66 @@ impl ExecuteError {
     pub(crate) fn token_old_revision() -> Self {
         Self::AuthError("token's revision is older than current revision".to_owned())
     }
+
+    /// Alarm action is invalid
+    pub(crate) fn invalid_alarm_action(action: i32) -> Self {
+        Self::AlarmError(format!("invalid alarm action {action}"))
+    }
+
+    /// The backend is out of space, the request is rejected while the `NOSPACE` alarm is raised
+    pub(crate) fn no_space() -> Self {
+        Self::AlarmError("database space exceeded".to_owned())
+    }
+}
+
+/// Convert the error of a command executed on current node to the status of the client, the
+/// required revisions out of range are reported as `OUT_OF_RANGE` like etcd
+impl From<ExecuteError> for tonic::Status {
+    #[inline]
+    fn from(err: ExecuteError) -> Self {
+        if matches!(
+            err,
+            ExecuteError::RevisionCompacted { .. } | ExecuteError::RevisionTooLarge { .. }
+        ) {
+            tonic::Status::out_of_range(err.to_string())
+        } else {
+            tonic::Status::internal(format!("Execute failed: {err:?}"))
+        }
+    }
+}
+
+/// Convert the error message of a command executed through curp to the status of the client,
+/// curp carries the errors as their messages
+pub(crate) fn execution_error_status(message: String) -> tonic::Status {
+    if message == REVISION_COMPACTED || message == REVISION_TOO_LARGE {
+        tonic::Status::out_of_range(message)
+    } else {
+        tonic::Status::invalid_argument(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn out_of_range_revisions_are_reported_like_etcd() {
+        let compacted = ExecuteError::revision_compacted(1, 2);
+        let too_large = ExecuteError::revision_too_large(3, 2);
+        for err in [compacted, too_large] {
+            let status = execution_error_status(err.to_string());
+            assert_eq!(status.code(), tonic::Code::OutOfRange);
+            assert_eq!(tonic::Status::from(err).code(), tonic::Code::OutOfRange);
+        }
+        assert_eq!(
+            ExecuteError::revision_compacted(1, 2).to_string(),
+            "etcdserver: mvcc: required revision has been compacted"
+        );
+        assert_eq!(
+            ExecuteError::revision_too_large(3, 2).to_string(),
+            "etcdserver: mvcc: required revision is a future revision"
+        );
+        let status = execution_error_status(ExecuteError::key_not_found().to_string());
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
 }
@@ -21,6 +21,11 @@ impl Index {
         }
     }
 
+    /// Remove all keys, the index is rebuilt from the storage after it's reset
+    pub(crate) fn clear(&self) {
+        self.index.lock().clear();
+    }
+
     /// Filter out `KeyRevision` that is less than one revision and convert to `Revision`
     fn filter_revision(revs: &[KeyRevision], revision: i64) -> Vec<Revision> {
         revs.iter()
@@ -86,7 +91,9 @@ pub(crate) trait IndexOperate {
         version: i64,
     );
 
-    // TODO: fn compact(rev:i64)
+    /// Compact the index, drop the `KeyRevision`s older than the given revision and return them.
+    /// The latest `KeyRevision` no greater than the given revision is kept unless it's a deletion.
+    fn compact(&self, revision: i64) -> Vec<Revision>;
 }
 
 impl IndexOperate for Index {
@@ -227,6 +234,21 @@ impl IndexOperate for Index {
         let new_rev = KeyRevision::new(create_revision, version, revision, sub_revision);
         index.entry(key).or_insert_with(Vec::new).push(new_rev);
     }
+
+    fn compact(&self, revision: i64) -> Vec<Revision> {
+        let mut compacted = Vec::new();
+        self.index.lock().retain(|_k, revs| {
+            let le_count = revs.partition_point(|rev| rev.mod_revision <= revision);
+            let del_count = match le_count.checked_sub(1).and_then(|idx| revs.get(idx)) {
+                Some(latest) if !latest.is_deleted() => le_count.overflow_sub(1),
+                _ => le_count,
+            };
+            compacted.extend(revs.drain(..del_count).map(|rev| rev.as_revision()));
+            !revs.is_empty()
+        });
+        compacted.sort_unstable();
+        compacted
+    }
 }
 
 #[cfg(test)]
@@ -287,6 +309,33 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_compact() {
+        let index = init_and_test_insert();
+        index.insert_or_update_revision(b"foo", 4, 0);
+        let _ignore = index.delete(b"foo", b"", 5, 0);
+        index.insert_or_update_revision(b"bar", 6, 0);
+
+        assert_eq!(index.compact(2), vec![Revision::new(1, 3)]);
+        assert_eq!(
+            index.compact(5),
+            vec![
+                Revision::new(2, 2),
+                Revision::new(4, 0),
+                Revision::new(5, 0)
+            ]
+        );
+        assert_eq!(
+            *index.index.lock(),
+            BTreeMap::from_iter(vec![
+                (b"bar".to_vec(), vec![KeyRevision::new(6, 1, 6, 0)]),
+                (b"key".to_vec(), vec![KeyRevision::new(1, 3, 3, 1)]),
+            ])
+        );
+        assert_eq!(index.get(b"key", b"", 2), vec![]);
+        assert_eq!(index.get(b"key", b"", 3), vec![Revision::new(3, 1)]);
+    }
+
     #[test]
     fn test_restore() {
         let index = Index::new();
@@ -2,6 +2,7 @@ use std::{
     cmp::Ordering,
     collections::{HashMap, VecDeque},
     sync::Arc,
+    time::Duration,
 };
 
 use clippy_utilities::{Cast, OverflowArithmetic};
@@ -21,19 +22,23 @@ use crate::{
     header_gen::HeaderGenerator,
     revision_number::RevisionNumber,
     rpc::{
-        Compare, CompareResult, CompareTarget, DeleteRangeRequest, DeleteRangeResponse, Event,
-        EventType, KeyValue, PutRequest, PutResponse, RangeRequest, RangeResponse, Request,
-        RequestWithToken, RequestWrapper, ResponseWrapper, SortOrder, SortTarget, TargetUnion,
-        TxnRequest, TxnResponse,
+        CompactionRequest, CompactionResponse, Compare, CompareResult, CompareTarget,
+        DeleteRangeRequest, DeleteRangeResponse, Event, EventType, KeyValue, PutRequest,
+        PutResponse, RangeRequest, RangeResponse, Request, RequestWithToken, RequestWrapper,
+        ResponseWrapper, SortOrder, SortTarget, TargetUnion, TxnRequest, TxnResponse,
     },
-    server::command::{CommandResponse, KeyRange, SyncResponse},
+    server::command::{CommandResponse, KeyRange, SyncResponse, META_TABLE},
     storage::{db::WriteOp, ExecuteError},
 };
 
 /// KV table name
 pub(crate) const KV_TABLE: &str = "kv";
+/// Key of compacted revision
+pub(crate) const COMPACT_REVISION_KEY: &str = "compact_revision";
 /// Default channel size
 const CHANNEL_SIZE: usize = 128;
+/// Interval of the progress notifications sent to the idle watchers, the same as etcd
+const PROGRESS_NOTIFY_INTERVAL: Duration = Duration::from_secs(600);
 
 /// KV store
 #[derive(Debug)]
@@ -59,6 +64,8 @@ where
     db: Arc<DB>,
     /// Revision
     revision: Arc<RevisionNumber>,
+    /// Compacted revision, revisions below it are no longer available
+    compacted_revision: RevisionNumber,
     /// Header generator
     header_gen: Arc<HeaderGenerator>,
     /// KV update sender
@@ -86,7 +93,11 @@ where
             storage,
             index,
         ));
-        let kv_watcher = Arc::new(KvWatcher::new(Arc::clone(&inner), kv_update_rx));
+        let kv_watcher = Arc::new(KvWatcher::new(
+            Arc::clone(&inner),
+            kv_update_rx,
+            PROGRESS_NOTIFY_INTERVAL,
+        ));
         Self { inner, kv_watcher }
     }
 
@@ -122,9 +133,22 @@ where
         self.inner.kv_update_tx.clone()
     }
 
+    /// Get revision of KV store
+    pub(crate) fn revision(&self) -> i64 {
+        self.inner.revision()
+    }
+
+    /// Get compacted revision of KV store
+    pub(crate) fn compacted_revision(&self) -> i64 {
+        self.inner.compacted_revision()
+    }
+
     /// Recover data from persistent storage
     pub(crate) async fn recover(&self) -> Result<(), ExecuteError> {
-        self.inner.recover_from_current_db().await
+        self.inner.recover_from_current_db().await?;
+        // the progress notifications start from the recovered revision
+        self.inner.notify_updates(self.revision(), vec![]).await;
+        Ok(())
     }
 }
 
@@ -144,6 +168,7 @@ where
             index,
             db,
             revision: header_gen.revision_arc(),
+            compacted_revision: RevisionNumber::new(0),
             header_gen,
             kv_update_tx,
             lease_cmd_tx,
@@ -155,6 +180,11 @@ where
         self.revision.get()
     }
 
+    /// Get compacted revision of KV store
+    pub(crate) fn compacted_revision(&self) -> i64 {
+        self.compacted_revision.get()
+    }
+
     /// Notify KV changes to KV watcher
     async fn notify_updates(&self, revision: i64, updates: Vec<Event>) {
         assert!(
@@ -344,10 +374,21 @@ where
     async fn recover_from_current_db(&self) -> Result<(), ExecuteError> {
         let mut key_to_lease: HashMap<Vec<u8>, i64> = HashMap::new();
         let kvs = self.db.get_all(KV_TABLE)?;
+        // the keys and revisions before the storage is reset are dropped
+        self.index.clear();
+        self.compacted_revision.set(0);
 
+        if let Some(rev_bytes) = self.db.get_value(META_TABLE, COMPACT_REVISION_KEY)? {
+            let buf: [u8; 8] = rev_bytes.try_into().map_err(|_ignore| {
+                ExecuteError::DbError("Failed to decode compacted revision".to_owned())
+            })?;
+            self.compacted_revision.set(i64::from_le_bytes(buf));
+        }
+        // the last revision may be a deletion that has been compacted
         let current_rev = kvs
             .last()
-            .map_or(1, |pair| Revision::decode(&pair.0).revision());
+            .map_or(1, |pair| Revision::decode(&pair.0).revision())
+            .max(self.compacted_revision());
         self.revision.set(current_rev);
 
         for (key, value) in kvs {
@@ -437,17 +478,18 @@ where
         Ok((kvs, total))
     }
 
-    /// Get `KeyValue` start from a revision and convert to `Event`
+    /// Get `KeyValue` start from a revision and convert to `Event`, the previous `KeyValue`s
+    /// are looked up if `prev_kv` is set
     pub(crate) fn get_event_from_revision(
         &self,
         key_range: KeyRange,
         revision: i64,
+        prev_kv: bool,
     ) -> Result<Vec<Event>, ExecuteError> {
         let key = key_range.start.as_slice();
         let range_end = key_range.end.as_slice();
         let revisions = self.index.get_from_rev(key, range_end, revision);
-        let events = self
-            .get_values(&revisions)?
+        self.get_values(&revisions)?
             .into_iter()
             .map(|kv| {
                 // Delete
@@ -457,16 +499,31 @@ where
                 } else {
                     EventType::Put
                 };
+                let prev = if prev_kv {
+                    self.get_prev_kv(&kv)?
+                } else {
+                    None
+                };
                 let mut event = Event {
                     kv: Some(kv),
-                    prev_kv: None,
+                    prev_kv: prev,
                     ..Default::default()
                 };
                 event.set_type(event_type);
-                event
+                Ok(event)
             })
-            .collect();
-        Ok(events)
+            .collect()
+    }
+
+    /// Get the `KeyValue` of the key right before the revision of `kv`, return `None` if the
+    /// key didn't exist at that time or the revision has been compacted
+    fn get_prev_kv(&self, kv: &KeyValue) -> Result<Option<KeyValue>, ExecuteError> {
+        let prev_revision = kv.mod_revision.overflow_sub(1);
+        // revision 0 means the latest revision
+        if prev_revision <= 0 {
+            return Ok(None);
+        }
+        Ok(self.get_range(&kv.key, &[], prev_revision)?.pop())
     }
 }
 
@@ -499,6 +556,10 @@ where
                 debug!("Receive TxnRequest {:?}", req);
                 self.handle_txn_request(req).map(Into::into)
             }
+            RequestWrapper::CompactionRequest(ref req) => {
+                debug!("Receive CompactionRequest {:?}", req);
+                self.handle_compaction_request(req).map(Into::into)
+            }
             _ => unreachable!("Other request should not be sent to this store"),
         };
         res
@@ -507,6 +568,14 @@ where
     /// Handle `RangeRequest`
     fn handle_range_request(&self, req: &RangeRequest) -> Result<RangeResponse, ExecuteError> {
         debug!("handle_range_request kvs");
+        let compacted_revision = self.compacted_revision();
+        if req.revision > 0 && req.revision < compacted_revision {
+            return Err(ExecuteError::revision_compacted(req.revision, compacted_revision));
+        }
+        let current_revision = self.revision();
+        if req.revision > current_revision {
+            return Err(ExecuteError::revision_too_large(req.revision, current_revision));
+        }
         let storage_fetch_limit = if (req.sort_order() != SortOrder::None)
             || (req.max_mod_revision != 0)
             || (req.min_mod_revision != 0)
@@ -614,12 +683,35 @@ where
         })
     }
 
+    /// Handle `CompactionRequest`
+    fn handle_compaction_request(
+        &self,
+        req: &CompactionRequest,
+    ) -> Result<CompactionResponse, ExecuteError> {
+        let compacted_revision = self.compacted_revision();
+        if req.revision <= compacted_revision {
+            return Err(ExecuteError::revision_compacted(req.revision, compacted_revision));
+        }
+        let current_revision = self.revision();
+        if req.revision > current_revision {
+            return Err(ExecuteError::revision_too_large(req.revision, current_revision));
+        }
+        Ok(CompactionResponse {
+            header: Some(self.header_gen.gen_header()),
+        })
+    }
+
     /// Sync requests in kv store
     async fn sync_request(
         &self,
         id: &ProposeId,
         wrapper: &RequestWrapper,
     ) -> Result<i64, ExecuteError> {
+        // compaction doesn't generate a new revision
+        if let RequestWrapper::CompactionRequest(ref req) = *wrapper {
+            self.sync_compaction_request(id, req);
+            return Ok(self.revision());
+        }
         let next_revision = self.revision.next();
         #[allow(clippy::wildcard_enum_match_arm)] // only kv requests can be sent to kv store
         let events = match *wrapper {
@@ -645,6 +737,56 @@ where
         Ok(next_revision)
     }
 
+    /// Sync `CompactionRequest`, drop the compacted revisions from the index and the kv table
+    fn sync_compaction_request(&self, id: &ProposeId, req: &CompactionRequest) {
+        debug!("Sync CompactionRequest {:?}", req);
+        // the request may be synced again after restart
+        if req.revision <= self.compacted_revision() {
+            return;
+        }
+        let compacted = self.index.compact(req.revision);
+        let kept = self.index.get(&[0], &[0], req.revision);
+        for (from, to) in Self::compacted_ranges(compacted, kept) {
+            self.db
+                .buffer_op(id, WriteOp::DeleteKeyValueRange(from, to));
+        }
+        self.db
+            .buffer_op(id, WriteOp::PutCompactRevision(req.revision));
+        self.compacted_revision.set(req.revision);
+    }
+
+    /// Merge sorted compacted revisions into ranges `[from, to)` that never cover a kept revision
+    fn compacted_ranges(
+        compacted: Vec<Revision>,
+        mut kept: Vec<Revision>,
+    ) -> Vec<(Revision, Revision)> {
+        kept.sort_unstable();
+        let mut kept_iter = kept.into_iter().peekable();
+        let mut ranges = Vec::new();
+        let mut range: Option<(Revision, Revision)> = None;
+        for rev in compacted {
+            let mut split = false;
+            while kept_iter.next_if(|kept_rev| *kept_rev < rev).is_some() {
+                split = true;
+            }
+            range = match range {
+                Some((from, _)) if !split => Some((from, rev)),
+                prev => {
+                    ranges.extend(prev);
+                    Some((rev, rev))
+                }
+            };
+        }
+        ranges.extend(range);
+        ranges
+            .into_iter()
+            .map(|(from, last)| {
+                let to = Revision::new(last.revision(), last.sub_revision().overflow_add(1));
+                (from, to)
+            })
+            .collect()
+    }
+
     /// Sync `TxnRequest` and return if kvstore is changed
     async fn sync_txn_request(
         &self,
@@ -1012,6 +1154,131 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_compaction() -> Result<(), ExecuteError> {
+        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let store = init_store(Arc::clone(&db)).await?;
+        let put_req = RequestWithToken::new(
+            PutRequest {
+                key: "a".into(),
+                value: "a1".into(),
+                ..Default::default()
+            }
+            .into(),
+        );
+        let compaction_req = RequestWithToken::new(
+            CompactionRequest {
+                revision: 7,
+                physical: true,
+            }
+            .into(),
+        );
+        for (i, req) in [put_req, compaction_req].iter().enumerate() {
+            let _cmd_res = store.execute(req)?;
+            let id = ProposeId::new(format!("test-id-{i}"));
+            let _sync_res = store.after_sync(&id, req).await?;
+            store.inner.db.flush(&id)?;
+        }
+        assert_eq!(store.compacted_revision(), 7);
+        // the first version of "a" is dropped
+        assert_eq!(db.get_all(KV_TABLE)?.len(), 5);
+
+        let range_req = RangeRequest {
+            key: "a".into(),
+            revision: 3,
+            ..Default::default()
+        };
+        assert!(matches!(
+            store.inner.handle_range_request(&range_req),
+            Err(ExecuteError::RevisionCompacted { .. })
+        ));
+        let range_req = RangeRequest {
+            key: "a".into(),
+            revision: 8,
+            ..Default::default()
+        };
+        assert!(matches!(
+            store.inner.handle_range_request(&range_req),
+            Err(ExecuteError::RevisionTooLarge { .. })
+        ));
+        let range_req = RangeRequest {
+            key: "a".into(),
+            ..Default::default()
+        };
+        let res = store.inner.handle_range_request(&range_req)?;
+        assert_eq!(res.kvs[0].value, b"a1");
+
+        let new_store = init_empty_store(db);
+        new_store.recover().await?;
+        assert_eq!(new_store.compacted_revision(), 7);
+        assert_eq!(new_store.inner.revision(), 7);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_event_from_revision_with_prev_kv() -> Result<(), ExecuteError> {
+        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let store = init_store(db).await?;
+        let put_req = RequestWithToken::new(
+            PutRequest {
+                key: "a".into(),
+                value: "a1".into(),
+                ..Default::default()
+            }
+            .into(),
+        );
+        let delete_req = RequestWithToken::new(
+            DeleteRangeRequest {
+                key: "a".into(),
+                ..Default::default()
+            }
+            .into(),
+        );
+        for (i, req) in [put_req, delete_req].iter().enumerate() {
+            let _cmd_res = store.execute(req)?;
+            let id = ProposeId::new(format!("test-id-{i}"));
+            let _sync_res = store.after_sync(&id, req).await?;
+            store.inner.db.flush(&id)?;
+        }
+
+        let key_range = KeyRange {
+            start: "a".into(),
+            end: vec![],
+        };
+        let events = store
+            .inner
+            .get_event_from_revision(key_range.clone(), 1, true)?;
+        let prev_values: Vec<_> = events
+            .iter()
+            .map(|event| event.prev_kv.as_ref().map(|kv| kv.value.clone()))
+            .collect();
+        assert_eq!(
+            prev_values,
+            vec![None, Some(b"a".to_vec()), Some(b"a1".to_vec())]
+        );
+        let events = store.inner.get_event_from_revision(key_range, 1, false)?;
+        assert!(events.iter().all(|event| event.prev_kv.is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn compacted_ranges_will_not_cover_kept_revisions() {
+        let compacted = vec![
+            Revision::new(1, 0),
+            Revision::new(2, 0),
+            Revision::new(2, 1),
+            Revision::new(4, 0),
+        ];
+        let kept = vec![Revision::new(5, 0), Revision::new(3, 0)];
+        assert_eq!(
+            KvStoreBackend::<DBProxy>::compacted_ranges(compacted, kept),
+            vec![
+                (Revision::new(1, 0), Revision::new(2, 2)),
+                (Revision::new(4, 0), Revision::new(4, 1)),
+            ]
+        );
+    }
+
     fn sort_req(sort_order: SortOrder, sort_target: SortTarget) -> RangeRequest {
         RangeRequest {
             key: vec![0],
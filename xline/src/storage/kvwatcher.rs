@@ -2,20 +2,24 @@ use std::{
     collections::{HashMap, HashSet},
     hash::Hash,
     sync::Arc,
+    time::Duration,
 };
 
-use futures::{stream::FuturesUnordered, StreamExt};
+use futures::{future, stream::FuturesUnordered, StreamExt};
 use log::warn;
 use parking_lot::RwLock;
 use tokio::sync::mpsc;
 use utils::parking_lot_lock::RwLockMap;
 
-use super::storage_api::StorageApi;
+use super::{storage_api::StorageApi, ExecuteError};
 use crate::{rpc::Event, server::command::KeyRange, storage::kv_store::KvStoreBackend};
 
 /// Watch ID
 pub(crate) type WatchId = i64;
 
+/// Watch ID of the progress notifications that are not associated with any watcher
+pub(crate) const PROGRESS_WATCH_ID: WatchId = -1;
+
 /// Watcher
 #[derive(Debug)]
 struct Watcher {
@@ -27,6 +31,10 @@ struct Watcher {
     start_rev: i64,
     /// Event filters
     filters: Vec<i32>,
+    /// Whether to notify the progress periodically when the watcher is idle
+    progress_notify: bool,
+    /// Whether to send the previous key-values of the events
+    prev_kv: bool,
     /// Sender of watch event
     event_tx: mpsc::Sender<WatchEvent>,
 }
@@ -52,6 +60,8 @@ impl Watcher {
         watch_id: WatchId,
         start_rev: i64,
         filters: Vec<i32>,
+        progress_notify: bool,
+        prev_kv: bool,
         event_tx: mpsc::Sender<WatchEvent>,
     ) -> Self {
         Self {
@@ -59,6 +69,8 @@ impl Watcher {
             watch_id,
             start_rev,
             filters,
+            progress_notify,
+            prev_kv,
             event_tx,
         }
     }
@@ -78,21 +90,41 @@ impl Watcher {
         self.start_rev
     }
 
-    /// Notify events
-    async fn notify(&self, (revision, mut events): (i64, Vec<Event>)) {
+    /// Notify events, return whether any event is left after filtering
+    async fn notify(&self, (revision, mut events): (i64, Vec<Event>)) -> bool {
         if revision < self.start_rev() {
-            return;
+            return false;
         }
         events.retain(|event| self.filters.iter().all(|filter| filter != &event.r#type));
+        if !self.prev_kv {
+            for event in &mut events {
+                event.prev_kv = None;
+            }
+        }
+        let notified = !events.is_empty();
         let watch_event = WatchEvent {
             id: self.watch_id(),
             events,
             revision,
+            progress: false,
         };
         assert!(
             self.event_tx.send(watch_event).await.is_ok(),
             "WatchEvent receiver is closed"
         );
+        notified
+    }
+
+    /// Notify the progress at `revision` if the watcher asks for it
+    async fn notify_progress(&self, revision: i64) {
+        if !self.progress_notify {
+            return;
+        }
+        let watch_event = WatchEvent::progress(self.watch_id(), revision);
+        assert!(
+            self.event_tx.send(watch_event).await.is_ok(),
+            "WatchEvent receiver is closed"
+        );
     }
 }
 
@@ -104,6 +136,8 @@ where
 {
     /// Inner data
     inner: Arc<KvWatcherInner<S>>,
+    /// Sender of the progress requests, a request carries the event sender of the watch stream
+    progress_tx: mpsc::UnboundedSender<mpsc::Sender<WatchEvent>>,
 }
 
 /// KV watcher inner data
@@ -178,19 +212,47 @@ impl<S> KvWatcher<S>
 where
     S: StorageApi,
 {
-    /// New `KvWatcher`
+    /// New `KvWatcher`, the idle watchers which ask for the progress are notified every
+    /// `progress_notify_interval`
+    #[allow(clippy::integer_arithmetic)] // Introduced by tokio::select!
     pub(super) fn new(
         storage: Arc<KvStoreBackend<S>>,
         mut kv_update_rx: mpsc::Receiver<(i64, Vec<Event>)>,
+        progress_notify_interval: Duration,
     ) -> Self {
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+        // the updates and the progress are handled in the same task, so a progress is always
+        // sent after all the events up to its revision
+        let mut revision = storage.revision();
         let inner = Arc::new(KvWatcherInner::new(storage));
         let inner_clone = Arc::clone(&inner);
         let _handle = tokio::spawn(async move {
-            while let Some(updates) = kv_update_rx.recv().await {
-                inner_clone.handle_kv_updates(updates).await;
+            let mut ticker = tokio::time::interval(progress_notify_interval);
+            // watchers notified of any events since the last tick are not idle
+            let mut notified = HashSet::new();
+            loop {
+                tokio::select! {
+                    updates = kv_update_rx.recv() => {
+                        let Some(updates) = updates else {
+                            break;
+                        };
+                        revision = updates.0;
+                        notified.extend(inner_clone.handle_kv_updates(updates).await);
+                    }
+                    Some(event_tx) = progress_rx.recv() => {
+                        let watch_event = WatchEvent::progress(PROGRESS_WATCH_ID, revision);
+                        if event_tx.send(watch_event).await.is_err() {
+                            warn!("the watch stream is closed before the progress is sent");
+                        }
+                    }
+                    _ = ticker.tick() => {
+                        inner_clone.handle_progress_notify(revision, &notified).await;
+                        notified.clear();
+                    }
+                }
             }
         });
-        Self { inner }
+        Self { inner, progress_tx }
     }
 }
 
@@ -198,18 +260,28 @@ where
 #[allow(clippy::integer_arithmetic, clippy::indexing_slicing)] // Introduced by mockall::automock
 #[cfg_attr(test, mockall::automock)]
 pub(crate) trait KvWatcherOps {
-    /// Create a watch to KV store
+    /// Create a watch to KV store, return error if the start revision has been compacted
+    #[allow(clippy::too_many_arguments)] // the options of a watch
     fn watch(
         &self,
         id: WatchId,
         key_range: KeyRange,
         start_rev: i64,
         filters: Vec<i32>,
+        progress_notify: bool,
+        prev_kv: bool,
         event_tx: mpsc::Sender<WatchEvent>,
-    ) -> (Vec<Event>, i64);
+    ) -> Result<(Vec<Event>, i64), ExecuteError>;
 
     /// Cancel a watch from KV store
     fn cancel(&self, id: WatchId) -> i64;
+
+    /// Request a progress notification, it is sent through `event_tx` after all the events
+    /// up to its revision
+    fn request_progress(&self, event_tx: mpsc::Sender<WatchEvent>);
+
+    /// Get the compacted revision of KV store
+    fn compacted_revision(&self) -> i64;
 }
 
 impl<S> KvWatcherOps for KvWatcher<S>
@@ -217,22 +289,44 @@ where
     S: StorageApi,
 {
     /// Create a watch to KV store
+    #[allow(clippy::too_many_arguments)] // the options of a watch
     fn watch(
         &self,
         id: WatchId,
         key_range: KeyRange,
         start_rev: i64,
         filters: Vec<i32>,
+        progress_notify: bool,
+        prev_kv: bool,
         event_tx: mpsc::Sender<WatchEvent>,
-    ) -> (Vec<Event>, i64) {
-        self.inner
-            .watch(id, key_range, start_rev, filters, event_tx)
+    ) -> Result<(Vec<Event>, i64), ExecuteError> {
+        self.inner.watch(
+            id,
+            key_range,
+            start_rev,
+            filters,
+            progress_notify,
+            prev_kv,
+            event_tx,
+        )
     }
 
     /// Cancel a watch from KV store
     fn cancel(&self, id: WatchId) -> i64 {
         self.inner.cancel(id)
     }
+
+    /// Request a progress notification
+    fn request_progress(&self, event_tx: mpsc::Sender<WatchEvent>) {
+        if self.progress_tx.send(event_tx).is_err() {
+            warn!("failed to request progress, the KV watcher is stopped");
+        }
+    }
+
+    /// Get the compacted revision of KV store
+    fn compacted_revision(&self) -> i64 {
+        self.inner.storage.compacted_revision()
+    }
 }
 
 impl<S> KvWatcherInner<S>
@@ -247,16 +341,35 @@ where
         }
     }
 
-    /// Create a watch to KV store
+    /// Create a watch to KV store, the previous key-values of the initial events are looked up
+    /// if `prev_kv` is set
+    #[allow(clippy::too_many_arguments)] // the options of a watch
     fn watch(
         &self,
         id: WatchId,
         key_range: KeyRange,
         start_rev: i64,
         filters: Vec<i32>,
+        progress_notify: bool,
+        prev_kv: bool,
         event_tx: mpsc::Sender<WatchEvent>,
-    ) -> (Vec<Event>, i64) {
-        let watcher = Watcher::new(key_range.clone(), id, start_rev, filters, event_tx);
+    ) -> Result<(Vec<Event>, i64), ExecuteError> {
+        let compacted_revision = self.storage.compacted_revision();
+        if start_rev > 0 && start_rev < compacted_revision {
+            return Err(ExecuteError::revision_compacted(
+                start_rev,
+                compacted_revision,
+            ));
+        }
+        let watcher = Watcher::new(
+            key_range.clone(),
+            id,
+            start_rev,
+            filters,
+            progress_notify,
+            prev_kv,
+            event_tx,
+        );
 
         let revision = self.storage.revision();
         // TODO: handle racing that new event is generated before watcher is registered
@@ -264,7 +377,7 @@ where
             vec![]
         } else {
             self.storage
-                .get_event_from_revision(key_range, start_rev)
+                .get_event_from_revision(key_range, start_rev, prev_kv)
                 .unwrap_or_else(|e| {
                     warn!("failed to get initial events for watcher: {:?}", e);
                     vec![]
@@ -273,7 +386,7 @@ where
 
         self.watcher_map.write().insert(Arc::new(watcher));
 
-        (initial_events, revision)
+        Ok((initial_events, revision))
     }
 
     /// Cancel a watch from KV store
@@ -283,8 +396,8 @@ where
         revision
     }
 
-    /// Handle KV store updates
-    async fn handle_kv_updates(&self, (revision, all_events): (i64, Vec<Event>)) {
+    /// Handle KV store updates, return the watchers notified of any events
+    async fn handle_kv_updates(&self, (revision, all_events): (i64, Vec<Event>)) -> Vec<WatchId> {
         let watcher_events = self.watcher_map.map_read(|watcher_map_r| {
             let mut watcher_events: HashMap<Arc<Watcher>, Vec<Event>> = HashMap::new();
             for event in all_events {
@@ -315,9 +428,34 @@ where
             watcher_events
         });
 
-        let _ig = watcher_events
+        watcher_events
             .into_iter()
-            .map(|(watcher, events)| async move { watcher.notify((revision, events)).await })
+            .map(|(watcher, events)| async move {
+                watcher
+                    .notify((revision, events))
+                    .await
+                    .then(|| watcher.watch_id())
+            })
+            .collect::<FuturesUnordered<_>>()
+            .filter_map(future::ready)
+            .collect()
+            .await
+    }
+
+    /// Notify the progress at `revision` to the watchers which ask for it, except the ones in
+    /// `notified` which are not idle
+    async fn handle_progress_notify(&self, revision: i64, notified: &HashSet<WatchId>) {
+        let watchers: Vec<Arc<Watcher>> = self.watcher_map.map_read(|watcher_map_r| {
+            watcher_map_r
+                .watchers
+                .iter()
+                .filter(|&(id, _)| !notified.contains(id))
+                .map(|(_, watcher)| Arc::clone(watcher))
+                .collect()
+        });
+        let _ig = watchers
+            .iter()
+            .map(|watcher| watcher.notify_progress(revision))
             .collect::<FuturesUnordered<_>>()
             .collect::<Vec<_>>()
             .await;
@@ -333,9 +471,27 @@ pub(crate) struct WatchEvent {
     events: Vec<Event>,
     /// Revision when this event is generated
     revision: i64,
+    /// Whether it's a progress notification, which means all the events up to the revision
+    /// have been sent
+    progress: bool,
 }
 
 impl WatchEvent {
+    /// New progress notification at `revision`
+    fn progress(id: WatchId, revision: i64) -> Self {
+        Self {
+            id,
+            events: vec![],
+            revision,
+            progress: true,
+        }
+    }
+
+    /// Check if it's a progress notification
+    pub(crate) fn is_progress(&self) -> bool {
+        self.progress
+    }
+
     /// Get revision
     pub(crate) fn revision(&self) -> i64 {
         self.revision
@@ -351,3 +507,139 @@ impl WatchEvent {
         std::mem::take(&mut self.events)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use tokio::time::timeout;
+    use utils::config::StorageConfig;
+
+    use super::*;
+    use crate::{
+        header_gen::HeaderGenerator,
+        rpc::{EventType, KeyValue},
+        storage::{db::DBProxy, index::Index},
+    };
+
+    /// Timeout of receiving a watch event
+    const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn init_watcher(
+        progress_notify_interval: Duration,
+    ) -> Result<(KvWatcher<DBProxy>, mpsc::Sender<(i64, Vec<Event>)>), ExecuteError> {
+        let db = DBProxy::open(&StorageConfig::Memory)?;
+        let (kv_update_tx, kv_update_rx) = mpsc::channel(128);
+        let (lease_cmd_tx, _lease_cmd_rx) = mpsc::channel(128);
+        let storage = Arc::new(KvStoreBackend::new(
+            kv_update_tx.clone(),
+            lease_cmd_tx,
+            Arc::new(HeaderGenerator::new(0, 0)),
+            db,
+            Arc::new(Index::new()),
+        ));
+        let watcher = KvWatcher::new(storage, kv_update_rx, progress_notify_interval);
+        Ok((watcher, kv_update_tx))
+    }
+
+    fn put_event(key: &str, revision: i64) -> Event {
+        let mut event = Event {
+            kv: Some(KeyValue {
+                key: key.into(),
+                mod_revision: revision,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        event.set_type(EventType::Put);
+        event
+    }
+
+    fn key_range(key: &str) -> KeyRange {
+        KeyRange {
+            start: key.into(),
+            end: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prev_kv_is_only_sent_when_requested() -> Result<(), Box<dyn Error>> {
+        let (watcher, kv_update_tx) = init_watcher(Duration::from_secs(600))?;
+        let (prev_tx, mut prev_rx) = mpsc::channel(128);
+        let (no_prev_tx, mut no_prev_rx) = mpsc::channel(128);
+        let _ig = watcher.watch(1, key_range("foo"), 0, vec![], false, true, prev_tx)?;
+        let _ig = watcher.watch(2, key_range("foo"), 0, vec![], false, false, no_prev_tx)?;
+
+        let mut event = put_event("foo", 3);
+        event.prev_kv = Some(KeyValue {
+            key: "foo".into(),
+            mod_revision: 2,
+            ..Default::default()
+        });
+        kv_update_tx.send((3, vec![event])).await?;
+        let mut event = timeout(RECV_TIMEOUT, prev_rx.recv()).await?.unwrap();
+        assert_eq!(
+            event.take_events()[0]
+                .prev_kv
+                .as_ref()
+                .unwrap()
+                .mod_revision,
+            2
+        );
+        let mut event = timeout(RECV_TIMEOUT, no_prev_rx.recv()).await?.unwrap();
+        assert!(event.take_events()[0].prev_kv.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_progress_notify_to_idle_watchers() -> Result<(), Box<dyn Error>> {
+        let (watcher, kv_update_tx) = init_watcher(Duration::from_millis(100))?;
+        let (notify_tx, mut notify_rx) = mpsc::channel(128);
+        let (silent_tx, mut silent_rx) = mpsc::channel(128);
+        let _ig = watcher.watch(1, key_range("foo"), 0, vec![], true, false, notify_tx)?;
+        let _ig = watcher.watch(2, key_range("foo"), 0, vec![], false, false, silent_tx)?;
+
+        kv_update_tx.send((2, vec![put_event("foo", 2)])).await?;
+        for rx in [&mut notify_rx, &mut silent_rx] {
+            let event = timeout(RECV_TIMEOUT, rx.recv()).await?.unwrap();
+            assert!(!event.is_progress());
+            assert_eq!(event.revision(), 2);
+        }
+
+        let mut progress = timeout(RECV_TIMEOUT, notify_rx.recv()).await?.unwrap();
+        assert!(progress.is_progress());
+        assert_eq!(progress.watch_id(), 1);
+        assert_eq!(progress.revision(), 2);
+        assert!(progress.take_events().is_empty());
+        assert!(timeout(Duration::from_millis(300), silent_rx.recv())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_progress_request() -> Result<(), Box<dyn Error>> {
+        let (watcher, kv_update_tx) = init_watcher(Duration::from_secs(600))?;
+        let (event_tx, mut event_rx) = mpsc::channel(128);
+        let _ig = watcher.watch(
+            1,
+            key_range("foo"),
+            0,
+            vec![],
+            false,
+            false,
+            event_tx.clone(),
+        )?;
+
+        kv_update_tx.send((3, vec![put_event("foo", 3)])).await?;
+        let event = timeout(RECV_TIMEOUT, event_rx.recv()).await?.unwrap();
+        assert_eq!(event.revision(), 3);
+
+        watcher.request_progress(event_tx);
+        let progress = timeout(RECV_TIMEOUT, event_rx.recv()).await?.unwrap();
+        assert!(progress.is_progress());
+        assert_eq!(progress.watch_id(), PROGRESS_WATCH_ID);
+        assert_eq!(progress.revision(), 3);
+        Ok(())
+    }
+}
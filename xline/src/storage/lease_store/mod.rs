@@ -403,11 +403,11 @@ where
     /// Recover data form persistent storage
     fn recover_from_current_db(&self) -> Result<(), ExecuteError> {
         let leases = self.get_all()?;
+        let mut lease_collection = self.lease_collection.write();
+        // the leases before the storage is reset are dropped
+        *lease_collection = LeaseCollection::new();
         for lease in leases {
-            let _ignore = self
-                .lease_collection
-                .write()
-                .grant(lease.id, lease.ttl, false);
+            let _ignore = lease_collection.grant(lease.id, lease.ttl, false);
         }
         Ok(())
     }
@@ -1,3 +1,5 @@
+/// Storage for alarms
+pub(crate) mod alarm_store;
 /// Storage for Auth
 pub(crate) mod auth_store;
 /// Database module
@@ -18,6 +20,6 @@ pub(crate) mod revision;
 pub(crate) mod storage_api;
 
 pub(crate) use self::{
-    auth_store::AuthStore, execute_error::ExecuteError, kv_store::KvStore, lease_store::LeaseStore,
-    revision::Revision,
+    alarm_store::AlarmStore, auth_store::AuthStore, execute_error::ExecuteError, kv_store::KvStore,
+    lease_store::LeaseStore, revision::Revision,
 };
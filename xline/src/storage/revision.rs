@@ -14,7 +14,7 @@ pub(crate) struct KeyRevision {
 }
 
 /// Revision
-#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub struct Revision {
     /// Main revision
     revision: i64,
@@ -41,6 +41,27 @@ pub trait StorageApi: Send + Sync + 'static + std::fmt::Debug {
     /// if error occurs in storage, return `Err(error)`
     fn reset(&self) -> Result<(), ExecuteError>;
 
+    /// Take a snapshot of all the data in the storage
+    ///
+    /// # Errors
+    ///
+    /// if error occurs in storage, return `Err(error)`
+    fn snapshot(&self) -> Result<Vec<u8>, ExecuteError>;
+
+    /// Replace all the data in the storage with the snapshot
+    ///
+    /// # Errors
+    ///
+    /// if error occurs in storage or the snapshot is corrupted, return `Err(error)`
+    fn restore(&self, snapshot: &[u8]) -> Result<(), ExecuteError>;
+
+    /// Get the size of the storage in bytes
+    ///
+    /// # Errors
+    ///
+    /// if error occurs in storage, return `Err(error)`
+    fn size(&self) -> Result<u64, ExecuteError>;
+
     /// Put a write operation to the buffer
     fn buffer_op(&self, id: &ProposeId, op: WriteOp);
 
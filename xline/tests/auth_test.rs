@@ -2,10 +2,10 @@ mod common;
 
 use std::error::Error;
 
-use etcd_client::{AuthClient, ConnectOptions, GetOptions, Permission, PermissionType};
+use etcd_client::{ConnectOptions, GetOptions};
 use xline::client::kv_types::{PutRequest, RangeRequest};
 
-use crate::common::Cluster;
+use crate::common::{enable_auth, set_user, Cluster};
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
 async fn test_auth_empty_user_get() -> Result<(), Box<dyn Error>> {
@@ -195,31 +195,3 @@ async fn test_auth_wrong_password() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
-
-async fn set_user(
-    client: &mut AuthClient,
-    name: &str,
-    password: &str,
-    role: &str,
-    key: &[u8],
-    range_end: &[u8],
-) -> Result<(), Box<dyn Error>> {
-    client.user_add(name, password, None).await?;
-    client.role_add(role).await?;
-    client.user_grant_role(name, role).await?;
-    if !key.is_empty() {
-        client
-            .role_grant_permission(
-                role,
-                Permission::new(PermissionType::Readwrite, key).with_range_end(range_end),
-            )
-            .await?;
-    }
-    Ok(())
-}
-
-async fn enable_auth(client: &mut AuthClient) -> Result<(), Box<dyn Error>> {
-    set_user(client, "root", "123", "root", &[], &[]).await?;
-    client.auth_enable().await?;
-    Ok(())
-}
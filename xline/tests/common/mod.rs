@@ -1,5 +1,9 @@
-use std::collections::{BTreeMap, HashMap};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+};
 
+use etcd_client::{AuthClient, Permission, PermissionType};
 use jsonwebtoken::{DecodingKey, EncodingKey};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use tokio::{
@@ -7,44 +11,112 @@ use tokio::{
     sync::broadcast::{self, Sender},
     time::{self, Duration},
 };
-use utils::config::{ClientTimeout, CurpConfig, StorageConfig};
-use xline::{client::Client, server::XlineServer, storage::db::DBProxy};
+use tonic::transport::{ClientTlsConfig, ServerTlsConfig};
+use utils::config::{
+    default_quota_backend_bytes, ClientTimeout, ClusterConfig, CurpConfig, StorageConfig,
+};
+use xline::{
+    client::{tls::TlsOptions, Client},
+    server::{ServerTls, XlineServer},
+    storage::db::DBProxy,
+};
 
 /// Cluster
 pub struct Cluster {
     /// listeners of members
     listeners: BTreeMap<usize, TcpListener>,
+    /// client listeners of members, the clients are served by `listeners` if it is empty
+    client_listeners: BTreeMap<usize, TcpListener>,
     /// address of members
     all_members: HashMap<String, String>,
+    /// client address of members
+    client_addrs: HashMap<String, String>,
     /// Client of cluster
     client: Option<Client>,
     /// Stop sender
     stop_tx: Option<Sender<()>>,
     /// Cluster size
     size: usize,
+    /// Tls config of the members
+    server_tls_config: Option<ServerTlsConfig>,
+    /// Tls config used by the members to connect to each other
+    peer_tls_config: Option<ClientTlsConfig>,
+    /// Tls options of the client
+    client_tls_options: Option<TlsOptions>,
 }
 
 impl Cluster {
     /// New `Cluster`
     pub(crate) async fn new(size: usize) -> Self {
-        let mut listeners = BTreeMap::new();
-        for i in 0..size {
-            listeners.insert(i, TcpListener::bind("0.0.0.0:0").await.unwrap());
+        let listeners = Self::bind(size).await;
+        let all_members = Self::addrs_of(&listeners);
+
+        Self {
+            listeners,
+            client_listeners: BTreeMap::new(),
+            client_addrs: all_members.clone(),
+            all_members,
+            client: None,
+            stop_tx: None,
+            size,
+            server_tls_config: None,
+            peer_tls_config: None,
+            client_tls_options: None,
         }
-        let all_members: HashMap<String, String> = listeners
-            .iter()
-            .map(|(i, l)| (format!("server{}", i), l.local_addr().unwrap().to_string()))
-            .collect();
+    }
+
+    /// New `Cluster` whose members serve the clients and each other through tls
+    #[allow(dead_code)] // used in tests but get warning
+    pub(crate) async fn new_with_tls(
+        size: usize,
+        server_tls_config: ServerTlsConfig,
+        peer_tls_config: ClientTlsConfig,
+        client_tls_options: TlsOptions,
+    ) -> Self {
+        let mut cluster = Self::new(size).await;
+        cluster.server_tls_config = Some(server_tls_config);
+        cluster.peer_tls_config = Some(peer_tls_config);
+        cluster.client_tls_options = Some(client_tls_options);
+        cluster
+    }
+
+    /// New `Cluster` whose members serve the peers and the clients on different listeners
+    #[allow(dead_code)] // used in tests but get warning
+    pub(crate) async fn new_with_client_listeners(size: usize) -> Self {
+        let listeners = Self::bind(size).await;
+        let client_listeners = Self::bind(size).await;
+        let all_members = Self::addrs_of(&listeners);
+        let client_addrs = Self::addrs_of(&client_listeners);
 
         Self {
             listeners,
+            client_listeners,
             all_members,
+            client_addrs,
             client: None,
             stop_tx: None,
             size,
+            server_tls_config: None,
+            peer_tls_config: None,
+            client_tls_options: None,
         }
     }
 
+    async fn bind(size: usize) -> BTreeMap<usize, TcpListener> {
+        let mut listeners = BTreeMap::new();
+        for i in 0..size {
+            listeners.insert(i, TcpListener::bind("0.0.0.0:0").await.unwrap());
+        }
+        listeners
+    }
+
+    fn addrs_of(listeners: &BTreeMap<usize, TcpListener>) -> HashMap<String, String> {
+        listeners
+            .iter()
+            .map(|(i, l)| (format!("server{}", i), l.local_addr().unwrap().to_string()))
+            .collect()
+    }
+
     /// Start `Cluster`
     pub(crate) async fn start(&mut self) {
         let (stop_tx, _) = broadcast::channel(1);
@@ -54,27 +126,51 @@ impl Cluster {
             let is_leader = i == 0;
             let mut rx = stop_tx.subscribe();
             let listener = self.listeners.remove(&i).unwrap();
+            let client_listener = self.client_listeners.remove(&i);
+            let client_urls = client_listener
+                .as_ref()
+                .map(|_| vec![self.client_addrs[&name].clone()])
+                .unwrap_or_default();
             let all_members = self.all_members.clone();
+            let server_tls_config = self.server_tls_config.clone();
+            let peer_tls_config = self.peer_tls_config.clone();
             #[allow(clippy::unwrap_used)]
             let db = DBProxy::open(&StorageConfig::Memory).unwrap();
             tokio::spawn(async move {
-                let server = XlineServer::new(
+                let cluster_config = ClusterConfig::new(
                     name,
                     all_members,
                     is_leader,
-                    Self::test_key_pair(),
+                    false,
                     CurpConfig {
                         data_dir: format!("/tmp/curp-{}", random_id()).into(),
                         ..Default::default()
                     },
                     ClientTimeout::default(),
+                    vec![],
+                    vec![],
+                    vec![],
+                    client_urls,
+                );
+                let server = XlineServer::new(
+                    cluster_config,
+                    Self::test_key_pair(),
+                    None,
+                    default_quota_backend_bytes(),
                     db,
+                    ServerTls {
+                        server: server_tls_config.clone(),
+                        peer_server: server_tls_config,
+                        client: peer_tls_config,
+                    },
                 )
                 .await;
                 let signal = async {
                     let _ = rx.recv().await;
                 };
-                let result = server.start_from_listener_shutdown(listener, signal).await;
+                let result = server
+                    .start_from_listener_shutdown(listener, client_listener, None, signal)
+                    .await;
                 if let Err(e) = result {
                     panic!("Server start error: {e}");
                 }
@@ -88,18 +184,30 @@ impl Cluster {
     /// Create or get the client with the specified index
     pub(crate) async fn client(&mut self) -> &mut Client {
         if self.client.is_none() {
-            let client = Client::new(self.all_members.clone(), true, ClientTimeout::default())
-                .await
-                .unwrap_or_else(|e| {
-                    panic!("Client connect error: {:?}", e);
-                });
+            let client = Client::new(
+                self.client_addrs.clone(),
+                true,
+                ClientTimeout::default(),
+                self.client_tls_options.clone(),
+            )
+            .await
+            .unwrap_or_else(|e| {
+                panic!("Client connect error: {:?}", e);
+            });
             self.client = Some(client);
         }
         self.client.as_mut().unwrap()
     }
 
+    /// Client address of members
     #[allow(dead_code)] // used in tests but get warning
     pub fn addrs(&self) -> &HashMap<String, String> {
+        &self.client_addrs
+    }
+
+    /// Peer address of members
+    #[allow(dead_code)] // used in tests but get warning
+    pub fn peer_addrs(&self) -> &HashMap<String, String> {
         &self.all_members
     }
 
@@ -119,3 +227,35 @@ fn random_id() -> String {
         .map(char::from)
         .collect()
 }
+
+/// Add a user granted a role with the readwrite permission of the key range
+#[allow(dead_code)] // used in tests but get warning
+pub(crate) async fn set_user(
+    client: &mut AuthClient,
+    name: &str,
+    password: &str,
+    role: &str,
+    key: &[u8],
+    range_end: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    client.user_add(name, password, None).await?;
+    client.role_add(role).await?;
+    client.user_grant_role(name, role).await?;
+    if !key.is_empty() {
+        client
+            .role_grant_permission(
+                role,
+                Permission::new(PermissionType::Readwrite, key).with_range_end(range_end),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Add the root user and enable auth
+#[allow(dead_code)] // used in tests but get warning
+pub(crate) async fn enable_auth(client: &mut AuthClient) -> Result<(), Box<dyn Error>> {
+    set_user(client, "root", "123", "root", &[], &[]).await?;
+    client.auth_enable().await?;
+    Ok(())
+}
@@ -174,6 +174,25 @@ async fn test_range_redirect() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_kv_is_only_served_on_client_listeners() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new_with_client_listeners(3).await;
+    cluster.start().await;
+    let client_addr = cluster.addrs()["server1"].clone();
+    let peer_addr = cluster.peer_addrs()["server1"].clone();
+
+    let mut kv_client = Client::connect([client_addr], None).await?.kv_client();
+    let _ignore = kv_client.put("foo", "bar", None).await?;
+    let res = kv_client.get("foo", None).await?;
+    assert_eq!(res.kvs().len(), 1);
+    assert_eq!(res.kvs()[0].value(), b"bar");
+
+    let mut peer_kv_client = Client::connect([peer_addr], None).await?.kv_client();
+    assert!(peer_kv_client.get("foo", None).await.is_err());
+
+    Ok(())
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
 async fn test_kv_delete() -> Result<(), Box<dyn Error>> {
     struct TestCase<'a> {
@@ -73,3 +73,21 @@ async fn test_lease_keep_alive() -> Result<(), Box<dyn Error>> {
     assert_eq!(res.kvs.len(), 0);
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_lease_requests_are_forwarded_to_leader_peer_listener() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new_with_client_listeners(3).await;
+    cluster.start().await;
+    let non_leader_ep = cluster.addrs()["server1"].to_string();
+    let client = cluster.client().await;
+
+    let res = client.lease_grant(LeaseGrantRequest::new(60)).await?;
+    let lease_id = res.id;
+    assert!(lease_id > 0);
+
+    let mut c = etcd_client::Client::connect(vec![non_leader_ep], None).await?;
+    let res = c.lease_time_to_live(lease_id, None).await?;
+    assert_eq!(res.id(), lease_id);
+    assert!(res.ttl() > 0);
+    Ok(())
+}
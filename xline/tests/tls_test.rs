@@ -0,0 +1,192 @@
+mod common;
+
+use std::error::Error;
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use tonic::transport::{
+    Certificate as TonicCertificate, ClientTlsConfig, Identity, ServerTlsConfig,
+};
+use utils::config::ClientTimeout;
+use xline::client::{
+    kv_types::{PutRequest, RangeRequest},
+    tls::TlsOptions,
+    Client,
+};
+
+use crate::common::{enable_auth, set_user, Cluster};
+
+/// Domain name in the certificates of the servers, the members are addressed by ip
+const DOMAIN_NAME: &str = "xline.test";
+
+/// PEM encoded certificates and keys signed by a test CA
+struct TestCerts {
+    /// CA certificate
+    ca_cert: String,
+    /// Certificate of the servers
+    server_cert: String,
+    /// Private key of the servers
+    server_key: String,
+    /// Certificate of the clients
+    client_cert: String,
+    /// Private key of the clients
+    client_key: String,
+}
+
+impl TestCerts {
+    /// Generate a CA and the certificates signed by it
+    fn generate() -> Self {
+        Self::generate_with_client_name("client")
+    }
+
+    /// Generate a CA and the certificates signed by it, the common name of the client
+    /// certificate is `client_name`
+    fn generate_with_client_name(client_name: &str) -> Self {
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let server =
+            Certificate::from_params(CertificateParams::new(vec![DOMAIN_NAME.to_owned()])).unwrap();
+        let mut client_params = CertificateParams::new(vec![client_name.to_owned()]);
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, client_name);
+        let client = Certificate::from_params(client_params).unwrap();
+        Self {
+            ca_cert: ca.serialize_pem().unwrap(),
+            server_cert: server.serialize_pem_with_signer(&ca).unwrap(),
+            server_key: server.serialize_private_key_pem(),
+            client_cert: client.serialize_pem_with_signer(&ca).unwrap(),
+            client_key: client.serialize_private_key_pem(),
+        }
+    }
+
+    /// Tls config of the servers, clients and peers must present a certificate
+    fn server_tls_config(&self) -> ServerTlsConfig {
+        ServerTlsConfig::new()
+            .identity(Identity::from_pem(&self.server_cert, &self.server_key))
+            .client_ca_root(TonicCertificate::from_pem(&self.ca_cert))
+    }
+
+    /// Tls config used by the servers to connect to each other
+    fn peer_tls_config(&self) -> ClientTlsConfig {
+        ClientTlsConfig::new()
+            .ca_certificate(TonicCertificate::from_pem(&self.ca_cert))
+            .identity(Identity::from_pem(&self.client_cert, &self.client_key))
+            .domain_name(DOMAIN_NAME)
+    }
+
+    /// Tls options of a client without a certificate
+    fn anonymous_client_tls_options(&self) -> TlsOptions {
+        TlsOptions::new()
+            .with_ca_cert(self.ca_cert.as_bytes())
+            .with_domain_name(DOMAIN_NAME)
+    }
+
+    /// Tls options of a client presenting its certificate
+    fn client_tls_options(&self) -> TlsOptions {
+        self.anonymous_client_tls_options()
+            .with_identity(self.client_cert.as_bytes(), self.client_key.as_bytes())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_tls_client_and_peers() -> Result<(), Box<dyn Error>> {
+    let certs = TestCerts::generate();
+    let mut cluster = Cluster::new_with_tls(
+        3,
+        certs.server_tls_config(),
+        certs.peer_tls_config(),
+        certs.client_tls_options(),
+    )
+    .await;
+    cluster.start().await;
+    let client = cluster.client().await;
+
+    // the proposal is replicated to the other members through tls before it's committed
+    client.put(PutRequest::new("foo", "bar")).await?;
+    let res = client.range(RangeRequest::new("foo")).await?;
+    assert_eq!(res.kvs.len(), 1);
+    assert_eq!(res.kvs[0].value, b"bar");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_tls_client_without_certificate_will_be_rejected() -> Result<(), Box<dyn Error>> {
+    let certs = TestCerts::generate();
+    let mut cluster = Cluster::new_with_tls(
+        3,
+        certs.server_tls_config(),
+        certs.peer_tls_config(),
+        certs.client_tls_options(),
+    )
+    .await;
+    cluster.start().await;
+
+    let client = Client::new(
+        cluster.addrs().clone(),
+        false,
+        ClientTimeout::default(),
+        Some(certs.anonymous_client_tls_options()),
+    )
+    .await;
+    // the handshake may fail when connecting or on the first request
+    let res = match client {
+        Ok(mut client) => client.put(PutRequest::new("foo", "bar")).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_tls_client_is_authenticated_as_the_user_of_its_certificate(
+) -> Result<(), Box<dyn Error>> {
+    let certs = TestCerts::generate_with_client_name("u1");
+    let mut cluster = Cluster::new_with_tls(
+        3,
+        certs.server_tls_config(),
+        certs.peer_tls_config(),
+        certs.client_tls_options(),
+    )
+    .await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut auth_client = client.auth_client();
+    set_user(&mut auth_client, "u1", "123", "r1", b"foo", &[]).await?;
+    enable_auth(&mut auth_client).await?;
+
+    // the requests carry no token, the permissions of u1 are checked
+    let mut kv_client = client.kv_client();
+    kv_client.put("foo", "bar", None).await?;
+    let res = kv_client.get("foo", None).await?;
+    assert_eq!(res.kvs().len(), 1);
+    assert_eq!(res.kvs()[0].value(), b"bar");
+    assert!(kv_client.put("fop", "bar", None).await.is_err());
+    assert!(kv_client.get("fop", None).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_tls_client_of_unknown_user_will_be_rejected() -> Result<(), Box<dyn Error>> {
+    let certs = TestCerts::generate_with_client_name("unknown");
+    let mut cluster = Cluster::new_with_tls(
+        3,
+        certs.server_tls_config(),
+        certs.peer_tls_config(),
+        certs.client_tls_options(),
+    )
+    .await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut auth_client = client.auth_client();
+    enable_auth(&mut auth_client).await?;
+
+    let mut kv_client = client.kv_client();
+    assert!(kv_client.put("foo", "bar", None).await.is_err());
+    assert!(kv_client.get("foo", None).await.is_err());
+
+    Ok(())
+}
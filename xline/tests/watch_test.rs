@@ -2,7 +2,7 @@ mod common;
 
 use std::error::Error;
 
-use etcd_client::EventType;
+use etcd_client::{EventType, WatchOptions};
 use xline::client::kv_types::{DeleteRangeRequest, PutRequest};
 
 use crate::common::Cluster;
@@ -38,3 +38,60 @@ async fn test_watch() -> Result<(), Box<dyn Error>> {
     handle.await?;
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_watch_progress_request() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut watch_client = client.watch_client();
+
+    let (mut watcher, mut stream) = watch_client
+        .watch("foo", Some(WatchOptions::new().with_progress_notify()))
+        .await?;
+    client.put(PutRequest::new("foo", "bar")).await?;
+    let res = stream.message().await?.unwrap();
+    let revision = res.events().get(0).unwrap().kv().unwrap().mod_revision();
+
+    watcher.request_progress().await?;
+    let res = stream.message().await?.unwrap();
+    assert_eq!(res.watch_id(), -1);
+    assert!(res.events().is_empty());
+    assert_eq!(res.header().unwrap().revision(), revision);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+async fn test_watch_prev_kv() -> Result<(), Box<dyn Error>> {
+    let mut cluster = Cluster::new(3).await;
+    cluster.start().await;
+    let client = cluster.client().await;
+    let mut watch_client = client.watch_client();
+
+    let (_watcher, mut stream) = watch_client
+        .watch("foo", Some(WatchOptions::new().with_prev_key()))
+        .await?;
+    client.put(PutRequest::new("foo", "bar")).await?;
+    client.put(PutRequest::new("foo", "baz")).await?;
+    let res = stream.message().await?.unwrap();
+    let event = res.events().get(0).unwrap();
+    assert!(event.prev_kv().is_none());
+    let revision = event.kv().unwrap().mod_revision();
+    let res = stream.message().await?.unwrap();
+    let event = res.events().get(0).unwrap();
+    assert_eq!(event.prev_kv().unwrap().value(), b"bar");
+
+    // the history is sent with the previous key-values as well
+    let options = WatchOptions::new()
+        .with_prev_key()
+        .with_start_revision(revision);
+    let (_watcher, mut stream) = watch_client.watch("foo", Some(options)).await?;
+    let res = stream.message().await?.unwrap();
+    let prev_values: Vec<_> = res
+        .events()
+        .iter()
+        .map(|event| event.prev_kv().map(|kv| kv.value().to_vec()))
+        .collect();
+    assert_eq!(prev_values, vec![None, Some(b"bar".to_vec())]);
+    Ok(())
+}